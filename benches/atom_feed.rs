@@ -0,0 +1,125 @@
+//! Compares the allocations made while writing a 1000-row Atom feed into a buffer
+//! growing on demand and into one pre-allocated using the size estimate.
+//!
+//! Run with `cargo bench --bench atom_feed`
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use datafusion::arrow::{
+    array::{Float64Array, Int64Array, RecordBatch, StringArray},
+    datatypes::{DataType, Field, Schema},
+};
+use datafusion_odata::{
+    atom::{estimate_atom_feed_size, write_atom_feed_from_records},
+    collection::CollectionAddr,
+    session::ODataSessionContext,
+};
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+///////////////////////////////////////////////////////////////////////////////
+
+const ROWS: usize = 1000;
+const ITERATIONS: u32 = 100;
+
+fn batch() -> RecordBatch {
+    let offsets: Vec<i64> = (0..ROWS as i64).collect();
+    RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("__id__", DataType::Int64, false),
+            Field::new("symbol", DataType::Utf8, false),
+            Field::new("close", DataType::Float64, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(offsets.clone())),
+            Arc::new(Int64Array::from(offsets.clone())),
+            Arc::new(StringArray::from(vec!["SPY"; ROWS])),
+            Arc::new(Float64Array::from_iter_values(
+                offsets.iter().map(|o| 100.0 + *o as f64 / 8.0),
+            )),
+        ],
+    )
+    .unwrap()
+}
+
+fn run(name: &str, ctx: &ODataSessionContext, batch: &RecordBatch, capacity: usize) {
+    let updated_time = chrono::DateTime::from_timestamp(0, 0).unwrap();
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    for _ in 0..ITERATIONS {
+        let mut buf = Vec::with_capacity(capacity);
+        write_atom_feed_from_records(
+            &batch.schema(),
+            vec![batch.clone()],
+            ctx,
+            updated_time,
+            None,
+            None,
+            false,
+            &mut quick_xml::Writer::new(&mut buf),
+        )
+        .unwrap();
+        std::hint::black_box(buf);
+    }
+
+    let elapsed = start.elapsed() / ITERATIONS;
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / ITERATIONS as usize;
+    let allocated_bytes =
+        (ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated_bytes) / ITERATIONS as usize;
+    println!(
+        "{name:>13}: {elapsed:>10.2?}/feed, {allocations} allocations, {allocated_bytes} bytes"
+    );
+}
+
+fn main() {
+    let batch = batch();
+    let ctx =
+        ODataSessionContext::from_batches("http://example.com/odata/", [("spy", batch.clone())])
+            .unwrap()
+            .with_addr(CollectionAddr::decode("spy").unwrap());
+
+    run("growing", &ctx, &batch, 0);
+    run(
+        "pre-allocated",
+        &ctx,
+        &batch,
+        estimate_atom_feed_size(&batch.schema(), ROWS),
+    );
+}
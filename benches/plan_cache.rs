@@ -0,0 +1,80 @@
+//! Compares the time it takes to plan the same query on every request and to reuse the
+//! plan from the cache of the session.
+//!
+//! Run with `cargo bench --bench plan_cache`
+
+use std::time::Instant;
+
+use datafusion::prelude::*;
+use datafusion_odata::{
+    collection::{CollectionAddr, QueryParamsRaw},
+    context::CollectionContext,
+    session::ODataSessionContext,
+};
+
+///////////////////////////////////////////////////////////////////////////////
+
+const ITERATIONS: u32 = 1000;
+
+async fn session() -> ODataSessionContext {
+    let ctx = SessionContext::new();
+    ctx.register_parquet(
+        "tickers",
+        "examples/data/tickers.parquet",
+        ParquetReadOptions {
+            file_extension: ".parquet",
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    ODataSessionContext::new(ctx, "http://example.com/odata/")
+        .with_addr(CollectionAddr::decode("tickers").unwrap())
+}
+
+fn query() -> QueryParamsRaw {
+    QueryParamsRaw {
+        select: Some("offset,close".to_string()),
+        order_by: Some("close desc".to_string()),
+        skip: None,
+        skip_token: None,
+        top: Some(10),
+        filter: Some("close gt 130 and event_time lt now()".parse().unwrap()),
+        expand: None,
+        format: None,
+        count: Some(true),
+        inline_count: None,
+        apply: None,
+    }
+}
+
+async fn run(name: &str, ctx: &ODataSessionContext) {
+    let start = Instant::now();
+
+    // Planning only, which is the part the cache saves on
+    for _ in 0..ITERATIONS {
+        let applied = ctx
+            .query(
+                query().decode().unwrap(),
+                &ctx.apply_options().await.unwrap(),
+            )
+            .await
+            .unwrap();
+        std::hint::black_box(applied);
+    }
+
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!(
+        "{name:>8}: {elapsed:>10.2?}/query, {} hits",
+        ctx.plan_cache_hits()
+    );
+}
+
+fn main() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        run("uncached", &session().await).await;
+        run("cached", &session().await.with_plan_cache(16)).await;
+    });
+}
@@ -1,16 +1,17 @@
 use std::sync::Arc;
 
 use chrono::{DateTime, Utc};
-use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::datatypes::{DataType, SchemaRef};
+use datafusion::datasource::{file_format::parquet::ParquetFormat, listing::ListingOptions};
 use datafusion::{prelude::*, sql::TableReference};
 
-use axum::response::Response;
+use axum::{body::Body, response::Response};
 
 use datafusion_odata::{
-    collection::{CollectionAddr, QueryParams, QueryParamsRaw},
-    context::{CollectionContext, OnUnsupported, ServiceContext},
-    error::{CollectionNotFound, ODataError},
-    handlers::{MEDIA_TYPE_ATOM, MEDIA_TYPE_XML},
+    collection::{AppliedQuery, ApplyOptions, CollectionAddr, QueryParams, QueryParamsRaw},
+    context::{join_url, CollectionContext, OnUnsupported, ServiceContext},
+    error::ODataError,
+    media_type::{MEDIA_TYPE_ATOM, MEDIA_TYPE_XML},
 };
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -25,9 +26,10 @@ const DEFAULT_MAX_ROWS: usize = 100;
 pub async fn odata_service_handler(
     axum::extract::State(query_ctx): axum::extract::State<SessionContext>,
     host: axum::extract::Host,
+    headers: axum::http::HeaderMap,
 ) -> Result<Response<String>, ODataError> {
     let ctx = Arc::new(ODataContext::new_service(query_ctx, host));
-    datafusion_odata::handlers::odata_service_handler(axum::Extension(ctx)).await
+    datafusion_odata::handlers::odata_service_handler(axum::Extension(ctx), headers).await
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -48,13 +50,29 @@ pub async fn odata_collection_handler(
     axum::extract::Path(collection_path_element): axum::extract::Path<String>,
     query: axum::extract::Query<QueryParamsRaw>,
     headers: axum::http::HeaderMap,
+) -> Result<Response<Body>, ODataError> {
+    let addr = CollectionAddr::decode(&collection_path_element)?;
+
+    let ctx = Arc::new(ODataContext::new_collection(query_ctx, host, addr));
+    datafusion_odata::handlers::odata_collection_stream_handler(
+        axum::Extension(ctx),
+        query,
+        headers,
+    )
+    .await
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+pub async fn odata_collection_schema_handler(
+    axum::extract::State(query_ctx): axum::extract::State<SessionContext>,
+    host: axum::extract::Host,
+    axum::extract::Path(collection_path_element): axum::extract::Path<String>,
 ) -> Result<Response<String>, ODataError> {
-    let Some(addr) = CollectionAddr::decode(&collection_path_element) else {
-        Err(CollectionNotFound::new(collection_path_element))?
-    };
+    let addr = CollectionAddr::decode(&collection_path_element)?;
 
     let ctx = Arc::new(ODataContext::new_collection(query_ctx, host, addr));
-    datafusion_odata::handlers::odata_collection_handler(axum::Extension(ctx), query, headers).await
+    datafusion_odata::handlers::odata_collection_schema_handler(axum::Extension(ctx)).await
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -148,9 +166,7 @@ impl CollectionContext for ODataContext {
     }
 
     fn collection_base_url(&self) -> Result<String, ODataError> {
-        let service_base_url = &self.service_base_url;
-        let collection_name = self.collection_name()?;
-        Ok(format!("{service_base_url}{collection_name}"))
+        Ok(join_url(&self.service_base_url, &self.collection_name()?))
     }
 
     fn collection_name(&self) -> Result<String, ODataError> {
@@ -175,7 +191,11 @@ impl CollectionContext for ODataContext {
             .schema())
     }
 
-    async fn query(&self, query: QueryParams) -> Result<DataFrame, ODataError> {
+    async fn query(
+        &self,
+        query: QueryParams,
+        options: &ApplyOptions,
+    ) -> Result<AppliedQuery, ODataError> {
         let df = self
             .query_ctx
             .table(TableReference::bare(self.collection_name()?))
@@ -187,16 +207,7 @@ impl CollectionContext for ODataContext {
                 )
             })?;
 
-        query
-            .apply(
-                df,
-                self.addr()?,
-                "offset",
-                &self.key_column_alias(),
-                DEFAULT_MAX_ROWS,
-                usize::MAX,
-            )
-            .map_err(ODataError::internal)
+        query.apply(df, self.addr()?, options, DEFAULT_MAX_ROWS, usize::MAX)
     }
 
     fn on_unsupported_feature(&self) -> OnUnsupported {
@@ -237,6 +248,24 @@ async fn mock_odata_collection_handler() -> axum::response::Response<String> {
 
 ///////////////////////////////////////////////////////////////////////////////
 
+/// Registers a directory of parquet files with hive-style partitioning (`<col>=<value>/*.parquet`),
+/// partition columns become regular properties of the collection
+async fn register_partitioned_parquet(
+    ctx: &SessionContext,
+    table_name: &str,
+    path: &str,
+    partition_cols: Vec<(String, DataType)>,
+) -> datafusion::error::Result<()> {
+    let options = ListingOptions::new(Arc::new(ParquetFormat::default()))
+        .with_file_extension(".parquet")
+        .with_table_partition_cols(partition_cols);
+
+    ctx.register_listing_table(table_name, path, options, None, None)
+        .await
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt()
@@ -270,6 +299,23 @@ async fn main() {
     .await
     .unwrap();
 
+    // E.g. `PARTITIONED_DATA=path/to/dir/ PARTITION_COLS=region,year`
+    if let Ok(path) = std::env::var("PARTITIONED_DATA") {
+        let partition_cols = std::env::var("PARTITION_COLS").unwrap_or_default();
+        register_partitioned_parquet(
+            &ctx,
+            "partitioned",
+            &path,
+            partition_cols
+                .split(',')
+                .filter(|c| !c.is_empty())
+                .map(|c| (c.to_string(), DataType::Utf8))
+                .collect(),
+        )
+        .await
+        .unwrap();
+    }
+
     ///////////////////////////
 
     let app = axum::Router::new()
@@ -288,6 +334,10 @@ async fn main() {
         .route("/", axum::routing::get(odata_service_handler))
         .route("/$metadata", axum::routing::get(odata_metadata_handler))
         .route("/:collection", axum::routing::get(odata_collection_handler))
+        .route(
+            "/:collection/$schema",
+            axum::routing::get(odata_collection_schema_handler),
+        )
         .layer(tower_http::trace::TraceLayer::new_for_http())
         .layer(
             tower_http::cors::CorsLayer::new()
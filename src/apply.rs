@@ -0,0 +1,249 @@
+use datafusion::{
+    functions_aggregate::expr_fn::{avg, count, count_distinct, max, min, sum},
+    prelude::*,
+};
+
+use crate::error::{ODataError, UnsupportedFeature};
+
+///////////////////////////////////////////////////////////////////////////////
+
+// https://docs.oasis-open.org/odata/odata-data-aggregation-ext/v4.0/odata-data-aggregation-ext-v4.0.html
+//
+// $apply=groupby((province),aggregate(total_daily with sum as total))
+
+/// Aggregation transformation requested via `$apply`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Aggregation {
+    /// Columns to group by, the whole collection is aggregated when empty
+    pub group_by: Vec<String>,
+    pub aggregates: Vec<Aggregate>,
+}
+
+/// Single `<column> with <method> as <alias>` (or `$count as <alias>`) element
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Aggregate {
+    /// Aggregated column, `None` for `$count`
+    pub column: Option<String>,
+    pub method: AggregateMethod,
+    pub alias: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AggregateMethod {
+    Sum,
+    Min,
+    Max,
+    Average,
+    Count,
+    CountDistinct,
+}
+
+impl Aggregation {
+    pub fn decode(apply: &str) -> Result<Self, ODataError> {
+        let apply = apply.trim();
+        if split_top_level(apply, '/').len() > 1 {
+            Err(UnsupportedFeature::new("Chained transformations in $apply"))?
+        }
+
+        if let Some(args) = call_args(apply, "groupby") {
+            let args = split_top_level(args, ',');
+            let (group_by, aggregates) = match &args[..] {
+                [group_by] => (group_by, None),
+                [group_by, aggregates] => (group_by, Some(aggregates)),
+                _ => Err(invalid_apply(apply))?,
+            };
+
+            let group_by = group_by
+                .trim()
+                .strip_prefix('(')
+                .and_then(|s| s.strip_suffix(')'))
+                .ok_or_else(|| invalid_apply(apply))?;
+            let group_by: Vec<_> = group_by.split(',').map(|c| c.trim().to_string()).collect();
+            if group_by.iter().any(String::is_empty) {
+                Err(invalid_apply(apply))?
+            }
+
+            let aggregates = match aggregates {
+                Some(aggregates) => call_args(aggregates.trim(), "aggregate")
+                    .ok_or_else(|| invalid_apply(apply))
+                    .and_then(Aggregate::decode_list)?,
+                None => Vec::new(),
+            };
+
+            Ok(Self {
+                group_by,
+                aggregates,
+            })
+        } else if let Some(args) = call_args(apply, "aggregate") {
+            Ok(Self {
+                group_by: Vec::new(),
+                aggregates: Aggregate::decode_list(args)?,
+            })
+        } else {
+            let name = apply.split('(').next().unwrap_or(apply).trim();
+            Err(UnsupportedFeature::new(format!(
+                "Transformation {name}() in $apply"
+            )))?
+        }
+    }
+}
+
+impl Aggregate {
+    fn decode_list(aggregates: &str) -> Result<Vec<Self>, ODataError> {
+        split_top_level(aggregates, ',')
+            .into_iter()
+            .map(Self::decode)
+            .collect()
+    }
+
+    fn decode(aggregate: &str) -> Result<Self, ODataError> {
+        let invalid = || ODataError::bad_request(format!("Invalid aggregate: {aggregate}"));
+        let tokens: Vec<_> = aggregate.split_whitespace().collect();
+
+        match tokens[..] {
+            ["$count", "as", alias] => Ok(Self {
+                column: None,
+                method: AggregateMethod::Count,
+                alias: alias.to_string(),
+            }),
+            [column, "with", method, "as", alias] => Ok(Self {
+                column: Some(column.to_string()),
+                method: match method {
+                    "sum" => AggregateMethod::Sum,
+                    "min" => AggregateMethod::Min,
+                    "max" => AggregateMethod::Max,
+                    "average" | "avg" => AggregateMethod::Average,
+                    "count" => AggregateMethod::Count,
+                    "countdistinct" => AggregateMethod::CountDistinct,
+                    _ => Err(invalid())?,
+                },
+                alias: alias.to_string(),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    pub(crate) fn to_expr(&self) -> Expr {
+        let column = match &self.column {
+            Some(c) => col(Column::new_unqualified(c)),
+            None => lit(1),
+        };
+        let expr = match self.method {
+            AggregateMethod::Sum => sum(column),
+            AggregateMethod::Min => min(column),
+            AggregateMethod::Max => max(column),
+            AggregateMethod::Average => avg(column),
+            AggregateMethod::Count => count(column),
+            AggregateMethod::CountDistinct => count_distinct(column),
+        };
+        expr.alias(&self.alias)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn invalid_apply(apply: &str) -> ODataError {
+    ODataError::bad_request(format!("Invalid $apply: {apply}"))
+}
+
+/// Arguments of the `name(...)` call, if the string is one
+fn call_args<'a>(s: &'a str, name: &str) -> Option<&'a str> {
+    s.strip_prefix(name)?
+        .trim_start()
+        .strip_prefix('(')?
+        .strip_suffix(')')
+}
+
+/// Splits the string by the separator, ignoring the ones within parentheses
+fn split_top_level(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            c if c == sep && depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregation_decode() {
+        assert_eq!(
+            Aggregation::decode(
+                "groupby((province, op),aggregate(total_daily with sum as total, $count as n))"
+            )
+            .unwrap(),
+            Aggregation {
+                group_by: vec!["province".to_string(), "op".to_string()],
+                aggregates: vec![
+                    Aggregate {
+                        column: Some("total_daily".to_string()),
+                        method: AggregateMethod::Sum,
+                        alias: "total".to_string(),
+                    },
+                    Aggregate {
+                        column: None,
+                        method: AggregateMethod::Count,
+                        alias: "n".to_string(),
+                    },
+                ],
+            }
+        );
+
+        assert_eq!(
+            Aggregation::decode("groupby((province))").unwrap(),
+            Aggregation {
+                group_by: vec!["province".to_string()],
+                aggregates: vec![],
+            }
+        );
+
+        assert_eq!(
+            Aggregation::decode("aggregate(close with average as avg_close)").unwrap(),
+            Aggregation {
+                group_by: vec![],
+                aggregates: vec![Aggregate {
+                    column: Some("close".to_string()),
+                    method: AggregateMethod::Average,
+                    alias: "avg_close".to_string(),
+                }],
+            }
+        );
+
+        for invalid in [
+            "groupby(province)",
+            "groupby((),aggregate(x with sum as y))",
+            "groupby((a),aggregate(x with median as y))",
+            "groupby((a),aggregate(x as y))",
+        ] {
+            assert!(
+                matches!(Aggregation::decode(invalid), Err(ODataError::BadRequest(_))),
+                "{invalid}"
+            );
+        }
+
+        for unsupported in ["filter(a eq 1)", "filter(a eq 1)/groupby((a))"] {
+            assert!(
+                matches!(
+                    Aggregation::decode(unsupported),
+                    Err(ODataError::UnsupportedFeature(_))
+                ),
+                "{unsupported}"
+            );
+        }
+    }
+}
@@ -1,38 +1,210 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use chrono::{DateTime, Utc};
 use datafusion::arrow::{
     array::{Array, AsArray, RecordBatch},
     datatypes::{DataType, *},
+    util::display::array_value_to_string,
 };
 use quick_xml::events::*;
 
 use crate::{
-    context::{CollectionContext, OnUnsupported},
-    error::{ODataError, UnsupportedDataType, UnsupportedNetProtocol},
-    metadata::to_edm_type,
+    collection::{AppliedQuery, ApplyOptions, CollectionAddr, KeyValue, QueryParams},
+    context::{
+        is_groups_schema, join_url, key_column_from_metadata, CollectionContext,
+        NavigationPropertyDeclaration, OnInvalidXmlChars, OnNullKey, OnUnsupported,
+        PropertyNameMapper, PropertyOverride, DEFAULT_NAMESPACE,
+    },
+    error::{
+        BadRequest, KeyColumnNotAssigned, ODataError, UnsupportedDataType, UnsupportedNetProtocol,
+    },
+    metadata::{to_edm_type, EDM_STRING_FALLBACK},
+    spatial::is_wkb_point,
 };
 
+/// Default namespace of Atom documents
+pub const NS_ATOM: &str = "http://www.w3.org/2005/Atom";
+/// Namespace of entity properties
+pub const NS_DATA_SERVICES: &str = "http://schemas.microsoft.com/ado/2007/08/dataservices";
+/// Namespace of OData-specific elements and attributes
+pub const NS_METADATA: &str = "http://schemas.microsoft.com/ado/2007/08/dataservices/metadata";
+/// Scheme of the entry `<category>` element
+pub const NS_SCHEME: &str = "http://schemas.microsoft.com/ado/2007/08/dataservices/scheme";
+/// Prefix of the `rel` of the entry links to the related entities, followed by the name of
+/// the navigation property
+pub const NS_RELATED: &str = "http://schemas.microsoft.com/ado/2007/08/dataservices/related/";
+
+/// Namespace declarations of the root element of feed and entry documents, binding the
+/// `d:` and `m:` prefixes used by the elements
+pub const ATOM_NAMESPACE_DECLARATIONS: [(&str, &str); 3] = [
+    ("xmlns", NS_ATOM),
+    ("xmlns:d", NS_DATA_SERVICES),
+    ("xmlns:m", NS_METADATA),
+];
+
+///////////////////////////////////////////////////////////////////////////////
+
 // TODO: Replace with an interface similar to Encoder
 // See: https://github.com/kamu-data/kamu-cli/blob/385bbf56036d4485efdf54bf458a95bfba048b2b/src/utils/data-utils/src/data/format/traits.rs#L69
-struct Edm {
-    typ: String,
-    tag: String,
+pub(crate) struct Edm {
+    pub(crate) name: String,
+    pub(crate) typ: String,
+    pub(crate) tag: String,
+    pub(crate) encoding: EdmEncoding,
+    /// Set once a value out of the advertised range was reported, see [`Edm::check_int64_range`]
+    exceeds_int64: AtomicBool,
+    /// Set once a value that is not a point was reported, see [`Edm::invalid_geometry`]
+    invalid_geometry: AtomicBool,
+}
+
+/// How values of a column are rendered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EdmEncoding {
+    /// Native representation of the EDM type
+    Primitive,
+    /// Text representation of an unsupported type (see [`OnUnsupported::Stringify`])
+    Stringify,
+    /// Nulls in place of an unsupported type (see [`OnUnsupported::Null`])
+    Null,
+    /// Binary WKB declared as a spatial point (see [`crate::spatial::is_wkb_point`])
+    WkbPoint,
 }
 
 impl Edm {
     fn from_field(field: &Arc<Field>) -> Result<Self, UnsupportedDataType> {
+        let typ = to_edm_type(field.data_type())?;
+        Ok(Self::new(field, typ, EdmEncoding::Primitive))
+    }
+
+    fn new(field: &Arc<Field>, typ: &str, encoding: EdmEncoding) -> Self {
         // TODO: Escape field name
-        let tag = format!("d:{}", field.name());
-        let typ = to_edm_type(field.data_type())?.to_string();
-        Ok(Self { typ, tag })
+        let name = field.name().clone();
+        let tag = format!("d:{name}");
+        Self {
+            name,
+            typ: typ.to_string(),
+            tag,
+            encoding,
+            exceeds_int64: AtomicBool::new(false),
+            invalid_geometry: AtomicBool::new(false),
+        }
+    }
+
+    /// Resolves the EDM type of the field, declared by its [`PropertyOverride::edm_type`]
+    /// or derived from its arrow type, or `None` if the field is skipped as unsupported
+    pub(crate) fn resolve(
+        field: &Arc<Field>,
+        override_type: Option<&str>,
+        on_unsupported: OnUnsupported,
+    ) -> Result<Option<Self>, UnsupportedDataType> {
+        let mut edm = match (Self::from_field(field), override_type) {
+            (_, Some(typ)) if is_wkb_point(field.data_type(), override_type) => {
+                Self::new(field, typ, EdmEncoding::WkbPoint)
+            }
+            (Ok(edm), _) => edm,
+            (Err(err), _) => match on_unsupported {
+                OnUnsupported::Error => return Err(err),
+                OnUnsupported::Warn => {
+                    tracing::warn!(
+                        field = field.name(),
+                        error = %err,
+                        error_dbg = ?err,
+                        "Unsupported field type - skipping",
+                    );
+                    return Ok(None);
+                }
+                OnUnsupported::Stringify => {
+                    Self::new(field, EDM_STRING_FALLBACK, EdmEncoding::Stringify)
+                }
+                OnUnsupported::Null => Self::new(field, EDM_STRING_FALLBACK, EdmEncoding::Null),
+            },
+        };
+        if let Some(typ) = override_type {
+            edm.typ = typ.to_string();
+        }
+        Ok(Some(edm))
+    }
+
+    fn rename(&mut self, name: String) {
+        self.tag = format!("d:{name}");
+        self.name = name;
+    }
+
+    /// Whether the value is rendered as null, see [`EdmEncoding::Null`]
+    fn is_null(&self, col: &Arc<dyn Array>, row: usize) -> bool {
+        self.encoding == EdmEncoding::Null || col.is_null(row)
+    }
+
+    /// `UInt64` columns are advertised as `Edm.Int64` (see [`crate::metadata::to_edm_type`]),
+    /// so larger values are still rendered exactly but may be rejected by strict clients.
+    ///
+    /// Reported once per property, as a column rarely has a single such value.
+    pub(crate) fn check_int64_range(&self, col: &Arc<dyn Array>, row: usize) {
+        let Some(values) = col.as_primitive_opt::<UInt64Type>() else {
+            return;
+        };
+        if col.is_null(row) || i64::try_from(values.value(row)).is_ok() {
+            return;
+        }
+        if !self.exceeds_int64.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                property = self.name,
+                value = values.value(row),
+                "UInt64 value exceeds the Edm.Int64 range - consider overriding the property type",
+            );
+        }
+    }
+
+    /// Values that are not WKB points are rendered as nulls rather than failing the whole
+    /// response, reported once per property
+    pub(crate) fn invalid_geometry(&self) {
+        if !self.invalid_geometry.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                property = self.name,
+                "Value is not a valid WKB point - rendering as null",
+            );
+        }
+    }
+
+    pub(crate) fn encode<'a>(
+        &self,
+        col: &'a Arc<dyn Array>,
+        row: usize,
+    ) -> Result<BytesText<'a>, UnsupportedDataType> {
+        match self.encoding {
+            EdmEncoding::Primitive => {
+                self.check_int64_range(col, row);
+                encode_primitive_dyn(col, row)
+            }
+            EdmEncoding::Stringify => encode_stringified(col, row),
+            EdmEncoding::Null => Ok(BytesText::new("null")),
+            EdmEncoding::WkbPoint if col.is_null(row) => Ok(BytesText::new("null")),
+            EdmEncoding::WkbPoint => match crate::spatial::encode_gml_point(col, row, &self.typ) {
+                Some(gml) => Ok(BytesText::from_escaped(gml)),
+                None => {
+                    self.invalid_geometry();
+                    Ok(BytesText::new("null"))
+                }
+            },
+        }
     }
 }
 
-fn to_edms(
+/// Returns the properties of the entity along with the index of the key column, which is
+/// not a property itself when it is synthetic (see [`CollectionContext::synthetic_key_column`])
+pub(crate) fn to_edms(
     schema: &Schema,
     key_column: &str,
+    synthetic_key_column: bool,
     on_unsupported: OnUnsupported,
+    overrides: &HashMap<String, PropertyOverride>,
+    property_name_mapper: Option<&PropertyNameMapper>,
 ) -> Result<(Vec<(Edm, usize)>, usize), UnsupportedDataType> {
     let mut edms = Vec::new();
     let mut key_edm_index = usize::MAX;
@@ -40,29 +212,86 @@ fn to_edms(
     for (index, field) in schema.fields().iter().enumerate() {
         if field.name() == key_column {
             key_edm_index = index;
-            continue;
+            if synthetic_key_column {
+                continue;
+            }
         }
-        let edm = match Edm::from_field(field) {
-            Ok(typ) => typ,
-            Err(err) => match on_unsupported {
-                OnUnsupported::Error => return Err(err),
-                OnUnsupported::Warn => {
-                    tracing::warn!(
-                        field = field.name(),
-                        error = %err,
-                        error_dbg = ?err,
-                        "Unsupported field type - skipping",
-                    );
-                    continue;
-                }
-            },
+        let field_override = overrides.get(field.name());
+        let override_type = field_override.and_then(|o| o.edm_type.as_deref());
+
+        let Some(mut edm) = Edm::resolve(field, override_type, on_unsupported)? else {
+            continue;
         };
+        if let Some(mapper) = property_name_mapper {
+            edm.rename(mapper(field.name()));
+        }
 
         edms.push((edm, index));
     }
     Ok((edms, key_edm_index))
 }
 
+/// Batches normally share the schema of the query, but a table that is re-registered with a
+/// different schema (e.g. a live-updating data lake) may produce batches with columns added,
+/// removed or changed in the meantime.
+///
+/// Such columns are matched by name, properties missing from the batch or of a different type
+/// are skipped with a warning, and the columns unknown to the schema are ignored.
+fn batch_columns(
+    schema: &Schema,
+    batch: &RecordBatch,
+    edms: &[(Edm, usize)],
+) -> Vec<Option<Arc<dyn Array>>> {
+    if batch.schema().fields() == schema.fields() {
+        return edms
+            .iter()
+            .map(|(_, index)| Some(batch.column(*index).clone()))
+            .collect();
+    }
+
+    edms.iter()
+        .map(|(edm, index)| {
+            let field = schema.field(*index);
+            let col = batch_column(batch, field).cloned();
+            if col.is_none() {
+                tracing::warn!(
+                    field = field.name(),
+                    property = edm.name,
+                    "Column of the feed schema is missing from the record batch - skipping",
+                );
+            }
+            col
+        })
+        .collect()
+}
+
+fn batch_column<'a>(batch: &'a RecordBatch, field: &Field) -> Option<&'a Arc<dyn Array>> {
+    let col = batch.column_by_name(field.name())?;
+    (col.data_type() == field.data_type()).then_some(col)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Estimated size of the feed envelope, i.e. the XML declaration, `<feed>` and its links
+const FEED_OVERHEAD: usize = 1024;
+/// Estimated size of the `<entry>` elements except for the properties: id, links, category etc.
+const ENTRY_OVERHEAD: usize = 512;
+/// Estimated size of a property except for its name: the `d:` prefix, `m:type` and the value
+const PROPERTY_OVERHEAD: usize = 48;
+
+/// Approximate size of the Atom feed containing `num_rows` entries of the given schema,
+/// used to pre-allocate the response buffer to avoid growing it repeatedly while writing.
+///
+/// The names of the properties are counted twice, as they appear in the start and end tags.
+pub fn estimate_atom_feed_size(schema: &Schema, num_rows: usize) -> usize {
+    let properties_size: usize = schema
+        .fields()
+        .iter()
+        .map(|f| 2 * f.name().len() + PROPERTY_OVERHEAD)
+        .sum();
+    FEED_OVERHEAD + num_rows * (ENTRY_OVERHEAD + properties_size)
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 
 // https://www.odata.org/documentation/odata-version-3-0/atom-format/
@@ -77,7 +306,7 @@ fn to_edms(
 //   <id>http://a5d4b8ec90d5144a08efb47e789d49d5-1706314482.us-west-2.elb.amazonaws.com/tickers_spy/</id>
 //   <title type="text">tickers_spy</title>
 //   <updated>2024-03-10T00:36:45Z</updated>
-//   <link rel="self" title="tickers_spy" href="tickers_spy" />
+//   <link rel="self" title="tickers_spy" href="http://a5d4b8ec90d5144a08efb47e789d49d5-1706314482.us-west-2.elb.amazonaws.com/tickers_spy" />
 //
 //   <entry>
 //     <id>http://a5d4b8ec90d5144a08efb47e789d49d5-1706314482.us-west-2.elb.amazonaws.com/tickers_spy(0)</id>
@@ -118,106 +347,240 @@ fn to_edms(
 // </feed>
 //
 // TODO: Use erased dyn Writer type
-// TODO: Extract `CollectionInfo` type to avoid propagating
-//       a bunch of individual parameters
+#[allow(clippy::too_many_arguments)]
 pub fn write_atom_feed_from_records<W>(
     schema: &Schema,
     record_batches: Vec<RecordBatch>,
     ctx: &dyn CollectionContext,
     updated_time: DateTime<Utc>,
+    count: Option<usize>,
+    next_link: Option<&str>,
+    omit_nulls: bool,
     writer: &mut quick_xml::Writer<W>,
 ) -> Result<(), ODataError>
 where
     W: std::io::Write,
 {
-    let mut service_base_url = ctx.service_base_url()?;
-    let mut collection_base_url = ctx.collection_base_url()?;
-    let collection_name = ctx.collection_name()?;
-    let type_name = ctx.collection_name()?;
-    let type_namespace = ctx.collection_namespace()?;
-
-    if !service_base_url.starts_with("http") {
-        return Err(UnsupportedNetProtocol::new(service_base_url).into());
-    }
-    if !collection_base_url.starts_with("http") {
-        return Err(UnsupportedNetProtocol::new(collection_base_url).into());
+    let mut feed = AtomFeedWriter::begin(schema, ctx, updated_time, count, omit_nulls, writer)?;
+    for batch in &record_batches {
+        feed.write_batch(batch)?;
     }
+    feed.finish(next_link)
+}
 
-    if !service_base_url.ends_with('/') {
-        service_base_url.push('/');
-    }
-    if collection_base_url.ends_with('/') {
-        collection_base_url.pop();
-    }
+/// Writes an Atom feed incrementally: the feed header and `<m:count>` on
+/// [`AtomFeedWriter::begin`], the entries of every batch on [`AtomFeedWriter::write_batch`],
+/// and the optional `<link rel="next">` along with the closing tag on [`AtomFeedWriter::finish`]
+pub struct AtomFeedWriter<'a, W: std::io::Write> {
+    schema: Schema,
+    writer: &'a mut quick_xml::Writer<W>,
+    collection_base_url: String,
+    collection_name: String,
+    category_scheme: String,
+    category_term: String,
+    entry_link_rel: &'static str,
+    edms: Vec<(Edm, usize)>,
+    key_edm_index: usize,
+    on_invalid_chars: OnInvalidXmlChars,
+    on_null_key: OnNullKey,
+    composite_key: Vec<(String, String)>,
+    updated_column: Option<String>,
+    etag_column: Option<String>,
+    navigation_properties: Vec<NavigationPropertyDeclaration>,
+    /// Groups of an aggregation are not addressable, so their entries have no links
+    groups: bool,
+    updated_time: DateTime<Utc>,
+    omit_nulls: bool,
+    flush_interval: Option<usize>,
+    unflushed: usize,
+    skipped: usize,
+}
 
-    let fq_type = format!("{type_namespace}.{type_name}");
+impl<'a, W> AtomFeedWriter<'a, W>
+where
+    W: std::io::Write,
+{
+    pub fn begin(
+        schema: &Schema,
+        ctx: &dyn CollectionContext,
+        updated_time: DateTime<Utc>,
+        count: Option<usize>,
+        omit_nulls: bool,
+        writer: &'a mut quick_xml::Writer<W>,
+    ) -> Result<Self, ODataError> {
+        let mut service_base_url = ctx.service_base_url()?;
+        let mut collection_base_url = ctx.collection_base_url()?;
+        let collection_name = ctx.collection_name()?;
 
-    let (edms, key_edm_index) = to_edms(
-        schema,
-        &ctx.key_column_alias(),
-        ctx.on_unsupported_feature(),
-    )?;
+        if !service_base_url.starts_with("http") {
+            return Err(UnsupportedNetProtocol::new(service_base_url).into());
+        }
+        if !collection_base_url.starts_with("http") {
+            return Err(UnsupportedNetProtocol::new(collection_base_url).into());
+        }
 
-    writer.write_event(quick_xml::events::Event::Decl(BytesDecl::new(
-        "1.0",
-        Some("utf-8"),
-        None,
-    )))?;
+        if !service_base_url.ends_with('/') {
+            service_base_url.push('/');
+        }
+        if collection_base_url.ends_with('/') {
+            collection_base_url.pop();
+        }
 
-    let mut feed = BytesStart::new("feed");
-    feed.push_attribute(("xml:base", service_base_url.as_str()));
-    feed.push_attribute(("xmlns", "http://www.w3.org/2005/Atom"));
-    feed.push_attribute((
-        "xmlns:d",
-        "http://schemas.microsoft.com/ado/2007/08/dataservices",
-    ));
-    feed.push_attribute((
-        "xmlns:m",
-        "http://schemas.microsoft.com/ado/2007/08/dataservices/metadata",
-    ));
-
-    writer.write_event(Event::Start(feed))?;
-
-    // <id>http://a5d4b8ec90d5144a08efb47e789d49d5-1706314482.us-west-2.elb.amazonaws.com/tickers_spy/</id>
-    // <title type="text">tickers_spy</title>
-    // <updated>2024-03-10T00:36:45Z</updated>
-    // <link rel="self" title="tickers_spy" href="tickers_spy" />
-    writer
-        .create_element("id")
-        .write_text_content(BytesText::from_escaped(&collection_base_url))?;
-    writer
-        .create_element("title")
-        .with_attribute(("type", "text"))
-        .write_text_content(BytesText::from_escaped(&collection_name))?;
-    writer
-        .create_element("updated")
-        .write_text_content(encode_date_time(&updated_time))?;
-    writer
-        .create_element("link")
-        .with_attributes([
-            ("rel", "self"),
-            ("title", collection_name.as_str()),
-            ("href", collection_name.as_str()),
-        ])
-        .write_empty()?;
+        // The ordinal of a group is its only declared property
+        let groups = is_groups_schema(schema);
+        let (edms, key_edm_index) = to_edms(
+            schema,
+            &ctx.id_column()?,
+            ctx.synthetic_key_column() && !groups,
+            ctx.on_unsupported_feature(),
+            &ctx.property_overrides(),
+            ctx.property_name_mapper().as_ref(),
+        )?;
+
+        writer.write_event(quick_xml::events::Event::Decl(BytesDecl::new(
+            "1.0",
+            Some("utf-8"),
+            None,
+        )))?;
+
+        writer.write_event(Event::Start(root_element("feed", &service_base_url)))?;
+
+        // <id>http://a5d4b8ec90d5144a08efb47e789d49d5-1706314482.us-west-2.elb.amazonaws.com/tickers_spy/</id>
+        // <title type="text">tickers_spy</title>
+        // <updated>2024-03-10T00:36:45Z</updated>
+        // <link rel="self" title="tickers_spy" href="http://a5d4b8ec90d5144a08efb47e789d49d5-1706314482.us-west-2.elb.amazonaws.com/tickers_spy" />
+        writer
+            .create_element("id")
+            .write_text_content(BytesText::from_escaped(&collection_base_url))?;
+        writer
+            .create_element("title")
+            .with_attribute(("type", "text"))
+            .write_text_content(BytesText::from_escaped(&collection_name))?;
+        writer
+            .create_element("updated")
+            .write_text_content(encode_date_time(&updated_time))?;
+        writer
+            .create_element("link")
+            .with_attributes([
+                ("rel", "self"),
+                ("title", collection_name.as_str()),
+                // Same absolute form as the feed id, entries keep relative links resolved via `xml:base`
+                ("href", collection_base_url.as_str()),
+            ])
+            .write_empty()?;
+
+        // <m:count>1</m:count>
+        if let Some(count) = count {
+            writer
+                .create_element("m:count")
+                .write_text_content(BytesText::from_escaped(count.to_string()))?;
+        }
+
+        Ok(Self {
+            schema: schema.clone(),
+            writer,
+            collection_base_url,
+            collection_name,
+            category_scheme: ctx.category_scheme(),
+            category_term: if groups {
+                format!("{}.{}", ctx.collection_namespace()?, ctx.group_type_name()?)
+            } else {
+                ctx.category_term()?
+            },
+            entry_link_rel: if ctx.is_writable() { "edit" } else { "self" },
+            edms,
+            key_edm_index,
+            on_invalid_chars: ctx.on_invalid_xml_chars(),
+            on_null_key: ctx.on_null_key(),
+            composite_key: if groups {
+                Vec::new()
+            } else {
+                composite_key_columns(ctx)
+            },
+            updated_column: ctx.updated_column(),
+            etag_column: ctx.etag_column(),
+            navigation_properties: ctx.navigation_properties(),
+            groups,
+            updated_time,
+            omit_nulls,
+            flush_interval: None,
+            unflushed: 0,
+            skipped: 0,
+        })
+    }
+
+    /// Flushes the underlying writer after every `rows` entries, so that a streamed response
+    /// reaches the client incrementally
+    pub fn with_flush_interval(self, rows: Option<usize>) -> Self {
+        Self {
+            flush_interval: rows.filter(|rows| *rows > 0),
+            ..self
+        }
+    }
+
+    /// Underlying writer, e.g. to pass on what was written so far
+    pub fn get_mut(&mut self) -> &mut W {
+        self.writer.get_mut()
+    }
+
+    pub fn write_batch(&mut self, batch: &RecordBatch) -> Result<(), ODataError> {
+        let writer = &mut *self.writer;
+        let columns = batch_columns(&self.schema, batch, &self.edms);
+        let key_field = self
+            .schema
+            .fields()
+            .get(self.key_edm_index)
+            .ok_or(KeyColumnNotAssigned)?;
+        let key_col = batch
+            .column_by_name(key_field.name())
+            .ok_or(KeyColumnNotAssigned)?;
+        // Keys of another type can't be told apart from the ones of the feed
+        if key_col.data_type() != key_field.data_type() {
+            Err(BadRequest::new(format!(
+                "Key column {} is of type {} rather than {}",
+                key_field.name(),
+                key_col.data_type(),
+                key_field.data_type(),
+            )))?
+        }
+        let updated_col = self
+            .updated_column
+            .as_ref()
+            .and_then(|c| batch.column_by_name(c));
+        let etag_col = self
+            .etag_column
+            .as_ref()
+            .and_then(|c| batch.column_by_name(c));
 
-    for batch in record_batches {
         for row in 0..batch.num_rows() {
-            writer.write_event(Event::Start(BytesStart::new("entry")))?;
+            let Some(id) =
+                encode_entity_key(batch, key_col, &self.composite_key, row, &self.on_null_key)?
+            else {
+                self.skipped += 1;
+                continue;
+            };
+
+            let mut entry = BytesStart::new("entry");
+            if let Some(etag) = etag_col.and_then(|col| row_etag(col.as_ref(), row)) {
+                entry.push_attribute(("m:etag", etag.as_str()));
+            }
+            writer.write_event(Event::Start(entry))?;
 
             // <id>http://a5d4b8ec90d5144a08efb47e789d49d5-1706314482.us-west-2.elb.amazonaws.com/tickers_spy(1)</id>
             // <category term="ODataDemo.tickers_spy" scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" />
-            // <link rel="edit" title="tickers_spy" href="tickers_spy(1)" />
+            // <link rel="self" title="tickers_spy" href="tickers_spy(1)" />
             // <title />
             // <updated>2024-03-10T00:36:45Z</updated>
             // <author>
             //   <name />
             // </author>
 
-            let id = encode_primitive_dyn(batch.column(key_edm_index), row)?.unescape()?;
-
-            let entry_url_rel = format!("{collection_name}({id})");
-            let entry_url_full = format!("{collection_base_url}({id})");
+            let entry_url_rel = format!("{}({id})", self.collection_name);
+            let entry_url_full = if self.groups {
+                format!("{}#{id}", self.collection_base_url)
+            } else {
+                format!("{}({id})", self.collection_base_url)
+            };
 
             writer
                 .create_element("id")
@@ -225,25 +588,28 @@ where
             writer
                 .create_element("category")
                 .with_attributes([
-                    (
-                        "scheme",
-                        "http://schemas.microsoft.com/ado/2007/08/dataservices/scheme",
-                    ),
-                    ("term", &fq_type),
-                ])
-                .write_empty()?;
-            writer
-                .create_element("link")
-                .with_attributes([
-                    ("rel", "edit"),
-                    ("title", &collection_name),
-                    ("href", &entry_url_rel),
+                    ("scheme", self.category_scheme.as_str()),
+                    ("term", self.category_term.as_str()),
                 ])
                 .write_empty()?;
+            if !self.groups {
+                writer
+                    .create_element("link")
+                    .with_attributes([
+                        ("rel", self.entry_link_rel),
+                        ("title", &self.collection_name),
+                        ("href", &entry_url_rel),
+                    ])
+                    .write_empty()?;
+                write_navigation_links(&self.navigation_properties, &entry_url_rel, writer)?;
+            }
             writer.create_element("title").write_empty()?;
+            let entry_updated_time = updated_col
+                .and_then(|col| row_updated_time(col.as_ref(), row))
+                .unwrap_or(self.updated_time);
             writer
                 .create_element("updated")
-                .write_text_content(encode_date_time(&updated_time))?;
+                .write_text_content(encode_date_time(&entry_updated_time))?;
             writer.write_event(Event::Start(BytesStart::new("author")))?;
             writer.create_element("name").write_empty()?;
             writer.write_event(Event::End(BytesEnd::new("author")))?;
@@ -261,25 +627,220 @@ where
             ))?;
             writer.write_event(Event::Start(BytesStart::new("m:properties")))?;
 
-            for (edm, index) in &edms {
-                let col = batch.column(*index);
+            for ((edm, _), col) in self.edms.iter().zip(&columns) {
+                let Some(col) = col else {
+                    continue;
+                };
+                if self.omit_nulls && edm.is_null(col, row) {
+                    continue;
+                }
 
                 let mut start = BytesStart::new(&edm.tag);
                 start.push_attribute(("m:type", edm.typ.as_str()));
                 writer.write_event(Event::Start(start))?;
-                writer.write_event(Event::Text(encode_primitive_dyn(col, row)?))?;
+                let text =
+                    sanitize_xml_text(edm.encode(col, row)?, &edm.name, self.on_invalid_chars)?;
+                writer.write_event(Event::Text(text))?;
                 writer.write_event(Event::End(BytesEnd::new(&edm.tag)))?;
             }
 
             writer.write_event(Event::End(BytesEnd::new("m:properties")))?;
             writer.write_event(Event::End(BytesEnd::new("content")))?;
             writer.write_event(Event::End(BytesEnd::new("entry")))?;
+
+            if let Some(flush_interval) = self.flush_interval {
+                self.unflushed += 1;
+                if self.unflushed >= flush_interval {
+                    writer.get_mut().flush().map_err(ODataError::internal)?;
+                    self.unflushed = 0;
+                }
+            }
         }
+        Ok(())
     }
 
-    writer.write_event(Event::End(BytesEnd::new("feed")))?;
+    /// Closes the feed, the link to the next page (if any) can only be known after the
+    /// entries of the current one were written, so it follows them
+    pub fn finish(self, next_link: Option<&str>) -> Result<(), ODataError> {
+        // <link rel="next" href="http://a5d4b8ec90d5144a08efb47e789d49d5-1706314482.us-west-2.elb.amazonaws.com/tickers_spy?$skip=100" />
+        if let Some(next_link) = next_link {
+            self.writer
+                .create_element("link")
+                .with_attributes([("rel", "next"), ("href", next_link)])
+                .write_empty()?;
+        }
 
-    Ok(())
+        self.writer.write_event(Event::End(BytesEnd::new("feed")))?;
+        if self.flush_interval.is_some() {
+            self.writer
+                .get_mut()
+                .flush()
+                .map_err(ODataError::internal)?;
+        }
+
+        warn_if_null_keys_skipped(self.skipped);
+
+        Ok(())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Collection the record batches converted by [`record_batches_to_atom_feed`] belong to
+#[derive(Debug, Clone)]
+pub struct FeedOptions {
+    service_base_url: String,
+    collection_name: String,
+    namespace: String,
+    key_column: Option<String>,
+    updated_time: DateTime<Utc>,
+}
+
+impl FeedOptions {
+    pub fn new(service_base_url: impl Into<String>, collection_name: impl Into<String>) -> Self {
+        Self {
+            service_base_url: service_base_url.into(),
+            collection_name: collection_name.into(),
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            key_column: None,
+            updated_time: Utc::now(),
+        }
+    }
+
+    /// Namespace qualifying the type of the entities, [`DEFAULT_NAMESPACE`] by default
+    pub fn with_namespace(self, namespace: impl Into<String>) -> Self {
+        Self {
+            namespace: namespace.into(),
+            ..self
+        }
+    }
+
+    /// Column identifying the entities, otherwise the one declared in the arrow metadata
+    /// (see [`key_column_from_metadata`]), otherwise the first column
+    pub fn with_key_column(self, key_column: impl Into<String>) -> Self {
+        Self {
+            key_column: Some(key_column.into()),
+            ..self
+        }
+    }
+
+    /// `<updated>` of the feed and its entries, the time of the conversion by default
+    pub fn with_updated_time(self, updated_time: DateTime<Utc>) -> Self {
+        Self {
+            updated_time,
+            ..self
+        }
+    }
+}
+
+/// Converts record batches to an Atom feed without implementing a [`CollectionContext`],
+/// e.g. to export the results of a batch job. The key column is used for the entity IDs as
+/// is, as no query adds the synthetic one.
+pub fn record_batches_to_atom_feed(
+    schema: &Schema,
+    record_batches: Vec<RecordBatch>,
+    options: FeedOptions,
+) -> Result<String, ODataError> {
+    let key_column = match &options.key_column {
+        Some(key_column) => {
+            schema.index_of(key_column).map_err(ODataError::internal)?;
+            key_column.clone()
+        }
+        None => match key_column_from_metadata(schema) {
+            Some(key_column) => key_column,
+            None => match schema.fields().first() {
+                Some(field) => field.name().clone(),
+                None => Err(KeyColumnNotAssigned)?,
+            },
+        },
+    };
+    let ctx = FeedContext {
+        addr: CollectionAddr {
+            name: options.collection_name.clone(),
+            key: None,
+        },
+        key_column,
+        options,
+    };
+
+    let mut buf = Vec::new();
+    write_atom_feed_from_records(
+        schema,
+        record_batches,
+        &ctx,
+        ctx.options.updated_time,
+        None,
+        None,
+        false,
+        &mut quick_xml::Writer::new(&mut buf),
+    )?;
+    String::from_utf8(buf).map_err(ODataError::internal)
+}
+
+struct FeedContext {
+    options: FeedOptions,
+    addr: CollectionAddr,
+    key_column: String,
+}
+
+#[async_trait::async_trait]
+impl CollectionContext for FeedContext {
+    fn addr(&self) -> Result<&CollectionAddr, ODataError> {
+        Ok(&self.addr)
+    }
+
+    fn service_base_url(&self) -> Result<String, ODataError> {
+        Ok(self.options.service_base_url.clone())
+    }
+
+    fn collection_base_url(&self) -> Result<String, ODataError> {
+        Ok(join_url(
+            &self.options.service_base_url,
+            &self.options.collection_name,
+        ))
+    }
+
+    fn collection_namespace(&self) -> Result<String, ODataError> {
+        Ok(self.options.namespace.clone())
+    }
+
+    fn collection_name(&self) -> Result<String, ODataError> {
+        Ok(self.options.collection_name.clone())
+    }
+
+    fn key_column_alias(&self) -> String {
+        self.key_column.clone()
+    }
+
+    fn synthetic_key_column(&self) -> bool {
+        false
+    }
+
+    fn key_column(&self) -> Result<String, ODataError> {
+        Ok(self.key_column.clone())
+    }
+
+    async fn last_updated_time(&self) -> DateTime<Utc> {
+        self.options.updated_time
+    }
+
+    async fn schema(&self) -> Result<SchemaRef, ODataError> {
+        Err(ODataError::internal(
+            "Schema of the converted batches is passed along",
+        ))
+    }
+
+    async fn query(
+        &self,
+        _query: QueryParams,
+        _options: &ApplyOptions,
+    ) -> Result<AppliedQuery, ODataError> {
+        Err(ODataError::internal("Converted batches can't be queried"))
+    }
+
+    fn on_unsupported_feature(&self) -> OnUnsupported {
+        OnUnsupported::Error
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -317,6 +878,7 @@ pub fn write_atom_entry_from_record<W>(
     batch: RecordBatch,
     ctx: &dyn CollectionContext,
     updated_time: DateTime<Utc>,
+    omit_nulls: bool,
     writer: &mut quick_xml::Writer<W>,
 ) -> Result<(), ODataError>
 where
@@ -325,8 +887,6 @@ where
     let mut service_base_url = ctx.service_base_url()?;
     let mut collection_base_url = ctx.collection_base_url()?;
     let collection_name = ctx.collection_name()?;
-    let type_name = ctx.collection_name()?;
-    let type_namespace = ctx.collection_namespace()?;
 
     if !service_base_url.starts_with("http") {
         return Err(UnsupportedNetProtocol::new(service_base_url).into());
@@ -342,13 +902,19 @@ where
         collection_base_url.pop();
     }
 
-    let fq_type = format!("{type_namespace}.{type_name}");
+    let category_scheme = ctx.category_scheme();
+    let category_term = ctx.category_term()?;
+    let entry_link_rel = if ctx.is_writable() { "edit" } else { "self" };
 
     let (edms, key_edm_index) = to_edms(
         schema,
-        &ctx.key_column_alias(),
+        &ctx.id_column()?,
+        ctx.synthetic_key_column(),
         ctx.on_unsupported_feature(),
+        &ctx.property_overrides(),
+        ctx.property_name_mapper().as_ref(),
     )?;
+    let on_invalid_chars = ctx.on_invalid_xml_chars();
 
     writer.write_event(quick_xml::events::Event::Decl(BytesDecl::new(
         "1.0",
@@ -356,34 +922,41 @@ where
         None,
     )))?;
 
-    let mut entry = BytesStart::new("entry");
-    entry.push_attribute(("xml:base", service_base_url.as_str()));
-    entry.push_attribute(("xmlns", "http://www.w3.org/2005/Atom"));
-    entry.push_attribute((
-        "xmlns:d",
-        "http://schemas.microsoft.com/ado/2007/08/dataservices",
-    ));
-    entry.push_attribute((
-        "xmlns:m",
-        "http://schemas.microsoft.com/ado/2007/08/dataservices/metadata",
-    ));
-
+    let row = 0;
+    let mut entry = root_element("entry", &service_base_url);
+    let etag = ctx
+        .etag_column()
+        .and_then(|c| row_etag(batch.column_by_name(&c)?.as_ref(), row));
+    if let Some(etag) = etag {
+        entry.push_attribute(("m:etag", etag.as_str()));
+    }
     writer.write_event(Event::Start(entry))?;
 
     // <id>http://a5d4b8ec90d5144a08efb47e789d49d5-1706314482.us-west-2.elb.amazonaws.com/tickers_spy(1)</id>
     // <category term="ODataDemo.tickers_spy" scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" />
-    // <link rel="edit" title="tickers_spy" href="tickers_spy(1)" />
+    // <link rel="self" title="tickers_spy" href="tickers_spy(1)" />
     // <title />
     // <updated>2024-03-10T00:36:45Z</updated>
     // <author>
     //   <name />
     // </author>
 
-    let row = 0;
-    let id = encode_primitive_dyn(batch.column(key_edm_index), row)?.unescape()?;
-
-    let entry_url_rel = format!("{collection_name}({id})");
-    let entry_url_full = format!("{collection_base_url}({id})");
+    let (entry_url_rel, entry_url_full) = if ctx.is_singleton() {
+        (collection_name.clone(), collection_base_url.clone())
+    } else {
+        let id = encode_entity_key(
+            &batch,
+            batch.column(key_edm_index),
+            &composite_key_columns(ctx),
+            row,
+            &ctx.on_null_key(),
+        )?
+        .ok_or_else(|| ODataError::internal("Entity has a null key"))?;
+        (
+            format!("{collection_name}({id})"),
+            format!("{collection_base_url}({id})"),
+        )
+    };
 
     writer
         .create_element("id")
@@ -391,22 +964,24 @@ where
     writer
         .create_element("category")
         .with_attributes([
-            (
-                "scheme",
-                "http://schemas.microsoft.com/ado/2007/08/dataservices/scheme",
-            ),
-            ("term", &fq_type),
+            ("scheme", category_scheme.as_str()),
+            ("term", category_term.as_str()),
         ])
         .write_empty()?;
     writer
         .create_element("link")
         .with_attributes([
-            ("rel", "edit"),
+            ("rel", entry_link_rel),
             ("title", &collection_name),
             ("href", &entry_url_rel),
         ])
         .write_empty()?;
+    write_navigation_links(&ctx.navigation_properties(), &entry_url_rel, writer)?;
     writer.create_element("title").write_empty()?;
+    let updated_time = ctx
+        .updated_column()
+        .and_then(|c| row_updated_time(batch.column_by_name(&c)?.as_ref(), row))
+        .unwrap_or(updated_time);
     writer
         .create_element("updated")
         .write_text_content(encode_date_time(&updated_time))?;
@@ -429,11 +1004,15 @@ where
 
     for (edm, index) in &edms {
         let col = batch.column(*index);
+        if omit_nulls && edm.is_null(col, row) {
+            continue;
+        }
 
         let mut start = BytesStart::new(&edm.tag);
         start.push_attribute(("m:type", edm.typ.as_str()));
         writer.write_event(Event::Start(start))?;
-        writer.write_event(Event::Text(encode_primitive_dyn(col, row)?))?;
+        let text = sanitize_xml_text(edm.encode(col, row)?, &edm.name, on_invalid_chars)?;
+        writer.write_event(Event::Text(text))?;
         writer.write_event(Event::End(BytesEnd::new(&edm.tag)))?;
     }
 
@@ -446,10 +1025,100 @@ where
 
 ///////////////////////////////////////////////////////////////////////////////
 
+/// Renders the key of an entity in its URL literal form, e.g. `1` or `'a'`.
+///
+/// Null keys are rendered according to [`OnNullKey`], `None` means the entity is to be skipped.
+pub(crate) fn encode_key(
+    col: &Arc<dyn Array>,
+    row: usize,
+    on_null_key: &OnNullKey,
+) -> Result<Option<String>, ODataError> {
+    if col.is_null(row) {
+        return match on_null_key {
+            OnNullKey::Null => Ok(Some("null".to_string())),
+            OnNullKey::Skip => Ok(None),
+            OnNullKey::Sentinel(key) => Ok(Some(key.to_string())),
+        };
+    }
+    match KeyValue::from_array(col.as_ref(), row) {
+        Some(key) => Ok(Some(key.to_string())),
+        None => Ok(Some(
+            encode_primitive_dyn(col, row)?.unescape()?.into_owned(),
+        )),
+    }
+}
+
+/// Columns of a composite key along with their property names, empty when the collection
+/// has a single key column, see [`CollectionContext::key_columns`]
+pub(crate) fn composite_key_columns(ctx: &dyn CollectionContext) -> Vec<(String, String)> {
+    let key_columns = match ctx.key_columns() {
+        Ok(key_columns) if key_columns.len() > 1 => key_columns,
+        _ => return Vec::new(),
+    };
+    let mapper = ctx.property_name_mapper();
+    key_columns
+        .into_iter()
+        .map(|column| {
+            let property = match &mapper {
+                Some(mapper) => mapper(&column),
+                None => column.clone(),
+            };
+            (column, property)
+        })
+        .collect()
+}
+
+/// Renders the key of an entity like [`encode_key`], or `k1=v1,k2=v2` for a composite key.
+///
+/// Entities with a null in any part of a composite key are skipped regardless of [`OnNullKey`].
+pub(crate) fn encode_entity_key(
+    batch: &RecordBatch,
+    key_col: &Arc<dyn Array>,
+    composite_key: &[(String, String)],
+    row: usize,
+    on_null_key: &OnNullKey,
+) -> Result<Option<String>, ODataError> {
+    if composite_key.is_empty() {
+        return encode_key(key_col, row, on_null_key);
+    }
+
+    let mut values = Vec::new();
+    for (column, property) in composite_key {
+        let col = batch.column_by_name(column).ok_or(KeyColumnNotAssigned)?;
+        if col.is_null(row) {
+            return Ok(None);
+        }
+        let value = KeyValue::from_array(col.as_ref(), row)
+            .ok_or_else(|| UnsupportedDataType::new(col.data_type().clone()))?;
+        values.push((property.clone(), value));
+    }
+    Ok(Some(KeyValue::Composite(values).to_string()))
+}
+
+/// Entities with a null key are left out of the feeds silently otherwise,
+/// see [`OnNullKey::Skip`]
+pub(crate) fn warn_if_null_keys_skipped(skipped: usize) {
+    if skipped != 0 {
+        tracing::warn!(skipped, "Skipped entities with a null key");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// <feed xml:base="..." xmlns="..." xmlns:d="..." xmlns:m="...">
+fn root_element<'a>(name: &'a str, service_base_url: &str) -> BytesStart<'a> {
+    let mut start = BytesStart::new(name);
+    start.push_attribute(("xml:base", service_base_url));
+    start.extend_attributes(ATOM_NAMESPACE_DECLARATIONS);
+    start
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
 fn encode_primitive_dyn(
     col: &Arc<dyn Array>,
     row: usize,
-) -> Result<BytesText, UnsupportedDataType> {
+) -> Result<BytesText<'_>, UnsupportedDataType> {
     let col_type = col.data_type().clone();
     if col.is_null(row) {
         Ok(BytesText::new("null"))
@@ -468,17 +1137,29 @@ fn encode_primitive_dyn(
             DataType::UInt16 => Ok(encode_primitive::<UInt16Type>(col, row)),
             DataType::UInt32 => Ok(encode_primitive::<UInt32Type>(col, row)),
             DataType::UInt64 => Ok(encode_primitive::<UInt64Type>(col, row)),
-            DataType::Float16 => Ok(encode_primitive::<Float16Type>(col, row)),
-            DataType::Float32 => Ok(encode_primitive::<Float32Type>(col, row)),
-            DataType::Float64 => Ok(encode_primitive::<Float64Type>(col, row)),
-            DataType::Timestamp(_, _) => {
-                let arr = col.as_primitive::<TimestampMillisecondType>();
-                let ticks = arr.value(row);
-                let ts = chrono::DateTime::from_timestamp_millis(ticks)
+            // Half precision is widened so it is formatted exactly like `Edm.Single`
+            DataType::Float16 => Ok(encode_float(
+                col.as_primitive::<Float16Type>().value(row).to_f32(),
+            )),
+            DataType::Float32 => Ok(encode_float(col.as_primitive::<Float32Type>().value(row))),
+            DataType::Float64 => Ok(encode_float(col.as_primitive::<Float64Type>().value(row))),
+            DataType::Timestamp(_, Some(ref tz)) => {
+                let s = timestamp_millis(col, row)
+                    .and_then(|ticks| format_date_time_offset(ticks, tz.as_ref()))
+                    .ok_or_else(|| UnsupportedDataType::new(col_type.clone()))?;
+                Ok(BytesText::from_escaped(s))
+            }
+            DataType::Timestamp(_, None) => {
+                let ts = timestamp_millis(col, row)
+                    .and_then(chrono::DateTime::from_timestamp_millis)
                     .ok_or(UnsupportedDataType::new(col_type))?;
                 Ok(encode_date_time(&ts))
             }
             DataType::Date32 => Err(UnsupportedDataType::new(col_type)),
+            DataType::Decimal128(_, _) => {
+                let arr = col.as_primitive::<Decimal128Type>();
+                Ok(BytesText::from_escaped(arr.value_as_string(row)))
+            }
             DataType::Date64 => {
                 let arr = col.as_primitive::<Date64Type>();
                 let ticks = arr.value(row);
@@ -513,7 +1194,6 @@ fn encode_primitive_dyn(
             | DataType::Struct(_)
             | DataType::Union(_, _)
             | DataType::Dictionary(_, _)
-            | DataType::Decimal128(_, _)
             | DataType::Decimal256(_, _)
             | DataType::Map(_, _)
             | DataType::RunEndEncoded(_, _) => Err(UnsupportedDataType::new(col_type)),
@@ -523,7 +1203,71 @@ fn encode_primitive_dyn(
 
 ///////////////////////////////////////////////////////////////////////////////
 
-fn encode_primitive<T>(arr: &Arc<dyn Array>, row: usize) -> BytesText
+fn encode_stringified(
+    col: &Arc<dyn Array>,
+    row: usize,
+) -> Result<BytesText<'static>, UnsupportedDataType> {
+    if col.is_null(row) {
+        return Ok(BytesText::new("null"));
+    }
+    let val = stringify_value(col, row)?;
+    Ok(BytesText::from_escaped(
+        quick_xml::escape::partial_escape(&val).into_owned(),
+    ))
+}
+
+/// Renders a value of any type as text for [`OnUnsupported::Stringify`] mode.
+///
+/// Structs and maps are rendered as JSON so that clients can parse them back.
+pub(crate) fn stringify_value(
+    col: &Arc<dyn Array>,
+    row: usize,
+) -> Result<String, UnsupportedDataType> {
+    match col.data_type() {
+        DataType::Struct(_) | DataType::Map(_, _) => {
+            Ok(crate::json::encode_nested_json_value(col, row)?.to_string())
+        }
+        _ => datafusion::arrow::util::display::array_value_to_string(col, row)
+            .map_err(|_| UnsupportedDataType::new(col.data_type().clone())),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// Escaping can't make control characters like `\0` legal in XML 1.0
+// See: https://www.w3.org/TR/xml/#charsets
+fn is_xml_char(c: char) -> bool {
+    matches!(c,
+        '\u{9}' | '\u{A}' | '\u{D}'
+        | '\u{20}'..='\u{D7FF}'
+        | '\u{E000}'..='\u{FFFD}'
+        | '\u{10000}'..='\u{10FFFF}')
+}
+
+fn sanitize_xml_text<'a>(
+    text: BytesText<'a>,
+    property: &str,
+    on_invalid_chars: OnInvalidXmlChars,
+) -> Result<BytesText<'a>, ODataError> {
+    let s = std::str::from_utf8(&text).map_err(ODataError::internal)?;
+    if s.chars().all(is_xml_char) {
+        return Ok(text);
+    }
+
+    match on_invalid_chars {
+        OnInvalidXmlChars::Strip => {
+            let s: String = s.chars().filter(|c| is_xml_char(*c)).collect();
+            Ok(BytesText::from_escaped(s))
+        }
+        OnInvalidXmlChars::Error => Err(ODataError::internal(format!(
+            "Value of {property} contains characters not allowed in XML"
+        ))),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn encode_primitive<T>(arr: &Arc<dyn Array>, row: usize) -> BytesText<'_>
 where
     T: ArrowPrimitiveType,
     <T as ArrowPrimitiveType>::Native: std::fmt::Display,
@@ -533,13 +1277,125 @@ where
     BytesText::from_escaped(val)
 }
 
+/// Formats `Edm.Single` and `Edm.Double` values, spelling the special values
+/// the way OData literals do
+fn encode_float<T>(val: T) -> BytesText<'static>
+where
+    T: Into<f64> + std::fmt::Display + Copy,
+{
+    let wide: f64 = val.into();
+    let s = if wide.is_nan() {
+        "NaN".to_string()
+    } else if wide.is_infinite() && wide.is_sign_positive() {
+        "INF".to_string()
+    } else if wide.is_infinite() {
+        "-INF".to_string()
+    } else {
+        val.to_string()
+    };
+    BytesText::from_escaped(s)
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 
+/// Deferred links to the entities related to the one at `entry_url_rel`, which the clients
+/// follow lazily as the entities are not expanded inline
+fn write_navigation_links<W: std::io::Write>(
+    navigation_properties: &[NavigationPropertyDeclaration],
+    entry_url_rel: &str,
+    writer: &mut quick_xml::Writer<W>,
+) -> Result<(), ODataError> {
+    // <link rel="http://schemas.microsoft.com/ado/2007/08/dataservices/related/reports" type="application/atom+xml;type=feed" title="reports" href="tickers_spy(1)/reports" />
+    for navigation in navigation_properties {
+        let typ = if navigation.many {
+            "application/atom+xml;type=feed"
+        } else {
+            "application/atom+xml;type=entry"
+        };
+        writer
+            .create_element("link")
+            .with_attributes([
+                ("rel", format!("{NS_RELATED}{}", navigation.name).as_str()),
+                ("type", typ),
+                ("title", &navigation.name),
+                ("href", &format!("{entry_url_rel}/{}", navigation.name)),
+            ])
+            .write_empty()?;
+    }
+    Ok(())
+}
+
+/// Modification time of the entity, see [`CollectionContext::updated_column`]
+fn row_updated_time(col: &dyn Array, row: usize) -> Option<DateTime<Utc>> {
+    if col.is_null(row) {
+        return None;
+    }
+    let dt = match col.data_type() {
+        DataType::Timestamp(TimeUnit::Second, _) => col
+            .as_primitive::<TimestampSecondType>()
+            .value_as_datetime(row),
+        DataType::Timestamp(TimeUnit::Millisecond, _) => col
+            .as_primitive::<TimestampMillisecondType>()
+            .value_as_datetime(row),
+        DataType::Timestamp(TimeUnit::Microsecond, _) => col
+            .as_primitive::<TimestampMicrosecondType>()
+            .value_as_datetime(row),
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => col
+            .as_primitive::<TimestampNanosecondType>()
+            .value_as_datetime(row),
+        DataType::Date64 => col.as_primitive::<Date64Type>().value_as_datetime(row),
+        _ => None,
+    };
+    dt.map(|dt| dt.and_utc())
+}
+
+/// Weak entity tag of the entity, see [`CollectionContext::etag_column`]
+fn row_etag(col: &dyn Array, row: usize) -> Option<String> {
+    if col.is_null(row) {
+        return None;
+    }
+    let version = array_value_to_string(col, row).ok()?;
+    Some(format!("W/\"{version}\""))
+}
+
 fn encode_date_time(dt: &DateTime<Utc>) -> BytesText<'static> {
     let s = dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
     BytesText::from_escaped(s)
 }
 
+/// Milliseconds since the epoch of a timestamp of any unit, as the formatted values only
+/// carry milliseconds. Finer units are truncated towards the past.
+pub(crate) fn timestamp_millis(col: &dyn Array, row: usize) -> Option<i64> {
+    match col.data_type() {
+        DataType::Timestamp(TimeUnit::Second, _) => col
+            .as_primitive::<TimestampSecondType>()
+            .value(row)
+            .checked_mul(1_000),
+        DataType::Timestamp(TimeUnit::Millisecond, _) => {
+            Some(col.as_primitive::<TimestampMillisecondType>().value(row))
+        }
+        DataType::Timestamp(TimeUnit::Microsecond, _) => Some(
+            col.as_primitive::<TimestampMicrosecondType>()
+                .value(row)
+                .div_euclid(1_000),
+        ),
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => Some(
+            col.as_primitive::<TimestampNanosecondType>()
+                .value(row)
+                .div_euclid(1_000_000),
+        ),
+        _ => None,
+    }
+}
+
+/// Formats an `Edm.DateTimeOffset` in the timezone of the column, using `Z` for UTC
+/// and `+HH:MM` otherwise
+pub(crate) fn format_date_time_offset(ticks_millis: i64, tz: &str) -> Option<String> {
+    let tz: datafusion::arrow::array::timezone::Tz = tz.parse().ok()?;
+    let dt = DateTime::from_timestamp_millis(ticks_millis)?.with_timezone(&tz);
+    Some(dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 
 #[cfg(test)]
@@ -547,10 +1403,58 @@ mod tests {
     use super::*;
 
     use datafusion::arrow::{
-        array::{Array, Date64Array, Int64Array},
+        array::{
+            Array, Date64Array, Float32Array, Float64Array, Int64Array, StringArray,
+            TimestampMicrosecondArray, TimestampMillisecondArray, TimestampNanosecondArray,
+            TimestampSecondArray, UInt64Array,
+        },
+        compute::cast,
         datatypes::{ArrowPrimitiveType, Date64Type},
     };
 
+    #[test]
+    fn test_root_element_namespaces() {
+        let attrs = |start: &BytesStart| {
+            start
+                .attributes()
+                .map(|a| {
+                    let a = a.unwrap();
+                    (a.key.as_ref().to_vec(), a.value.to_vec())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let feed = root_element("feed", "http://example.com/odata/");
+        let entry = root_element("entry", "http://example.com/odata/");
+        assert_eq!(attrs(&feed), attrs(&entry));
+        assert_eq!(
+            attrs(&feed)[1..],
+            ATOM_NAMESPACE_DECLARATIONS
+                .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_check_int64_range_reported_once() {
+        let field = Arc::new(Field::new("hash", DataType::UInt64, true));
+        let edm = Edm::from_field(&field).unwrap();
+        let values = Arc::new(UInt64Array::from(vec![
+            Some(1),
+            None,
+            Some(u64::MAX),
+            Some(u64::MAX - 1),
+        ])) as Arc<dyn Array>;
+
+        edm.check_int64_range(&values, 0);
+        edm.check_int64_range(&values, 1);
+        assert!(!edm.exceeds_int64.load(Ordering::Relaxed));
+
+        for row in 0..values.len() {
+            edm.encode(&values, row).unwrap();
+        }
+        assert!(edm.exceeds_int64.load(Ordering::Relaxed));
+    }
+
     #[test]
     fn test_encode_primitive_dyn() {
         let values: Int64Array = vec![1, 2, 3].into();
@@ -570,4 +1474,131 @@ mod tests {
         let result = encode_primitive_dyn(&values, 0).unwrap();
         assert_eq!(result.borrow(), BytesText::new("2024-09-11T00:00:00.000Z"));
     }
+
+    #[test]
+    fn test_encode_timestamp_units() {
+        // 2024-09-11T00:00:00.123456789Z
+        let nanos = 1726012800123456789;
+        let values: [Arc<dyn Array>; 4] = [
+            Arc::new(TimestampSecondArray::from(vec![nanos / 1_000_000_000])),
+            Arc::new(TimestampMillisecondArray::from(vec![nanos / 1_000_000])),
+            Arc::new(TimestampMicrosecondArray::from(vec![nanos / 1_000])),
+            Arc::new(TimestampNanosecondArray::from(vec![nanos])),
+        ];
+        let expected = [
+            "2024-09-11T00:00:00.000Z",
+            "2024-09-11T00:00:00.123Z",
+            "2024-09-11T00:00:00.123Z",
+            "2024-09-11T00:00:00.123Z",
+        ];
+        for (values, expected) in values.iter().zip(expected) {
+            assert_eq!(
+                encode_primitive_dyn(values, 0).unwrap(),
+                BytesText::new(expected),
+                "{}",
+                values.data_type()
+            );
+        }
+
+        let values = TimestampMicrosecondArray::from(vec![nanos / 1_000]).with_timezone("+05:30");
+        assert_eq!(
+            encode_primitive_dyn(&(Arc::new(values) as Arc<dyn Array>), 0).unwrap(),
+            BytesText::new("2024-09-11T05:30:00.123+05:30")
+        );
+
+        // Before the epoch, truncated towards the past like the other instants
+        let values = TimestampNanosecondArray::from(vec![-1]);
+        assert_eq!(
+            encode_primitive_dyn(&(Arc::new(values) as Arc<dyn Array>), 0).unwrap(),
+            BytesText::new("1969-12-31T23:59:59.999Z")
+        );
+    }
+
+    #[test]
+    fn test_encode_float16_as_single() {
+        let input = Arc::new(Float32Array::from(vec![
+            0.1,
+            1.5,
+            -65504.0,
+            f32::INFINITY,
+            f32::NAN,
+        ])) as Arc<dyn Array>;
+        let values = cast(&input, &DataType::Float16).unwrap();
+        let singles = cast(&values, &DataType::Float32).unwrap();
+
+        for row in 0..values.len() {
+            assert_eq!(
+                encode_primitive_dyn(&values, row).unwrap(),
+                encode_primitive_dyn(&singles, row).unwrap()
+            );
+        }
+        assert_eq!(
+            encode_primitive_dyn(&values, 0).unwrap(),
+            BytesText::new("0.099975586")
+        );
+        assert_eq!(
+            encode_primitive_dyn(&values, 3).unwrap(),
+            BytesText::new("INF")
+        );
+        assert_eq!(
+            encode_primitive_dyn(&values, 4).unwrap(),
+            BytesText::new("NaN")
+        );
+    }
+
+    #[test]
+    fn test_record_batches_to_atom_feed() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("symbol", DataType::Utf8, false),
+            Field::new("close", DataType::Float64, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["spy", "qqq"])),
+                Arc::new(Float64Array::from(vec![Some(135.5), None])),
+            ],
+        )
+        .unwrap();
+        let updated_time = DateTime::from_timestamp(1726012800, 0).unwrap();
+
+        let feed = record_batches_to_atom_feed(
+            &schema,
+            vec![batch],
+            FeedOptions::new("http://example.com/odata", "tickers")
+                .with_namespace("market")
+                .with_key_column("symbol")
+                .with_updated_time(updated_time),
+        )
+        .unwrap();
+
+        assert!(feed.starts_with(concat!(
+            r#"<?xml version="1.0" encoding="utf-8"?>"#,
+            r#"<feed xml:base="http://example.com/odata/""#,
+        )));
+        assert!(feed.contains(concat!(
+            "<id>http://example.com/odata/tickers</id>",
+            r#"<title type="text">tickers</title>"#,
+            "<updated>2024-09-11T00:00:00.000Z</updated>",
+        )));
+        assert!(feed.contains(concat!(
+            "<entry><id>http://example.com/odata/tickers('spy')</id>",
+            r#"<category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="market.tickers"/>"#,
+            r#"<link rel="self" title="tickers" href="tickers(&apos;spy&apos;)"/>"#,
+        )));
+        assert!(feed.contains(concat!(
+            r#"<m:properties><d:symbol m:type="Edm.String">spy</d:symbol>"#,
+            r#"<d:close m:type="Edm.Double">135.5</d:close></m:properties>"#,
+        )));
+        assert!(feed.contains(r#"<d:close m:type="Edm.Double">null</d:close>"#));
+        assert!(feed.ends_with("</entry></feed>"));
+
+        let err = record_batches_to_atom_feed(
+            &schema,
+            Vec::new(),
+            FeedOptions::new("http://example.com/odata", "tickers").with_key_column("missing"),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ODataError::Internal(_)));
+    }
 }
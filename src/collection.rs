@@ -1,10 +1,30 @@
-use datafusion::prelude::*;
+use datafusion::{
+    arrow::{array::Array, datatypes::DataType},
+    common::{
+        tree_node::{Transformed, TreeNode},
+        DFSchema,
+    },
+    functions::string::expr_fn::lower,
+    functions_window::expr_fn::row_number,
+    logical_expr::ExprSchemable,
+    prelude::*,
+    scalar::ScalarValue,
+};
+use regex::Regex;
+use std::sync::LazyLock;
 
-use crate::{error::ODataError, filter::ODataFilter};
+use crate::{
+    apply::{Aggregate, Aggregation},
+    error::{CollectionNotFound, ODataError, UnsupportedFeature},
+    filter::{
+        check_filter_columns, check_in_list_sizes, coerce_filter_literals,
+        resolve_filter_functions, ODataFilter,
+    },
+};
 
 ///////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Default, serde::Deserialize)]
 pub struct QueryParamsRaw {
     #[serde(rename = "$select")]
     pub select: Option<String>,
@@ -12,52 +32,285 @@ pub struct QueryParamsRaw {
     pub order_by: Option<String>,
     #[serde(rename = "$skip")]
     pub skip: Option<u64>,
+    /// Opaque cursor of a server-driven paged result, see [`SkipToken`]
+    #[serde(rename = "$skiptoken")]
+    pub skip_token: Option<String>,
     #[serde(rename = "$top")]
     pub top: Option<u64>,
     #[serde(rename = "$filter")]
-    pub filter: Option<ODataFilter>,
+    pub filter: Option<String>,
+    #[serde(rename = "$expand")]
+    pub expand: Option<String>,
+    #[serde(rename = "$format")]
+    pub format: Option<String>,
+    /// OData v4 spelling of the total count request
+    #[serde(rename = "$count")]
+    pub count: Option<bool>,
+    /// OData v3 spelling of the total count request
+    #[serde(rename = "$inlinecount")]
+    pub inline_count: Option<String>,
+    #[serde(rename = "$apply")]
+    pub apply: Option<String>,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// System query option of a collection request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QueryOption {
+    Select,
+    OrderBy,
+    Skip,
+    SkipToken,
+    Top,
+    Filter,
+    Expand,
+    Format,
+    /// `$count` as well as `$inlinecount`
+    Count,
+    Apply,
+}
+
+impl QueryOption {
+    pub const ALL: [Self; 10] = [
+        Self::Select,
+        Self::OrderBy,
+        Self::Skip,
+        Self::SkipToken,
+        Self::Top,
+        Self::Filter,
+        Self::Expand,
+        Self::Format,
+        Self::Count,
+        Self::Apply,
+    ];
+}
+
+impl std::fmt::Display for QueryOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Select => "$select",
+            Self::OrderBy => "$orderby",
+            Self::Skip => "$skip",
+            Self::SkipToken => "$skiptoken",
+            Self::Top => "$top",
+            Self::Filter => "$filter",
+            Self::Expand => "$expand",
+            Self::Format => "$format",
+            Self::Count => "$count",
+            Self::Apply => "$apply",
+        })
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
 impl QueryParamsRaw {
+    /// Options present in the request
+    pub fn options(&self) -> Vec<QueryOption> {
+        [
+            (QueryOption::Select, self.select.is_some()),
+            (QueryOption::OrderBy, self.order_by.is_some()),
+            (QueryOption::Skip, self.skip.is_some()),
+            (QueryOption::SkipToken, self.skip_token.is_some()),
+            (QueryOption::Top, self.top.is_some()),
+            (QueryOption::Filter, self.filter.is_some()),
+            (QueryOption::Expand, self.expand.is_some()),
+            (QueryOption::Format, self.format.is_some()),
+            (
+                QueryOption::Count,
+                self.count.is_some() || self.inline_count.is_some(),
+            ),
+            (QueryOption::Apply, self.apply.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(option, present)| present.then_some(option))
+        .collect()
+    }
+
+    /// Rejects combinations of options that contradict each other
+    pub fn validate(&self) -> Result<(), ODataError> {
+        let inline_count = self.inline_count.as_deref().map(str::trim);
+        match (self.count, inline_count) {
+            (Some(true), Some("none")) | (Some(false), Some("allpages")) => {
+                Err(ODataError::bad_request(format!(
+                    "Conflicting $count={} and $inlinecount={}",
+                    self.count.unwrap_or_default(),
+                    inline_count.unwrap_or_default(),
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Options repeated on the following pages of a server-driven paged result, i.e. all
+    /// but `$skip` and `$skiptoken`, see [`next_page_url`]
+    pub fn paging_options(&self) -> Vec<(&'static str, String)> {
+        [
+            ("$filter", self.filter.clone()),
+            ("$select", self.select.clone()),
+            ("$orderby", self.order_by.clone()),
+            ("$top", self.top.map(|v| v.to_string())),
+            ("$expand", self.expand.clone()),
+            ("$apply", self.apply.clone()),
+            ("$format", self.format.clone()),
+            ("$count", self.count.map(|v| v.to_string())),
+            ("$inlinecount", self.inline_count.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(option, value)| Some((option, value?)))
+        .collect()
+    }
+
     pub fn decode(self) -> Result<QueryParams, ODataError> {
-        let select = self.select.unwrap_or_default();
-        let mut select: Vec<_> = select.split(',').map(|s| s.to_string()).collect();
-        select.retain(|i| !i.is_empty());
+        self.validate()?;
+
+        let select = decode_select(&self.select.unwrap_or_default());
 
         let order_by_s = self.order_by.unwrap_or_default();
-        let mut order_by_s: Vec<_> = order_by_s.split(',').collect();
+        let mut order_by_s: Vec<_> = order_by_s.split(',').map(str::trim).collect();
         order_by_s.retain(|i| !i.is_empty());
 
         let mut order_by = Vec::new();
         for el in order_by_s {
-            let (cname, asc) = if let Some(cname) = el.strip_suffix(" asc") {
-                (cname, true)
-            } else if let Some(cname) = el.strip_suffix(" desc") {
-                (cname, false)
-            } else {
-                (el, true)
+            let tokens: Vec<_> = el.split_whitespace().collect();
+            let (cname, asc) = match tokens[..] {
+                [cname] => (cname, true),
+                [cname, dir] if dir.eq_ignore_ascii_case("asc") => (cname, true),
+                [cname, dir] if dir.eq_ignore_ascii_case("desc") => (cname, false),
+                _ => Err(ODataError::bad_request(format!(
+                    "Invalid $orderby element: {el}"
+                )))?,
             };
             order_by.push((cname.to_string(), asc));
         }
 
-        let skip = self.skip.map(|v| v as usize);
-        let top = self.top.map(|v| v as usize);
+        let skip = decode_usize("$skip", self.skip)?;
+        let top = decode_usize("$top", self.top)?;
+        let skip_token = match self.skip_token {
+            Some(token) => Some(SkipToken::decode(&token)?),
+            None => None,
+        };
+
+        let expand = match self.expand {
+            Some(expand) => ExpandItem::decode_list(&expand)?,
+            None => Vec::new(),
+        };
+
+        let inline_count = match self.inline_count.as_deref().map(str::trim) {
+            None | Some("none") => false,
+            Some("allpages") => true,
+            Some(v) => Err(ODataError::bad_request(format!(
+                "Invalid $inlinecount value: {v}"
+            )))?,
+        };
+        let count = self.count.unwrap_or(false) || inline_count;
+
+        let apply = match self.apply {
+            Some(apply) => Some(Aggregation::decode(&apply)?),
+            None => None,
+        };
+
+        let filter = match self.filter {
+            Some(filter) => Some(filter.parse::<ODataFilter>()?.into()),
+            None => None,
+        };
 
         Ok(QueryParams {
             select,
             order_by,
             skip,
+            skip_token,
             top,
-            filter: self.filter.map(Into::into),
+            filter,
+            expand,
+            count,
+            apply,
         })
     }
 }
 
+/// Link to the page following the one served with `page_size` entities starting at `skip`,
+/// or `None` when the page isn't full and thus is the last one.
+///
+/// The link repeats the `options` of the request (see [`QueryParamsRaw::paging_options`])
+/// and continues after the `skip_token` of the last served entity when there's one,
+/// otherwise it advances `$skip` past the served entities. It is emitted by both the Atom
+/// (`<link rel="next">`) and the JSON (`__next` / `@odata.nextLink`) feeds.
+pub fn next_page_url(
+    collection_url: &str,
+    options: &[(&'static str, String)],
+    skip: usize,
+    skip_token: Option<&SkipToken>,
+    page_size: usize,
+    num_rows: usize,
+) -> Option<String> {
+    if page_size == 0 || num_rows < page_size {
+        return None;
+    }
+    let mut url = collection_url.trim_end_matches('/').to_string();
+    for (i, (option, value)) in options.iter().enumerate() {
+        url.push(if i == 0 { '?' } else { '&' });
+        url.push_str(option);
+        url.push('=');
+        url.push_str(&encode_query_value(value));
+    }
+    url.push(if options.is_empty() { '?' } else { '&' });
+    match skip_token {
+        Some(token) => url.push_str(&format!("$skiptoken={}", token.encode())),
+        None => url.push_str(&format!("$skip={}", skip + num_rows)),
+    }
+    Some(url)
+}
+
+// Percent-encodes everything but the unreserved characters and the few delimiters that are
+// commonly left as is in OData query options
+fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            b',' | b'(' | b')' | b'/' | b':' | b'$' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+// Values beyond `usize` (i.e. on 32-bit targets) would otherwise be truncated
+fn decode_usize<T>(option: &str, value: Option<T>) -> Result<Option<usize>, ODataError>
+where
+    T: TryInto<usize> + Copy + std::fmt::Display,
+{
+    value
+        .map(|v| {
+            v.try_into()
+                .map_err(|_| ODataError::bad_request(format!("{option} is out of range: {v}")))
+        })
+        .transpose()
+}
+
+fn decode_select(select: &str) -> Vec<String> {
+    let mut items: Vec<String> = Vec::new();
+    for item in select.split(',').map(str::trim) {
+        // Projection can't have duplicate columns, so the repeated ones are dropped
+        if !item.is_empty() && !items.iter().any(|i| i == item) {
+            items.push(item.to_string());
+        }
+    }
+
+    // Star selects all properties, making any other element redundant
+    if items.iter().any(|i| i == "*") {
+        items.clear();
+    }
+    items
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct QueryParams {
     /// Column names
     pub select: Vec<String>,
@@ -65,94 +318,1354 @@ pub struct QueryParams {
     pub order_by: Vec<(String, bool)>,
     /// Number of records to skip
     pub skip: Option<usize>,
+    /// Position to continue after, see [`QueryParams::cursor_columns`]
+    pub skip_token: Option<SkipToken>,
     /// Maximum number of records to return
     pub top: Option<usize>,
     /// Filter a collection of resources   
     pub filter: Option<Expr>,
+    /// Related entities to include inline
+    pub expand: Vec<ExpandItem>,
+    /// Whether to include the total number of matching entities
+    pub count: bool,
+    /// Aggregation that replaces the entities with the groups
+    pub apply: Option<Aggregation>,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Settings of the collection that [`QueryParams::apply`] honors, as opposed to the query
+/// options requested by the client, see [`crate::context::CollectionContext::apply_options`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ApplyOptions {
+    /// Column identifying the entities,
+    /// see [`crate::context::CollectionContext::resolve_key_column`]
+    pub key_column: String,
+    /// Column the entity IDs are read from, see [`crate::context::CollectionContext::id_column`]
+    pub key_column_alias: String,
+    /// Whether string columns are ordered regardless of case,
+    /// see [`crate::context::CollectionContext::case_insensitive_order_by`]
+    pub case_insensitive_order_by: bool,
+    /// Tuples (column_name, sql_expression),
+    /// see [`crate::context::CollectionContext::derived_columns`]
+    pub derived_columns: Vec<(String, String)>,
+    /// Key standing for the null one, see [`crate::context::OnNullKey::Sentinel`]
+    pub null_key_sentinel: Option<KeyValue>,
+    /// Whether the entities with a null key are left out, see [`crate::context::OnNullKey::Skip`]
+    pub skip_null_keys: bool,
+    /// Whether the entities are always ordered down to their keys,
+    /// see [`crate::context::CollectionContext::stable_order`]
+    pub stable_order: bool,
+    /// Whether the real key column is kept regardless of `$select`,
+    /// see [`crate::context::CollectionContext::select_includes_key`]
+    pub select_includes_key: bool,
+    /// Session functions allowed in `$filter`,
+    /// see [`crate::context::CollectionContext::filter_functions`]
+    pub filter_functions: Vec<String>,
+    /// Columns of a composite key, which are kept regardless of `$select`,
+    /// see [`crate::context::CollectionContext::key_columns`]
+    pub key_columns: Vec<String>,
+    /// Tuples (property_name, column_name) of the key columns, as the parts of a composite
+    /// key in the address are named after the properties,
+    /// see [`crate::context::CollectionContext::property_name_mapper`]
+    pub key_properties: Vec<(String, String)>,
+    /// Columns left out before any of the options is applied,
+    /// see [`crate::context::CollectionContext::visible_columns`]
+    pub hidden_columns: Vec<String>,
+    /// Upper bound of the values of an `in` operator in `$filter`,
+    /// see [`crate::context::CollectionContext::max_in_list_size`]
+    pub max_in_list_size: Option<usize>,
+    /// Names of the declared navigation properties,
+    /// see [`crate::context::CollectionContext::navigation_properties`]
+    pub navigation_properties: Vec<String>,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Entities selected by [`QueryParams::apply`]
+#[derive(Debug)]
+pub struct AppliedQuery {
+    pub df: DataFrame,
+    /// Entities matching the request before any paging, to be counted when
+    /// [`QueryParams::count`] is requested
+    pub count: Option<DataFrame>,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Keyset cursor of a server-driven paged result: the values of the
+/// [`QueryParams::cursor_columns`] of the last entity of a page.
+///
+/// Unlike `$skip`, continuing after these values neither skips nor repeats entities when
+/// the collection changes between the pages or when many of them share an `$orderby` value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SkipToken {
+    /// Values in their textual form, cast back to the types of the columns when applied
+    pub values: Vec<Option<String>>,
+}
+
+impl SkipToken {
+    /// Opaque form of the token, used as the `$skiptoken` option
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_string(&self.values).unwrap();
+        json.bytes().map(|b| format!("{b:02x}")).collect()
+    }
+
+    pub fn decode(token: &str) -> Result<Self, ODataError> {
+        let invalid = || ODataError::bad_request(format!("Invalid $skiptoken: {token}"));
+        if !token.len().is_multiple_of(2) || !token.is_ascii() {
+            Err(invalid())?
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&token[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let values = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+        Ok(Self { values })
+    }
+
+    /// Entities ordered after the cursor, i.e. the row-value comparison
+    /// `(c1, c2, ..) > (v1, v2, ..)` honoring the direction of each column and the nulls
+    /// coming first
+    fn filter(&self, columns: &[(Expr, bool)], schema: &DFSchema) -> Result<Expr, ODataError> {
+        if columns.len() != self.values.len() {
+            Err(ODataError::bad_request(
+                "$skiptoken doesn't match the ordering of the collection",
+            ))?
+        }
+
+        let mut filter = None;
+        for ((expr, asc), value) in columns.iter().zip(&self.values).rev() {
+            // The cursor holds the value of the column, which is compared through the same
+            // expression the entities are ordered by, e.g. `lower(name) > lower('Banana')`
+            let value = match value {
+                Some(value) => Some(
+                    expr.clone()
+                        .transform(|e| match e {
+                            Expr::Column(c) => {
+                                let data_type = Expr::Column(c).get_type(schema)?;
+                                Ok(Transformed::yes(cast(lit(value.as_str()), data_type)))
+                            }
+                            e => Ok(Transformed::no(e)),
+                        })
+                        .map_err(ODataError::from_query_error)?
+                        .data,
+                ),
+                None => None,
+            };
+            let after = match value.clone() {
+                Some(value) if *asc => expr.clone().gt(value),
+                Some(value) => expr.clone().lt(value),
+                None => expr.clone().is_not_null(),
+            };
+            filter = Some(match filter {
+                Some(next) => {
+                    let equal = match value {
+                        Some(value) => expr.clone().eq(value),
+                        None => expr.clone().is_null(),
+                    };
+                    after.or(equal.and(next))
+                }
+                None => after,
+            });
+        }
+        Ok(filter.unwrap_or(lit(true)))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Single element of the `$expand` option
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExpandItem {
+    /// Name of the navigation property
+    pub navigation_property: String,
+    /// Query options applied to the related entities (`Rel($select=a;$top=5)`)
+    pub options: ExpandOptions,
+}
+
+/// Nested query options of an `$expand` element
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ExpandOptions {
+    /// Column names
+    pub select: Vec<String>,
+    /// Filter of the related entities
+    pub filter: Option<Expr>,
+    /// Maximum number of related entities to include
+    pub top: Option<usize>,
+    /// Entities related to the related ones
+    pub expand: Vec<ExpandItem>,
+}
+
+impl ExpandItem {
+    pub fn decode_list(expand: &str) -> Result<Vec<Self>, ODataError> {
+        let mut parser = ExpandParser {
+            input: expand,
+            pos: 0,
+        };
+        let items = parser.parse_list()?;
+        parser.skip_whitespace();
+        if !parser.rest().is_empty() {
+            Err(parser.error("unexpected input"))?
+        }
+        Ok(items)
+    }
+
+    /// Number of nesting levels, e.g. `2` for `A($expand=B),C`
+    pub fn depth(items: &[Self]) -> usize {
+        items
+            .iter()
+            .map(|item| 1 + Self::depth(&item.options.expand))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Number of expanded navigation properties at all levels, e.g. `3` for `A($expand=B),C`
+    pub fn breadth(items: &[Self]) -> usize {
+        items
+            .iter()
+            .map(|item| 1 + Self::breadth(&item.options.expand))
+            .sum()
+    }
+}
+
+/// Recursive-descent parser of `$expand`, which is not comma-splittable because of
+/// the nested options:
+///
+/// ```text
+/// list   = [ item ] *( "," [ item ] )
+/// item   = identifier [ "(" option *( ";" option ) ")" ]
+/// option = "$select=" select / "$filter=" filter / "$top=" integer / "$expand=" list
+/// ```
+struct ExpandParser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> ExpandParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, reason: &str) -> ODataError {
+        ODataError::bad_request(format!(
+            "Invalid $expand, {reason} at position {}: {}",
+            self.pos, self.input
+        ))
+    }
+
+    fn parse_list(&mut self) -> Result<Vec<ExpandItem>, ODataError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            // Empty elements are tolerated the same way as in other list options
+            if !matches!(self.peek(), None | Some(',') | Some(')') | Some(';')) {
+                items.push(self.parse_item()?);
+            }
+            if !self.eat(",") {
+                return Ok(items);
+            }
+        }
+    }
+
+    fn parse_item(&mut self) -> Result<ExpandItem, ODataError> {
+        let len = self
+            .rest()
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            Err(self.error("expected navigation property"))?
+        }
+        let navigation_property = self.rest()[..len].to_string();
+        self.pos += len;
+
+        // Counting the related entities takes the same join as expanding them
+        if self.eat("/$count") {
+            Err(UnsupportedFeature::planned(format!(
+                "$expand of {navigation_property}/$count"
+            )))?
+        }
+        if self.peek() == Some('/') {
+            Err(self.error("unsupported path segment"))?
+        }
+
+        let options = if self.eat("(") {
+            self.parse_options()?
+        } else {
+            ExpandOptions::default()
+        };
+
+        Ok(ExpandItem {
+            navigation_property,
+            options,
+        })
+    }
+
+    fn parse_options(&mut self) -> Result<ExpandOptions, ODataError> {
+        let mut options = ExpandOptions::default();
+        loop {
+            self.skip_whitespace();
+            let Some(len) = self.rest().find('=') else {
+                Err(self.error("expected query option"))?
+            };
+            let name = self.rest()[..len].trim();
+            self.pos += len + 1;
+
+            match name {
+                "$expand" => options.expand = self.parse_list()?,
+                "$select" => options.select = decode_select(self.take_value()?),
+                "$filter" => {
+                    let filter: ODataFilter = self.take_value()?.parse()?;
+                    options.filter = Some(filter.into());
+                }
+                "$top" => {
+                    let top = self.take_value()?.trim();
+                    options.top = Some(
+                        top.parse()
+                            .map_err(|_| self.error(&format!("invalid $top value {top}")))?,
+                    );
+                }
+                _ => Err(self.error(&format!("unsupported nested option {name}")))?,
+            }
+
+            if self.eat(")") {
+                return Ok(options);
+            }
+            if !self.eat(";") {
+                Err(self.error("expected ';' or ')'"))?
+            }
+        }
+    }
+
+    /// Value of a nested option, which ends at the first `;` or `)` outside of
+    /// parentheses and string literals
+    fn take_value(&mut self) -> Result<&'a str, ODataError> {
+        let rest = self.rest();
+        let mut depth = 0;
+        let mut in_string = false;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '\'' => in_string = !in_string,
+                _ if in_string => {}
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                ';' | ')' => {
+                    self.pos += i;
+                    return Ok(&rest[..i]);
+                }
+                _ => {}
+            }
+        }
+        Err(self.error("unterminated nested options"))
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
 impl QueryParams {
+    /// Replaces the property names referenced by the query options with the names of the
+    /// columns they are exposed from (see [`crate::context::CollectionContext::property_name_mapper`]).
+    ///
+    /// Names other than the properties are rejected, as the columns themselves are not
+    /// advertised, except for the aggregated properties named by `$apply`.
+    pub fn map_property_names(
+        self,
+        column_name: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ODataError> {
+        let aliases: Vec<_> = self
+            .apply
+            .iter()
+            .flat_map(|a| &a.aggregates)
+            .map(|a| a.alias.clone())
+            .collect();
+        let map = |name: String| -> Result<String, ODataError> {
+            if aliases.contains(&name) {
+                return Ok(name);
+            }
+            column_name(&name)
+                .ok_or_else(|| ODataError::bad_request(format!("Property {name} not found")))
+        };
+
+        let filter = match self.filter {
+            Some(filter) => {
+                for c in filter.column_refs() {
+                    map(c.name.clone())?;
+                }
+                Some(
+                    filter
+                        .transform(|e| match e {
+                            Expr::Column(c) => Ok(Transformed::yes(Expr::Column(
+                                Column::new_unqualified(map(c.name.clone()).unwrap_or(c.name)),
+                            ))),
+                            e => Ok(Transformed::no(e)),
+                        })
+                        .map_err(ODataError::internal)?
+                        .data,
+                )
+            }
+            None => None,
+        };
+
+        let apply = match self.apply {
+            Some(aggregation) => Some(Aggregation {
+                group_by: aggregation
+                    .group_by
+                    .into_iter()
+                    .map(map)
+                    .collect::<Result<_, _>>()?,
+                aggregates: aggregation
+                    .aggregates
+                    .into_iter()
+                    .map(|a| {
+                        Ok(Aggregate {
+                            column: a.column.map(map).transpose()?,
+                            ..a
+                        })
+                    })
+                    .collect::<Result<_, ODataError>>()?,
+            }),
+            None => None,
+        };
+
+        Ok(Self {
+            select: self.select.into_iter().map(map).collect::<Result<_, _>>()?,
+            order_by: self
+                .order_by
+                .into_iter()
+                .map(|(c, asc)| Ok((map(c)?, asc)))
+                .collect::<Result<_, ODataError>>()?,
+            filter,
+            apply,
+            ..self
+        })
+    }
+
+    /// Columns the entities of a paged result are ordered by: the `$orderby` ones followed
+    /// by the keys, see [`SkipToken`]
+    pub fn cursor_columns(&self, options: &ApplyOptions) -> Vec<String> {
+        let ApplyOptions {
+            key_column,
+            key_column_alias,
+            ..
+        } = options;
+        let key_tie_breaker = (!self.orders_by_key(options)).then(|| key_column_alias.to_string());
+        self.order_by
+            .iter()
+            .map(|(c, _)| {
+                if c == key_column {
+                    key_column_alias.to_string()
+                } else {
+                    c.clone()
+                }
+            })
+            .chain(key_tie_breaker)
+            .chain(options.key_columns.iter().skip(1).cloned())
+            .collect()
+    }
+
+    // Whether `$orderby` already tells the entities apart by their (first) key, in either
+    // direction, so that it needn't be repeated as the tie-breaker. Lowercased keys may
+    // still tie.
+    fn orders_by_key(&self, options: &ApplyOptions) -> bool {
+        !options.case_insensitive_order_by
+            && self
+                .order_by
+                .iter()
+                .any(|(c, _)| *c == options.key_column || *c == options.key_column_alias)
+    }
+
     pub fn apply(
         self,
         df: DataFrame,
         addr: &CollectionAddr,
-        key_column: &str,
-        key_column_alias: &str,
+        options: &ApplyOptions,
         default_rows: usize,
         max_rows: usize,
-    ) -> datafusion::error::Result<DataFrame> {
-        // Add key column as alias
-        let df = df.with_column(key_column_alias, col(key_column))?;
+    ) -> Result<AppliedQuery, ODataError> {
+        let key_column = options.key_column.as_str();
+        let key_column_alias = options.key_column_alias.as_str();
+
+        // Declared navigation properties can't be expanded inline yet
+        for item in &self.expand {
+            if options
+                .navigation_properties
+                .contains(&item.navigation_property)
+            {
+                Err(UnsupportedFeature::planned(format!(
+                    "$expand of {}",
+                    item.navigation_property
+                )))?
+            } else {
+                Err(ODataError::bad_request(format!(
+                    "Navigation property not found: {}",
+                    item.navigation_property
+                )))?
+            }
+        }
+
+        // Key identifies a single entity, so ordering and paging it is meaningless
+        if addr.key.is_some() {
+            let option = if self.top.is_some() {
+                Some("$top")
+            } else if self.skip.is_some() {
+                Some("$skip")
+            } else if self.skip_token.is_some() {
+                Some("$skiptoken")
+            } else if !self.order_by.is_empty() {
+                Some("$orderby")
+            } else {
+                None
+            };
+            if let Some(option) = option {
+                Err(ODataError::bad_request(format!(
+                    "{option} can not be applied to a single entity"
+                )))?
+            }
+        }
+        let orders_by_key = self.orders_by_key(options);
+
+        // Derived columns behave like any other property, so they are added first
+        let mut df = df;
+        for (name, sql) in &options.derived_columns {
+            let expr = df.parse_sql_expr(sql).map_err(ODataError::internal)?;
+            df = df.with_column(name, expr).map_err(ODataError::internal)?;
+        }
+        // Hidden columns are unknown to all the options that follow
+        if !options.hidden_columns.is_empty() {
+            let hidden: Vec<_> = options.hidden_columns.iter().map(String::as_str).collect();
+            df = df.drop_columns(&hidden).map_err(ODataError::internal)?;
+        }
+
+        // Aggregation changes the shape of the collection, so it is evaluated before all
+        // the other options, which then refer to the aggregated properties
+        let df = if let Some(aggregation) = &self.apply {
+            if addr.key.is_some() {
+                Err(ODataError::bad_request(
+                    "$apply can not be applied to a single entity",
+                ))?
+            }
+            Self::apply_aggregation(df, aggregation, key_column_alias)?
+        } else if key_column_alias != key_column {
+            // Add key column as alias, unless the key column is used directly
+            df.with_column(key_column_alias, col(key_column))
+                .map_err(ODataError::internal)?
+        } else {
+            df
+        };
+
+        // Filter before narrowing the columns, as `$filter` can reference any property
+        // regardless of `$select`.
+        // If queried by key - filter by it instead of `$filter`
+        let df = if let Some(key) = &addr.key {
+            let filter = key_filter(key, options, df.schema())?;
+            let filter = coerce_filter_literals(filter, df.schema())?;
+            df.filter(filter).map_err(ODataError::from_query_error)?
+        } else {
+            match self.filter {
+                Some(filter) => {
+                    check_filter_columns(&filter, df.schema())?;
+                    if let Some(max_in_list_size) = options.max_in_list_size {
+                        check_in_list_sizes(&filter, max_in_list_size)?;
+                    }
+                    let filter = resolve_filter_functions(
+                        filter,
+                        &options.filter_functions,
+                        &df.task_ctx(),
+                    )?;
+                    let filter = coerce_filter_literals(filter, df.schema())?;
+                    df.filter(filter).map_err(ODataError::from_query_error)?
+                }
+                None => df,
+            }
+        };
+
+        // Entities the feeds would leave out for their null keys are not counted either
+        let df = if addr.key.is_none() && self.apply.is_none() {
+            let mut non_null_keys = Vec::new();
+            if options.skip_null_keys && options.key_columns.is_empty() {
+                non_null_keys.push(col(key_column_alias).is_not_null());
+            }
+            non_null_keys.extend(options.key_columns.iter().map(|c| col(c).is_not_null()));
+            match non_null_keys.into_iter().reduce(Expr::and) {
+                Some(filter) => df.filter(filter).map_err(ODataError::internal)?,
+                None => df,
+            }
+        } else {
+            df
+        };
+
+        // Total number of the matching entities, regardless of paging, so it is taken
+        // before the `$skiptoken` cursor narrows them down to the remaining pages
+        let count = self.count.then(|| df.clone());
+
+        // Entities are ordered by the `$orderby` properties, and down to their keys whenever
+        // the result is paged through, as that's not deterministic otherwise
+        let paged = self.top.is_some() || self.skip.is_some() || self.skip_token.is_some();
+        if paged && self.order_by.is_empty() && addr.key.is_none() {
+            tracing::warn!(
+                key_column,
+                "Paging requested without $orderby - implicitly ordering by key",
+            );
+        }
+        let mut ordering = Vec::new();
+        for (c, asc) in &self.order_by {
+            // Clients order by the real key column, which `$select` may have dropped,
+            // while the alias carries the same values and is always present, so the
+            // alias is what the entities are actually ordered by
+            let c = if c == key_column {
+                key_column_alias
+            } else {
+                c.as_str()
+            };
+            let is_string = matches!(
+                df.schema()
+                    .field_with_unqualified_name(c)
+                    .map(|f| f.data_type()),
+                Ok(DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View)
+            );
+            // Only the sort key is lowercased, the values are returned as is
+            let expr = if options.case_insensitive_order_by && is_string {
+                lower(col(c))
+            } else {
+                col(c)
+            };
+            ordering.push((expr, *asc));
+        }
+        // Entities with equal values are told apart by their keys, and groups are always
+        // returned in the order of their ordinals
+        if options.stable_order
+            || self.skip_token.is_some()
+            || self.apply.is_some()
+            || (paged && self.order_by.is_empty())
+        {
+            if !orders_by_key {
+                ordering.push((col(key_column_alias), true));
+            }
+            if self.apply.is_none() {
+                for key in options.key_columns.iter().skip(1) {
+                    ordering.push((col(Column::new_unqualified(key)), true));
+                }
+            }
+        }
+
+        // Continue after the last entity of the previous page
+        let df = match &self.skip_token {
+            Some(token) if addr.key.is_none() => {
+                let filter = token.filter(&ordering, df.schema())?;
+                df.filter(filter).map_err(ODataError::from_query_error)?
+            }
+            _ => df,
+        };
 
         // Select desired columns
         let df = if self.select.is_empty() {
             df
         } else {
             let mut select: Vec<_> = self.select.iter().map(String::as_str).collect();
-            select.push(key_column_alias);
-            df.select_columns(&select)?
+            // Groups have no key column, only the alias
+            let key_column =
+                (options.select_includes_key && self.apply.is_none()).then_some(key_column);
+            let keys = std::iter::once(key_column_alias)
+                .chain(key_column)
+                .chain(options.key_columns.iter().map(String::as_str));
+            for key in keys {
+                if !select.contains(&key) {
+                    select.push(key);
+                }
+            }
+            df.select_columns(&select)
+                .map_err(ODataError::from_query_error)?
         };
 
-        // If queried by key - ignore the rest
-        if let Some(key) = &addr.key {
-            return df.filter(col(key_column_alias).eq(lit(key.clone())));
+        // Single entity is only subject to `$select` (and `$expand`)
+        if addr.key.is_some() {
+            return Ok(AppliedQuery { df, count });
         }
 
-        let df = match self.filter {
-            Some(filter) => df.filter(filter)?,
-            None => df,
-        };
-
         // Order by
-        let df = if self.order_by.is_empty() {
+        let df = if ordering.is_empty() {
             df
         } else {
             df.sort(
-                self.order_by
+                ordering
                     .into_iter()
-                    .map(|(c, asc)| col(c).sort(asc, true))
+                    .map(|(expr, asc)| expr.sort(asc, true))
                     .collect(),
-            )?
+            )
+            .map_err(ODataError::from_query_error)?
         };
 
         // Skip / limit
-        df.limit(
-            self.skip.unwrap_or(0),
-            Some(std::cmp::min(self.top.unwrap_or(default_rows), max_rows)),
-        )
+        let df = df
+            .limit(
+                self.skip.unwrap_or(0),
+                Some(std::cmp::min(self.top.unwrap_or(default_rows), max_rows)),
+            )
+            .map_err(ODataError::from_query_error)?;
+
+        Ok(AppliedQuery { df, count })
+    }
+
+    /// Groups have no identity of their own, so they are keyed by their ordinal in the order
+    /// of their group-by values, which is the same for every evaluation of the query.
+    /// Ordinals are not addressable, see [`crate::context::CollectionContext::group_type_name`]
+    fn apply_aggregation(
+        df: DataFrame,
+        aggregation: &Aggregation,
+        key_column_alias: &str,
+    ) -> Result<DataFrame, ODataError> {
+        let group_by: Vec<_> = aggregation
+            .group_by
+            .iter()
+            .map(|c| col(Column::new_unqualified(c)))
+            .collect();
+        let ordinal = row_number()
+            .order_by(
+                group_by
+                    .iter()
+                    .map(|c| c.clone().sort(true, true))
+                    .collect(),
+            )
+            .build()
+            .map_err(ODataError::internal)?
+            .alias(key_column_alias);
+        let aggregates = aggregation
+            .aggregates
+            .iter()
+            .map(Aggregate::to_expr)
+            .collect();
+
+        let df = df
+            .aggregate(group_by, aggregates)
+            .map_err(ODataError::from_query_error)?;
+        let columns = df
+            .schema()
+            .columns()
+            .into_iter()
+            .map(Expr::Column)
+            .chain([ordinal])
+            .collect();
+        df.select(columns).map_err(ODataError::internal)
+    }
+}
+
+fn key_filter(
+    key: &KeyValue,
+    options: &ApplyOptions,
+    schema: &DFSchema,
+) -> Result<Expr, ODataError> {
+    let key_column_alias = options.key_column_alias.as_str();
+    match key {
+        key if Some(key) == options.null_key_sentinel.as_ref() => {
+            Ok(col(key_column_alias).is_null())
+        }
+        KeyValue::Composite(_) if options.key_properties.is_empty() => Err(
+            ODataError::bad_request("Collection has no key properties to address by name"),
+        ),
+        KeyValue::Composite(values) => {
+            if let Some((name, _)) = values
+                .iter()
+                .find(|(name, _)| !options.key_properties.iter().any(|(p, _)| p == name))
+            {
+                Err(ODataError::bad_request(format!(
+                    "Property {name} is not part of the key"
+                )))?
+            }
+            // Partial keys would address several entities
+            let mut filter = lit(true);
+            for (property, column) in &options.key_properties {
+                let mut parts = values.iter().filter(|(name, _)| name == property);
+                let (Some((_, value)), None) = (parts.next(), parts.next()) else {
+                    Err(ODataError::bad_request(format!(
+                        "Key requires the property {property} exactly once"
+                    )))?
+                };
+                let Some(value) = value.to_scalar() else {
+                    Err(ODataError::bad_request(
+                        "Nested composite keys are not supported",
+                    ))?
+                };
+                check_key_type(&value, column, schema)?;
+                filter = filter.and(col(Column::new_unqualified(column)).eq(lit(value)));
+            }
+            Ok(filter)
+        }
+        _ if options.key_properties.len() > 1 => Err(ODataError::bad_request(format!(
+            "Key requires the properties {}",
+            options
+                .key_properties
+                .iter()
+                .map(|(p, _)| p.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+        key => {
+            let value = key.to_scalar().unwrap();
+            check_key_type(&value, key_column_alias, schema)?;
+            Ok(col(key_column_alias).eq(lit(value)))
+        }
+    }
+}
+
+// Fractional keys make no sense for the non-numeric key columns, which DataFusion would
+// otherwise try to cast to numbers
+fn check_key_type(value: &ScalarValue, column: &str, schema: &DFSchema) -> Result<(), ODataError> {
+    let Ok(field) = schema.field_with_unqualified_name(column) else {
+        return Ok(());
+    };
+    match value {
+        ScalarValue::Float64(Some(_)) if !field.data_type().is_numeric() => {
+            Err(ODataError::bad_request(format!(
+                "Key {value} can not address the {} column {column}",
+                field.data_type()
+            )))
+        }
+        _ => Ok(()),
     }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct CollectionAddr {
     pub name: String,
-    pub key: Option<String>,
+    pub key: Option<KeyValue>,
 }
 
 impl CollectionAddr {
-    pub fn decode(collection_path_element: &str) -> Option<Self> {
-        let re = regex::Regex::new(r#"^(?<name>[A-Za-z0-9._-]+)(\((?<key>[^)]+)\))?$"#).unwrap();
-        let c = re.captures(collection_path_element)?;
+    /// Parses a path element such as `Coll` or `Coll(key)`.
+    ///
+    /// Element that doesn't name a collection results in [`CollectionNotFound`], while
+    /// a collection followed by a malformed key is a bad request.
+    pub fn decode(collection_path_element: &str) -> Result<Self, ODataError> {
+        static COLLECTION_ADDR: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r#"^(?<name>[A-Za-z0-9._-]+)(\((?<key>[^)]+)\))?$"#).unwrap()
+        });
 
-        let name = c.name("name")?.as_str().to_string();
-        let key = c.name("key").map(|m| m.as_str().to_string());
+        let Some(c) = COLLECTION_ADDR.captures(collection_path_element) else {
+            return Err(CollectionNotFound::new(collection_path_element).into());
+        };
 
-        Some(Self { name, key })
+        let name = c["name"].to_string();
+        let key = match c.name("key") {
+            Some(m) => Some(KeyValue::decode(m.as_str()).ok_or_else(|| {
+                ODataError::bad_request(format!("Invalid key of collection {name}: {}", m.as_str()))
+            })?),
+            None => None,
+        };
+
+        Ok(Self { name, key })
     }
+
+    /// Named value of a composite key, e.g. `region` in `Coll(region='EU')`.
+    ///
+    /// Parameterized collections (see [`crate::context::CollectionContext::is_parameterized`])
+    /// use these as the parameters bound into their queries.
+    pub fn parameter(&self, name: &str) -> Option<&KeyValue> {
+        match &self.key {
+            Some(KeyValue::Composite(values)) => values
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Entity key as it appears in the resource path, e.g. `Coll(1)`, `Coll('a')`,
+/// or `Coll(k1=1,k2='a')`
+#[derive(Debug, Clone)]
+pub enum KeyValue {
+    Int(i64),
+    Float(f64),
+    String(String),
+    Guid(String),
+    Date(chrono::NaiveDate),
+    Composite(Vec<(String, KeyValue)>),
+}
+
+impl KeyValue {
+    /// Parses the OData literal form of the key (the part between the parentheses)
+    pub fn decode(s: &str) -> Option<Self> {
+        let parts = split_unquoted(s, ',');
+
+        if parts.len() == 1 && find_unquoted(s, '=').is_none() {
+            return Self::decode_simple(s);
+        }
+
+        let mut values = Vec::new();
+        for part in parts {
+            let eq = find_unquoted(part, '=')?;
+            let name = part[..eq].trim();
+            if name.is_empty() {
+                return None;
+            }
+            values.push((name.to_string(), Self::decode_simple(&part[eq + 1..])?));
+        }
+        Some(Self::Composite(values))
+    }
+
+    fn decode_simple(s: &str) -> Option<Self> {
+        let s = s.trim();
+
+        if let Some(guid) = s.strip_prefix("guid'").and_then(|s| s.strip_suffix('\'')) {
+            return is_guid(guid).then(|| Self::Guid(guid.to_string()));
+        }
+        if s.len() >= 2 && s.starts_with('\'') && s.ends_with('\'') {
+            return Some(Self::String(s[1..s.len() - 1].replace("''", "'")));
+        }
+        if let Ok(v) = s.parse::<i64>() {
+            return Some(Self::Int(v));
+        }
+        if is_guid(s) {
+            return Some(Self::Guid(s.to_string()));
+        }
+        if let Ok(d) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Some(Self::Date(d));
+        }
+        // Also accepts the `d` (Edm.Double) and `f` (Edm.Single) literal suffixes
+        let number = s.trim_end_matches(['d', 'D', 'f', 'F']);
+        if number
+            .chars()
+            .all(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+        {
+            if let Ok(v) = number.parse::<f64>() {
+                return Some(Self::Float(v));
+            }
+        }
+        None
+    }
+
+    /// Whether entities can be addressed by the values of the type, see [`KeyValue::from_array`]
+    pub fn is_key_type(data_type: &DataType) -> bool {
+        matches!(
+            data_type,
+            DataType::Int8
+                | DataType::Int16
+                | DataType::Int32
+                | DataType::Int64
+                | DataType::UInt8
+                | DataType::UInt16
+                | DataType::UInt32
+                | DataType::UInt64
+                | DataType::Float32
+                | DataType::Float64
+                | DataType::Utf8
+                | DataType::LargeUtf8
+                | DataType::Date32
+        )
+    }
+
+    /// Reads the key of an entity from the specified row
+    pub fn from_array(col: &dyn Array, row: usize) -> Option<Self> {
+        use datafusion::arrow::{array::AsArray, datatypes::*};
+
+        if col.is_null(row) {
+            return None;
+        }
+        match col.data_type() {
+            DataType::Int8 => Some(Self::Int(col.as_primitive::<Int8Type>().value(row).into())),
+            DataType::Int16 => Some(Self::Int(col.as_primitive::<Int16Type>().value(row).into())),
+            DataType::Int32 => Some(Self::Int(col.as_primitive::<Int32Type>().value(row).into())),
+            DataType::Int64 => Some(Self::Int(col.as_primitive::<Int64Type>().value(row))),
+            DataType::UInt8 => Some(Self::Int(col.as_primitive::<UInt8Type>().value(row).into())),
+            DataType::UInt16 => Some(Self::Int(
+                col.as_primitive::<UInt16Type>().value(row).into(),
+            )),
+            DataType::UInt32 => Some(Self::Int(
+                col.as_primitive::<UInt32Type>().value(row).into(),
+            )),
+            DataType::UInt64 => col
+                .as_primitive::<UInt64Type>()
+                .value(row)
+                .try_into()
+                .ok()
+                .map(Self::Int),
+            DataType::Float32 => Some(Self::Float(
+                col.as_primitive::<Float32Type>().value(row).into(),
+            )),
+            DataType::Float64 => Some(Self::Float(col.as_primitive::<Float64Type>().value(row))),
+            DataType::Utf8 => Some(Self::String(col.as_string::<i32>().value(row).to_string())),
+            DataType::LargeUtf8 => {
+                Some(Self::String(col.as_string::<i64>().value(row).to_string()))
+            }
+            DataType::Date32 => col
+                .as_primitive::<Date32Type>()
+                .value_as_date(row)
+                .map(Self::Date),
+            _ => None,
+        }
+    }
+
+    /// Converts a non-composite key into a literal to compare the key column against
+    pub fn to_scalar(&self) -> Option<ScalarValue> {
+        match self {
+            Self::Int(v) => Some(ScalarValue::Int64(Some(*v))),
+            Self::Float(v) => Some(ScalarValue::Float64(Some(*v))),
+            Self::String(v) | Self::Guid(v) => Some(ScalarValue::Utf8(Some(v.clone()))),
+            Self::Date(d) => Some(ScalarValue::Date32(Some(
+                d.signed_duration_since(chrono::NaiveDate::default())
+                    .num_days()
+                    .try_into()
+                    .ok()?,
+            ))),
+            Self::Composite(_) => None,
+        }
+    }
+}
+
+// Float keys are compared by their bits, so that equality stays reflexive (even for `NaN`)
+// and addresses can be used as map keys
+impl PartialEq for KeyValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Int(a), Self::Int(b)) => a == b,
+            (Self::Float(a), Self::Float(b)) => a.to_bits() == b.to_bits(),
+            (Self::String(a), Self::String(b)) | (Self::Guid(a), Self::Guid(b)) => a == b,
+            (Self::Date(a), Self::Date(b)) => a == b,
+            (Self::Composite(a), Self::Composite(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for KeyValue {}
+
+impl std::hash::Hash for KeyValue {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::Int(v) => v.hash(state),
+            Self::Float(v) => v.to_bits().hash(state),
+            Self::String(v) | Self::Guid(v) => v.hash(state),
+            Self::Date(v) => v.hash(state),
+            Self::Composite(v) => v.hash(state),
+        }
+    }
+}
+
+impl std::fmt::Display for KeyValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int(v) => write!(f, "{v}"),
+            // Debug formatting keeps the fractional part of integral values (`2.0`)
+            Self::Float(v) => write!(f, "{v:?}"),
+            Self::String(v) => write!(f, "'{}'", v.replace('\'', "''")),
+            Self::Guid(v) => write!(f, "guid'{v}'"),
+            Self::Date(d) => write!(f, "{}", d.format("%Y-%m-%d")),
+            Self::Composite(values) => {
+                for (i, (name, value)) in values.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{name}={value}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn is_guid(s: &str) -> bool {
+    static GUID: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(
+            r#"^[0-9A-Fa-f]{8}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{12}$"#,
+        )
+        .unwrap()
+    });
+    GUID.is_match(s)
+}
+
+fn find_unquoted(s: &str, c: char) -> Option<usize> {
+    let mut quoted = false;
+    for (i, ch) in s.char_indices() {
+        match ch {
+            '\'' => quoted = !quoted,
+            ch if ch == c && !quoted => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while let Some(i) = find_unquoted(rest, sep) {
+        parts.push(&rest[..i]);
+        rest = &rest[i + sep.len_utf8()..];
+    }
+    parts.push(rest);
+    parts
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::collection::CollectionAddr;
+    use datafusion::{common::DFSchema, prelude::*, scalar::ScalarValue};
+
+    use crate::collection::{
+        decode_usize, key_filter, ApplyOptions, CollectionAddr, ExpandItem, ExpandOptions,
+        KeyValue, QueryParamsRaw, SkipToken,
+    };
+    use crate::error::ODataError;
+
+    fn decode_order_by(order_by: &str) -> Result<Vec<(String, bool)>, ODataError> {
+        let query = QueryParamsRaw {
+            select: None,
+            order_by: Some(order_by.to_string()),
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        };
+        Ok(query.decode()?.order_by)
+    }
+
+    #[test]
+    fn test_skip_top_out_of_range() {
+        assert_eq!(decode_usize("$skip", Some(10u64)).unwrap(), Some(10));
+        assert_eq!(decode_usize::<u64>("$skip", None).unwrap(), None);
+
+        // Wider than `usize` on any target, as `u64` is on the 32-bit ones
+        let res = decode_usize("$top", Some(u128::MAX));
+        assert!(
+            matches!(&res, Err(ODataError::BadRequest(e)) if e.to_string().contains("$top is out of range")),
+            "{res:?}"
+        );
+    }
+
+    #[test]
+    fn test_conflicting_count_options() {
+        let query = |count, inline_count: &str| QueryParamsRaw {
+            count: Some(count),
+            inline_count: Some(inline_count.to_string()),
+            ..Default::default()
+        };
+
+        assert!(query(true, "allpages").decode().unwrap().count);
+        assert!(!query(false, "none").decode().unwrap().count);
+        assert_eq!(
+            query(true, "none").decode().unwrap_err().to_string(),
+            "Conflicting $count=true and $inlinecount=none"
+        );
+        assert_eq!(
+            query(false, "allpages").decode().unwrap_err().to_string(),
+            "Conflicting $count=false and $inlinecount=allpages"
+        );
+    }
+
+    #[test]
+    fn test_skip_token_roundtrip() {
+        let token = SkipToken {
+            values: vec![Some("2021-05-31T00:00:00Z".to_string()), None],
+        };
+        assert_eq!(SkipToken::decode(&token.encode()).unwrap(), token);
+
+        for invalid in ["", "5b", "zz", "é"] {
+            assert_eq!(
+                SkipToken::decode(invalid).unwrap_err().to_string(),
+                format!("Invalid $skiptoken: {invalid}")
+            );
+        }
+    }
+
+    #[test]
+    fn test_select_decode() {
+        let decode_select = |select: &str| {
+            QueryParamsRaw {
+                select: Some(select.to_string()),
+                order_by: None,
+                skip: None,
+                skip_token: None,
+                top: None,
+                filter: None,
+                expand: None,
+                format: None,
+                count: None,
+                inline_count: None,
+                apply: None,
+            }
+            .decode()
+            .unwrap()
+            .select
+        };
+
+        assert_eq!(decode_select("offset, close"), vec!["offset", "close"]);
+        assert_eq!(decode_select(""), Vec::<String>::new());
+        assert_eq!(decode_select("*"), Vec::<String>::new());
+        assert_eq!(decode_select("*,close"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_order_by_decode() {
+        assert_eq!(
+            decode_order_by("offset DESC").unwrap(),
+            vec![("offset".to_string(), false)]
+        );
+        assert_eq!(
+            decode_order_by("offset  asc").unwrap(),
+            vec![("offset".to_string(), true)]
+        );
+        assert_eq!(
+            decode_order_by("offset desc , close\tAsc ,").unwrap(),
+            vec![("offset".to_string(), false), ("close".to_string(), true)]
+        );
+        assert_eq!(
+            decode_order_by(" offset ").unwrap(),
+            vec![("offset".to_string(), true)]
+        );
+        assert!(matches!(
+            decode_order_by("offset sideways"),
+            Err(ODataError::BadRequest(_))
+        ));
+        assert!(matches!(
+            decode_order_by("offset desc asc"),
+            Err(ODataError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_expand_decode() {
+        assert_eq!(
+            ExpandItem::decode_list("Orders, Customer").unwrap(),
+            vec![
+                ExpandItem {
+                    navigation_property: "Orders".to_string(),
+                    options: ExpandOptions::default(),
+                },
+                ExpandItem {
+                    navigation_property: "Customer".to_string(),
+                    options: ExpandOptions::default(),
+                },
+            ]
+        );
+        let res = ExpandItem::decode_list("Customer, Orders/$count");
+        assert!(
+            matches!(&res, Err(ODataError::UnsupportedFeature(e)) if e.feature == "$expand of Orders/$count"),
+            "{res:?}"
+        );
+        assert_eq!(ExpandItem::decode_list("").unwrap(), vec![]);
+        assert!(matches!(
+            ExpandItem::decode_list("/$count"),
+            Err(ODataError::BadRequest(_))
+        ));
+        assert!(matches!(
+            ExpandItem::decode_list("Orders/$value"),
+            Err(ODataError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_expand_depth_and_breadth() {
+        let items = ExpandItem::decode_list("A($expand=B($expand=C),D),E").unwrap();
+        assert_eq!(ExpandItem::depth(&items), 3);
+        assert_eq!(ExpandItem::breadth(&items), 5);
+        assert_eq!(ExpandItem::depth(&[]), 0);
+        assert_eq!(ExpandItem::breadth(&[]), 0);
+    }
+
+    #[test]
+    fn test_expand_decode_nested_options() {
+        assert_eq!(
+            ExpandItem::decode_list("Rel($select=a;$top=5)").unwrap(),
+            vec![ExpandItem {
+                navigation_property: "Rel".to_string(),
+                options: ExpandOptions {
+                    select: vec!["a".to_string()],
+                    top: Some(5),
+                    ..Default::default()
+                },
+            }]
+        );
+
+        assert_eq!(
+            ExpandItem::decode_list(
+                "Orders($select=id, total;$filter=(note eq 'a;b)') and total gt 1;\
+                 $expand=Items($top=2),Buyer), Customer"
+            )
+            .unwrap(),
+            vec![
+                ExpandItem {
+                    navigation_property: "Orders".to_string(),
+                    options: ExpandOptions {
+                        select: vec!["id".to_string(), "total".to_string()],
+                        filter: Some(
+                            col("note")
+                                .eq(lit(ScalarValue::LargeUtf8(Some("a;b)".to_string()))))
+                                .and(col("total").gt(lit(1i64)))
+                        ),
+                        top: None,
+                        expand: vec![
+                            ExpandItem {
+                                navigation_property: "Items".to_string(),
+                                options: ExpandOptions {
+                                    top: Some(2),
+                                    ..Default::default()
+                                },
+                            },
+                            ExpandItem {
+                                navigation_property: "Buyer".to_string(),
+                                options: ExpandOptions::default(),
+                            },
+                        ],
+                    },
+                },
+                ExpandItem {
+                    navigation_property: "Customer".to_string(),
+                    options: ExpandOptions::default(),
+                },
+            ]
+        );
+
+        for invalid in [
+            "Rel(",
+            "Rel($select=a",
+            "Rel($top=x)",
+            "Rel($orderby=a)",
+            "Rel($select=a))",
+            "Rel($select=a)Other",
+        ] {
+            assert!(
+                matches!(
+                    ExpandItem::decode_list(invalid),
+                    Err(ODataError::BadRequest(_))
+                ),
+                "{invalid}"
+            );
+        }
+    }
 
     #[test]
     fn test_collection_addr_decode() {
         assert_eq!(
-            CollectionAddr::decode("coll"),
+            CollectionAddr::decode("coll").ok(),
             Some(CollectionAddr {
                 name: "coll".to_string(),
                 key: None,
@@ -160,7 +1673,7 @@ mod tests {
         );
 
         assert_eq!(
-            CollectionAddr::decode("Coll123"),
+            CollectionAddr::decode("Coll123").ok(),
             Some(CollectionAddr {
                 name: "Coll123".to_string(),
                 key: None,
@@ -168,7 +1681,7 @@ mod tests {
         );
 
         assert_eq!(
-            CollectionAddr::decode("Coll.x_12-3"),
+            CollectionAddr::decode("Coll.x_12-3").ok(),
             Some(CollectionAddr {
                 name: "Coll.x_12-3".to_string(),
                 key: None,
@@ -176,19 +1689,117 @@ mod tests {
         );
 
         assert_eq!(
-            CollectionAddr::decode("Coll(123)"),
+            CollectionAddr::decode("Coll(123)").ok(),
             Some(CollectionAddr {
                 name: "Coll".to_string(),
-                key: Some("123".to_string()),
+                key: Some(KeyValue::Int(123)),
             })
         );
 
         assert_eq!(
-            CollectionAddr::decode("Coll('key')"),
+            CollectionAddr::decode("Coll('key')").ok(),
             Some(CollectionAddr {
                 name: "Coll".to_string(),
-                key: Some("'key'".to_string()),
+                key: Some(KeyValue::String("key".to_string())),
             })
         );
+
+        assert!(matches!(
+            CollectionAddr::decode("Coll(nonsense)"),
+            Err(ODataError::BadRequest(_))
+        ));
+        assert!(matches!(
+            CollectionAddr::decode("Coll/x"),
+            Err(ODataError::CollectionNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_key_value_decode() {
+        assert_eq!(KeyValue::decode("-5"), Some(KeyValue::Int(-5)));
+        assert_eq!(KeyValue::decode("1.5"), Some(KeyValue::Float(1.5)));
+        assert_eq!(KeyValue::Float(f64::NAN), KeyValue::Float(f64::NAN));
+        assert_ne!(KeyValue::Float(0.0), KeyValue::Float(-0.0));
+        assert_eq!(
+            KeyValue::decode("'it''s'"),
+            Some(KeyValue::String("it's".to_string()))
+        );
+        assert_eq!(
+            KeyValue::decode("guid'01234567-89ab-cdef-0123-456789abcdef'"),
+            Some(KeyValue::Guid(
+                "01234567-89ab-cdef-0123-456789abcdef".to_string()
+            ))
+        );
+        assert_eq!(
+            KeyValue::decode("01234567-89ab-cdef-0123-456789abcdef"),
+            Some(KeyValue::Guid(
+                "01234567-89ab-cdef-0123-456789abcdef".to_string()
+            ))
+        );
+        assert_eq!(
+            KeyValue::decode("2023-01-31"),
+            Some(KeyValue::Date(
+                chrono::NaiveDate::from_ymd_opt(2023, 1, 31).unwrap()
+            ))
+        );
+        assert_eq!(
+            KeyValue::decode("k1=1,k2='a,b=c'"),
+            Some(KeyValue::Composite(vec![
+                ("k1".to_string(), KeyValue::Int(1)),
+                ("k2".to_string(), KeyValue::String("a,b=c".to_string())),
+            ]))
+        );
+        assert_eq!(KeyValue::decode("1.5"), Some(KeyValue::Float(1.5)));
+        assert_eq!(KeyValue::decode("-2.5e3d"), Some(KeyValue::Float(-2500.0)));
+        assert_eq!(KeyValue::decode("0.5f"), Some(KeyValue::Float(0.5)));
+        assert_eq!(KeyValue::decode("NaN"), None);
+        assert_eq!(KeyValue::decode("k1=1,"), None);
+        assert_eq!(KeyValue::decode("=1"), None);
+    }
+
+    #[test]
+    fn test_key_value_display_roundtrip() {
+        for s in [
+            "1",
+            "1.5",
+            "2.0",
+            "'it''s'",
+            "guid'01234567-89ab-cdef-0123-456789abcdef'",
+            "2023-01-31",
+            "k1=1,k2='a'",
+        ] {
+            assert_eq!(KeyValue::decode(s).unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn test_key_filter() {
+        let key = |s| KeyValue::decode(s).unwrap();
+        let options = ApplyOptions {
+            key_column_alias: "id".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            key_filter(&key("1"), &options, &DFSchema::empty()).unwrap(),
+            col("id").eq(lit(1i64))
+        );
+        // Names of the key parts can't be checked without the declared key properties
+        assert!(matches!(
+            key_filter(&key("amount=1"), &options, &DFSchema::empty()),
+            Err(ODataError::BadRequest(_))
+        ));
+
+        let options = ApplyOptions {
+            key_properties: vec![("id".to_string(), "id".to_string())],
+            ..options
+        };
+        assert_eq!(
+            key_filter(&key("id=1"), &options, &DFSchema::empty()).unwrap(),
+            lit(true).and(col("id").eq(lit(1i64)))
+        );
+        assert!(matches!(
+            key_filter(&key("amount=1"), &options, &DFSchema::empty()),
+            Err(ODataError::BadRequest(_))
+        ));
     }
 }
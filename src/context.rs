@@ -1,20 +1,44 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use chrono::{DateTime, Utc};
 use datafusion::{
-    arrow::{datatypes::SchemaRef, record_batch::RecordBatch},
+    arrow::{
+        datatypes::{FieldRef, Schema, SchemaRef},
+        record_batch::RecordBatch,
+    },
     dataframe::DataFrame,
 };
+use tokio::sync::OnceCell;
 
 use crate::{
-    collection::{CollectionAddr, QueryParams},
-    error::{KeyColumnNotAssigned, ODataError},
+    collection::{
+        AppliedQuery, ApplyOptions, CollectionAddr, KeyValue, QueryOption, QueryParams,
+        QueryParamsRaw,
+    },
+    error::{KeyColumnNotAssigned, ODataError, UnsupportedFeature},
 };
 
 ///////////////////////////////////////////////////////////////////////////////
 
 pub const DEFAULT_NAMESPACE: &str = "default";
 
+/// Arrow metadata entry declaring the key column, see [`key_column_from_metadata`]
+pub const KEY_COLUMN_METADATA_KEY: &str = "odata.key";
+
+/// Arrow metadata entry marking a schema of the groups of an `$apply` aggregation,
+/// which are entities of [`CollectionContext::group_type_name`] rather than of the collection
+pub const GROUPS_METADATA_KEY: &str = "odata.groups";
+
+/// Arrow field metadata entry exposed as the property documentation,
+/// see [`ServiceContext::include_field_documentation`]
+pub const DESCRIPTION_METADATA_KEY: &str = "description";
+
 ///////////////////////////////////////////////////////////////////////////////
 
 #[async_trait::async_trait]
@@ -24,6 +48,87 @@ pub trait ServiceContext: Send + Sync {
     async fn list_collections(&self) -> Result<Vec<Arc<dyn CollectionContext>>, ODataError>;
 
     fn on_unsupported_feature(&self) -> OnUnsupported;
+
+    /// Context of the collection at the specified address, e.g. `tickers(1)`, used to resolve
+    /// the entity references of [`crate::handlers::odata_entity_ref_handler`]
+    async fn collection_context(
+        &self,
+        addr: CollectionAddr,
+    ) -> Result<Arc<dyn CollectionContext>, ODataError> {
+        Err(UnsupportedFeature::new(format!(
+            "Entity references to {addr:?}"
+        )))?
+    }
+
+    /// Functions declared in `$metadata` and served by
+    /// [`crate::handlers::odata_function_handler`]
+    fn functions(&self) -> Vec<FunctionDeclaration> {
+        Vec::new()
+    }
+
+    /// Entities returned by one of the [`ServiceContext::functions`], called with all of
+    /// its declared parameters
+    async fn call_function(
+        &self,
+        name: &str,
+        _args: Vec<(String, KeyValue)>,
+    ) -> Result<DataFrame, ODataError> {
+        Err(UnsupportedFeature::new(format!("Function {name}()")))?
+    }
+
+    /// Protocol version advertised by `$metadata`
+    fn odata_version(&self) -> ODataVersion {
+        ODataVersion::V3
+    }
+
+    /// Version strings of the CSDL 3.0 `$metadata` document
+    fn metadata_versions(&self) -> MetadataVersions {
+        MetadataVersions::default()
+    }
+
+    /// Whether the [`DESCRIPTION_METADATA_KEY`] entries of the arrow fields are
+    /// emitted as `<Documentation>` of the properties in `$metadata`
+    fn include_field_documentation(&self) -> bool {
+        false
+    }
+
+    /// `Content-Type` sent instead of the specified media type, for the clients and proxies
+    /// expecting a different one, e.g. `application/atom+xml` without the `type=feed` parameter.
+    /// Applies to the service document, `$metadata` and the responses of the collections,
+    /// see [`crate::media_type`] and [`CollectionContext::service`].
+    fn content_type(&self, media_type: &str) -> String {
+        media_type.to_string()
+    }
+
+    /// Whether the `$metadata` and service documents, as well as the Atom feeds and entries of
+    /// the collections, are indented, which eases reading them during development but inflates
+    /// the responses
+    fn pretty_print_xml(&self) -> bool {
+        false
+    }
+
+    /// Whether the responses of internal errors include the underlying message (e.g. of the
+    /// DataFusion error) instead of just `Internal error`. Meant for development, as the
+    /// messages can reveal details of the deployment.
+    fn verbose_errors(&self) -> bool {
+        false
+    }
+
+    /// Cache of the `$metadata` document, which is otherwise computed on every request by
+    /// resolving the schemas of all the collections. Has to outlive the requests, e.g. by
+    /// being held in an `Arc` by the context.
+    ///
+    /// The cache is bypassed while any collection restricts its
+    /// [`CollectionContext::visible_columns`], as the document then depends on the caller.
+    fn metadata_cache(&self) -> Option<Arc<MetadataCache>> {
+        None
+    }
+
+    /// Version of the collections and their schemas. Changing it whenever they change
+    /// invalidates the `$metadata` document kept in the [`ServiceContext::metadata_cache`].
+    fn schema_epoch(&self) -> u64 {
+        0
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -36,40 +141,766 @@ pub trait CollectionContext: Send + Sync {
 
     fn collection_base_url(&self) -> Result<String, ODataError>;
 
+    /// Service the collection belongs to, whose settings (e.g. [`ServiceContext::pretty_print_xml`])
+    /// then apply to the collection as well
+    fn service(&self) -> Option<&dyn ServiceContext> {
+        None
+    }
+
     fn collection_namespace(&self) -> Result<String, ODataError> {
         Ok(DEFAULT_NAMESPACE.to_string())
     }
 
     fn collection_name(&self) -> Result<String, ODataError>;
 
+    /// Name of the entity type, e.g. the singular `Order` for the `Orders` collection.
+    ///
+    /// The collection name is used for the entity set and the URLs, while this one is used
+    /// for the `EntityType` in `$metadata` and the type references of the entities.
+    ///
+    /// Type references are qualified by the namespace (`default.tickers_spy`), so the name
+    /// can't contain dots, see [`to_simple_identifier`].
+    fn entity_type_name(&self) -> Result<String, ODataError> {
+        Ok(to_simple_identifier(&self.collection_name()?))
+    }
+
+    /// Name of the entity type of the groups produced by `$apply`.
+    ///
+    /// Aggregated properties depend on the request, so the type is declared open, keyed
+    /// only by the ordinal of the group. Groups are not addressable and have no entity set.
+    fn group_type_name(&self) -> Result<String, ODataError> {
+        Ok(format!("{}_group", self.entity_type_name()?))
+    }
+
     // Synthetic column name that will be used to propagate entity IDs
     fn key_column_alias(&self) -> String {
         "__id__".to_string()
     }
 
+    /// Whether entity IDs are propagated via an extra column named [`CollectionContext::key_column_alias`].
+    ///
+    /// Contexts opting out must assign the key column (see [`CollectionContext::key_columns`]),
+    /// which is then used directly for IDs, links and lookups by key.
+    fn synthetic_key_column(&self) -> bool {
+        true
+    }
+
+    /// Column that entity IDs are read from, derived from [`CollectionContext::synthetic_key_column`]:
+    /// either the alias or the first assigned key column
+    fn id_column(&self) -> Result<String, ODataError> {
+        if self.synthetic_key_column() {
+            return Ok(self.key_column_alias());
+        }
+        match self.key_columns()?.into_iter().next() {
+            Some(key_column) => Ok(key_column),
+            None => Err(KeyColumnNotAssigned)?,
+        }
+    }
+
     fn key_column(&self) -> Result<String, ODataError> {
         Err(KeyColumnNotAssigned)?
     }
 
+    /// Ordered columns of a composite key, addressed as `Coll(k1=v1,k2=v2)`.
+    ///
+    /// The first column stands for the whole key wherever a single one is expected,
+    /// e.g. [`CollectionContext::key_column_alias`].
+    fn key_columns(&self) -> Result<Vec<String>, ODataError> {
+        Ok(vec![self.key_column()?])
+    }
+
+    /// Key column assigned via [`CollectionContext::key_columns`], otherwise the one declared
+    /// in the arrow metadata, otherwise the first column of a type that keys can be written in
+    /// (see [`KeyValue`])
+    async fn resolve_key_column(&self) -> Result<String, ODataError> {
+        match self.key_columns() {
+            Err(ODataError::KeyColumnNotAssigned(_)) => {}
+            Ok(key_columns) if key_columns.is_empty() => {}
+            res => return res.map(|key_columns| key_columns[0].clone()),
+        }
+
+        let schema = self.schema().await?;
+        if let Some(key_column) = key_column_from_metadata(&schema) {
+            return Ok(key_column);
+        }
+        match schema
+            .fields()
+            .iter()
+            .find(|f| KeyValue::is_key_type(f.data_type()))
+        {
+            Some(field) => Ok(field.name().clone()),
+            None => Err(KeyColumnNotAssigned)?,
+        }
+    }
+
+    /// Key columns assigned via [`CollectionContext::key_columns`], otherwise the single
+    /// [`CollectionContext::resolve_key_column`]
+    async fn resolve_key_columns(&self) -> Result<Vec<String>, ODataError> {
+        match self.key_columns() {
+            Ok(key_columns) if !key_columns.is_empty() => Ok(key_columns),
+            Ok(_) | Err(ODataError::KeyColumnNotAssigned(_)) => {
+                Ok(vec![self.resolve_key_column().await?])
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Names of the properties when they differ from the arrow column names,
+    /// e.g. [`snake_case_to_pascal_case`].
+    ///
+    /// Property names in `$select`, `$filter`, `$orderby`, `$apply` and composite keys are
+    /// mapped back to the column names before the query is built, so
+    /// [`CollectionContext::query`] and [`CollectionContext::property_overrides`] keep dealing
+    /// with the columns. Clients can't refer to the columns by their own names, and no two
+    /// columns may be mapped to the same property.
+    fn property_name_mapper(&self) -> Option<PropertyNameMapper> {
+        None
+    }
+
+    /// Computed properties as `(column_name, sql_expression)` tuples, e.g.
+    /// `("change", "close - open")`.
+    ///
+    /// Expressions are evaluated over the columns of the collection and the resulting columns
+    /// are exposed like any other, see [`CollectionContext::entity_schema`].
+    fn derived_columns(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Names of the scalar UDFs registered in the session that clients may call in `$filter`,
+    /// e.g. `mask_pii(email) eq 'x'`.
+    ///
+    /// Calling any other function not built into `$filter` is not supported.
+    fn filter_functions(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Per-column adjustments of the properties derived from the arrow schema, keyed by column name
+    fn property_overrides(&self) -> HashMap<String, PropertyOverride> {
+        HashMap::new()
+    }
+
+    /// Singletons are single-entity resources addressed without a key,
+    /// served as an entry rather than a feed
+    fn is_singleton(&self) -> bool {
+        false
+    }
+
+    /// Whether the entities can be modified, in which case their links are advertised as
+    /// `edit` links rather than read-only `self` links
+    fn is_writable(&self) -> bool {
+        false
+    }
+
+    /// Scheme of the `<category>` element of Atom entries
+    fn category_scheme(&self) -> String {
+        crate::atom::NS_SCHEME.to_string()
+    }
+
+    /// Term of the `<category>` element of Atom entries, the fully qualified type name by default
+    fn category_term(&self) -> Result<String, ODataError> {
+        Ok(format!(
+            "{}.{}",
+            self.collection_namespace()?,
+            self.entity_type_name()?
+        ))
+    }
+
+    /// Whether the key part of the address (e.g. `region='EU'` in `SalesByRegion(region='EU')`)
+    /// holds parameters of the collection rather than the key of a single entity.
+    ///
+    /// Such collections are served as feeds. Their [`CollectionContext::query`] is expected to
+    /// bind the parameters (see [`CollectionAddr::parameter`]) when building the
+    /// [`DataFrame`] and to apply the query options without the key, so that it is not used
+    /// as an entity filter.
+    fn is_parameterized(&self) -> bool {
+        false
+    }
+
+    /// Whether `$orderby` sorts string properties case-insensitively
+    fn case_insensitive_order_by(&self) -> bool {
+        false
+    }
+
+    /// Whether the entities are ordered by key when there's no `$orderby`, and by key after
+    /// the `$orderby` properties otherwise, so that repeated queries produce identical
+    /// responses regardless of the order in which the batches of a parallel execution arrive
+    fn stable_order(&self) -> bool {
+        false
+    }
+
+    /// Whether the key property is returned even when `$select` leaves it out, as OData
+    /// implies, rather than only identifying the entities via their IDs and links
+    fn select_includes_key(&self) -> bool {
+        false
+    }
+
+    /// Query options clients are allowed to use, e.g. to keep `$filter` and `$orderby` off the
+    /// collections that are too expensive to query arbitrarily. Requests using other options
+    /// are rejected with `400 Bad Request`. The next links of server-driven paging only use
+    /// the allowed `$skiptoken` or `$skip`, and are left out when neither is.
+    fn allowed_query_options(&self) -> HashSet<QueryOption> {
+        QueryOption::ALL.into_iter().collect()
+    }
+
+    /// Relationships to the entities of other collections, declared in `$metadata` as the
+    /// navigation properties of the entity type along with their associations
+    fn navigation_properties(&self) -> Vec<NavigationPropertyDeclaration> {
+        Vec::new()
+    }
+
+    /// Upper bound of the `$expand` nesting levels, see [`crate::collection::ExpandItem::depth`]
+    fn max_expand_depth(&self) -> Option<usize> {
+        None
+    }
+
+    /// Upper bound of the navigation properties expanded by a request,
+    /// see [`crate::collection::ExpandItem::breadth`]
+    fn max_expand_breadth(&self) -> Option<usize> {
+        None
+    }
+
+    /// Upper bound of the values of an `in` operator in `$filter`, as huge lists are costly to
+    /// plan and evaluate
+    fn max_in_list_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Upper bound of the page size clients can request via `Prefer: odata.maxpagesize`
+    fn max_page_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Maximum time the query is allowed to run before failing with `504 Gateway Timeout`
+    fn query_timeout(&self) -> Option<std::time::Duration> {
+        None
+    }
+
+    /// Number of rows per record batch produced when executing the query,
+    /// the session default is used when not set
+    fn batch_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Number of entries after which an Atom feed streamed by
+    /// [`crate::handlers::odata_collection_stream_handler`] is flushed to the client, so that
+    /// latency-sensitive clients receive the entries incrementally rather than in one chunk
+    /// per record batch
+    fn flush_interval_rows(&self) -> Option<usize> {
+        None
+    }
+
     async fn last_updated_time(&self) -> DateTime<Utc>;
 
+    /// Timestamp column holding the modification time of each entity, used as the `<updated>`
+    /// of its Atom entry.
+    ///
+    /// Entries fall back to [`CollectionContext::last_updated_time`] when the value is null or
+    /// the column is left out by `$select`.
+    fn updated_column(&self) -> Option<String> {
+        None
+    }
+
+    /// Column holding the version of each entity (e.g. a revision number or a hash of the
+    /// row), emitted as the `m:etag` of its Atom entry for clients doing optimistic
+    /// concurrency.
+    ///
+    /// Entries carry no etag when the value is null or the column is left out by `$select`.
+    fn etag_column(&self) -> Option<String> {
+        None
+    }
+
     async fn schema(&self) -> Result<SchemaRef, ODataError>;
 
-    async fn query(&self, query: QueryParams) -> Result<DataFrame, ODataError>;
+    /// Schema of the entities, i.e. [`CollectionContext::schema`] extended with the
+    /// [`CollectionContext::derived_columns`]
+    async fn entity_schema(&self) -> Result<SchemaRef, ODataError> {
+        let schema = self.schema().await?;
+        check_unique_field_names(&schema)?;
+        let hidden_columns = self.hidden_columns().await?;
+        let derived_columns = self.derived_columns();
+        if derived_columns.is_empty() && hidden_columns.is_empty() {
+            return Ok(schema);
+        }
+
+        // Derived columns are planned by the session of the collection, which may provide
+        // functions of its own, so their types are taken from the query it actually builds
+        let options = self.apply_options().await?;
+        let query = QueryParamsRaw::default().decode()?;
+        let AppliedQuery { df, .. } = self.query(query, &options).await?;
+        let synthetic_key_column = options.key_column_alias != options.key_column;
+        let fields: Vec<FieldRef> = df
+            .schema()
+            .fields()
+            .iter()
+            .filter(|f| !(synthetic_key_column && f.name() == &options.key_column_alias))
+            .cloned()
+            .collect();
+        Ok(Arc::new(Schema::new_with_metadata(
+            fields,
+            schema.metadata().clone(),
+        )))
+    }
+
+    /// Columns of the [`CollectionContext::schema`] exposed to the client of the current
+    /// request, e.g. all but the PII ones for unprivileged callers. All of them by default.
+    ///
+    /// The other columns are absent from `$metadata` and the entities, and referencing them
+    /// in the query options is rejected the same as referencing unknown properties. The key
+    /// columns stay visible regardless, as they make up the entity IDs.
+    fn visible_columns(&self) -> Option<HashSet<String>> {
+        None
+    }
+
+    /// Columns hidden by [`CollectionContext::visible_columns`], apart from the keys and the
+    /// [`CollectionContext::derived_columns`] replacing them
+    async fn hidden_columns(&self) -> Result<Vec<String>, ODataError> {
+        let Some(visible_columns) = self.visible_columns() else {
+            return Ok(Vec::new());
+        };
+        let key_columns = self.resolve_key_columns().await?;
+        let derived_columns = self.derived_columns();
+        Ok(self
+            .schema()
+            .await?
+            .fields()
+            .iter()
+            .map(|f| f.name())
+            .filter(|c| {
+                !visible_columns.contains(*c)
+                    && !key_columns.contains(c)
+                    && !derived_columns.iter().any(|(name, _)| name == *c)
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// Settings of the collection honored by [`QueryParams::apply`], as gathered from the
+    /// other methods of the context
+    async fn apply_options(&self) -> Result<ApplyOptions, ODataError> {
+        Ok(ApplyOptions {
+            key_column: self.resolve_key_column().await?,
+            key_column_alias: self.id_column()?,
+            case_insensitive_order_by: self.case_insensitive_order_by(),
+            derived_columns: self.derived_columns(),
+            null_key_sentinel: match self.on_null_key() {
+                OnNullKey::Sentinel(key) => Some(key),
+                _ => None,
+            },
+            skip_null_keys: self.on_null_key() == OnNullKey::Skip,
+            stable_order: self.stable_order(),
+            select_includes_key: self.select_includes_key(),
+            filter_functions: self.filter_functions(),
+            key_columns: match self.key_columns() {
+                Ok(key_columns) if key_columns.len() > 1 => key_columns,
+                _ => Vec::new(),
+            },
+            key_properties: match (self.key_columns(), self.property_name_mapper()) {
+                (Ok(key_columns), mapper) => key_columns
+                    .into_iter()
+                    .map(|column| match &mapper {
+                        Some(mapper) => (mapper(&column), column),
+                        None => (column.clone(), column),
+                    })
+                    .collect(),
+                (Err(_), _) => Vec::new(),
+            },
+            hidden_columns: self.hidden_columns().await?,
+            max_in_list_size: self.max_in_list_size(),
+            navigation_properties: self
+                .navigation_properties()
+                .into_iter()
+                .map(|navigation| navigation.name)
+                .collect(),
+        })
+    }
+
+    /// Entities requested by the query, usually selected by [`QueryParams::apply`] with
+    /// the given options
+    async fn query(
+        &self,
+        query: QueryParams,
+        options: &ApplyOptions,
+    ) -> Result<AppliedQuery, ODataError>;
 
     fn on_unsupported_feature(&self) -> OnUnsupported;
 
+    /// What to do with string values that contain characters not allowed in XML documents
+    fn on_invalid_xml_chars(&self) -> OnInvalidXmlChars {
+        OnInvalidXmlChars::Strip
+    }
+
+    /// What to do with entities whose key is null, as those can't be addressed by key
+    fn on_null_key(&self) -> OnNullKey {
+        OnNullKey::Null
+    }
+
     /// Validates the record batches that retunred from datafusion before encode them to xml
     async fn validate(&self, _record_batches: &[RecordBatch]) -> Result<(), ODataError> {
         Ok(())
     }
+
+    /// Transforms every validated record batch right before it's encoded, e.g. to redact
+    /// values. Columns can be modified or dropped, but their types must stay the ones
+    /// declared in `$metadata`.
+    fn transform_batch(&self, batch: RecordBatch) -> Result<RecordBatch, ODataError> {
+        Ok(batch)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Finds the key column declared either in the schema metadata (`"odata.key": "<column>"`)
+/// or in the metadata of the key field itself (`"odata.key": "true"`)
+pub fn key_column_from_metadata(schema: &Schema) -> Option<String> {
+    if let Some(key_column) = schema.metadata().get(KEY_COLUMN_METADATA_KEY) {
+        if schema.field_with_name(key_column).is_ok() {
+            return Some(key_column.clone());
+        }
+        tracing::warn!(key_column, "Key column declared in metadata does not exist");
+    }
+
+    schema
+        .fields()
+        .iter()
+        .find(|f| {
+            f.metadata()
+                .get(KEY_COLUMN_METADATA_KEY)
+                .is_some_and(|v| v.eq_ignore_ascii_case("true"))
+        })
+        .map(|f| f.name().clone())
+}
+
+/// Whether the schema holds the groups of an `$apply` aggregation, see [`GROUPS_METADATA_KEY`]
+pub(crate) fn is_groups_schema(schema: &Schema) -> bool {
+    schema.metadata().contains_key(GROUPS_METADATA_KEY)
+}
+
+/// Rejects schemas with several fields of the same name (e.g. the output of a join), as the
+/// properties of the entity are addressed by name
+pub(crate) fn check_unique_field_names(schema: &Schema) -> Result<(), ODataError> {
+    let mut names = HashSet::new();
+    for field in schema.fields() {
+        if !names.insert(field.name()) {
+            Err(UnsupportedFeature::new(format!(
+                "Duplicate field name {} in the collection schema",
+                field.name()
+            )))?
+        }
+    }
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Maps the name of an arrow column to the name of the property it is exposed as,
+/// see [`CollectionContext::property_name_mapper`]
+pub type PropertyNameMapper = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Columns of the schema by the names of the properties they are exposed as, rejecting
+/// a mapper that gives several columns the same name
+pub(crate) fn property_columns(
+    schema: &Schema,
+    mapper: &PropertyNameMapper,
+) -> Result<HashMap<String, String>, ODataError> {
+    let mut columns = HashMap::new();
+    for field in schema.fields() {
+        let property = mapper(field.name());
+        if let Some(other) = columns.insert(property.clone(), field.name().clone()) {
+            Err(ODataError::internal(format!(
+                "Columns {other} and {} are both exposed as property {property}",
+                field.name()
+            )))?
+        }
+    }
+    Ok(columns)
+}
+
+/// Replaces the characters not allowed in the CSDL simple identifiers with underscores,
+/// e.g. `tickers.spy` with `tickers_spy`, as a dotted name would be taken for a namespace
+/// qualified one. Identifiers can't start with a digit either, so `2024.sales` gives
+/// `_2024_sales`.
+///
+/// Distinct names can give the same identifier (e.g. `a.b` and `a_b`), which `$metadata`
+/// rejects as duplicate entity types.
+pub fn to_simple_identifier(name: &str) -> String {
+    let mut identifier: String = name
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    if !identifier.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        identifier.insert(0, '_');
+    }
+    identifier
+}
+
+/// Appends a path segment to a base URL with exactly one slash in between, e.g. both
+/// `http://example.com/odata` and `http://example.com/odata/` with `tickers` give
+/// `http://example.com/odata/tickers`
+pub fn join_url(base_url: &str, segment: &str) -> String {
+    format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        segment.trim_start_matches('/')
+    )
+}
+
+/// Converts `snake_case` column names to `PascalCase` property names, e.g. `from_symbol`
+/// to `FromSymbol`
+pub fn snake_case_to_pascal_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Overrides the property attributes otherwise derived from the arrow field
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PropertyOverride {
+    /// Nullability to advertise instead of [`datafusion::arrow::datatypes::Field::is_nullable`]
+    pub nullable: Option<bool>,
+    /// EDM type to advertise instead of the one mapped from the arrow type
+    pub edm_type: Option<String>,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ODataVersion {
+    /// CSDL 3.0 (`http://schemas.microsoft.com/ado/2009/11/edm`)
+    V3,
+    /// CSDL 4.0 (`http://docs.oasis-open.org/odata/ns/edm`)
+    V4,
+}
+
+/// Versions declared by the `<edmx:Edmx>` and `<edmx:DataServices>` elements of `$metadata`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataVersions {
+    /// `Version` of `<edmx:Edmx>`
+    pub edmx: String,
+    /// `m:DataServiceVersion` of `<edmx:DataServices>`
+    pub data_service: String,
+    /// `m:MaxDataServiceVersion` of `<edmx:DataServices>`
+    pub max_data_service: String,
+}
+
+impl Default for MetadataVersions {
+    fn default() -> Self {
+        Self {
+            edmx: "1.0".to_string(),
+            data_service: "3.0".to_string(),
+            max_data_service: "3.0".to_string(),
+        }
+    }
+}
+
+/// Number of `$metadata` documents kept by [`MetadataCache::default`]
+pub const DEFAULT_METADATA_CACHE_CAPACITY: usize = 4;
+
+/// `$metadata` documents keyed by the [`ServiceContext::schema_epoch`] they were computed at,
+/// see [`ServiceContext::metadata_cache`]
+pub struct MetadataCache {
+    /// In the order the epochs were first requested, so the oldest one is evicted first
+    documents: Mutex<VecDeque<(u64, Arc<OnceCell<String>>)>>,
+    capacity: usize,
+    hits: AtomicUsize,
+}
+
+impl MetadataCache {
+    /// Keeps the documents of up to `capacity` distinct epochs, which only matters when the
+    /// context switches back and forth between them
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            documents: Mutex::new(VecDeque::new()),
+            capacity,
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn clear(&self) {
+        self.documents.lock().unwrap().clear();
+    }
+
+    /// Number of `$metadata` requests served from the cache
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Document of the epoch, computed by `document` unless cached. Concurrent requests of
+    /// an uncached epoch wait for a single computation, and share its document.
+    pub(crate) async fn get_or_try_insert<F, Fut>(
+        &self,
+        epoch: u64,
+        document: F,
+    ) -> Result<String, ODataError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<String, ODataError>>,
+    {
+        let cell = {
+            let mut documents = self.documents.lock().unwrap();
+            match documents.iter().find(|(e, _)| *e == epoch) {
+                Some((_, cell)) => cell.clone(),
+                None => {
+                    // Documents of the previous epochs are unlikely to be requested again
+                    if documents.len() >= self.capacity {
+                        documents.pop_front();
+                    }
+                    let cell = Arc::new(OnceCell::new());
+                    documents.push_back((epoch, cell.clone()));
+                    cell
+                }
+            }
+        };
+
+        let mut computed = false;
+        let document = cell
+            .get_or_try_init(|| {
+                computed = true;
+                document()
+            })
+            .await?;
+        if !computed {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(document.clone())
+    }
+}
+
+impl Default for MetadataCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_METADATA_CACHE_CAPACITY)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Side-effect free function of the service, e.g. `GetTopMovers(count=5)`,
+/// see [`ServiceContext::functions`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionDeclaration {
+    pub name: String,
+    /// Tuples (parameter_name, edm_type), e.g. `("count", "Edm.Int32")`
+    pub parameters: Vec<(String, String)>,
+    /// Collection of the returned entities
+    pub entity_set: String,
+}
+
+/// Relationship of an entity to the ones of another collection, e.g. the `Category` of a
+/// `Product`, see [`CollectionContext::navigation_properties`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NavigationPropertyDeclaration {
+    pub name: String,
+    /// Collection of the related entities
+    pub entity_set: String,
+    /// Whether an entity can relate to many entities, rather than to at most one
+    pub many: bool,
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OnUnsupported {
     /// Return an error or crash
     Error,
     /// Log error and recover as gracefully as possible
     Warn,
+    /// Keep any column of an unmapped type as `Edm.String` and render its values as text using
+    /// arrow display formatting (structs and maps as JSON)
+    Stringify,
+    /// Keep the column as `Edm.String` and emit all its values as nulls
+    Null,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnInvalidXmlChars {
+    /// Remove the offending characters from the value
+    Strip,
+    /// Fail the request
+    Error,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OnNullKey {
+    /// Identify the entity as `Collection(null)`, which can't be looked up
+    Null,
+    /// Leave the entity out of the feeds and their count, and log a warning
+    Skip,
+    /// Identify the entity by the specified key, looking up this key then matches the
+    /// entities whose key is null, so it should be a value the key column never holds
+    Sentinel(KeyValue),
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_metadata_cache_evicts_oldest_epoch() {
+        let cache = MetadataCache::new(2);
+        let computed = AtomicUsize::new(0);
+        let get = |epoch: u64| {
+            let computed = &computed;
+            cache.get_or_try_insert(epoch, move || async move {
+                computed.fetch_add(1, Ordering::Relaxed);
+                Ok(format!("epoch {epoch}"))
+            })
+        };
+
+        assert_eq!(get(1).await.unwrap(), "epoch 1");
+        assert_eq!(get(2).await.unwrap(), "epoch 2");
+        assert_eq!(get(3).await.unwrap(), "epoch 3");
+        assert_eq!(computed.load(Ordering::Relaxed), 3);
+
+        // Only the document of the first epoch made room for the third one
+        assert_eq!(get(2).await.unwrap(), "epoch 2");
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(get(1).await.unwrap(), "epoch 1");
+        assert_eq!(computed.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_cache_single_flight() {
+        let cache = MetadataCache::default();
+        let computed = AtomicUsize::new(0);
+        let get = || {
+            cache.get_or_try_insert(0, || async {
+                computed.fetch_add(1, Ordering::Relaxed);
+                // Lets the other requests in while the document is being computed
+                tokio::task::yield_now().await;
+                Ok("document".to_string())
+            })
+        };
+
+        let documents = tokio::join!(get(), get(), get());
+        assert_eq!(documents.0.unwrap(), "document");
+        assert_eq!(documents.2.unwrap(), "document");
+        assert_eq!(computed.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.hits(), 2);
+
+        // Failed computations are not cached
+        let res = cache
+            .get_or_try_insert(1, || async { Err(ODataError::internal("no schema")) })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(
+            cache
+                .get_or_try_insert(1, || async { Ok("retried".to_string()) })
+                .await
+                .unwrap(),
+            "retried"
+        );
+    }
 }
@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use datafusion::arrow::{array::RecordBatch, csv::WriterBuilder, datatypes::Schema};
+
+use crate::{context::CollectionContext, error::ODataError};
+
+///////////////////////////////////////////////////////////////////////////////
+
+// Not an OData format, but a convenient way to export the query results
+//
+// offset,from_symbol,close
+// 0,spy,135.5625
+// 1,spy,136.5622
+pub fn write_csv_from_records<W>(
+    record_batches: Vec<RecordBatch>,
+    ctx: &dyn CollectionContext,
+    writer: &mut W,
+) -> Result<(), ODataError>
+where
+    W: std::io::Write,
+{
+    let key_column_alias = ctx.id_column()?;
+    let mut writer = WriterBuilder::new().with_header(true).build(writer);
+
+    for mut batch in record_batches {
+        // Synthetic key column is not a part of the entity
+        if ctx.synthetic_key_column() {
+            if let Ok(index) = batch.schema().index_of(&key_column_alias) {
+                batch.remove_column(index);
+            }
+        }
+        if let Some(mapper) = ctx.property_name_mapper() {
+            let fields: Vec<_> = batch
+                .schema()
+                .fields()
+                .iter()
+                .map(|f| f.as_ref().clone().with_name(mapper(f.name())))
+                .collect();
+            batch = RecordBatch::try_new(Arc::new(Schema::new(fields)), batch.columns().to_vec())
+                .map_err(ODataError::internal)?;
+        }
+        writer.write(&batch).map_err(ODataError::internal)?;
+    }
+
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use datafusion::arrow::datatypes::DataType;
+use datafusion::{arrow::datatypes::DataType, common::SchemaError, error::DataFusionError};
 use std::string::FromUtf8Error;
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -22,6 +22,8 @@ pub enum ODataError {
     #[error(transparent)]
     KeyColumnNotAssigned(#[from] KeyColumnNotAssigned),
     #[error(transparent)]
+    QueryTimeout(#[from] QueryTimeout),
+    #[error(transparent)]
     Internal(InternalError),
 }
 
@@ -30,18 +32,59 @@ impl ODataError {
         Self::Internal(InternalError::new(error))
     }
 
+    /// Makes the response of an internal error include the underlying message, which is
+    /// hidden from the clients otherwise, see [`crate::context::ServiceContext::verbose_errors`]
+    pub fn with_details(self, verbose: bool) -> Self {
+        match self {
+            Self::Internal(e) if verbose => Self::Internal(InternalError { verbose, ..e }),
+            Self::FromUtf8Error(e) if verbose => Self::Internal(InternalError {
+                source: e.into(),
+                verbose,
+            }),
+            e => e,
+        }
+    }
+
     pub fn bad_request(
         error: impl Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
     ) -> Self {
         Self::BadRequest(BadRequest::new(error))
     }
 
+    /// Classifies an error of building or executing a query from the options of the request.
+    ///
+    /// Errors of the plan (e.g. an unknown property or incomparable types) are caused by the
+    /// options, so they are client errors, while the other ones are internal. [`ODataError`]s
+    /// raised from within DataFusion (see [`DataFusionError::External`]) are passed through.
+    ///
+    /// DataFusion messages describe the whole schema, including the hidden columns, so clients
+    /// only receive the name of the offending property, or a generic message.
+    pub fn from_query_error(error: DataFusionError) -> Self {
+        match error {
+            DataFusionError::External(e) if e.is::<ODataError>() => *e.downcast().unwrap(),
+            DataFusionError::Context(_, e) => Self::from_query_error(*e),
+            DataFusionError::SchemaError(SchemaError::FieldNotFound { field, .. }, _) => {
+                Self::bad_request(format!("Property {} not found", field.name))
+            }
+            DataFusionError::SchemaError(SchemaError::AmbiguousReference { field }, _) => {
+                Self::bad_request(format!("Property {} is ambiguous", field.name))
+            }
+            DataFusionError::Plan(_)
+            | DataFusionError::SchemaError(..)
+            | DataFusionError::SQL(..) => {
+                tracing::debug!(%error, "Query options rejected by the planner");
+                Self::bad_request("Query options can not be applied to the collection")
+            }
+            _ => Self::internal(error),
+        }
+    }
+
     pub fn handle_no_table_as_collection_not_found(
         collection: impl Into<String>,
-        err: datafusion::error::DataFusionError,
+        err: DataFusionError,
     ) -> Self {
         match err {
-            datafusion::error::DataFusionError::Plan(e) if e.contains("No table named") => {
+            DataFusionError::Plan(e) if e.contains("No table named") => {
                 Self::CollectionNotFound(CollectionNotFound::new(collection))
             }
             _ => Self::internal(err),
@@ -52,6 +95,11 @@ impl ODataError {
 impl axum::response::IntoResponse for ODataError {
     fn into_response(self) -> axum::response::Response {
         match self {
+            Self::Internal(e) if e.verbose => (
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Internal error: {}", e.source),
+            )
+                .into_response(),
             Self::Internal(_) | Self::FromUtf8Error(_) => {
                 (http::StatusCode::INTERNAL_SERVER_ERROR, "Internal error").into_response()
             }
@@ -62,6 +110,7 @@ impl axum::response::IntoResponse for ODataError {
             Self::CollectionAddressNotAssigned(e) => e.into_response(),
             Self::KeyColumnNotAssigned(e) => e.into_response(),
             Self::UnsupportedNetProtocol(e) => e.into_response(),
+            Self::QueryTimeout(e) => e.into_response(),
         }
     }
 }
@@ -73,14 +122,43 @@ impl axum::response::IntoResponse for ODataError {
 pub struct InternalError {
     #[source]
     pub source: Box<dyn std::error::Error + Send + Sync + 'static>,
+    /// Whether the response includes the message of the source, see [`ODataError::with_details`]
+    verbose: bool,
 }
 
 impl InternalError {
     pub fn new(error: impl Into<Box<dyn std::error::Error + Send + Sync + 'static>>) -> Self {
         Self {
             source: error.into(),
+            verbose: false,
         }
     }
+
+    /// Kind of the underlying DataFusion error (e.g. `plan`, `execution`), if caused by one
+    pub fn datafusion_category(&self) -> Option<&'static str> {
+        use datafusion::error::DataFusionError as E;
+
+        let err = self.source.downcast_ref::<E>()?.find_root();
+        Some(match err {
+            E::ArrowError(..) => "arrow",
+            E::ObjectStore(_) => "object_store",
+            E::IoError(_) => "io",
+            E::SQL(..) => "sql",
+            E::NotImplemented(_) => "not_implemented",
+            E::Internal(_) => "internal",
+            E::Plan(_) => "plan",
+            E::Configuration(_) => "configuration",
+            E::SchemaError(..) => "schema",
+            E::Execution(_) | E::ExecutionJoin(_) => "execution",
+            E::ResourcesExhausted(_) => "resources_exhausted",
+            E::External(_) => "external",
+            E::Context(..) => "context",
+            E::Substrait(_) => "substrait",
+            // Variants that depend on optional features
+            #[allow(unreachable_patterns)]
+            _ => "other",
+        })
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -195,15 +273,26 @@ impl axum::response::IntoResponse for UnsupportedNetProtocol {
 ///////////////////////////////////////////////////////////////////////////////
 
 #[derive(thiserror::Error, Debug)]
-#[error("Unsupported feature: {feature}")]
+#[error("Unsupported feature: {feature}{}", if *.planned { " (planned)" } else { "" })]
 pub struct UnsupportedFeature {
+    /// Specific construct that is not supported, e.g. `Function year() in $filter`
     pub feature: String,
+    /// Whether the support is on the roadmap
+    pub planned: bool,
 }
 
 impl UnsupportedFeature {
     pub fn new(feature: impl Into<String>) -> Self {
         Self {
             feature: feature.into(),
+            planned: false,
+        }
+    }
+
+    pub fn planned(feature: impl Into<String>) -> Self {
+        Self {
+            feature: feature.into(),
+            planned: true,
         }
     }
 }
@@ -216,8 +305,34 @@ impl axum::response::IntoResponse for UnsupportedFeature {
 
 ///////////////////////////////////////////////////////////////////////////////
 
+#[derive(thiserror::Error, Debug)]
+#[error("Query timed out after {timeout:?}")]
+pub struct QueryTimeout {
+    pub timeout: std::time::Duration,
+}
+
+impl QueryTimeout {
+    pub fn new(timeout: std::time::Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl axum::response::IntoResponse for QueryTimeout {
+    fn into_response(self) -> axum::response::Response {
+        (http::StatusCode::GATEWAY_TIMEOUT, self.to_string()).into_response()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
 impl From<quick_xml::Error> for ODataError {
     fn from(error: quick_xml::Error) -> Self {
         ODataError::Internal(InternalError::new(error))
     }
 }
+
+impl From<serde_json::Error> for ODataError {
+    fn from(error: serde_json::Error) -> Self {
+        ODataError::Internal(InternalError::new(error))
+    }
+}
@@ -1,10 +1,23 @@
-use chrono::{DateTime, Utc};
+use std::sync::{Arc, LazyLock};
+
+use bigdecimal::BigDecimal;
+use chrono::{NaiveDate, NaiveTime};
 use datafusion::{
-    logical_expr::{expr::InList, BinaryExpr, Operator},
+    arrow::datatypes::{DataType, IntervalMonthDayNano, TimeUnit, DECIMAL128_MAX_PRECISION},
+    common::{
+        tree_node::{Transformed, TreeNode, TreeNodeRecursion},
+        DFSchema,
+    },
+    error::DataFusionError,
+    execution::FunctionRegistry,
+    logical_expr::{
+        expr::{InList, ScalarFunction},
+        BinaryExpr, ColumnarValue, Operator, ScalarUDF, ScalarUDFImpl, Signature, Volatility,
+    },
     prelude::*,
     scalar::ScalarValue,
 };
-use odata_params::filters as odata_filters;
+use regex::Regex;
 
 use crate::error::*;
 
@@ -25,12 +38,27 @@ impl std::str::FromStr for ODataFilter {
     type Err = ODataError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let odata_exprs = odata_params::filters::parse_str(s).map_err(ODataError::bad_request)?;
-        let df_exprs = odata_expr_to_df_expr(&odata_exprs)?;
-        Ok(ODataFilter(df_exprs))
+        let mut parser = FilterParser { input: s, pos: 0 };
+        let expr = parser.parse_or()?;
+        parser.skip_whitespace();
+        if !parser.rest().is_empty() {
+            Err(parser.error("unexpected input"))?
+        }
+        Ok(ODataFilter(expr))
     }
 }
 
+/// Parses the `$filter` string the same way requests do, returning the resulting expression
+/// along with its SQL-like representation, e.g. `offset = Int64(1)`, for logging and debugging.
+///
+/// Literals are not yet coerced to the types of the compared columns at this point, as that
+/// requires the schema of the collection.
+pub fn filter_to_sql(filter: &str) -> Result<(Expr, String), ODataError> {
+    let expr = Expr::from(filter.parse::<ODataFilter>()?);
+    let sql = expr.to_string();
+    Ok((expr, sql))
+}
+
 impl<'de> serde::Deserialize<'de> for ODataFilter {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -56,75 +84,812 @@ impl<'de> serde::de::Visitor<'de> for ODataFilterVisitor {
 
 ///////////////////////////////////////////////////////////////////////////////
 
-fn odata_expr_to_df_expr(res: &odata_filters::Expr) -> Result<Expr, ODataError> {
-    match res {
-        odata_filters::Expr::Or(l, r) => Ok(Expr::BinaryExpr(BinaryExpr::new(
-            Box::new(odata_expr_to_df_expr(l)?),
-            Operator::Or,
-            Box::new(odata_expr_to_df_expr(r)?),
-        ))),
-        odata_filters::Expr::And(l, r) => Ok(Expr::BinaryExpr(BinaryExpr::new(
-            Box::new(odata_expr_to_df_expr(l)?),
-            Operator::And,
-            Box::new(odata_expr_to_df_expr(r)?),
-        ))),
-        odata_filters::Expr::Compare(l, op, r) => Ok(Expr::BinaryExpr(BinaryExpr::new(
-            Box::new(odata_expr_to_df_expr(l)?),
-            odata_op_to_df_op(op),
-            Box::new(odata_expr_to_df_expr(r)?),
-        ))),
-        odata_filters::Expr::Value(v) => Ok(Expr::Literal(odata_value_to_df_value(v)?)),
-        odata_filters::Expr::Not(e) => Ok(Expr::Not(Box::new(odata_expr_to_df_expr(e)?))),
-        odata_filters::Expr::In(i, l) => Ok(Expr::InList(InList::new(
-            Box::new(odata_expr_to_df_expr(i)?),
-            l.iter()
-                .map(odata_expr_to_df_expr)
-                .collect::<Result<Vec<Expr>, ODataError>>()?,
-            false,
-        ))),
-        odata_filters::Expr::Identifier(s) => Ok(Expr::Column(Column::new_unqualified(s))),
-        odata_filters::Expr::Function(..) => {
-            Err(UnsupportedFeature::new("Function within the filter is not supported").into())
-        }
-    }
-}
-
-fn odata_value_to_df_value(v: &odata_filters::Value) -> Result<ScalarValue, ODataError> {
-    match v {
-        odata_filters::Value::String(s) => Ok(ScalarValue::LargeUtf8(Some(s.clone()))),
-        odata_filters::Value::Bool(b) => Ok(ScalarValue::Boolean(Some(*b))),
-        odata_filters::Value::Null => Ok(ScalarValue::Null),
-        odata_filters::Value::Number(d) => {
-            let d = d
-                .to_string()
+const UTC: &str = "UTC";
+
+static GUID_LITERAL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^[0-9A-Fa-f]{8}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{12}")
+        .unwrap()
+});
+
+static DATE_TIME_LITERAL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?<date>[0-9]{4}-[0-9]{2}-[0-9]{2})(T(?<time>[0-9]{1,2}:[0-9]{2}(:[0-9]{2}(\.[0-9]{1,9})?)?)(?<offset>Z|[+-][0-9]{2}(:?[0-9]{2})?))?",
+    )
+    .unwrap()
+});
+
+static TIME_LITERAL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[0-9]{1,2}:[0-9]{2}(:[0-9]{2}(\.[0-9]{1,9})?)?").unwrap());
+
+static DURATION_LITERAL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^duration'(?<sign>-)?P((?<days>[0-9]+)D)?(?<time>T((?<hours>[0-9]+)H)?((?<minutes>[0-9]+)M)?((?<seconds>[0-9]+)(\.(?<fraction>[0-9]{1,9}))?S)?)?'",
+    )
+    .unwrap()
+});
+
+static NUMBER_LITERAL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^-?[0-9]+(\.[0-9]*)?([eE][+-]?[0-9]+)?").unwrap());
+
+/// Recursive-descent parser of `$filter`, which translates it into an [`Expr`] as it goes:
+///
+/// ```text
+/// or      = and *( "or" and )
+/// and     = not *( "and" not )
+/// not     = "not" not / compare
+/// compare = sum [ ( "eq" / "ne" / "gt" / "ge" / "lt" / "le" ) sum / "in" "(" list ")" ]
+/// sum     = operand *( ( "add" / "sub" ) operand )
+/// operand = "(" or ")" / literal / identifier [ "(" [ list ] ")" ]
+/// list    = or *( "," or )
+/// ```
+///
+/// Literals are strings (`'it''s'`), numbers (`-1.5e3`), `true`, `false`, `null`, GUIDs,
+/// dates (`2020-03-10`), date-times (`2020-03-10T10:00:00Z`) and durations (`duration'P1DT2H'`).
+struct FilterParser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> FilterParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    // Keywords are only recognized as whole words, e.g. `order` is not `or` followed by `der`
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let is_keyword = rest.starts_with(keyword)
+            && !rest[keyword.len()..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_');
+        if is_keyword {
+            self.pos += keyword.len();
+        }
+        is_keyword
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), ODataError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{token}'")))
+        }
+    }
+
+    fn error(&self, reason: &str) -> ODataError {
+        ODataError::bad_request(format!(
+            "Invalid $filter, {reason} at position {}: {}",
+            self.pos, self.input
+        ))
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ODataError> {
+        let mut expr = self.parse_and()?;
+        while self.eat_keyword("or") {
+            expr = expr.or(self.parse_and()?);
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ODataError> {
+        let mut expr = self.parse_not()?;
+        while self.eat_keyword("and") {
+            expr = expr.and(self.parse_not()?);
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, ODataError> {
+        if self.eat_keyword("not") {
+            Ok(Expr::Not(Box::new(self.parse_not()?)))
+        } else {
+            self.parse_compare()
+        }
+    }
+
+    fn parse_compare(&mut self) -> Result<Expr, ODataError> {
+        let left = self.parse_sum()?;
+        for (keyword, op) in [
+            ("eq", Operator::Eq),
+            ("ne", Operator::NotEq),
+            ("gt", Operator::Gt),
+            ("ge", Operator::GtEq),
+            ("lt", Operator::Lt),
+            ("le", Operator::LtEq),
+        ] {
+            if self.eat_keyword(keyword) {
+                let right = self.parse_sum()?;
+                return Ok(Expr::BinaryExpr(BinaryExpr::new(
+                    Box::new(left),
+                    op,
+                    Box::new(right),
+                )));
+            }
+        }
+        if self.eat_keyword("in") {
+            self.expect("(")?;
+            let list = self.parse_list()?;
+            return Ok(Expr::InList(InList::new(Box::new(left), list, false)));
+        }
+        Ok(left)
+    }
+
+    fn parse_sum(&mut self) -> Result<Expr, ODataError> {
+        let mut expr = self.parse_operand()?;
+        loop {
+            let op = if self.eat_keyword("add") {
+                Operator::Plus
+            } else if self.eat_keyword("sub") {
+                Operator::Minus
+            } else {
+                return Ok(expr);
+            };
+            let right = self.parse_operand()?;
+            expr = Expr::BinaryExpr(BinaryExpr::new(Box::new(expr), op, Box::new(right)));
+        }
+    }
+
+    /// Elements up to the closing parenthesis, which is consumed too
+    fn parse_list(&mut self) -> Result<Vec<Expr>, ODataError> {
+        let mut list = Vec::new();
+        if self.eat(")") {
+            return Ok(list);
+        }
+        loop {
+            list.push(self.parse_or()?);
+            if self.eat(")") {
+                return Ok(list);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Expr, ODataError> {
+        self.skip_whitespace();
+        let rest = self.rest();
+
+        if self.eat("(") {
+            let expr = self.parse_or()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        if rest.starts_with('\'') {
+            return self.parse_string();
+        }
+        // GUIDs can start with a letter, so they are told apart from identifiers first
+        if let Some(m) = GUID_LITERAL.find(rest) {
+            self.pos += m.len();
+            return Ok(lit(ScalarValue::LargeUtf8(Some(m.as_str().to_string()))));
+        }
+        if let Some(c) = DATE_TIME_LITERAL.captures(rest) {
+            self.pos += c[0].len();
+            return self.date_time_literal(&c);
+        }
+        if rest.starts_with("duration'") {
+            let Some(c) = DURATION_LITERAL.captures(rest) else {
+                Err(self.error("invalid duration"))?
+            };
+            self.pos += c[0].len();
+            return self.duration_literal(&c);
+        }
+        if let Some(m) = TIME_LITERAL.find(rest) {
+            Err(UnsupportedFeature::new(format!(
+                "Time literal {} in $filter",
+                m.as_str()
+            )))?
+        }
+        if let Some(m) = NUMBER_LITERAL.find(rest) {
+            let number = m
+                .as_str()
+                .parse::<BigDecimal>()
+                .map_err(|_| self.error("invalid number"))?;
+            self.pos += m.len();
+            return Ok(lit(decode_number(&number)?));
+        }
+
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if len == 0 || rest.starts_with(|c: char| c.is_ascii_digit()) {
+            Err(self.error("expected operand"))?
+        }
+        let name = &rest[..len];
+        self.pos += len;
+
+        if name.eq_ignore_ascii_case("true") {
+            return Ok(lit(true));
+        }
+        if name.eq_ignore_ascii_case("false") {
+            return Ok(lit(false));
+        }
+        if name.eq_ignore_ascii_case("null") {
+            return Ok(lit(ScalarValue::Null));
+        }
+        if self.eat("(") {
+            let args = self.parse_list()?;
+            return odata_function_to_df_expr(name, args);
+        }
+        Ok(Expr::Column(Column::new_unqualified(name)))
+    }
+
+    /// String literal, escaping the quote by doubling it (`'it''s'`) or with a backslash
+    /// along with the other usual escape sequences (`'it\'s'`, `'\n'`, `'é'`)
+    fn parse_string(&mut self) -> Result<Expr, ODataError> {
+        let start = self.pos;
+        let mut value = String::new();
+        let mut chars = self.rest().char_indices().skip(1).peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\'' if chars.peek().is_some_and(|(_, c)| *c == '\'') => {
+                    chars.next();
+                    value.push('\'');
+                }
+                '\'' => {
+                    self.pos += i + 1;
+                    return Ok(lit(ScalarValue::LargeUtf8(Some(value))));
+                }
+                '\\' => {
+                    let escaped = match chars.next() {
+                        Some((_, '\'')) => Some('\''),
+                        Some((_, '\\')) => Some('\\'),
+                        Some((_, 'n')) => Some('\n'),
+                        Some((_, 'r')) => Some('\r'),
+                        Some((_, 't')) => Some('\t'),
+                        Some((_, 'u')) => {
+                            let mut code = String::new();
+                            while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_hexdigit()) {
+                                code.push(c);
+                            }
+                            u32::from_str_radix(&code, 16).ok().and_then(char::from_u32)
+                        }
+                        _ => None,
+                    };
+                    match escaped {
+                        Some(c) => value.push(c),
+                        None => {
+                            self.pos = start + i;
+                            Err(self.error("invalid escape sequence"))?
+                        }
+                    }
+                }
+                c => value.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    // Date-times are converted to UTC instants and dates to the midnight of the day
+    fn date_time_literal(&self, c: &regex::Captures) -> Result<Expr, ODataError> {
+        let date = NaiveDate::parse_from_str(&c["date"], "%Y-%m-%d")
+            .map_err(|_| self.error("invalid date"))?;
+        let Some(time) = c.name("time") else {
+            let timestamp = date.and_time(NaiveTime::MIN).and_utc().timestamp_millis();
+            return Ok(lit(ScalarValue::Date64(Some(timestamp))));
+        };
+
+        let time = ["%H:%M:%S%.f", "%H:%M"]
+            .into_iter()
+            .find_map(|format| NaiveTime::parse_from_str(time.as_str(), format).ok())
+            .ok_or_else(|| self.error("invalid time"))?;
+        let offset = match &c["offset"] {
+            "Z" => 0,
+            offset => {
+                let sign = if offset.starts_with('-') { -1 } else { 1 };
+                let digits = offset[1..].replace(':', "");
+                let hours: i64 = digits[..2].parse().unwrap();
+                let minutes: i64 = digits.get(2..).unwrap_or("0").parse().unwrap();
+                sign * (hours * 60 + minutes)
+            }
+        };
+        let instant = date.and_time(time).and_utc() - chrono::Duration::minutes(offset);
+        Ok(lit(ScalarValue::TimestampNanosecond(
+            Some(
+                instant
+                    .timestamp_nanos_opt()
+                    .ok_or_else(|| self.error("out of range date-time"))?,
+            ),
+            Some(UTC.into()),
+        )))
+    }
+
+    // Durations are exact, so a day is 24 hours regardless of the timezone it is added in
+    fn duration_literal(&self, c: &regex::Captures) -> Result<Expr, ODataError> {
+        let units = [
+            ("days", 86_400_000_000_000),
+            ("hours", 3_600_000_000_000),
+            ("minutes", 60_000_000_000),
+            ("seconds", 1_000_000_000),
+        ];
+        // Either part can be left out, but not both, nor all the components of the time part
+        if units.iter().all(|(name, _)| c.name(name).is_none())
+            || c.name("time").is_some_and(|m| m.as_str() == "T")
+        {
+            Err(self.error("invalid duration"))?
+        }
+
+        let out_of_range = || self.error("out of range duration");
+        let mut nanos: i64 = 0;
+        for (name, unit) in units {
+            let Some(m) = c.name(name) else {
+                continue;
+            };
+            nanos = m
+                .as_str()
                 .parse::<i64>()
-                .map_err(|_| BadRequest::new("Filter contains invalid number"))?;
-            Ok(ScalarValue::Int64(Some(d)))
+                .ok()
+                .and_then(|part| part.checked_mul(unit))
+                .and_then(|part| nanos.checked_add(part))
+                .ok_or_else(out_of_range)?;
         }
-        odata_filters::Value::DateTime(d) => Ok(ScalarValue::Date64(Some(d.timestamp()))),
-        odata_filters::Value::Date(d) => {
-            let d = d
-                .and_hms_opt(0, 0, 0)
-                .ok_or(BadRequest::new("Filter contains invalid date"))?;
-            let timestamp = DateTime::<Utc>::from_naive_utc_and_offset(d, Utc).timestamp();
-            Ok(ScalarValue::Date64(Some(timestamp)))
+        if let Some(fraction) = c.name("fraction") {
+            let fraction: i64 = format!("{:0<9}", fraction.as_str()).parse().unwrap();
+            nanos = nanos.checked_add(fraction).ok_or_else(out_of_range)?;
         }
-        odata_filters::Value::Uuid(u) => Ok(ScalarValue::LargeUtf8(Some(u.to_string()))),
-        odata_filters::Value::Time(_) => {
-            Err(UnsupportedFeature::new("Time value in filter is not supported").into())
+        if c.name("sign").is_some() {
+            nanos = -nanos;
         }
+
+        Ok(lit(ScalarValue::IntervalMonthDayNano(Some(
+            IntervalMonthDayNano::new(0, 0, nanos),
+        ))))
     }
 }
 
-fn odata_op_to_df_op(op: &odata_filters::CompareOperator) -> Operator {
-    match op {
-        odata_filters::CompareOperator::Equal => Operator::Eq,
-        odata_filters::CompareOperator::NotEqual => Operator::NotEq,
-        odata_filters::CompareOperator::LessThan => Operator::Lt,
-        odata_filters::CompareOperator::GreaterThan => Operator::Gt,
-        odata_filters::CompareOperator::LessOrEqual => Operator::LtEq,
-        odata_filters::CompareOperator::GreaterOrEqual => Operator::GtEq,
+///////////////////////////////////////////////////////////////////////////////
+
+fn odata_function_to_df_expr(name: &str, args: Vec<Expr>) -> Result<Expr, ODataError> {
+    let arity = |expected: usize| {
+        if args.len() == expected {
+            Ok(())
+        } else {
+            Err(BadRequest::new(format!(
+                "Function {name}() takes {expected} argument(s), {} given",
+                args.len()
+            )))
+        }
+    };
+
+    let part = match name {
+        // Evaluated once per execution, when the plan is optimized, so that all rows see
+        // the same instant while the plan itself can be reused (see `with_plan_cache`)
+        "now" => {
+            arity(0)?;
+            return Ok(datafusion::functions::datetime::expr_fn::now());
+        }
+        "year" | "month" | "day" | "hour" | "minute" | "second" => name,
+        // Can only be resolved against the functions registered in the session,
+        // see [`resolve_filter_functions`]
+        _ => {
+            let udf = ScalarUDF::new_from_impl(UnresolvedFunction::new(name));
+            return Ok(Expr::ScalarFunction(ScalarFunction::new_udf(
+                Arc::new(udf),
+                args,
+            )));
+        }
+    };
+
+    arity(1)?;
+    // The parts are integers in OData, while `date_part` also yields the fractional seconds
+    Ok(cast(
+        date_part(lit(part), args.into_iter().next().unwrap()),
+        DataType::Int64,
+    ))
+}
+
+/// Stand-in for a function that is not built into `$filter`, until it's replaced by
+/// the UDF of the same name registered in the session
+#[derive(Debug)]
+struct UnresolvedFunction {
+    name: String,
+    signature: Signature,
+}
+
+impl UnresolvedFunction {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            signature: Signature::variadic_any(Volatility::Volatile),
+        }
+    }
+}
+
+impl ScalarUDFImpl for UnresolvedFunction {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn return_type(&self, _arg_types: &[DataType]) -> datafusion::error::Result<DataType> {
+        Ok(DataType::Null)
+    }
+
+    fn invoke(&self, _args: &[ColumnarValue]) -> datafusion::error::Result<ColumnarValue> {
+        Err(DataFusionError::Plan(format!(
+            "Function {}() in $filter is not resolved",
+            self.name
+        )))
+    }
+}
+
+/// Rejects the filters referencing properties that are not in the schema of the collection,
+/// which the planner would otherwise report in its own terms
+pub fn check_filter_columns(expr: &Expr, schema: &DFSchema) -> Result<(), ODataError> {
+    let mut columns: Vec<_> = expr.column_refs().into_iter().collect();
+    columns.sort_by(|a, b| a.name.cmp(&b.name));
+    for column in columns {
+        if !schema.has_column(column) {
+            Err(ODataError::bad_request(format!(
+                "Property {} referenced by $filter not found",
+                column.name
+            )))?
+        }
+    }
+    Ok(())
+}
+
+/// Rejects the `in` operators with more than `max_size` values,
+/// see [`crate::context::CollectionContext::max_in_list_size`]
+pub fn check_in_list_sizes(expr: &Expr, max_size: usize) -> Result<(), ODataError> {
+    let mut size = None;
+    expr.apply(|e| {
+        if let Expr::InList(InList { list, .. }) = e {
+            if list.len() > max_size {
+                size = Some(list.len());
+                return Ok(TreeNodeRecursion::Stop);
+            }
+        }
+        Ok(TreeNodeRecursion::Continue)
+    })
+    .map_err(ODataError::internal)?;
+    match size {
+        Some(size) => Err(ODataError::bad_request(format!(
+            "Operator in of $filter has {size} values, more than the maximum of {max_size}"
+        )))?,
+        None => Ok(()),
+    }
+}
+
+/// Replaces the calls of functions unknown to `$filter` with the scalar UDFs of the same name
+/// registered in the session, as long as they are allowed by
+/// [`crate::context::CollectionContext::filter_functions`].
+///
+/// Calls of any other function are not supported.
+pub fn resolve_filter_functions(
+    expr: Expr,
+    allowed: &[String],
+    registry: &dyn FunctionRegistry,
+) -> Result<Expr, ODataError> {
+    let expr = expr.transform_up(|expr| match expr {
+        Expr::ScalarFunction(f) if f.func.inner().as_any().is::<UnresolvedFunction>() => {
+            let name = f.func.name();
+            if !allowed.iter().any(|a| a == name) {
+                return Err(DataFusionError::External(Box::new(ODataError::from(
+                    UnsupportedFeature::planned(format!("Function {name}() in $filter")),
+                ))));
+            }
+            let udf = registry.udf(name)?;
+            Ok(Transformed::yes(Expr::ScalarFunction(
+                ScalarFunction::new_udf(udf, f.args),
+            )))
+        }
+        expr => Ok(Transformed::no(expr)),
+    });
+
+    expr.map(|expr| expr.data)
+        .map_err(ODataError::from_query_error)
+}
+
+// Integral numbers are decoded as `Int64` and fractional ones as exact `Decimal128`
+// so that no precision is lost before they are coerced to the type of the compared column
+fn decode_number(d: &BigDecimal) -> Result<ScalarValue, ODataError> {
+    let invalid_number = || BadRequest::new("Filter contains invalid number");
+
+    let (digits, exponent) = d.as_bigint_and_exponent();
+    let mut mantissa: i128 = digits.to_string().parse().map_err(|_| invalid_number())?;
+    let mut scale = exponent;
+
+    if scale < 0 {
+        mantissa = 10i128
+            .checked_pow(u32::try_from(-scale).map_err(|_| invalid_number())?)
+            .and_then(|m| mantissa.checked_mul(m))
+            .ok_or_else(invalid_number)?;
+        scale = 0;
+    }
+
+    if scale == 0 {
+        if let Ok(v) = i64::try_from(mantissa) {
+            return Ok(ScalarValue::Int64(Some(v)));
+        }
+    }
+
+    let scale = i8::try_from(scale).map_err(|_| invalid_number())?;
+    let precision = count_digits(mantissa).max(scale as u8).max(1);
+    if precision > DECIMAL128_MAX_PRECISION {
+        Err(invalid_number())?;
+    }
+
+    Ok(ScalarValue::Decimal128(Some(mantissa), precision, scale))
+}
+
+fn count_digits(v: i128) -> u8 {
+    let mut v = v.unsigned_abs();
+    let mut n = 0;
+    while v > 0 {
+        v /= 10;
+        n += 1;
     }
+    n
 }
 
 ///////////////////////////////////////////////////////////////////////////////
+
+/// Coerces the literals of a translated filter to the types of the columns they are
+/// compared with, as OData literals alone do not carry enough type information
+/// (e.g. the precision and scale of an `Edm.Decimal`)
+pub fn coerce_filter_literals(expr: Expr, schema: &DFSchema) -> Result<Expr, ODataError> {
+    match expr {
+        Expr::BinaryExpr(BinaryExpr { left, op, right }) => {
+            let (left, op, right) = match (*left, *right) {
+                // Operands of arithmetic (e.g. durations added to date-times) keep their types
+                (left, right) if !op.is_comparison_operator() => (
+                    coerce_filter_literals(left, schema)?,
+                    op,
+                    coerce_filter_literals(right, schema)?,
+                ),
+                (Expr::Column(c), Expr::Literal(v)) => {
+                    let (op, v) = coerce_literal_to_column(v, &c, op, schema)?;
+                    (Expr::Column(c), op, Expr::Literal(v))
+                }
+                // Coerced as if the column was on the left, e.g. `5 lt price` as `price gt 5`
+                (Expr::Literal(v), Expr::Column(c)) => match op.swap() {
+                    Some(swapped) => {
+                        let (op, v) = coerce_literal_to_column(v, &c, swapped, schema)?;
+                        (Expr::Literal(v), op.swap().unwrap(), Expr::Column(c))
+                    }
+                    None => {
+                        let (op, v) = coerce_literal_to_column(v, &c, op, schema)?;
+                        (Expr::Literal(v), op, Expr::Column(c))
+                    }
+                },
+                // Both sides are typed by the schema, the planner will coerce them
+                (Expr::Column(l), Expr::Column(r)) => (Expr::Column(l), op, Expr::Column(r)),
+                (left, right) => (
+                    coerce_filter_literals(left, schema)?,
+                    op,
+                    coerce_filter_literals(right, schema)?,
+                ),
+            };
+            Ok(Expr::BinaryExpr(BinaryExpr::new(
+                Box::new(left),
+                op,
+                Box::new(right),
+            )))
+        }
+        Expr::Not(e) => Ok(Expr::Not(Box::new(coerce_filter_literals(*e, schema)?))),
+        Expr::InList(InList {
+            expr,
+            list,
+            negated,
+        }) => {
+            let list = match expr.as_ref() {
+                Expr::Column(c) => list
+                    .into_iter()
+                    .map(|e| match e {
+                        Expr::Literal(v) => {
+                            let (_, v) = coerce_literal_to_column(v, c, Operator::Eq, schema)?;
+                            Ok(Expr::Literal(v))
+                        }
+                        e => Ok(e),
+                    })
+                    .collect::<Result<Vec<_>, ODataError>>()?,
+                _ => list,
+            };
+            check_in_list_types(&list)?;
+            Ok(Expr::InList(InList::new(expr, list, negated)))
+        }
+        e => Ok(e),
+    }
+}
+
+// Values of different kinds (e.g. `name in ('a', 1)`) would otherwise be coerced to a common
+// type by the planner, or fail in its own terms. Numbers of different types are fine.
+fn check_in_list_types(list: &[Expr]) -> Result<(), ODataError> {
+    let mut kinds: Vec<String> = Vec::new();
+    for e in list {
+        let Expr::Literal(v) = e else {
+            continue;
+        };
+        let kind = match v.data_type() {
+            _ if v.is_null() => continue,
+            typ if typ.is_numeric() => "number".to_string(),
+            DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => "string".to_string(),
+            DataType::Boolean => "boolean".to_string(),
+            typ => typ.to_string(),
+        };
+        if !kinds.contains(&kind) {
+            kinds.push(kind);
+        }
+    }
+    if kinds.len() > 1 {
+        Err(BadRequest::new(format!(
+            "Values of operator in of $filter have mixed types: {}",
+            kinds.join(", ")
+        )))?
+    }
+    Ok(())
+}
+
+/// Converts the literal the column is compared to (with the column on the left of `op`) to
+/// the type of the column, along with the operator comparing them the same way after that
+fn coerce_literal_to_column(
+    value: ScalarValue,
+    column: &Column,
+    op: Operator,
+    schema: &DFSchema,
+) -> Result<(Operator, ScalarValue), ODataError> {
+    // Unknown columns are left for the planner to report
+    let Ok(field) = schema.field_with_unqualified_name(&column.name) else {
+        return Ok((op, value));
+    };
+
+    match field.data_type() {
+        DataType::Decimal128(precision, scale) => {
+            coerce_to_decimal128(value, op, *precision, *scale, &column.name)
+        }
+        DataType::Float32 | DataType::Float64 => {
+            Ok((op, coerce_to_float(value, field.data_type())?))
+        }
+        DataType::UInt64 => Ok((op, coerce_to_uint64(value))),
+        DataType::Timestamp(unit, tz) => {
+            coerce_to_timestamp(value, op, *unit, tz.clone(), &column.name)
+        }
+        _ => Ok((op, value)),
+    }
+}
+
+/// Divides the literal by `factor` to the coarser unit of the column. A remainder means no
+/// value of the column equals the literal, while the ordering comparisons keep holding for
+/// the same values when the literal is rounded towards them, e.g. `price gt 1.005` of
+/// a `Decimal(10, 2)` column becomes `price ge 1.01`
+fn coarsen_literal(op: Operator, value: i128, factor: i128) -> Option<(Operator, i128)> {
+    let (floor, rem) = (value.div_euclid(factor), value.rem_euclid(factor));
+    match op {
+        _ if rem == 0 => Some((op, floor)),
+        Operator::Gt | Operator::GtEq => Some((Operator::GtEq, floor + 1)),
+        Operator::Lt | Operator::LtEq => Some((Operator::LtEq, floor)),
+        _ => None,
+    }
+}
+
+// Literals above `i64::MAX` are decoded as decimals, and comparing them (or any integer)
+// as signed would overflow on the large values of the column
+fn coerce_to_uint64(value: ScalarValue) -> ScalarValue {
+    let v = match &value {
+        ScalarValue::Int64(Some(v)) => u64::try_from(*v).ok(),
+        ScalarValue::Decimal128(Some(v), _, 0) => u64::try_from(*v).ok(),
+        _ => None,
+    };
+    match v {
+        Some(v) => ScalarValue::UInt64(Some(v)),
+        None => value,
+    }
+}
+
+// Datetime literals are converted to the unit of the column and, for the columns with a
+// timezone, to the same instant in it. Timezone-naive columns are assumed to hold UTC
+// wall-clock times, which is also how they are rendered
+fn coerce_to_timestamp(
+    value: ScalarValue,
+    op: Operator,
+    unit: TimeUnit,
+    tz: Option<Arc<str>>,
+    column_name: &str,
+) -> Result<(Operator, ScalarValue), ODataError> {
+    let nanos = match &value {
+        ScalarValue::TimestampNanosecond(Some(v), _) => *v,
+        ScalarValue::Date64(Some(v)) => match v.checked_mul(1_000_000) {
+            Some(v) => v,
+            None => return Ok((op, value)),
+        },
+        _ => return Ok((op, value)),
+    };
+    let factor = match unit {
+        TimeUnit::Second => 1_000_000_000,
+        TimeUnit::Millisecond => 1_000_000,
+        TimeUnit::Microsecond => 1_000,
+        TimeUnit::Nanosecond => 1,
+    };
+    let Some((op, v)) = coarsen_literal(op, nanos.into(), factor) else {
+        Err(BadRequest::new(format!(
+            "Filter value {} is more precise than the {unit:?} unit of column {column_name}",
+            chrono::DateTime::from_timestamp_nanos(nanos)
+                .to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+        )))?
+    };
+    // Dividing by the factor keeps the value in range
+    let v = Some(i64::try_from(v).unwrap());
+    let value = match unit {
+        TimeUnit::Second => ScalarValue::TimestampSecond(v, tz),
+        TimeUnit::Millisecond => ScalarValue::TimestampMillisecond(v, tz),
+        TimeUnit::Microsecond => ScalarValue::TimestampMicrosecond(v, tz),
+        TimeUnit::Nanosecond => ScalarValue::TimestampNanosecond(v, tz),
+    };
+    Ok((op, value))
+}
+
+fn coerce_to_float(value: ScalarValue, typ: &DataType) -> Result<ScalarValue, ODataError> {
+    match &value {
+        ScalarValue::Int64(Some(_))
+        | ScalarValue::Float64(Some(_))
+        | ScalarValue::Decimal128(Some(_), _, _) => {
+            value.cast_to(typ).map_err(ODataError::bad_request)
+        }
+        _ => Ok(value),
+    }
+}
+
+fn coerce_to_decimal128(
+    value: ScalarValue,
+    op: Operator,
+    precision: u8,
+    scale: i8,
+    column_name: &str,
+) -> Result<(Operator, ScalarValue), ODataError> {
+    let (mantissa, from_scale) = match &value {
+        ScalarValue::Int64(Some(v)) => (i128::from(*v), 0),
+        ScalarValue::Decimal128(Some(v), _, s) => (*v, *s),
+        _ => return Ok((op, value)),
+    };
+
+    let literal = BigDecimal::new(mantissa.into(), from_scale.into());
+    let overflow = || {
+        BadRequest::new(format!(
+            "Filter value {literal} does not fit the Decimal({precision}, {scale}) column {column_name}"
+        ))
+    };
+
+    let shift = i32::from(scale) - i32::from(from_scale);
+    let factor = 10i128
+        .checked_pow(shift.unsigned_abs())
+        .ok_or_else(overflow)?;
+
+    let (op, mantissa) = if shift >= 0 {
+        (op, mantissa.checked_mul(factor).ok_or_else(overflow)?)
+    } else {
+        coarsen_literal(op, mantissa, factor).ok_or_else(|| {
+            BadRequest::new(format!(
+                "Filter value {literal} has more fractional digits than the Decimal({precision}, {scale}) column {column_name}"
+            ))
+        })?
+    };
+
+    if count_digits(mantissa) > precision {
+        Err(overflow())?;
+    }
+
+    Ok((
+        op,
+        ScalarValue::Decimal128(Some(mantissa), precision, scale),
+    ))
+}
@@ -1,30 +1,89 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use axum::{extract::Query, response::Response, Extension};
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    response::Response,
+    Extension,
+};
+use datafusion::{
+    arrow::{
+        array::{Array, AsArray, RecordBatch},
+        datatypes::{DataType, Schema},
+        util::display::{array_value_to_string, ArrayFormatter, FormatOptions},
+    },
+    execution::SendableRecordBatchStream,
+};
+use futures::{SinkExt, StreamExt};
 
 use crate::{
-    collection::QueryParamsRaw,
-    context::{CollectionContext, OnUnsupported, ServiceContext, DEFAULT_NAMESPACE},
-    error::{ODataError, UnsupportedDataType},
+    atom::{AtomFeedWriter, Edm, EdmEncoding},
+    collection::{
+        AppliedQuery, CollectionAddr, ExpandItem, KeyValue, QueryOption, QueryParams,
+        QueryParamsRaw, SkipToken,
+    },
+    context::{
+        check_unique_field_names, property_columns, CollectionContext, ODataVersion,
+        ServiceContext, DEFAULT_NAMESPACE, DESCRIPTION_METADATA_KEY, GROUPS_METADATA_KEY,
+    },
+    error::{CollectionNotFound, ODataError, QueryTimeout},
+    json::{JsonFeedWriter, JsonFormat},
     metadata::{
-        to_edm_type, DataServices, Edmx, EntityContainer, EntityKey, EntitySet, EntityType,
-        Property, PropertyRef,
+        to_edm_type, Association, AssociationSet, DataServices, Edmx, EdmxV4, End, EntityContainer,
+        EntityKey, EntitySet, EntityType, FunctionImport, NavigationProperty, Parameter, Property,
+        PropertyRef, SchemaV4, Singleton,
     },
     service::{Collection, Service, Workspace},
 };
 
+pub use crate::media_type::{
+    MEDIA_TYPE_ATOM, MEDIA_TYPE_CSV, MEDIA_TYPE_JSON, MEDIA_TYPE_JSON_PLAIN,
+    MEDIA_TYPE_JSON_VERBOSE, MEDIA_TYPE_OCTET_STREAM, MEDIA_TYPE_TEXT, MEDIA_TYPE_XML,
+};
+
 ///////////////////////////////////////////////////////////////////////////////
 
-pub const MEDIA_TYPE_ATOM: &str = "application/atom+xml;type=feed;charset=utf-8";
-pub const MEDIA_TYPE_XML: &str = "application/xml;charset=utf-8";
+pub const HEADER_PREFER: &str = "prefer";
+pub const HEADER_PREFERENCE_APPLIED: &str = "preference-applied";
+pub const HEADER_DATA_SERVICE_VERSION: &str = "dataserviceversion";
+pub const HEADER_MAX_DATA_SERVICE_VERSION: &str = "maxdataserviceversion";
+pub const HEADER_ODATA_VERSION: &str = "odata-version";
+pub const HEADER_ODATA_MAX_VERSION: &str = "odata-maxversion";
 
 const DEFAULT_COLLECTION_RESPONSE_SIZE: usize = 512_000;
 
 ///////////////////////////////////////////////////////////////////////////////
 
+/// Serves the AtomPub service document, or the OData v4 JSON one when the client asks for JSON
 pub async fn odata_service_handler(
     Extension(odata_ctx): Extension<Arc<dyn ServiceContext>>,
+    headers: axum::http::HeaderMap,
 ) -> Result<Response<String>, ODataError> {
+    let verbose_errors = odata_ctx.verbose_errors();
+    serve_service(odata_ctx, headers)
+        .await
+        .map_err(|e| e.with_details(verbose_errors))
+}
+
+async fn serve_service(
+    odata_ctx: Arc<dyn ServiceContext>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response<String>, ODataError> {
+    if let ResponseFormat::Json(JsonFormat::V4) = ResponseFormat::from_headers(&headers) {
+        let mut buf = Vec::new();
+        crate::json::write_json_service_document(
+            odata_ctx.service_base_url(),
+            &odata_ctx.list_collections().await?,
+            &mut buf,
+        )?;
+
+        return Response::builder()
+            .header(http::header::CONTENT_TYPE.as_str(), MEDIA_TYPE_JSON)
+            .header(HEADER_ODATA_VERSION, ProtocolVersion::V4.to_string())
+            .body(String::from_utf8(buf).map_err(ODataError::internal)?)
+            .map_err(ODataError::internal);
+    }
+
     let mut collections = Vec::new();
 
     for coll in odata_ctx.list_collections().await? {
@@ -42,10 +101,13 @@ pub async fn odata_service_handler(
         },
     );
 
-    let xml = write_object_to_xml("service", &service)?;
+    let xml = write_object_to_xml("service", &service, odata_ctx.pretty_print_xml())?;
 
     Response::builder()
-        .header(http::header::CONTENT_TYPE.as_str(), MEDIA_TYPE_XML)
+        .header(
+            http::header::CONTENT_TYPE.as_str(),
+            odata_ctx.content_type(MEDIA_TYPE_XML),
+        )
         .body(xml)
         .map_err(ODataError::internal)
 }
@@ -55,46 +117,106 @@ pub async fn odata_service_handler(
 pub async fn odata_metadata_handler(
     Extension(odata_ctx): Extension<Arc<dyn ServiceContext>>,
 ) -> Result<Response<String>, ODataError> {
+    let verbose_errors = odata_ctx.verbose_errors();
+    serve_metadata(odata_ctx)
+        .await
+        .map_err(|e| e.with_details(verbose_errors))
+}
+
+async fn serve_metadata(
+    odata_ctx: Arc<dyn ServiceContext>,
+) -> Result<Response<String>, ODataError> {
+    // The cached document is shared by all the requests of an epoch, so it can't reflect the
+    // per-request visibility of the columns
+    let restricted_visibility = odata_ctx
+        .list_collections()
+        .await?
+        .iter()
+        .any(|coll| coll.visible_columns().is_some());
+    let xml = match odata_ctx.metadata_cache() {
+        Some(cache) if !restricted_visibility => {
+            cache
+                .get_or_try_insert(odata_ctx.schema_epoch(), || {
+                    metadata_document(odata_ctx.as_ref())
+                })
+                .await?
+        }
+        _ => metadata_document(odata_ctx.as_ref()).await?,
+    };
+
+    Response::builder()
+        .header(
+            http::header::CONTENT_TYPE.as_str(),
+            odata_ctx.content_type(MEDIA_TYPE_XML),
+        )
+        .body(xml)
+        .map_err(ODataError::internal)
+}
+
+async fn metadata_document(odata_ctx: &dyn ServiceContext) -> Result<String, ODataError> {
     let mut entity_types = Vec::new();
     let mut entity_container = EntityContainer {
         name: DEFAULT_NAMESPACE.to_string(),
         is_default: true,
         entity_set: Vec::new(),
+        singletons: Vec::new(),
+        association_sets: Vec::new(),
+        function_imports: Vec::new(),
     };
+    let mut entity_types_by_set = HashMap::new();
+    // Collections by the names of their entity types, which have to be unique in the namespace
+    let mut collections_by_type = HashMap::new();
+    let mut associations = Vec::new();
+    // Tuples (association, source_type_name, source_set, navigation_property), resolved once the
+    // entity types of all the collections are known
+    let mut navigations = Vec::new();
 
     for coll in odata_ctx.list_collections().await? {
         let collection_name = coll.collection_name()?;
+        let entity_type_name = coll.entity_type_name()?;
+        let overrides = coll.property_overrides();
+        let property_name = |column: &str| match coll.property_name_mapper() {
+            Some(mapper) => mapper(column),
+            None => column.to_string(),
+        };
         let mut properties = Vec::new();
 
-        for field in coll.schema().await?.fields() {
-            let typ = match to_edm_type(field.data_type()) {
-                Ok(typ) => typ,
-                Err(err) => match odata_ctx.on_unsupported_feature() {
-                    OnUnsupported::Error => {
-                        Err(UnsupportedDataType::new(field.data_type().clone()))?
-                    }
-                    OnUnsupported::Warn => {
-                        tracing::error!(
-                            table = collection_name,
-                            field = field.name(),
-                            error = %err,
-                            error_dbg = ?err,
-                            "Unsupported field type - skipping",
-                        );
-                        continue;
-                    }
-                },
+        let schema = coll.entity_schema().await?;
+        if let Some(mapper) = coll.property_name_mapper() {
+            property_columns(&schema, &mapper)?;
+        }
+        for field in schema.fields() {
+            let field_override = overrides.get(field.name());
+
+            let override_type = field_override.and_then(|o| o.edm_type.as_deref());
+
+            let on_unsupported = odata_ctx.on_unsupported_feature();
+            let Some(edm) = Edm::resolve(field, override_type, on_unsupported)? else {
+                continue;
             };
 
-            properties.push(Property::primitive(field.name(), typ, field.is_nullable()));
+            // Values of the columns kept in `OnUnsupported::Null` mode are all nulls
+            let nullable = edm.encoding == EdmEncoding::Null
+                || field_override
+                    .and_then(|o| o.nullable)
+                    .unwrap_or(field.is_nullable());
+
+            let property = Property::primitive(property_name(field.name()), &edm.typ, nullable);
+            let property = match field.metadata().get(DESCRIPTION_METADATA_KEY) {
+                Some(description) if odata_ctx.include_field_documentation() => {
+                    property.with_documentation(description)
+                }
+                _ => property,
+            };
+            properties.push(property);
         }
 
         // https://www.odata.org/documentation/odata-version-3-0/common-schema-definition-language-csdl/#csdl6.3
-        let property_ref_name = match coll.key_column() {
-            Ok(kc) => kc,
+        let property_ref_names = match coll.resolve_key_columns().await {
+            Ok(key_columns) => key_columns.iter().map(|kc| property_name(kc)).collect(),
             Err(ODataError::KeyColumnNotAssigned(_)) => match properties.first() {
-                Some(prop) => prop.name.clone(),
-                None => collection_name.to_string(),
+                Some(prop) => vec![prop.name.clone()],
+                None => vec![collection_name.to_string()],
             },
             Err(err) => {
                 tracing::error!(
@@ -107,67 +229,482 @@ pub async fn odata_metadata_handler(
             }
         };
 
+        // https://www.odata.org/documentation/odata-version-3-0/common-schema-definition-language-csdl/#csdl7.1
+        let mut navigation_properties = Vec::new();
+        for navigation in coll.navigation_properties() {
+            let association = format!("{entity_type_name}_{}", navigation.name);
+            navigation_properties.push(NavigationProperty {
+                name: navigation.name.clone(),
+                relationship: format!("{DEFAULT_NAMESPACE}.{association}"),
+                from_role: entity_type_name.clone(),
+                to_role: navigation.name.clone(),
+            });
+            navigations.push((
+                association,
+                entity_type_name.clone(),
+                collection_name.clone(),
+                navigation,
+            ));
+        }
+
+        check_unique_entity_type(
+            &mut collections_by_type,
+            &entity_type_name,
+            &collection_name,
+        )?;
         entity_types.push(EntityType {
-            name: collection_name.clone(),
-            key: EntityKey::new(vec![PropertyRef {
-                name: property_ref_name,
-            }]),
+            name: entity_type_name.clone(),
+            open_type: None,
+            key: EntityKey::new(
+                property_ref_names
+                    .into_iter()
+                    .map(|name| PropertyRef { name })
+                    .collect(),
+            ),
             properties,
+            navigation_properties,
         });
 
-        entity_container.entity_set.push(EntitySet {
-            name: collection_name.clone(),
-            entity_type: format!("{DEFAULT_NAMESPACE}.{collection_name}"),
+        // Groups of `$apply` are keyed by their ordinal, their other properties depend
+        // on the aggregation
+        let aggregated =
+            !coll.is_singleton() && coll.allowed_query_options().contains(&QueryOption::Apply);
+        if let (true, Ok(id_column)) = (aggregated, coll.id_column()) {
+            let ordinal = property_name(&id_column);
+            let group_type_name = coll.group_type_name()?;
+            check_unique_entity_type(&mut collections_by_type, &group_type_name, &collection_name)?;
+            entity_types.push(EntityType {
+                name: group_type_name,
+                open_type: Some(true),
+                key: EntityKey::new(vec![PropertyRef {
+                    name: ordinal.clone(),
+                }]),
+                properties: vec![Property::primitive(ordinal, "Edm.Int64", false)],
+                navigation_properties: Vec::new(),
+            });
+        }
+
+        if coll.is_singleton() {
+            entity_container.singletons.push(Singleton {
+                name: collection_name.clone(),
+                typ: format!("{DEFAULT_NAMESPACE}.{entity_type_name}"),
+            });
+        } else {
+            entity_container.entity_set.push(EntitySet {
+                name: collection_name.clone(),
+                entity_type: format!("{DEFAULT_NAMESPACE}.{entity_type_name}"),
+            });
+            entity_types_by_set.insert(
+                collection_name.clone(),
+                format!("{DEFAULT_NAMESPACE}.{entity_type_name}"),
+            );
+        }
+    }
+
+    for (association, source_type_name, source_set, navigation) in navigations {
+        let Some(target_type) = entity_types_by_set.get(&navigation.entity_set) else {
+            Err(ODataError::internal(format!(
+                "Collection {} navigated to by {source_set}/{} not found",
+                navigation.entity_set, navigation.name
+            )))?
+        };
+        associations.push(Association {
+            name: association.clone(),
+            ends: vec![
+                End::association(
+                    format!("{DEFAULT_NAMESPACE}.{source_type_name}"),
+                    &source_type_name,
+                    "*",
+                ),
+                End::association(
+                    target_type,
+                    &navigation.name,
+                    if navigation.many { "*" } else { "0..1" },
+                ),
+            ],
+        });
+        entity_container.association_sets.push(AssociationSet {
+            name: association.clone(),
+            association: format!("{DEFAULT_NAMESPACE}.{association}"),
+            ends: vec![
+                End::association_set(source_type_name, source_set),
+                End::association_set(navigation.name, navigation.entity_set),
+            ],
         });
     }
 
-    let metadata = Edmx::new(DataServices::new(vec![crate::metadata::Schema::new(
+    for function in odata_ctx.functions() {
+        let Some(entity_type) = entity_types_by_set.get(&function.entity_set) else {
+            Err(ODataError::internal(format!(
+                "Collection {} returned by function {} not found",
+                function.entity_set, function.name
+            )))?
+        };
+        entity_container.function_imports.push(FunctionImport::new(
+            function.name,
+            function.entity_set,
+            entity_type,
+            function
+                .parameters
+                .into_iter()
+                .map(|(name, typ)| Parameter::input(name, typ))
+                .collect(),
+        ));
+    }
+
+    let schema = crate::metadata::Schema::new(
         DEFAULT_NAMESPACE.to_string(),
         entity_types,
         vec![entity_container],
-    )]));
+    )
+    .with_associations(associations);
 
-    let xml = write_object_to_xml("edmx:Edmx", &metadata)?;
+    let xml = match odata_ctx.odata_version() {
+        ODataVersion::V3 => {
+            let versions = odata_ctx.metadata_versions();
+            let ds = DataServices::new(vec![schema])
+                .with_versions(versions.data_service, versions.max_data_service);
+            write_object_to_xml(
+                "edmx:Edmx",
+                &Edmx::new(ds).with_version(versions.edmx),
+                odata_ctx.pretty_print_xml(),
+            )?
+        }
+        ODataVersion::V4 => write_object_to_xml(
+            "edmx:Edmx",
+            &EdmxV4::new(vec![SchemaV4::from_v3(schema)]),
+            odata_ctx.pretty_print_xml(),
+        )?,
+    };
+    Ok(xml)
+}
 
-    Response::builder()
-        .header(http::header::CONTENT_TYPE.as_str(), MEDIA_TYPE_XML)
-        .body(xml)
-        .map_err(ODataError::internal)
+// Distinct collection names can map to the same entity type name, e.g. `a.b` and `a_b`,
+// see [`crate::context::to_simple_identifier`]
+fn check_unique_entity_type(
+    collections_by_type: &mut HashMap<String, String>,
+    entity_type_name: &str,
+    collection_name: &str,
+) -> Result<(), ODataError> {
+    if let Some(other) =
+        collections_by_type.insert(entity_type_name.to_string(), collection_name.to_string())
+    {
+        Err(ODataError::internal(format!(
+            "Collections {other} and {collection_name} both declare entity type {entity_type_name}"
+        )))?
+    }
+    Ok(())
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
+/// Serves a collection as a feed, or a single entity (addressed by key or a singleton) as an entry.
+///
+/// A query matching no entities is not an error: feeds are returned with `200 OK` and no entries,
+/// even when the `$filter` excludes everything. Only a single entity that doesn't exist results
+/// in `404 Not Found`.
 pub async fn odata_collection_handler(
     Extension(ctx): Extension<Arc<dyn CollectionContext>>,
     Query(query): Query<QueryParamsRaw>,
-    _headers: axum::http::HeaderMap,
+    headers: axum::http::HeaderMap,
 ) -> Result<Response<String>, ODataError> {
+    let verbose_errors = verbose_errors(ctx.as_ref());
+    let resp = serve_collection(ctx, query, headers, false)
+        .await
+        .map_err(|e| e.with_details(verbose_errors))?;
+    let (parts, body) = resp.into_parts();
+    match body {
+        CollectionBody::Buffered(body) => Ok(Response::from_parts(parts, body)),
+        CollectionBody::Streamed(_) => Err(ODataError::internal("Unexpected streamed body")),
+    }
+}
+
+/// Same as [`odata_collection_handler`], but the Atom and JSON feeds are written to the response
+/// as the record batches of the query arrive rather than once the whole result is collected,
+/// see [`CollectionContext::flush_interval_rows`].
+///
+/// The status is sent along with the first batch, so an error past it can't be reported with
+/// an error status anymore and aborts the response instead.
+pub async fn odata_collection_stream_handler(
+    Extension(ctx): Extension<Arc<dyn CollectionContext>>,
+    Query(query): Query<QueryParamsRaw>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response<Body>, ODataError> {
+    let verbose_errors = verbose_errors(ctx.as_ref());
+    let resp = serve_collection(ctx, query, headers, true)
+        .await
+        .map_err(|e| e.with_details(verbose_errors))?;
+    Ok(resp.map(|body| match body {
+        CollectionBody::Buffered(body) => Body::from(body),
+        CollectionBody::Streamed(body) => body,
+    }))
+}
+
+/// Response of [`serve_collection`], streamed only when requested
+enum CollectionBody {
+    Buffered(String),
+    Streamed(Body),
+}
+
+/// Outcome of the query of [`serve_collection`]
+enum QueryResults {
+    Collected(Vec<RecordBatch>),
+    Streamed(SendableRecordBatchStream),
+}
+
+async fn serve_collection(
+    ctx: Arc<dyn CollectionContext>,
+    query: QueryParamsRaw,
+    headers: axum::http::HeaderMap,
+    stream: bool,
+) -> Result<Response<CollectionBody>, ODataError> {
+    let allowed_options = ctx.allowed_query_options();
+    let disallowed_options: Vec<_> = query
+        .options()
+        .into_iter()
+        .filter(|option| !allowed_options.contains(option))
+        .map(|option| option.to_string())
+        .collect();
+    if !disallowed_options.is_empty() {
+        Err(ODataError::bad_request(format!(
+            "Query options not allowed for the collection: {}",
+            disallowed_options.join(", ")
+        )))?
+    }
+
+    let format = ResponseFormat::negotiate(query.format.as_deref(), &headers)?;
+
+    check_unique_field_names(ctx.schema().await?.as_ref())?;
+
+    let paging_options = query.paging_options();
     let query = query.decode()?;
-    tracing::debug!(?query, "Decoded query");
+    check_expand_limits(ctx.as_ref(), &query.expand)?;
+    let mut query = map_property_names(ctx.as_ref(), query).await?;
+    let mut options = ctx.apply_options().await?;
 
-    let df = ctx.query(query).await.map_err(ODataError::from)?;
+    // Clients that don't speak OData v4 get the verbose JSON instead of the v4 one
+    let max_version = ProtocolVersion::max_from_headers(&headers);
+    let format = match format {
+        ResponseFormat::Json(JsonFormat::V4)
+            if max_version.is_some_and(|max| max < ProtocolVersion::V4) =>
+        {
+            ResponseFormat::Json(JsonFormat::Verbose)
+        }
+        format => format,
+    };
+    let response_version = format.required_version(&query);
+    if let (Some(required), Some(max)) = (response_version, max_version) {
+        if required > max {
+            Err(ODataError::bad_request(format!(
+                "Response requires OData version {required}, \
+                 but the client only accepts up to {max}"
+            )))?
+        }
+    }
 
-    let schema: datafusion::arrow::datatypes::Schema = df.schema().clone().into();
-    let record_batches = df.collect().await.map_err(ODataError::internal)?;
+    // Singletons and entities addressed by key are served as a single entry
+    let single_entity =
+        ctx.is_singleton() || (ctx.addr()?.key.is_some() && !ctx.is_parameterized());
 
-    ctx.validate(&record_batches).await?;
+    // Server-driven paging only applies when the client didn't ask for a specific page size
+    let mut max_page_size_applied = None;
+    if query.top.is_none() && !single_entity {
+        if let Some(max_page_size) = prefer_max_page_size(&headers) {
+            let max_page_size = match ctx.max_page_size() {
+                Some(limit) => std::cmp::min(max_page_size, limit),
+                None => max_page_size,
+            };
+            query.top = Some(max_page_size);
+            options.stable_order = true;
+            max_page_size_applied = Some(max_page_size);
+        }
+    }
+    // Pages continue after the values of these columns in the last entity of the previous one,
+    // or at an offset, in which case the collection has to allow `$skip` for the link to work
+    let cursor_columns = match max_page_size_applied {
+        Some(_) if allowed_options.contains(&QueryOption::SkipToken) => {
+            Some(query.cursor_columns(&options))
+        }
+        _ => None,
+    };
+
+    // Only Atom entries can leave the properties out
+    let omit_nulls = format == ResponseFormat::Atom && prefer_omit_nulls(&headers);
+    // Only v4 JSON has control information to annotate the properties with
+    let include_annotations = match format {
+        ResponseFormat::Json(JsonFormat::V4) => prefer_include_annotations(&headers),
+        _ => None,
+    };
+
+    tracing::debug!(?query, ?format, "Decoded query");
+    // Kept for the log of a failed query, which is only formatted then
+    let query_desc = query.clone();
+    let aggregated = query.apply.is_some();
+    // A full page produced by server-driven paging links to the following one
+    let next_page = max_page_size_applied.map(|page_size| NextPage {
+        page_size,
+        paging_options,
+        skip: query.skip.unwrap_or(0),
+        cursor_columns,
+        skip_allowed: allowed_options.contains(&QueryOption::Skip),
+    });
+    // CSV is written by arrow all at once, and a single entity has nothing to stream
+    let stream = stream && !single_entity && format != ResponseFormat::Csv;
+
+    let content_type = content_type(ctx.as_ref(), format);
+    let mut resp = Response::builder().header(http::header::CONTENT_TYPE.as_str(), &content_type);
+    let mut preferences_applied = Vec::new();
+    if let Some(max_page_size) = max_page_size_applied {
+        preferences_applied.push(format!("odata.maxpagesize={max_page_size}"));
+    }
+    if omit_nulls {
+        preferences_applied.push("omit-values=nulls".to_string());
+    }
+    if let Some(annotations) = &include_annotations {
+        preferences_applied.push(format!("odata.include-annotations=\"{annotations}\""));
+    }
+    if !preferences_applied.is_empty() {
+        resp = resp.header(HEADER_PREFERENCE_APPLIED, preferences_applied.join(", "));
+    }
+    match response_version {
+        Some(version) if version >= ProtocolVersion::V4 => {
+            resp = resp.header(HEADER_ODATA_VERSION, version.to_string());
+        }
+        Some(version) => {
+            resp = resp.header(HEADER_DATA_SERVICE_VERSION, format!("{version};"));
+        }
+        None => {}
+    }
+
+    // Dropping this future (e.g. when the client disconnects) cancels the query execution
+    let started = tokio::time::Instant::now();
+    let execute = async {
+        let AppliedQuery { df, count } = ctx.query(query, &options).await?;
+
+        // Total number of matching entities, regardless of paging
+        let count = match count {
+            Some(count) => Some(count.count().await.map_err(ODataError::from_query_error)?),
+            None => None,
+        };
+
+        let df = match ctx.batch_size() {
+            Some(batch_size) => {
+                let (mut state, plan) = df.into_parts();
+                state.config_mut().options_mut().execution.batch_size = batch_size;
+                datafusion::dataframe::DataFrame::new(state, plan)
+            }
+            None => df,
+        };
+
+        let schema: datafusion::arrow::datatypes::Schema = df.schema().clone().into();
+        let results = if stream {
+            QueryResults::Streamed(
+                df.execute_stream()
+                    .await
+                    .map_err(ODataError::from_query_error)?,
+            )
+        } else {
+            QueryResults::Collected(df.collect().await.map_err(ODataError::from_query_error)?)
+        };
+        Ok::<_, ODataError>((schema, results, count))
+    };
+
+    let (schema, results, count) = match ctx.query_timeout() {
+        Some(timeout) => tokio::time::timeout(timeout, execute)
+            .await
+            .unwrap_or_else(|_| Err(QueryTimeout::new(timeout).into())),
+        None => execute.await,
+    }
+    .map_err(|e| log_query_error(ctx.as_ref(), &query_desc, e))?;
+
+    let record_batches = match results {
+        QueryResults::Collected(record_batches) => record_batches,
+        QueryResults::Streamed(mut stream) => {
+            let feed = FeedStream {
+                ctx: ctx.clone(),
+                format,
+                count,
+                next_page,
+                omit_nulls,
+                type_annotations: include_annotations.is_some(),
+                deadline: ctx
+                    .query_timeout()
+                    .map(|timeout| (started + timeout, timeout)),
+            };
+            // Failures of the query mostly surface before the first batch, which can still be
+            // reported with an error status
+            let first = feed
+                .next_batch(&mut stream)
+                .await
+                .map_err(|e| log_query_error(ctx.as_ref(), &query_desc, e))?;
+            let schema = feed_schema(schema, first.as_ref().map(|(batch, _)| batch), aggregated);
+
+            tracing::debug!(media_type = content_type, "Streaming a response");
+            return resp
+                .body(CollectionBody::Streamed(
+                    feed.into_body(schema, first, stream),
+                ))
+                .map_err(ODataError::internal);
+        }
+    };
 
+    // Cursor is read before the transformation, which may mask the columns it consists of
     let num_rows: usize = record_batches.iter().map(|b| b.num_rows()).sum();
+    let next_link = match &next_page {
+        Some(next_page) => next_page.link(
+            &ctx.collection_base_url()?,
+            record_batches
+                .iter()
+                .rev()
+                .find(|b| b.num_rows() > 0)
+                .and_then(|b| next_page.skip_token(b))
+                .as_ref(),
+            num_rows,
+        ),
+        None => None,
+    };
+
+    let record_batches = prepare_batches(ctx.as_ref(), record_batches).await?;
+    let schema = feed_schema(schema, record_batches.first(), aggregated);
+
     let raw_bytes: usize = record_batches
         .iter()
         .map(|b: &datafusion::arrow::array::RecordBatch| b.get_array_memory_size())
         .sum();
 
-    let mut writer = quick_xml::Writer::new(Vec::<u8>::new());
+    let mut buf = match format {
+        ResponseFormat::Atom if !single_entity => {
+            Vec::<u8>::with_capacity(crate::atom::estimate_atom_feed_size(&schema, num_rows))
+        }
+        _ => Vec::<u8>::new(),
+    };
 
-    if ctx.addr()?.key.is_none() {
-        crate::atom::write_atom_feed_from_records(
-            &schema,
-            record_batches,
-            ctx.as_ref(),
-            ctx.last_updated_time().await,
-            &mut writer,
-        )?;
+    if !single_entity {
+        match format {
+            ResponseFormat::Atom => crate::atom::write_atom_feed_from_records(
+                &schema,
+                record_batches,
+                ctx.as_ref(),
+                ctx.last_updated_time().await,
+                count,
+                next_link.as_deref(),
+                omit_nulls,
+                &mut xml_writer(&mut buf, pretty_print_xml(ctx.as_ref())),
+            )?,
+            ResponseFormat::Json(json_format) => crate::json::write_json_feed_from_records(
+                &schema,
+                record_batches,
+                ctx.as_ref(),
+                json_format,
+                count,
+                next_link.as_deref(),
+                include_annotations.is_some(),
+                &mut buf,
+            )?,
+            ResponseFormat::Csv => {
+                crate::csv::write_csv_from_records(record_batches, ctx.as_ref(), &mut buf)?
+            }
+        }
     } else {
         let num_rows: usize = record_batches.iter().map(|b| b.num_rows()).sum();
         if num_rows > 1 {
@@ -187,7 +724,7 @@ pub async fn odata_collection_handler(
             None => {
                 return Response::builder()
                     .status(http::StatusCode::NOT_FOUND)
-                    .body(String::new())
+                    .body(CollectionBody::Buffered(String::new()))
                     .map_err(ODataError::internal);
             }
         };
@@ -195,43 +732,1103 @@ pub async fn odata_collection_handler(
         if record_batch.num_rows() != 1 {
             return Response::builder()
                 .status(http::StatusCode::NOT_FOUND)
-                .body(String::new())
+                .body(CollectionBody::Buffered(String::new()))
                 .map_err(ODataError::internal);
         }
 
-        crate::atom::write_atom_entry_from_record(
-            &schema,
-            record_batch,
-            ctx.as_ref(),
-            ctx.last_updated_time().await,
-            &mut writer,
-        )?;
+        match format {
+            ResponseFormat::Atom => crate::atom::write_atom_entry_from_record(
+                &schema,
+                record_batch,
+                ctx.as_ref(),
+                ctx.last_updated_time().await,
+                omit_nulls,
+                &mut xml_writer(&mut buf, pretty_print_xml(ctx.as_ref())),
+            )?,
+            ResponseFormat::Json(json_format) => crate::json::write_json_entry_from_record(
+                &schema,
+                record_batch,
+                ctx.as_ref(),
+                json_format,
+                include_annotations.is_some(),
+                &mut buf,
+            )?,
+            ResponseFormat::Csv => {
+                crate::csv::write_csv_from_records(vec![record_batch], ctx.as_ref(), &mut buf)?
+            }
+        }
     }
 
-    let body = String::from_utf8(writer.into_inner()).map_err(ODataError::internal)?;
+    let body = String::from_utf8(buf).map_err(ODataError::internal)?;
 
     tracing::debug!(
-        media_type = MEDIA_TYPE_ATOM,
+        media_type = content_type,
         num_rows,
         raw_bytes,
-        xml_bytes = body.len(),
+        body_bytes = body.len(),
         "Prepared a response"
     );
 
+    resp.body(CollectionBody::Buffered(body))
+        .map_err(ODataError::internal)
+}
+
+fn check_expand_limits(
+    ctx: &dyn CollectionContext,
+    expand: &[ExpandItem],
+) -> Result<(), ODataError> {
+    if let Some(max_depth) = ctx.max_expand_depth() {
+        let depth = ExpandItem::depth(expand);
+        if depth > max_depth {
+            Err(ODataError::bad_request(format!(
+                "$expand is nested {depth} levels deep, at most {max_depth} allowed"
+            )))?
+        }
+    }
+    if let Some(max_breadth) = ctx.max_expand_breadth() {
+        let breadth = ExpandItem::breadth(expand);
+        if breadth > max_breadth {
+            Err(ODataError::bad_request(format!(
+                "$expand includes {breadth} navigation properties, at most {max_breadth} allowed"
+            )))?
+        }
+    }
+    Ok(())
+}
+
+// Clients refer to the properties, while the query is built from the columns
+async fn map_property_names(
+    ctx: &dyn CollectionContext,
+    query: QueryParams,
+) -> Result<QueryParams, ODataError> {
+    let Some(mapper) = ctx.property_name_mapper() else {
+        return Ok(query);
+    };
+    let columns = property_columns(ctx.entity_schema().await?.as_ref(), &mapper)?;
+    query.map_property_names(|name| columns.get(name).cloned())
+}
+
+/// Link to the page following a full one of server-driven paging
+struct NextPage {
+    page_size: usize,
+    paging_options: Vec<(&'static str, String)>,
+    skip: usize,
+    /// Set when the collection allows `$skiptoken`, see [`QueryParams::cursor_columns`]
+    cursor_columns: Option<Vec<String>>,
+    skip_allowed: bool,
+}
+
+impl NextPage {
+    /// Cursor of the last row of `batch`, which has to be read before the batch is transformed
+    /// (see [`CollectionContext::transform_batch`]), as that may mask the cursor columns
+    fn skip_token(&self, batch: &RecordBatch) -> Option<SkipToken> {
+        last_skip_token(batch, self.cursor_columns.as_ref()?)
+    }
+
+    /// Link following the page of `num_rows` entities ending with the one of `skip_token`,
+    /// if the page is full and the collection allows one of the paging options
+    fn link(
+        &self,
+        collection_base_url: &str,
+        skip_token: Option<&SkipToken>,
+        num_rows: usize,
+    ) -> Option<String> {
+        if skip_token.is_none() && !self.skip_allowed {
+            return None;
+        }
+        crate::collection::next_page_url(
+            collection_base_url,
+            &self.paging_options,
+            self.skip,
+            skip_token,
+            self.page_size,
+            num_rows,
+        )
+    }
+}
+
+// Cursor of the last entity, unless the `$select` left out some of the columns it consists of
+fn last_skip_token(batch: &RecordBatch, cursor_columns: &[String]) -> Option<SkipToken> {
+    let row = batch.num_rows().checked_sub(1)?;
+    let mut values = Vec::new();
+    for name in cursor_columns {
+        let column = batch.column_by_name(name)?;
+        let value = if column.is_null(row) {
+            None
+        } else {
+            Some(array_value_to_string(column, row).ok()?)
+        };
+        values.push(value);
+    }
+    Some(SkipToken { values })
+}
+
+/// Validates the rows about to be served and transforms them,
+/// see [`CollectionContext::validate`] and [`CollectionContext::transform_batch`]
+async fn prepare_batches(
+    ctx: &dyn CollectionContext,
+    record_batches: Vec<RecordBatch>,
+) -> Result<Vec<RecordBatch>, ODataError> {
+    ctx.validate(&record_batches).await?;
+    record_batches
+        .into_iter()
+        .map(|batch| ctx.transform_batch(batch))
+        .collect()
+}
+
+// Transformation may have dropped some of the columns, and the groups of an aggregation are
+// written as the entities of their own type
+fn feed_schema(schema: Schema, first_batch: Option<&RecordBatch>, aggregated: bool) -> Schema {
+    let mut schema = match first_batch {
+        Some(batch) => batch.schema().as_ref().clone(),
+        None => schema,
+    };
+    if aggregated {
+        schema
+            .metadata
+            .insert(GROUPS_METADATA_KEY.to_string(), "true".to_string());
+    }
+    schema
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Feed written to the response body as the record batches of the query arrive,
+/// see [`odata_collection_stream_handler`]
+struct FeedStream {
+    ctx: Arc<dyn CollectionContext>,
+    format: ResponseFormat,
+    count: Option<usize>,
+    next_page: Option<NextPage>,
+    omit_nulls: bool,
+    type_annotations: bool,
+    /// Point in time the query times out at, see [`CollectionContext::query_timeout`]
+    deadline: Option<(tokio::time::Instant, std::time::Duration)>,
+}
+
+impl FeedStream {
+    /// Next batch of the stream, validated and transformed like the collected ones, along
+    /// with the cursor of its last row when paged through `$skiptoken`
+    async fn next_batch(
+        &self,
+        stream: &mut SendableRecordBatchStream,
+    ) -> Result<Option<(RecordBatch, Option<SkipToken>)>, ODataError> {
+        let next = match self.deadline {
+            Some((deadline, timeout)) => tokio::time::timeout_at(deadline, stream.next())
+                .await
+                .map_err(|_| QueryTimeout::new(timeout))?,
+            None => stream.next().await,
+        };
+        let Some(batch) = next.transpose().map_err(ODataError::from_query_error)? else {
+            return Ok(None);
+        };
+        let skip_token = match &self.next_page {
+            Some(next_page) => next_page.skip_token(&batch),
+            None => None,
+        };
+        let batch = prepare_batches(self.ctx.as_ref(), vec![batch]).await?;
+        Ok(batch.into_iter().next().map(|batch| (batch, skip_token)))
+    }
+
+    /// Body the feed is written to by a separate task, which stops as soon as the body is
+    /// dropped, e.g. when the client disconnects
+    fn into_body(
+        self,
+        schema: Schema,
+        first_batch: Option<(RecordBatch, Option<SkipToken>)>,
+        stream: SendableRecordBatchStream,
+    ) -> Body {
+        let (mut tx, rx) = futures::channel::mpsc::channel(1);
+        tokio::spawn(async move {
+            if let Err(e) = self.write(schema, first_batch, stream, &mut tx).await {
+                // The status was sent already, so the client can only tell from the aborted body
+                tracing::error!(error = %e, error_dbg = ?e, "Failed to stream a feed");
+                tx.send(Err(e)).await.ok();
+            }
+        });
+        Body::from_stream(rx)
+    }
+
+    async fn write(
+        &self,
+        schema: Schema,
+        first_batch: Option<(RecordBatch, Option<SkipToken>)>,
+        mut stream: SendableRecordBatchStream,
+        tx: &mut futures::channel::mpsc::Sender<Result<Vec<u8>, ODataError>>,
+    ) -> Result<(), ODataError> {
+        let ctx = self.ctx.as_ref();
+        let flush_interval = ctx.flush_interval_rows();
+        let mut xml = xml_writer(BodyChunks::default(), pretty_print_xml(ctx));
+        let mut json = BodyChunks::default();
+        let mut feed = match self.format {
+            ResponseFormat::Atom => FeedWriter::Atom(
+                AtomFeedWriter::begin(
+                    &schema,
+                    ctx,
+                    ctx.last_updated_time().await,
+                    self.count,
+                    self.omit_nulls,
+                    &mut xml,
+                )?
+                .with_flush_interval(flush_interval),
+            ),
+            ResponseFormat::Json(format) => FeedWriter::Json(
+                JsonFeedWriter::begin(&schema, ctx, format, self.count, &mut json)?
+                    .with_type_annotations(self.type_annotations),
+            ),
+            ResponseFormat::Csv => Err(ODataError::internal("CSV can't be streamed"))?,
+        };
+        // Atom feeds are flushed every few entries when configured to, other feeds per batch
+        let flush_per_batch = !matches!(feed, FeedWriter::Atom(_)) || flush_interval.is_none();
+
+        let mut num_rows = 0;
+        let mut last_skip_token = None;
+        let mut next = first_batch;
+        while let Some((batch, skip_token)) = next {
+            feed.write_batch(&batch)?;
+            num_rows += batch.num_rows();
+            if skip_token.is_some() {
+                last_skip_token = skip_token;
+            }
+            if flush_per_batch {
+                feed.body().flush_chunk();
+            }
+            for chunk in feed.body().chunks.drain(..) {
+                // The client went away, which also cancels the query
+                if tx.send(Ok(chunk)).await.is_err() {
+                    return Ok(());
+                }
+            }
+            next = self.next_batch(&mut stream).await?;
+        }
+
+        let next_link = match &self.next_page {
+            Some(next_page) => next_page.link(
+                &ctx.collection_base_url()?,
+                last_skip_token.as_ref(),
+                num_rows,
+            ),
+            None => None,
+        };
+        feed.finish(next_link.as_deref())?;
+
+        for mut body in [xml.into_inner(), json] {
+            body.flush_chunk();
+            for chunk in body.chunks {
+                tx.send(Ok(chunk)).await.ok();
+            }
+        }
+        tracing::debug!(num_rows, "Streamed a response");
+        Ok(())
+    }
+}
+
+/// Feed writers of the streamed formats
+enum FeedWriter<'a> {
+    Atom(AtomFeedWriter<'a, BodyChunks>),
+    Json(JsonFeedWriter<'a, BodyChunks>),
+}
+
+impl FeedWriter<'_> {
+    fn write_batch(&mut self, batch: &RecordBatch) -> Result<(), ODataError> {
+        match self {
+            Self::Atom(feed) => feed.write_batch(batch),
+            Self::Json(feed) => feed.write_batch(batch),
+        }
+    }
+
+    fn body(&mut self) -> &mut BodyChunks {
+        match self {
+            Self::Atom(feed) => feed.get_mut(),
+            Self::Json(feed) => feed.get_mut(),
+        }
+    }
+
+    fn finish(self, next_link: Option<&str>) -> Result<(), ODataError> {
+        match self {
+            Self::Atom(feed) => feed.finish(next_link),
+            Self::Json(feed) => feed.finish(next_link),
+        }
+    }
+}
+
+/// Body of a streamed response, split into the chunks sent to the client where it's flushed
+#[derive(Default)]
+struct BodyChunks {
+    buf: Vec<u8>,
+    chunks: Vec<Vec<u8>>,
+}
+
+impl BodyChunks {
+    fn flush_chunk(&mut self) {
+        if !self.buf.is_empty() {
+            self.chunks.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+impl std::io::Write for BodyChunks {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flush_chunk();
+        Ok(())
+    }
+}
+
+// Client only receives a generic message for internal errors, so the details are logged here
+fn log_query_error(
+    ctx: &dyn CollectionContext,
+    query: &QueryParams,
+    err: ODataError,
+) -> ODataError {
+    if let ODataError::Internal(e) = &err {
+        tracing::error!(
+            collection = ctx.addr().map(|a| a.name.as_str()).unwrap_or_default(),
+            ?query,
+            category = e.datafusion_category(),
+            error = %e.source,
+            error_dbg = ?e.source,
+            "Query failed",
+        );
+    }
+    err
+}
+
+/// Extracts the `odata.maxpagesize` (or v4 `maxpagesize`) preference from the `Prefer` header,
+/// e.g. `Prefer: odata.allow-entityreferences, odata.maxpagesize=50`.
+///
+/// Empty pages would never advance, so a size of zero is ignored like any invalid value.
+fn prefer_max_page_size(headers: &axum::http::HeaderMap) -> Option<usize> {
+    headers
+        .get_all(HEADER_PREFER)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .find_map(|preference| {
+            let preference = preference.split(';').next()?;
+            let (name, value) = preference.split_once('=')?;
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("odata.maxpagesize")
+                || name.eq_ignore_ascii_case("maxpagesize")
+            {
+                value
+                    .trim()
+                    .trim_matches('"')
+                    .parse()
+                    .ok()
+                    .filter(|&size| size != 0)
+            } else {
+                None
+            }
+        })
+}
+
+/// Whether the client prefers null properties left out (`Prefer: omit-values=nulls`)
+fn prefer_omit_nulls(headers: &axum::http::HeaderMap) -> bool {
+    headers
+        .get_all(HEADER_PREFER)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|preference| {
+            let preference = preference.split(';').next().unwrap_or_default();
+            let Some((name, value)) = preference.split_once('=') else {
+                return false;
+            };
+            name.trim().eq_ignore_ascii_case("omit-values")
+                && value.trim().trim_matches('"').eq_ignore_ascii_case("nulls")
+        })
+}
+
+/// Extracts the `odata.include-annotations` preference (or v4.01 `include-annotations`) from the
+/// `Prefer` header when it includes the `odata.type` annotations, e.g.
+/// `Prefer: odata.include-annotations="*"` or `Prefer: odata.include-annotations="odata.*"`
+fn prefer_include_annotations(headers: &axum::http::HeaderMap) -> Option<String> {
+    headers
+        .get_all(HEADER_PREFER)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        // The list of annotations is comma-separated too, but it's quoted
+        .flat_map(|v| {
+            let mut in_quotes = false;
+            v.split(move |c| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                c == ',' && !in_quotes
+            })
+        })
+        .find_map(|preference| {
+            let preference = preference.split(';').next()?;
+            let (name, value) = preference.split_once('=')?;
+            let name = name.trim();
+            if !name.eq_ignore_ascii_case("odata.include-annotations")
+                && !name.eq_ignore_ascii_case("include-annotations")
+            {
+                return None;
+            }
+            let value = value.trim().trim_matches('"');
+            let includes_type = value
+                .split(',')
+                .map(str::trim)
+                .any(|pattern| matches!(pattern, "*" | "odata.*" | "odata.type"));
+            includes_type.then(|| value.to_string())
+        })
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct EntityRefParams {
+    /// Canonical URL of the entity
+    #[serde(rename = "$id")]
+    pub id: Option<String>,
+}
+
+/// Serves the entity referenced by its canonical URL, i.e. `service/$entity?$id=collection(key)`.
+///
+/// The `$id` is either absolute, in which case it has to point into this service, or relative
+/// to the service root. The other query options (e.g. `$select` or `$format`) apply to the
+/// entity the same way as in [`odata_collection_handler`].
+pub async fn odata_entity_ref_handler(
+    Extension(odata_ctx): Extension<Arc<dyn ServiceContext>>,
+    Query(params): Query<EntityRefParams>,
+    query: Query<QueryParamsRaw>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response<String>, ODataError> {
+    let verbose_errors = odata_ctx.verbose_errors();
+    serve_entity_ref(odata_ctx, params, query, headers)
+        .await
+        .map_err(|e| e.with_details(verbose_errors))
+}
+
+async fn serve_entity_ref(
+    odata_ctx: Arc<dyn ServiceContext>,
+    params: EntityRefParams,
+    query: Query<QueryParamsRaw>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response<String>, ODataError> {
+    let Some(id) = params.id else {
+        Err(ODataError::bad_request("$entity requires the $id option"))?
+    };
+    let addr = decode_entity_id(&odata_ctx.service_base_url(), &id)?;
+    let ctx = odata_ctx.collection_context(addr).await?;
+    odata_collection_handler(Extension(ctx), query, headers).await
+}
+
+fn decode_entity_id(service_base_url: &str, id: &str) -> Result<CollectionAddr, ODataError> {
+    let path = if id.contains("://") {
+        // The prefix has to end at a path segment, e.g. `.../odata2/` is not under `.../odata/`
+        id.strip_prefix(service_base_url.trim_end_matches('/'))
+            .filter(|path| path.starts_with('/'))
+            .ok_or_else(|| {
+                ODataError::bad_request(format!("Entity {id} does not belong to this service"))
+            })?
+    } else {
+        id
+    };
+
+    match CollectionAddr::decode(path.trim_start_matches('/')) {
+        Ok(addr) if addr.key.is_some() => Ok(addr),
+        _ => Err(ODataError::bad_request(format!("Invalid entity id: {id}"))),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Calls one of the [`ServiceContext::functions`], e.g. `service/GetTopMovers(count=5)`,
+/// serving the returned entities as a feed of the function's entity set
+pub async fn odata_function_handler(
+    Extension(odata_ctx): Extension<Arc<dyn ServiceContext>>,
+    Path(function_call): Path<String>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response<String>, ODataError> {
+    let verbose_errors = odata_ctx.verbose_errors();
+    serve_function(odata_ctx, function_call, headers)
+        .await
+        .map_err(|e| e.with_details(verbose_errors))
+}
+
+async fn serve_function(
+    odata_ctx: Arc<dyn ServiceContext>,
+    function_call: String,
+    headers: axum::http::HeaderMap,
+) -> Result<Response<String>, ODataError> {
+    let (name, mut args) = decode_function_call(&function_call)?;
+    let Some(function) = odata_ctx.functions().into_iter().find(|f| f.name == name) else {
+        Err(CollectionNotFound::new(name))?
+    };
+
+    if let Some((arg, _)) = args
+        .iter()
+        .find(|(arg, _)| !function.parameters.iter().any(|(p, _)| p == arg))
+    {
+        Err(ODataError::bad_request(format!(
+            "Function {name}() has no parameter {arg}"
+        )))?
+    }
+    let mut ordered_args = Vec::new();
+    for (param, _) in &function.parameters {
+        let Some(i) = args.iter().position(|(arg, _)| arg == param) else {
+            Err(ODataError::bad_request(format!(
+                "Function {name}() requires the parameter {param}"
+            )))?
+        };
+        ordered_args.push(args.swap_remove(i));
+    }
+
+    let ctx = odata_ctx
+        .collection_context(CollectionAddr {
+            name: function.entity_set.clone(),
+            key: None,
+        })
+        .await?;
+    let df = odata_ctx
+        .call_function(&function.name, ordered_args)
+        .await?;
+
+    // Entities are identified the same way as those of the entity set
+    let key_column_alias = ctx.id_column()?;
+    let df = if df
+        .schema()
+        .has_column_with_unqualified_name(&key_column_alias)
+    {
+        df
+    } else {
+        let key_column = ctx.resolve_key_column().await?;
+        df.with_column(
+            &key_column_alias,
+            datafusion::prelude::col(datafusion::common::Column::new_unqualified(key_column)),
+        )
+        .map_err(ODataError::internal)?
+    };
+    // The results are entities of the set, so they hide the same columns
+    let hidden_columns = ctx.hidden_columns().await?;
+    let df = if hidden_columns.is_empty() {
+        df
+    } else {
+        let hidden: Vec<_> = hidden_columns.iter().map(String::as_str).collect();
+        df.drop_columns(&hidden).map_err(ODataError::internal)?
+    };
+
+    let schema: datafusion::arrow::datatypes::Schema = df.schema().clone().into();
+    let record_batches = df.collect().await.map_err(ODataError::from_query_error)?;
+    let record_batches = prepare_batches(ctx.as_ref(), record_batches).await?;
+    let schema = feed_schema(schema, record_batches.first(), false);
+
+    let format = ResponseFormat::from_headers(&headers);
+    let mut buf = Vec::new();
+    match format {
+        ResponseFormat::Atom => crate::atom::write_atom_feed_from_records(
+            &schema,
+            record_batches,
+            ctx.as_ref(),
+            ctx.last_updated_time().await,
+            None,
+            None,
+            false,
+            &mut xml_writer(&mut buf, pretty_print_xml(ctx.as_ref())),
+        )?,
+        ResponseFormat::Json(json_format) => crate::json::write_json_feed_from_records(
+            &schema,
+            record_batches,
+            ctx.as_ref(),
+            json_format,
+            None,
+            None,
+            false,
+            &mut buf,
+        )?,
+        ResponseFormat::Csv => {
+            crate::csv::write_csv_from_records(record_batches, ctx.as_ref(), &mut buf)?
+        }
+    }
+
     Response::builder()
-        .header(http::header::CONTENT_TYPE.as_str(), MEDIA_TYPE_ATOM)
+        .header(
+            http::header::CONTENT_TYPE.as_str(),
+            content_type(ctx.as_ref(), format),
+        )
+        .body(String::from_utf8(buf)?)
+        .map_err(ODataError::internal)
+}
+
+/// Splits `GetTopMovers(count=5)` into the function name and its named arguments
+fn decode_function_call(
+    function_call: &str,
+) -> Result<(String, Vec<(String, KeyValue)>), ODataError> {
+    let invalid = || ODataError::bad_request(format!("Invalid function call: {function_call}"));
+
+    if let Some(name) = function_call.strip_suffix("()") {
+        return match CollectionAddr::decode(name) {
+            Ok(addr) if addr.key.is_none() => Ok((addr.name, Vec::new())),
+            _ => Err(invalid()),
+        };
+    }
+    match CollectionAddr::decode(function_call) {
+        Ok(CollectionAddr {
+            name,
+            key: Some(KeyValue::Composite(args)),
+        }) => Ok((name, args)),
+        _ => Err(invalid()),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Raw value of a property of a single entity, i.e. `service/collection(id)/property/$value`.
+///
+/// Binary values are served as is and support `Range` requests, which are answered with
+/// `206 Partial Content`. Other values are served as plain text. A `null` value results in
+/// `404 Not Found`, same as a missing entity.
+pub async fn odata_property_value_handler(
+    Extension(ctx): Extension<Arc<dyn CollectionContext>>,
+    Path(property): Path<String>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response<Body>, ODataError> {
+    let verbose_errors = verbose_errors(ctx.as_ref());
+    serve_property_value(ctx, property, headers)
+        .await
+        .map_err(|e| e.with_details(verbose_errors))
+}
+
+async fn serve_property_value(
+    ctx: Arc<dyn CollectionContext>,
+    property: String,
+    headers: axum::http::HeaderMap,
+) -> Result<Response<Body>, ODataError> {
+    let single_entity =
+        ctx.is_singleton() || (ctx.addr()?.key.is_some() && !ctx.is_parameterized());
+    if !single_entity {
+        Err(ODataError::bad_request(
+            "$value can only be requested for a property of a single entity",
+        ))?
+    }
+
+    let query = QueryParamsRaw {
+        select: Some(property),
+        ..Default::default()
+    }
+    .decode()?;
+    let query = map_property_names(ctx.as_ref(), query).await?;
+    let options = ctx.apply_options().await?;
+    let query_desc = query.clone();
+    let Some(column) = query.select.first().cloned() else {
+        Err(ODataError::bad_request("$value requires a single property"))?
+    };
+
+    let record_batches = async {
+        ctx.query(query, &options)
+            .await?
+            .df
+            .collect()
+            .await
+            .map_err(ODataError::from_query_error)
+    }
+    .await
+    .map_err(|e| log_query_error(ctx.as_ref(), &query_desc, e))?;
+
+    let num_rows: usize = record_batches.iter().map(|b| b.num_rows()).sum();
+    if num_rows > 1 {
+        return Err(ODataError::internal(BatchUnexpectedRowsNumber::new(
+            num_rows,
+        )));
+    }
+    // Value is served the same way it appears in the entity
+    let record_batches = prepare_batches(ctx.as_ref(), record_batches).await?;
+
+    let not_found = || {
+        Response::builder()
+            .status(http::StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .map_err(ODataError::internal)
+    };
+
+    // Transformation may have dropped the column
+    let Some(value) = record_batches
+        .iter()
+        .find(|b| b.num_rows() == 1)
+        .and_then(|b| b.column_by_name(&column))
+    else {
+        return not_found();
+    };
+    if value.is_null(0) {
+        return not_found();
+    }
+
+    let bytes = match value.data_type() {
+        DataType::Binary => value.as_binary::<i32>().value(0),
+        DataType::LargeBinary => value.as_binary::<i64>().value(0),
+        _ => {
+            let text = ArrayFormatter::try_new(value.as_ref(), &FormatOptions::default())
+                .map_err(ODataError::internal)?
+                .value(0)
+                .to_string();
+            return Response::builder()
+                .header(http::header::CONTENT_TYPE, MEDIA_TYPE_TEXT)
+                .body(Body::from(text))
+                .map_err(ODataError::internal);
+        }
+    };
+
+    let resp = Response::builder()
+        .header(http::header::CONTENT_TYPE, MEDIA_TYPE_OCTET_STREAM)
+        .header(http::header::ACCEPT_RANGES, "bytes");
+    let len = bytes.len();
+    match requested_byte_range(&headers, len) {
+        ByteRange::Full => resp.body(Body::from(bytes.to_vec())),
+        ByteRange::Partial(start, end) => resp
+            .status(http::StatusCode::PARTIAL_CONTENT)
+            .header(
+                http::header::CONTENT_RANGE,
+                format!("bytes {start}-{end}/{len}"),
+            )
+            .body(Body::from(bytes[start..=end].to_vec())),
+        ByteRange::Unsatisfiable => resp
+            .status(http::StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(http::header::CONTENT_RANGE, format!("bytes */{len}"))
+            .body(Body::empty()),
+    }
+    .map_err(ODataError::internal)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    /// Inclusive bounds
+    Partial(usize, usize),
+    Unsatisfiable,
+}
+
+/// Single byte range of the `Range` header, e.g. `bytes=0-1023`, `bytes=1024-` or `bytes=-512`
+/// (see RFC 9110, section 14.2).
+///
+/// Headers that can't be parsed and multiple ranges are ignored by serving the whole value,
+/// as allowed by the RFC.
+fn requested_byte_range(headers: &axum::http::HeaderMap, len: usize) -> ByteRange {
+    let Some(range) = headers
+        .get(http::header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().strip_prefix("bytes="))
+    else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = range.split_once('-') else {
+        return ByteRange::Full;
+    };
+    if range.contains(',') {
+        return ByteRange::Full;
+    }
+
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        // Suffix of the given length
+        return match end.parse::<usize>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial(len.saturating_sub(suffix), len - 1),
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let Ok(start) = start.parse::<usize>() else {
+        return ByteRange::Full;
+    };
+    let end = if end.is_empty() {
+        usize::MAX
+    } else {
+        match end.parse::<usize>() {
+            Ok(end) if end >= start => end,
+            _ => return ByteRange::Full,
+        }
+    };
+    if start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, std::cmp::min(end, len - 1))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Representation of the collection resources negotiated with the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Atom,
+    Json(JsonFormat),
+    Csv,
+}
+
+impl ResponseFormat {
+    /// Picks the format from the first supported media range of the `Accept` header,
+    /// falling back to Atom
+    pub fn from_headers(headers: &axum::http::HeaderMap) -> Self {
+        let Some(accept) = headers
+            .get(http::header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return Self::Atom;
+        };
+
+        for media_range in accept.split(',') {
+            let mut parts = media_range.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+
+            match media_type.as_str() {
+                "application/json" => {
+                    let verbose = parts.any(|p| p.eq_ignore_ascii_case("odata=verbose"));
+                    return Self::Json(if verbose {
+                        JsonFormat::Verbose
+                    } else {
+                        JsonFormat::V4
+                    });
+                }
+                "text/csv" => return Self::Csv,
+                "application/atom+xml" | "application/xml" | "*/*" => return Self::Atom,
+                _ => {}
+            }
+        }
+
+        Self::Atom
+    }
+
+    /// Interprets the `$format` query option, which takes precedence over the `Accept` header
+    pub fn from_format_option(format: &str) -> Result<Self, ODataError> {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "atom" | "xml" => Ok(Self::Atom),
+            "json" => Ok(Self::Json(JsonFormat::V4)),
+            "csv" => Ok(Self::Csv),
+            _ => Err(ODataError::bad_request(format!(
+                "Unsupported $format: {format}"
+            ))),
+        }
+    }
+
+    /// Resolves the format from the `$format` query option or, in its absence, the `Accept`
+    /// header. A `$format` that none of the formats listed in `Accept` agree with is rejected.
+    pub fn negotiate(
+        format: Option<&str>,
+        headers: &axum::http::HeaderMap,
+    ) -> Result<Self, ODataError> {
+        let Some(format) = format else {
+            return Ok(Self::from_headers(headers));
+        };
+        let resolved = Self::from_format_option(format)?;
+
+        let Some(accept) = headers
+            .get(http::header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return Ok(resolved);
+        };
+
+        // Media types unknown to the service and wildcards are not considered a conflict
+        let mut conflicting = false;
+        for media_range in accept.split(',') {
+            let media_type = media_range.split(';').next().unwrap_or_default().trim();
+            let listed = match media_type.to_ascii_lowercase().as_str() {
+                "application/json" => Self::Json(JsonFormat::V4),
+                "text/csv" => Self::Csv,
+                "application/atom+xml" | "application/xml" => Self::Atom,
+                "*/*" => return Ok(resolved),
+                _ => continue,
+            };
+            match (listed, resolved) {
+                (Self::Json(_), Self::Json(_)) => return Ok(resolved),
+                (listed, resolved) if listed == resolved => return Ok(resolved),
+                _ => conflicting = true,
+            }
+        }
+
+        if conflicting {
+            Err(ODataError::bad_request(format!(
+                "$format={format} conflicts with Accept: {accept}"
+            )))?
+        }
+        Ok(resolved)
+    }
+
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Self::Atom => MEDIA_TYPE_ATOM,
+            Self::Json(JsonFormat::Verbose) => MEDIA_TYPE_JSON_VERBOSE,
+            Self::Json(JsonFormat::V4) => MEDIA_TYPE_JSON,
+            Self::Csv => MEDIA_TYPE_CSV,
+        }
+    }
+
+    /// Lowest protocol version able to represent the response to the query,
+    /// `None` for the formats that are not part of the protocol
+    pub fn required_version(&self, query: &QueryParams) -> Option<ProtocolVersion> {
+        match self {
+            // Projections and inline counts were introduced by OData v2
+            Self::Atom | Self::Json(JsonFormat::Verbose) => {
+                if query.count || !query.select.is_empty() {
+                    Some(ProtocolVersion::V2)
+                } else {
+                    Some(ProtocolVersion::V1)
+                }
+            }
+            Self::Json(JsonFormat::V4) => Some(ProtocolVersion::V4),
+            Self::Csv => None,
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// OData protocol version, as declared by the `DataServiceVersion` family of headers
+/// (`OData-Version` in v4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProtocolVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl ProtocolVersion {
+    pub const V1: Self = Self::new(1, 0);
+    pub const V2: Self = Self::new(2, 0);
+    pub const V3: Self = Self::new(3, 0);
+    pub const V4: Self = Self::new(4, 0);
+
+    pub const fn new(major: u8, minor: u8) -> Self {
+        Self { major, minor }
+    }
+
+    /// Parses header values like `2.0`, `3.0;NetFx` or `4.01`
+    pub fn parse(value: &str) -> Option<Self> {
+        let version = value.split(';').next()?.trim();
+        let (major, minor) = version.split_once('.').unwrap_or((version, "0"));
+        Some(Self::new(major.parse().ok()?, minor.parse().ok()?))
+    }
+
+    /// Highest version the client accepts, from `MaxDataServiceVersion` (v1-v3) or
+    /// `OData-MaxVersion` (v4), `None` when neither is sent or they can't be parsed
+    pub fn max_from_headers(headers: &axum::http::HeaderMap) -> Option<Self> {
+        [HEADER_ODATA_MAX_VERSION, HEADER_MAX_DATA_SERVICE_VERSION]
+            .into_iter()
+            .filter_map(|name| headers.get(name)?.to_str().ok())
+            .filter_map(Self::parse)
+            .max()
+    }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Diagnostic endpoint (not part of the OData spec) that describes the arrow schema
+/// of a collection and how each of its fields maps onto EDM types
+pub async fn odata_collection_schema_handler(
+    Extension(ctx): Extension<Arc<dyn CollectionContext>>,
+) -> Result<Response<String>, ODataError> {
+    let verbose_errors = verbose_errors(ctx.as_ref());
+    serve_collection_schema(ctx)
+        .await
+        .map_err(|e| e.with_details(verbose_errors))
+}
+
+async fn serve_collection_schema(
+    ctx: Arc<dyn CollectionContext>,
+) -> Result<Response<String>, ODataError> {
+    let schema = ctx.entity_schema().await?;
+
+    let fields = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            let (edm_type, unsupported) = match to_edm_type(field.data_type()) {
+                Ok(typ) => (Some(typ.to_string()), None),
+                Err(err) => (None, Some(err.to_string())),
+            };
+            SchemaField {
+                name: field.name().clone(),
+                arrow_type: field.data_type().to_string(),
+                nullable: field.is_nullable(),
+                edm_type,
+                unsupported,
+            }
+        })
+        .collect();
+
+    let body = serde_json::to_string(&CollectionSchema {
+        name: ctx.collection_name()?,
+        fields,
+    })?;
+
+    Response::builder()
+        .header(http::header::CONTENT_TYPE.as_str(), MEDIA_TYPE_JSON_PLAIN)
         .body(body)
         .map_err(ODataError::internal)
 }
 
+#[derive(Debug, serde::Serialize)]
+struct CollectionSchema {
+    name: String,
+    fields: Vec<SchemaField>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SchemaField {
+    name: String,
+    arrow_type: String,
+    nullable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    edm_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unsupported: Option<String>,
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 
-fn write_object_to_xml<T>(tag: &str, object: &T) -> Result<String, ODataError>
+/// `Content-Type` of the collection responses in the specified format,
+/// see [`ServiceContext::content_type`]
+fn content_type(ctx: &dyn CollectionContext, format: ResponseFormat) -> String {
+    match ctx.service() {
+        Some(service) => service.content_type(format.media_type()),
+        None => format.media_type().to_string(),
+    }
+}
+
+/// Whether the service of the collection indents the documents,
+/// see [`ServiceContext::pretty_print_xml`]
+fn pretty_print_xml(ctx: &dyn CollectionContext) -> bool {
+    ctx.service()
+        .is_some_and(|service| service.pretty_print_xml())
+}
+
+/// Whether the service of the collection exposes the messages of internal errors,
+/// see [`ServiceContext::verbose_errors`]
+fn verbose_errors(ctx: &dyn CollectionContext) -> bool {
+    ctx.service()
+        .is_some_and(|service| service.verbose_errors())
+}
+
+/// Indented writer when `pretty` is set, see [`ServiceContext::pretty_print_xml`]
+fn xml_writer<W: std::io::Write>(inner: W, pretty: bool) -> quick_xml::Writer<W> {
+    if pretty {
+        quick_xml::Writer::new_with_indent(inner, b' ', 2)
+    } else {
+        quick_xml::Writer::new(inner)
+    }
+}
+
+fn write_object_to_xml<T>(tag: &str, object: &T, pretty: bool) -> Result<String, ODataError>
 where
     T: serde::ser::Serialize,
 {
-    let mut writer =
-        quick_xml::Writer::new(Vec::<u8>::with_capacity(DEFAULT_COLLECTION_RESPONSE_SIZE));
+    let mut writer = xml_writer(
+        Vec::<u8>::with_capacity(DEFAULT_COLLECTION_RESPONSE_SIZE),
+        pretty,
+    );
 
     writer
         .write_event(quick_xml::events::Event::Decl(
@@ -0,0 +1,716 @@
+use std::sync::Arc;
+
+use datafusion::arrow::{
+    array::{Array, AsArray, RecordBatch},
+    datatypes::{DataType, *},
+};
+use serde::{Serialize, Serializer};
+use serde_json::{value::RawValue, Map, Value};
+
+use crate::{
+    atom::{
+        composite_key_columns, encode_entity_key, format_date_time_offset, stringify_value,
+        timestamp_millis, to_edms, warn_if_null_keys_skipped, Edm, EdmEncoding,
+    },
+    context::{is_groups_schema, CollectionContext, OnNullKey},
+    error::{ODataError, UnsupportedDataType, UnsupportedNetProtocol},
+};
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Flavor of the JSON representation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonFormat {
+    /// OData v3 verbose JSON (`{"d": ...}`)
+    Verbose,
+    /// OData v4 JSON (`{"@odata.context": ..., ...}`)
+    V4,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// https://www.odata.org/documentation/odata-version-3-0/json-verbose-format/
+//
+// {
+//   "d": {
+//     "__count": "1",
+//     "results": [
+//       {
+//         "__metadata": {
+//           "id": "http://example.com/odata/tickers_spy(0)",
+//           "uri": "http://example.com/odata/tickers_spy(0)",
+//           "type": "default.tickers_spy"
+//         },
+//         "offset": "0",
+//         "close": 135.5625
+//       }
+//     ]
+//   }
+// }
+//
+// https://docs.oasis-open.org/odata/odata-json-format/v4.01/odata-json-format-v4.01.html
+//
+// {
+//   "@odata.context": "http://example.com/odata/$metadata#tickers_spy",
+//   "@odata.count": 1,
+//   "value": [
+//     {
+//       "offset": 0,
+//       "close": 135.5625
+//     }
+//   ]
+// }
+#[allow(clippy::too_many_arguments)]
+pub fn write_json_feed_from_records<W>(
+    schema: &Schema,
+    record_batches: Vec<RecordBatch>,
+    ctx: &dyn CollectionContext,
+    format: JsonFormat,
+    count: Option<usize>,
+    next_link: Option<&str>,
+    type_annotations: bool,
+    writer: &mut W,
+) -> Result<(), ODataError>
+where
+    W: std::io::Write,
+{
+    let mut feed = JsonFeedWriter::begin(schema, ctx, format, count, writer)?
+        .with_type_annotations(type_annotations);
+    for batch in &record_batches {
+        feed.write_batch(batch)?;
+    }
+    feed.finish(next_link)
+}
+
+/// Writes a JSON feed incrementally: the envelope and `@odata.count` on [`JsonFeedWriter::begin`],
+/// the entities of every batch on [`JsonFeedWriter::write_batch`], and the closing brackets
+/// along with the optional `@odata.nextLink` on [`JsonFeedWriter::finish`]
+pub struct JsonFeedWriter<'a, W> {
+    info: EntityInfo,
+    format: JsonFormat,
+    writer: &'a mut W,
+    first: bool,
+    skipped: usize,
+}
+
+impl<'a, W> JsonFeedWriter<'a, W>
+where
+    W: std::io::Write,
+{
+    pub fn begin(
+        schema: &Schema,
+        ctx: &dyn CollectionContext,
+        format: JsonFormat,
+        count: Option<usize>,
+        writer: &'a mut W,
+    ) -> Result<Self, ODataError> {
+        let info = EntityInfo::new(schema, ctx)?;
+
+        match format {
+            JsonFormat::Verbose => {
+                writer
+                    .write_all(br#"{"d":{"#)
+                    .map_err(ODataError::internal)?;
+                if let Some(count) = count {
+                    // Verbose JSON represents the count as a string, same as `Edm.Int64`
+                    write!(writer, r#""__count":"{count}","#).map_err(ODataError::internal)?;
+                }
+                writer
+                    .write_all(br#""results":["#)
+                    .map_err(ODataError::internal)?;
+            }
+            JsonFormat::V4 => {
+                let context_url = if info.groups {
+                    format!(
+                        "{}$metadata#Collection({})",
+                        info.service_base_url, info.fq_type
+                    )
+                } else {
+                    format!(
+                        "{}$metadata#{}",
+                        info.service_base_url, info.collection_name
+                    )
+                };
+                writer
+                    .write_all(br#"{"@odata.context":"#)
+                    .map_err(ODataError::internal)?;
+                serde_json::to_writer(&mut *writer, &context_url)?;
+                if let Some(count) = count {
+                    write!(writer, r#","@odata.count":{count}"#).map_err(ODataError::internal)?;
+                }
+                writer
+                    .write_all(br#","value":["#)
+                    .map_err(ODataError::internal)?;
+            }
+        }
+
+        Ok(Self {
+            info,
+            format,
+            writer,
+            first: true,
+            skipped: 0,
+        })
+    }
+
+    /// Annotates the v4 properties with their types, see [`EntityInfo::type_annotation`]
+    pub fn with_type_annotations(mut self, type_annotations: bool) -> Self {
+        self.info.type_annotations = type_annotations;
+        self
+    }
+
+    /// Underlying writer, e.g. to pass on what was written so far
+    pub fn get_mut(&mut self) -> &mut W {
+        self.writer
+    }
+
+    pub fn write_batch(&mut self, batch: &RecordBatch) -> Result<(), ODataError> {
+        for row in 0..batch.num_rows() {
+            if self.info.is_skipped(batch, row) {
+                self.skipped += 1;
+                continue;
+            }
+            // Separators go before the entities, as the last one is not known in advance
+            if !self.first {
+                self.writer.write_all(b",").map_err(ODataError::internal)?;
+            }
+            self.first = false;
+
+            let entity = self
+                .info
+                .encode_entity(batch, row, self.format, Entity::default())?;
+            serde_json::to_writer(&mut *self.writer, &entity)?;
+        }
+        Ok(())
+    }
+
+    /// Closes the feed, the link to the next page (if any) can only be known after the
+    /// entities of the current one were written, so it follows them
+    pub fn finish(self, next_link: Option<&str>) -> Result<(), ODataError> {
+        self.writer.write_all(b"]").map_err(ODataError::internal)?;
+        if let Some(next_link) = next_link {
+            let key = match self.format {
+                JsonFormat::Verbose => "__next",
+                JsonFormat::V4 => "@odata.nextLink",
+            };
+            write!(self.writer, r#","{key}":"#).map_err(ODataError::internal)?;
+            serde_json::to_writer(&mut *self.writer, next_link)?;
+        }
+        match self.format {
+            JsonFormat::Verbose => self.writer.write_all(b"}}"),
+            JsonFormat::V4 => self.writer.write_all(b"}"),
+        }
+        .map_err(ODataError::internal)?;
+
+        warn_if_null_keys_skipped(self.skipped);
+        Ok(())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// {"d": {"__metadata": {...}, "offset": "0", "close": 135.5625}}
+//
+// {"@odata.context": "http://example.com/odata/$metadata#tickers_spy/$entity", "offset": 0, "close": 135.5625}
+pub fn write_json_entry_from_record<W>(
+    schema: &Schema,
+    batch: RecordBatch,
+    ctx: &dyn CollectionContext,
+    format: JsonFormat,
+    type_annotations: bool,
+    writer: &mut W,
+) -> Result<(), ODataError>
+where
+    W: std::io::Write,
+{
+    let mut info = EntityInfo::new(schema, ctx)?;
+    info.type_annotations = type_annotations;
+    let row = 0;
+
+    match format {
+        JsonFormat::Verbose => {
+            let entity = info.encode_entity(&batch, row, format, Entity::default())?;
+            writer
+                .write_all(br#"{"d":"#)
+                .map_err(ODataError::internal)?;
+            serde_json::to_writer(&mut *writer, &entity)?;
+            writer.write_all(b"}").map_err(ODataError::internal)?;
+        }
+        JsonFormat::V4 => {
+            let mut entity = Entity::default();
+            entity.insert(
+                "@odata.context".to_string(),
+                Value::String(if info.is_singleton {
+                    format!(
+                        "{}$metadata#{}",
+                        info.service_base_url, info.collection_name
+                    )
+                } else {
+                    format!(
+                        "{}$metadata#{}/$entity",
+                        info.service_base_url, info.collection_name
+                    )
+                }),
+            );
+            let entity = info.encode_entity(&batch, row, format, entity)?;
+            serde_json::to_writer(&mut *writer, &entity)?;
+        }
+    }
+
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// {"@odata.context": "http://example.com/odata/$metadata", "value": [{"name": "tickers_spy", "kind": "EntitySet", "url": "tickers_spy"}]}
+pub fn write_json_service_document<W>(
+    mut service_base_url: String,
+    collections: &[Arc<dyn CollectionContext>],
+    writer: &mut W,
+) -> Result<(), ODataError>
+where
+    W: std::io::Write,
+{
+    #[derive(serde::Serialize)]
+    struct ServiceDocument {
+        #[serde(rename = "@odata.context")]
+        context: String,
+        value: Vec<ServiceElement>,
+    }
+
+    #[derive(serde::Serialize)]
+    struct ServiceElement {
+        name: String,
+        kind: &'static str,
+        url: String,
+    }
+
+    if !service_base_url.ends_with('/') {
+        service_base_url.push('/');
+    }
+
+    let mut value = Vec::new();
+    for coll in collections {
+        let name = coll.collection_name()?;
+        value.push(ServiceElement {
+            url: name.clone(),
+            name,
+            kind: if coll.is_singleton() {
+                "Singleton"
+            } else {
+                "EntitySet"
+            },
+        });
+    }
+
+    let document = ServiceDocument {
+        context: format!("{service_base_url}$metadata"),
+        value,
+    };
+    serde_json::to_writer(&mut *writer, &document)?;
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct EntityInfo {
+    service_base_url: String,
+    collection_base_url: String,
+    collection_name: String,
+    fq_type: String,
+    edms: Vec<(Edm, usize)>,
+    key_edm_index: usize,
+    composite_key: Vec<(String, String)>,
+    is_singleton: bool,
+    /// Groups of an aggregation are not addressable, see [`CollectionContext::group_type_name`]
+    groups: bool,
+    on_null_key: OnNullKey,
+    type_annotations: bool,
+}
+
+impl EntityInfo {
+    fn new(schema: &Schema, ctx: &dyn CollectionContext) -> Result<Self, ODataError> {
+        let mut service_base_url = ctx.service_base_url()?;
+        let mut collection_base_url = ctx.collection_base_url()?;
+        let collection_name = ctx.collection_name()?;
+        let groups = is_groups_schema(schema);
+        let type_name = if groups {
+            ctx.group_type_name()?
+        } else {
+            ctx.entity_type_name()?
+        };
+        let type_namespace = ctx.collection_namespace()?;
+
+        if !service_base_url.starts_with("http") {
+            return Err(UnsupportedNetProtocol::new(service_base_url).into());
+        }
+        if !collection_base_url.starts_with("http") {
+            return Err(UnsupportedNetProtocol::new(collection_base_url).into());
+        }
+
+        if !service_base_url.ends_with('/') {
+            service_base_url.push('/');
+        }
+        if collection_base_url.ends_with('/') {
+            collection_base_url.pop();
+        }
+
+        let (edms, key_edm_index) = to_edms(
+            schema,
+            &ctx.id_column()?,
+            // The ordinal of a group is its only declared property
+            ctx.synthetic_key_column() && !groups,
+            ctx.on_unsupported_feature(),
+            &ctx.property_overrides(),
+            ctx.property_name_mapper().as_ref(),
+        )?;
+
+        Ok(Self {
+            service_base_url,
+            collection_base_url,
+            collection_name,
+            fq_type: format!("{type_namespace}.{type_name}"),
+            edms,
+            key_edm_index,
+            composite_key: if groups {
+                Vec::new()
+            } else {
+                composite_key_columns(ctx)
+            },
+            is_singleton: ctx.is_singleton(),
+            groups,
+            on_null_key: ctx.on_null_key(),
+            type_annotations: false,
+        })
+    }
+
+    /// `@odata.type` of the v4 properties whose type can't be told from their JSON values,
+    /// e.g. `#Decimal` for `Edm.Decimal`
+    fn type_annotation(typ: &str) -> Option<String> {
+        match typ {
+            "Edm.String" | "Edm.Boolean" | "Edm.Double" | "Edm.Int32" => None,
+            // `Edm.DateTime` was removed in 4.0, see [`crate::metadata::PropertyV4::from_v3`]
+            "Edm.DateTime" => Some("#DateTimeOffset".to_string()),
+            typ => Some(format!("#{}", typ.strip_prefix("Edm.").unwrap_or(typ))),
+        }
+    }
+
+    /// Whether the entity is left out of the feed for having a null key
+    fn is_skipped(&self, batch: &RecordBatch, row: usize) -> bool {
+        if self.is_singleton {
+            return false;
+        }
+        if !self.composite_key.is_empty() {
+            return self.composite_key.iter().any(|(column, _)| {
+                batch
+                    .column_by_name(column)
+                    .is_some_and(|col| col.is_null(row))
+            });
+        }
+        self.on_null_key == OnNullKey::Skip && batch.column(self.key_edm_index).is_null(row)
+    }
+
+    fn encode_entity(
+        &self,
+        batch: &RecordBatch,
+        row: usize,
+        format: JsonFormat,
+        mut entity: Entity,
+    ) -> Result<Entity, ODataError> {
+        if format == JsonFormat::Verbose {
+            let entry_url_full = if self.is_singleton {
+                self.collection_base_url.clone()
+            } else {
+                let id = encode_entity_key(
+                    batch,
+                    batch.column(self.key_edm_index),
+                    &self.composite_key,
+                    row,
+                    &self.on_null_key,
+                )?
+                .ok_or_else(|| ODataError::internal("Entity has a null key"))?;
+                if self.groups {
+                    format!("{}#{id}", self.collection_base_url)
+                } else {
+                    format!("{}({id})", self.collection_base_url)
+                }
+            };
+
+            let mut metadata = Map::new();
+            metadata.insert("id".to_string(), Value::String(entry_url_full.clone()));
+            if !self.groups {
+                metadata.insert("uri".to_string(), Value::String(entry_url_full));
+            }
+            metadata.insert("type".to_string(), Value::String(self.fq_type.clone()));
+            entity.insert("__metadata".to_string(), Value::Object(metadata));
+        }
+
+        for (edm, index) in &self.edms {
+            let col = batch.column(*index);
+            let value = match edm.encoding {
+                EdmEncoding::Primitive => {
+                    edm.check_int64_range(col, row);
+                    encode_property_value(col, row, format)?
+                }
+                EdmEncoding::Stringify if col.is_null(row) => Value::Null.into(),
+                EdmEncoding::Stringify => Value::String(stringify_value(col, row)?).into(),
+                EdmEncoding::Null => Value::Null.into(),
+                EdmEncoding::WkbPoint if col.is_null(row) => Value::Null.into(),
+                EdmEncoding::WkbPoint => match crate::spatial::encode_geojson_point(col, row) {
+                    Some(point) => point.into(),
+                    None => {
+                        edm.invalid_geometry();
+                        Value::Null.into()
+                    }
+                },
+            };
+            if self.type_annotations && format == JsonFormat::V4 {
+                if let Some(annotation) = Self::type_annotation(&edm.typ) {
+                    entity.insert(
+                        format!("{}@odata.type", edm.name),
+                        Value::String(annotation),
+                    );
+                }
+            }
+            entity.insert(edm.name.clone(), value);
+        }
+
+        Ok(entity)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Properties of an entity in the order they are written
+#[derive(Default)]
+struct Entity(Vec<(String, PropertyValue)>);
+
+impl Entity {
+    fn insert(&mut self, name: String, value: impl Into<PropertyValue>) {
+        self.0.push((name, value.into()));
+    }
+}
+
+impl Serialize for Entity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(name, value)| (name, value)))
+    }
+}
+
+/// Value of a property, where the numbers that [`Value`] can only hold as `f64` are kept
+/// as the raw JSON tokens
+enum PropertyValue {
+    Json(Value),
+    Raw(Box<RawValue>),
+}
+
+impl From<Value> for PropertyValue {
+    fn from(value: Value) -> Self {
+        Self::Json(value)
+    }
+}
+
+impl Serialize for PropertyValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Json(value) => value.serialize(serializer),
+            Self::Raw(value) => value.serialize(serializer),
+        }
+    }
+}
+
+fn encode_property_value(
+    col: &Arc<dyn Array>,
+    row: usize,
+    format: JsonFormat,
+) -> Result<PropertyValue, UnsupportedDataType> {
+    match (col.data_type(), format) {
+        // All the digits of `Edm.Decimal`, as converting it to `f64` would round the value
+        (DataType::Decimal128(_, scale), JsonFormat::V4) if !col.is_null(row) => {
+            let val = col.as_primitive::<Decimal128Type>().value(row);
+            RawValue::from_string(format_decimal(val, *scale))
+                .map(PropertyValue::Raw)
+                .map_err(|_| UnsupportedDataType::new(col.data_type().clone()))
+        }
+        _ => encode_json_value(col, row, format).map(PropertyValue::Json),
+    }
+}
+
+fn encode_json_value(
+    col: &Arc<dyn Array>,
+    row: usize,
+    format: JsonFormat,
+) -> Result<Value, UnsupportedDataType> {
+    let col_type = col.data_type().clone();
+    if col.is_null(row) {
+        return Ok(Value::Null);
+    }
+
+    match col_type {
+        DataType::Null => Ok(Value::Null),
+        DataType::Boolean => Ok(Value::Bool(col.as_boolean().value(row))),
+        DataType::Int8 => Ok(col.as_primitive::<Int8Type>().value(row).into()),
+        DataType::Int16 => Ok(col.as_primitive::<Int16Type>().value(row).into()),
+        DataType::Int32 => Ok(col.as_primitive::<Int32Type>().value(row).into()),
+        DataType::UInt8 => Ok(col.as_primitive::<UInt8Type>().value(row).into()),
+        DataType::UInt16 => Ok(col.as_primitive::<UInt16Type>().value(row).into()),
+        DataType::UInt32 => Ok(col.as_primitive::<UInt32Type>().value(row).into()),
+        // Verbose JSON represents `Edm.Int64` as a string to avoid precision loss in clients
+        DataType::Int64 => {
+            let val = col.as_primitive::<Int64Type>().value(row);
+            Ok(match format {
+                JsonFormat::Verbose => Value::String(val.to_string()),
+                JsonFormat::V4 => val.into(),
+            })
+        }
+        DataType::UInt64 => {
+            let val = col.as_primitive::<UInt64Type>().value(row);
+            Ok(match format {
+                JsonFormat::Verbose => Value::String(val.to_string()),
+                JsonFormat::V4 => val.into(),
+            })
+        }
+        DataType::Float16 => Ok(encode_float(
+            col.as_primitive::<Float16Type>().value(row).to_f64(),
+        )),
+        DataType::Float32 => Ok(encode_float(
+            col.as_primitive::<Float32Type>().value(row).into(),
+        )),
+        DataType::Float64 => Ok(encode_float(col.as_primitive::<Float64Type>().value(row))),
+        // Both flavors represent `Edm.DateTimeOffset` in ISO 8601 format
+        DataType::Timestamp(_, Some(ref tz)) => timestamp_millis(col, row)
+            .and_then(|ticks| format_date_time_offset(ticks, tz))
+            .map(Value::String)
+            .ok_or_else(|| UnsupportedDataType::new(col_type.clone())),
+        DataType::Timestamp(_, None) => timestamp_millis(col, row)
+            .and_then(|ticks| encode_date_time_millis(ticks, format))
+            .ok_or(UnsupportedDataType::new(col_type)),
+        DataType::Date64 => {
+            let ticks = col.as_primitive::<Date64Type>().value(row);
+            encode_date_time_millis(ticks, format).ok_or(UnsupportedDataType::new(col_type))
+        }
+        // Verbose JSON represents `Edm.Decimal` as a string to avoid precision loss in clients,
+        // see [`encode_property_value`] for v4
+        DataType::Decimal128(_, scale) => {
+            let val = col.as_primitive::<Decimal128Type>().value(row);
+            let val = format_decimal(val, scale);
+            Ok(match format {
+                JsonFormat::Verbose => Value::String(val),
+                JsonFormat::V4 => encode_float(
+                    val.parse()
+                        .map_err(|_| UnsupportedDataType::new(col_type))?,
+                ),
+            })
+        }
+        DataType::Utf8 => Ok(Value::String(col.as_string::<i32>().value(row).to_string())),
+        DataType::LargeUtf8 => Ok(Value::String(col.as_string::<i64>().value(row).to_string())),
+        DataType::Date32
+        | DataType::Time32(_)
+        | DataType::Time64(_)
+        | DataType::Duration(_)
+        | DataType::Interval(_)
+        | DataType::Binary
+        | DataType::FixedSizeBinary(_)
+        | DataType::LargeBinary
+        | DataType::BinaryView
+        | DataType::Utf8View
+        | DataType::List(_)
+        | DataType::FixedSizeList(_, _)
+        | DataType::LargeList(_)
+        | DataType::ListView(_)
+        | DataType::LargeListView(_)
+        | DataType::Struct(_)
+        | DataType::Union(_, _)
+        | DataType::Dictionary(_, _)
+        | DataType::Decimal256(_, _)
+        | DataType::Map(_, _)
+        | DataType::RunEndEncoded(_, _) => Err(UnsupportedDataType::new(col_type)),
+    }
+}
+
+/// Renders nested values (structs, maps and lists) as JSON for [`crate::context::OnUnsupported::Stringify`] mode
+pub(crate) fn encode_nested_json_value(
+    col: &Arc<dyn Array>,
+    row: usize,
+) -> Result<Value, UnsupportedDataType> {
+    if col.is_null(row) {
+        return Ok(Value::Null);
+    }
+
+    match col.data_type() {
+        DataType::Struct(fields) => {
+            let col = col.as_struct();
+            let mut obj = Map::new();
+            for (field, child) in fields.iter().zip(col.columns()) {
+                obj.insert(field.name().clone(), encode_nested_json_value(child, row)?);
+            }
+            Ok(Value::Object(obj))
+        }
+        DataType::Map(_, _) => {
+            let entries = col.as_map().value(row);
+            let (keys, values) = (entries.column(0), entries.column(1));
+            let mut obj = Map::new();
+            for i in 0..entries.len() {
+                let key = match encode_nested_json_value(keys, i)? {
+                    Value::String(s) => s,
+                    key => key.to_string(),
+                };
+                obj.insert(key, encode_nested_json_value(values, i)?);
+            }
+            Ok(Value::Object(obj))
+        }
+        DataType::List(_) => encode_json_list(&col.as_list::<i32>().value(row)),
+        DataType::LargeList(_) => encode_json_list(&col.as_list::<i64>().value(row)),
+        DataType::FixedSizeList(_, _) => encode_json_list(&col.as_fixed_size_list().value(row)),
+        _ => match encode_json_value(col, row, JsonFormat::V4) {
+            Ok(value) => Ok(value),
+            Err(_) => Ok(Value::String(stringify_value(col, row)?)),
+        },
+    }
+}
+
+fn encode_json_list(values: &Arc<dyn Array>) -> Result<Value, UnsupportedDataType> {
+    (0..values.len())
+        .map(|i| encode_nested_json_value(values, i))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Value::Array)
+}
+
+// JSON has no representation for NaN and infinities, so those are emitted as strings
+// the same way OData literals spell them
+fn encode_float(val: f64) -> Value {
+    match serde_json::Number::from_f64(val) {
+        Some(n) => Value::Number(n),
+        None if val.is_nan() => Value::String("NaN".to_string()),
+        None if val.is_sign_positive() => Value::String("INF".to_string()),
+        None => Value::String("-INF".to_string()),
+    }
+}
+
+/// Renders all the digits of a decimal, as converting it to `f64` would round the value
+fn format_decimal(val: i128, scale: i8) -> String {
+    let sign = if val < 0 { "-" } else { "" };
+    let digits = val.unsigned_abs().to_string();
+    if scale <= 0 {
+        return format!(
+            "{sign}{digits}{}",
+            "0".repeat(scale.unsigned_abs() as usize)
+        );
+    }
+    let scale = scale as usize;
+    let digits = format!("{digits:0>width$}", width = scale + 1);
+    let (int, frac) = digits.split_at(digits.len() - scale);
+    format!("{sign}{int}.{frac}")
+}
+
+fn encode_date_time_millis(ticks: i64, format: JsonFormat) -> Option<Value> {
+    match format {
+        JsonFormat::Verbose => Some(Value::String(format!("/Date({ticks})/"))),
+        JsonFormat::V4 => {
+            let dt = chrono::DateTime::from_timestamp_millis(ticks)?;
+            Some(Value::String(
+                dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            ))
+        }
+    }
+}
@@ -1,8 +1,14 @@
+pub mod apply;
 pub mod atom;
 pub mod collection;
 pub mod context;
+pub mod csv;
 pub mod error;
 pub mod filter;
 pub mod handlers;
+pub mod json;
+pub mod media_type;
 pub mod metadata;
 pub mod service;
+pub mod session;
+pub mod spatial;
@@ -0,0 +1,12 @@
+//! `Content-Type` values of the responses, see [`crate::context::ServiceContext::content_type`]
+
+///////////////////////////////////////////////////////////////////////////////
+
+pub const MEDIA_TYPE_ATOM: &str = "application/atom+xml;type=feed;charset=utf-8";
+pub const MEDIA_TYPE_XML: &str = "application/xml;charset=utf-8";
+pub const MEDIA_TYPE_JSON: &str = "application/json;odata.metadata=minimal;charset=utf-8";
+pub const MEDIA_TYPE_JSON_PLAIN: &str = "application/json;charset=utf-8";
+pub const MEDIA_TYPE_JSON_VERBOSE: &str = "application/json;odata=verbose;charset=utf-8";
+pub const MEDIA_TYPE_CSV: &str = "text/csv;charset=utf-8";
+pub const MEDIA_TYPE_TEXT: &str = "text/plain;charset=utf-8";
+pub const MEDIA_TYPE_OCTET_STREAM: &str = "application/octet-stream";
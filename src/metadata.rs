@@ -29,6 +29,13 @@ impl Edmx {
             version: "1.0".to_string(),
         }
     }
+
+    pub fn with_version(self, version: impl Into<String>) -> Self {
+        Self {
+            version: version.into(),
+            ..self
+        }
+    }
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -47,11 +54,19 @@ impl DataServices {
     pub fn new(schemas: Vec<Schema>) -> Self {
         Self {
             schemas,
-            ns_m: "http://schemas.microsoft.com/ado/2007/08/dataservices/metadata".to_string(),
+            ns_m: crate::atom::NS_METADATA.to_string(),
             version: "3.0".to_string(),
             max_version: "3.0".to_string(),
         }
     }
+
+    pub fn with_versions(self, version: impl Into<String>, max_version: impl Into<String>) -> Self {
+        Self {
+            version: version.into(),
+            max_version: max_version.into(),
+            ..self
+        }
+    }
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -60,6 +75,8 @@ pub struct Schema {
     pub namespace: String,
     #[serde(rename = "EntityType")]
     pub entity_types: Vec<EntityType>,
+    #[serde(rename = "Association")]
+    pub associations: Vec<Association>,
     #[serde(rename = "EntityContainer")]
     pub entity_containers: Vec<EntityContainer>,
     #[serde(rename = "@xmlns")]
@@ -75,20 +92,34 @@ impl Schema {
         Self {
             namespace,
             entity_types,
+            associations: Vec::new(),
             entity_containers,
             ns: "http://schemas.microsoft.com/ado/2009/11/edm".to_string(),
         }
     }
+
+    pub fn with_associations(self, associations: Vec<Association>) -> Self {
+        Self {
+            associations,
+            ..self
+        }
+    }
 }
 
 #[derive(Debug, serde::Serialize)]
 pub struct EntityType {
     #[serde(rename = "@Name")]
     pub name: String,
+    /// Open types have properties beyond the declared ones
+    #[serde(rename = "@OpenType")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_type: Option<bool>,
     #[serde(rename = "Key")]
     pub key: EntityKey,
     #[serde(rename = "Property")]
     pub properties: Vec<Property>,
+    #[serde(rename = "NavigationProperty")]
+    pub navigation_properties: Vec<NavigationProperty>,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -124,6 +155,9 @@ pub struct Property {
     #[serde(rename = "@Unicode")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub unicode: Option<bool>,
+    #[serde(rename = "Documentation")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub documentation: Option<Documentation>,
 }
 
 impl Property {
@@ -134,6 +168,7 @@ impl Property {
             nullable,
             fixed_length: None,
             unicode: None,
+            documentation: None,
         }
     }
 
@@ -144,10 +179,116 @@ impl Property {
             nullable,
             fixed_length: Some(false),
             unicode: Some(true),
+            documentation: None,
+        }
+    }
+
+    pub fn with_documentation(self, summary: impl Into<String>) -> Self {
+        Self {
+            documentation: Some(Documentation {
+                summary: summary.into(),
+            }),
+            ..self
+        }
+    }
+}
+
+// <NavigationProperty Name="Category" Relationship="ODataDemo.Product_Category" FromRole="Product" ToRole="Category"/>
+
+#[derive(Debug, serde::Serialize)]
+pub struct NavigationProperty {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    /// Qualified name of the [`Association`]
+    #[serde(rename = "@Relationship")]
+    pub relationship: String,
+    #[serde(rename = "@FromRole")]
+    pub from_role: String,
+    #[serde(rename = "@ToRole")]
+    pub to_role: String,
+}
+
+// <Association Name="Product_Category">
+//   <End Type="ODataDemo.Product" Role="Product" Multiplicity="*"/>
+//   <End Type="ODataDemo.Category" Role="Category" Multiplicity="0..1"/>
+// </Association>
+
+#[derive(Debug, serde::Serialize)]
+pub struct Association {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    #[serde(rename = "End")]
+    pub ends: Vec<End>,
+}
+
+// <AssociationSet Name="Product_Category" Association="ODataDemo.Product_Category">
+//   <End Role="Product" EntitySet="Products"/>
+//   <End Role="Category" EntitySet="Categories"/>
+// </AssociationSet>
+
+#[derive(Debug, serde::Serialize)]
+pub struct AssociationSet {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    /// Qualified name of the [`Association`]
+    #[serde(rename = "@Association")]
+    pub association: String,
+    #[serde(rename = "End")]
+    pub ends: Vec<End>,
+}
+
+/// End of an [`Association`], which declares the type and multiplicity of the role,
+/// or of an [`AssociationSet`], which binds the role to an entity set
+#[derive(Debug, serde::Serialize)]
+pub struct End {
+    #[serde(rename = "@Type")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+    #[serde(rename = "@Role")]
+    pub role: String,
+    #[serde(rename = "@Multiplicity")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multiplicity: Option<String>,
+    #[serde(rename = "@EntitySet")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_set: Option<String>,
+}
+
+impl End {
+    /// `multiplicity` is one of `1`, `0..1` or `*`
+    pub fn association(
+        typ: impl Into<String>,
+        role: impl Into<String>,
+        multiplicity: impl Into<String>,
+    ) -> Self {
+        Self {
+            typ: Some(typ.into()),
+            role: role.into(),
+            multiplicity: Some(multiplicity.into()),
+            entity_set: None,
+        }
+    }
+
+    pub fn association_set(role: impl Into<String>, entity_set: impl Into<String>) -> Self {
+        Self {
+            typ: None,
+            role: role.into(),
+            multiplicity: None,
+            entity_set: Some(entity_set.into()),
         }
     }
 }
 
+// <Documentation>
+//   <Summary>Closing price in USD</Summary>
+// </Documentation>
+
+#[derive(Debug, serde::Serialize)]
+pub struct Documentation {
+    #[serde(rename = "Summary")]
+    pub summary: String,
+}
+
 // <EntityContainer Name="DemoService" m:IsDefaultEntityContainer="true">
 //   <EntitySet Name="Products" EntityType="ODataDemo.Product"/>
 
@@ -159,6 +300,12 @@ pub struct EntityContainer {
     pub is_default: bool,
     #[serde(rename = "EntitySet")]
     pub entity_set: Vec<EntitySet>,
+    #[serde(rename = "Singleton")]
+    pub singletons: Vec<Singleton>,
+    #[serde(rename = "AssociationSet")]
+    pub association_sets: Vec<AssociationSet>,
+    #[serde(rename = "FunctionImport")]
+    pub function_imports: Vec<FunctionImport>,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -169,8 +316,320 @@ pub struct EntitySet {
     pub entity_type: String,
 }
 
+// <Singleton Name="Config" Type="ODataDemo.Config"/>
+
+#[derive(Debug, serde::Serialize)]
+pub struct Singleton {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    #[serde(rename = "@Type")]
+    pub typ: String,
+}
+
+// <FunctionImport Name="GetTopMovers" EntitySet="Products" ReturnType="Collection(ODataDemo.Product)" m:HttpMethod="GET">
+//   <Parameter Name="count" Type="Edm.Int32" Mode="In"/>
+// </FunctionImport>
+
+#[derive(Debug, serde::Serialize)]
+pub struct FunctionImport {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    #[serde(rename = "@EntitySet")]
+    pub entity_set: String,
+    #[serde(rename = "@ReturnType")]
+    pub return_type: String,
+    #[serde(rename = "@m:HttpMethod")]
+    pub http_method: String,
+    #[serde(rename = "Parameter")]
+    pub parameters: Vec<Parameter>,
+}
+
+impl FunctionImport {
+    /// Side-effect free function returning the entities of the entity set
+    pub fn new(
+        name: impl Into<String>,
+        entity_set: impl Into<String>,
+        entity_type: impl std::fmt::Display,
+        parameters: Vec<Parameter>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            entity_set: entity_set.into(),
+            return_type: format!("Collection({entity_type})"),
+            http_method: "GET".to_string(),
+            parameters,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Parameter {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    #[serde(rename = "@Type")]
+    pub typ: String,
+    /// Direction of the parameter, which CSDL 4.0 no longer has
+    #[serde(rename = "@Mode")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+}
+
+impl Parameter {
+    pub fn input(name: impl Into<String>, typ: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            typ: typ.into(),
+            mode: Some("In".to_string()),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// CSDL 4.0
 ///////////////////////////////////////////////////////////////////////////////
 
+// <edmx:Edmx xmlns:edmx="http://docs.oasis-open.org/odata/ns/edmx" Version="4.0">
+//   <edmx:DataServices>
+//     <Schema xmlns="http://docs.oasis-open.org/odata/ns/edm" Namespace="ODataDemo">
+//       <EntityType Name="Product">
+//         <Key>
+//           <PropertyRef Name="ID"/>
+//         </Key>
+//         <Property Name="Name" Type="Edm.String"/>
+//       </EntityType>
+//       <EntityContainer Name="DemoService">
+//         <EntitySet Name="Products" EntityType="ODataDemo.Product"/>
+
+/// See: https://docs.oasis-open.org/odata/odata-csdl-xml/v4.01/odata-csdl-xml-v4.01.html
+#[derive(Debug, serde::Serialize)]
+pub struct EdmxV4 {
+    #[serde(rename = "edmx:DataServices")]
+    pub ds: DataServicesV4,
+    #[serde(rename = "@xmlns:edmx")]
+    pub ns_edmx: String,
+    #[serde(rename = "@Version")]
+    pub version: String,
+}
+
+impl EdmxV4 {
+    pub fn new(schemas: Vec<SchemaV4>) -> Self {
+        Self {
+            ds: DataServicesV4 { schemas },
+            ns_edmx: "http://docs.oasis-open.org/odata/ns/edmx".to_string(),
+            version: "4.0".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct DataServicesV4 {
+    #[serde(rename = "Schema")]
+    pub schemas: Vec<SchemaV4>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SchemaV4 {
+    #[serde(rename = "@Namespace")]
+    pub namespace: String,
+    #[serde(rename = "EntityType")]
+    pub entity_types: Vec<EntityTypeV4>,
+    #[serde(rename = "Function")]
+    pub functions: Vec<FunctionV4>,
+    #[serde(rename = "EntityContainer")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_container: Option<EntityContainerV4>,
+    #[serde(rename = "@xmlns")]
+    pub ns: String,
+}
+
+impl SchemaV4 {
+    /// Converts the CSDL 3.0 schema, keeping only its default entity container
+    /// as 4.0 allows at most one per schema.
+    ///
+    /// Function imports are split into the functions declared in the schema and their
+    /// imports into the container.
+    ///
+    /// Associations are left out, as 4.0 declares the navigation properties along with the
+    /// types they lead to instead, which is not supported yet.
+    pub fn from_v3(schema: Schema) -> Self {
+        let namespace = schema.namespace;
+        let mut functions = Vec::new();
+        let entity_container = schema
+            .entity_containers
+            .into_iter()
+            .find(|c| c.is_default)
+            .map(|c| EntityContainerV4 {
+                name: c.name,
+                entity_set: c.entity_set,
+                singletons: c.singletons,
+                function_imports: c
+                    .function_imports
+                    .into_iter()
+                    .map(|f| {
+                        let import = FunctionImportV4 {
+                            name: f.name.clone(),
+                            function: format!("{namespace}.{}", f.name),
+                            entity_set: f.entity_set,
+                        };
+                        functions.push(FunctionV4 {
+                            name: f.name,
+                            parameters: f
+                                .parameters
+                                .into_iter()
+                                .map(|p| Parameter { mode: None, ..p })
+                                .collect(),
+                            return_type: ReturnTypeV4 { typ: f.return_type },
+                        });
+                        import
+                    })
+                    .collect(),
+            });
+
+        Self {
+            namespace,
+            entity_types: schema
+                .entity_types
+                .into_iter()
+                .map(EntityTypeV4::from_v3)
+                .collect(),
+            functions,
+            entity_container,
+            ns: "http://docs.oasis-open.org/odata/ns/edm".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct EntityTypeV4 {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    #[serde(rename = "@OpenType")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_type: Option<bool>,
+    #[serde(rename = "Key")]
+    pub key: EntityKey,
+    #[serde(rename = "Property")]
+    pub properties: Vec<PropertyV4>,
+}
+
+impl EntityTypeV4 {
+    pub fn from_v3(typ: EntityType) -> Self {
+        Self {
+            name: typ.name,
+            open_type: typ.open_type,
+            key: typ.key,
+            properties: typ
+                .properties
+                .into_iter()
+                .map(PropertyV4::from_v3)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PropertyV4 {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    #[serde(rename = "@Type")]
+    pub typ: String,
+    /// Properties are nullable unless stated otherwise
+    #[serde(rename = "@Nullable")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nullable: Option<bool>,
+    #[serde(rename = "@Unicode")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unicode: Option<bool>,
+    #[serde(rename = "Annotation")]
+    pub annotations: Vec<Annotation>,
+}
+
+impl PropertyV4 {
+    pub fn from_v3(property: Property) -> Self {
+        // `Edm.DateTime` was removed in 4.0
+        let typ = match property.typ.as_str() {
+            "Edm.DateTime" => "Edm.DateTimeOffset".to_string(),
+            _ => property.typ,
+        };
+
+        Self {
+            name: property.name,
+            typ,
+            nullable: (!property.nullable).then_some(false),
+            unicode: property.unicode,
+            annotations: property
+                .documentation
+                .into_iter()
+                .map(|d| Annotation {
+                    term: "Org.OData.Core.V1.Description".to_string(),
+                    string: d.summary,
+                })
+                .collect(),
+        }
+    }
+}
+
+// <Annotation Term="Org.OData.Core.V1.Description" String="Closing price in USD"/>
+
+#[derive(Debug, serde::Serialize)]
+pub struct Annotation {
+    #[serde(rename = "@Term")]
+    pub term: String,
+    #[serde(rename = "@String")]
+    pub string: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct EntityContainerV4 {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    #[serde(rename = "EntitySet")]
+    pub entity_set: Vec<EntitySet>,
+    #[serde(rename = "Singleton")]
+    pub singletons: Vec<Singleton>,
+    #[serde(rename = "FunctionImport")]
+    pub function_imports: Vec<FunctionImportV4>,
+}
+
+// <Function Name="GetTopMovers">
+//   <Parameter Name="count" Type="Edm.Int32"/>
+//   <ReturnType Type="Collection(ODataDemo.Product)"/>
+// </Function>
+
+#[derive(Debug, serde::Serialize)]
+pub struct FunctionV4 {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    #[serde(rename = "Parameter")]
+    pub parameters: Vec<Parameter>,
+    #[serde(rename = "ReturnType")]
+    pub return_type: ReturnTypeV4,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ReturnTypeV4 {
+    #[serde(rename = "@Type")]
+    pub typ: String,
+}
+
+// <FunctionImport Name="GetTopMovers" Function="ODataDemo.GetTopMovers" EntitySet="Products"/>
+
+#[derive(Debug, serde::Serialize)]
+pub struct FunctionImportV4 {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    #[serde(rename = "@Function")]
+    pub function: String,
+    #[serde(rename = "@EntitySet")]
+    pub entity_set: String,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Type of the columns kept under [`crate::context::OnUnsupported::Stringify`] and
+/// [`crate::context::OnUnsupported::Null`] modes
+pub const EDM_STRING_FALLBACK: &str = "Edm.String";
+
 // See: https://www.odata.org/documentation/odata-version-3-0/common-schema-definition-language-csdl/
 pub fn to_edm_type(dt: &DataType) -> std::result::Result<&'static str, UnsupportedDataType> {
     match dt {
@@ -184,15 +643,20 @@ pub fn to_edm_type(dt: &DataType) -> std::result::Result<&'static str, Unsupport
         DataType::UInt8 => Ok("Edm.Int16"),
         DataType::UInt16 => Ok("Edm.Int16"),
         DataType::UInt32 => Ok("Edm.Int32"),
+        // Values above `i64::MAX` are not valid `Edm.Int64`, but the type has to be decided
+        // from the schema alone - use `PropertyOverride::edm_type` to advertise `Edm.Decimal`
+        // for columns that may hold them
         DataType::UInt64 => Ok("Edm.Int64"),
         DataType::Utf8 => Ok("Edm.String"),
         DataType::LargeUtf8 => Ok("Edm.String"),
         DataType::Float16 => Ok("Edm.Single"),
         DataType::Float32 => Ok("Edm.Single"),
         DataType::Float64 => Ok("Edm.Double"),
-        DataType::Timestamp(_, _) => Ok("Edm.DateTime"),
+        DataType::Timestamp(_, Some(_)) => Ok("Edm.DateTimeOffset"),
+        DataType::Timestamp(_, None) => Ok("Edm.DateTime"),
         DataType::Date32 => Ok("Edm.DateTime"),
         DataType::Date64 => Ok("Edm.DateTime"),
+        DataType::Decimal128(_, _) => Ok("Edm.Decimal"),
         DataType::Null
         | DataType::Utf8View
         | DataType::Time32(_)
@@ -211,7 +675,6 @@ pub fn to_edm_type(dt: &DataType) -> std::result::Result<&'static str, Unsupport
         | DataType::Struct(_)
         | DataType::Union(_, _)
         | DataType::Dictionary(_, _)
-        | DataType::Decimal128(_, _)
         | DataType::Decimal256(_, _)
         | DataType::Map(_, _)
         | DataType::RunEndEncoded(_, _) => Err(UnsupportedDataType::new(dt.clone())),
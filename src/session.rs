@@ -0,0 +1,353 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use datafusion::{
+    arrow::{array::RecordBatch, datatypes::SchemaRef},
+    logical_expr::LogicalPlan,
+    prelude::*,
+    sql::TableReference,
+};
+
+use crate::{
+    collection::{AppliedQuery, ApplyOptions, CollectionAddr, QueryParams},
+    context::{join_url, CollectionContext, OnUnsupported, ServiceContext},
+    error::{CollectionAddressNotAssigned, CollectionNotFound, ODataError},
+};
+
+///////////////////////////////////////////////////////////////////////////////
+
+const DEFAULT_ROWS: usize = 100;
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Ready-made context that serves the tables registered in a [`SessionContext`].
+///
+/// By default every table of the default schema is exposed as a collection of the same name.
+/// Tables can be of any kind, e.g. parquet files or in-memory batches (see
+/// [`ODataSessionContext::from_batches`]).
+/// Use [`ODataSessionContext::with_collections`] to expose only specific tables and under
+/// different names, e.g. `tickers.spy` as `Tickers`.
+///
+/// The context is cheap to clone and safe to share between concurrent requests: clones
+/// share the underlying [`SessionContext`] and the plan cache (if enabled).
+#[derive(Clone)]
+pub struct ODataSessionContext {
+    query_ctx: SessionContext,
+    service_base_url: String,
+    addr: Option<CollectionAddr>,
+    /// Collection name -> table
+    collections: Option<Arc<HashMap<String, TableReference>>>,
+    on_unsupported: OnUnsupported,
+    default_rows: usize,
+    max_rows: usize,
+    plan_cache: Option<Arc<PlanCache>>,
+}
+
+impl ODataSessionContext {
+    pub fn new(query_ctx: SessionContext, service_base_url: impl Into<String>) -> Self {
+        Self {
+            query_ctx,
+            service_base_url: service_base_url.into(),
+            addr: None,
+            collections: None,
+            on_unsupported: OnUnsupported::Error,
+            default_rows: DEFAULT_ROWS,
+            max_rows: usize::MAX,
+            plan_cache: None,
+        }
+    }
+
+    /// Serves in-memory record batches, each registered as a table of the given name,
+    /// which is also the name of the collection
+    pub fn from_batches<N: AsRef<str>>(
+        service_base_url: impl Into<String>,
+        batches: impl IntoIterator<Item = (N, RecordBatch)>,
+    ) -> Result<Self, ODataError> {
+        let query_ctx = SessionContext::new();
+        for (name, batch) in batches {
+            query_ctx
+                .register_batch(name.as_ref(), batch)
+                .map_err(ODataError::internal)?;
+        }
+        Ok(Self::new(query_ctx, service_base_url))
+    }
+
+    /// Exposes only the specified tables, keyed by their collection names
+    pub fn with_collections(self, collections: HashMap<String, TableReference>) -> Self {
+        Self {
+            collections: Some(Arc::new(collections)),
+            ..self
+        }
+    }
+
+    /// Narrows the context to the collection addressed by the request
+    pub fn with_addr(self, addr: CollectionAddr) -> Self {
+        Self {
+            addr: Some(addr),
+            ..self
+        }
+    }
+
+    pub fn with_on_unsupported(self, on_unsupported: OnUnsupported) -> Self {
+        Self {
+            on_unsupported,
+            ..self
+        }
+    }
+
+    /// Number of rows returned when `$top` is not specified and the upper limit for it
+    pub fn with_row_limits(self, default_rows: usize, max_rows: usize) -> Self {
+        Self {
+            default_rows,
+            max_rows,
+            ..self
+        }
+    }
+
+    /// Reuses the logical plans of up to `capacity` distinct queries instead of planning
+    /// them again on every request.
+    ///
+    /// Plans capture the table providers they were built from, so the cache has to be
+    /// cleared with [`ODataSessionContext::clear_plan_cache`] when tables are re-registered.
+    pub fn with_plan_cache(self, capacity: usize) -> Self {
+        Self {
+            plan_cache: Some(Arc::new(PlanCache::new(capacity))),
+            ..self
+        }
+    }
+
+    pub fn clear_plan_cache(&self) {
+        if let Some(cache) = &self.plan_cache {
+            cache.plans.lock().unwrap().clear();
+        }
+    }
+
+    /// Number of queries served from the plan cache
+    pub fn plan_cache_hits(&self) -> usize {
+        self.plan_cache
+            .as_ref()
+            .map_or(0, |cache| cache.hits.load(Ordering::Relaxed))
+    }
+
+    fn collection_names(&self) -> Vec<String> {
+        let mut names = match &self.collections {
+            Some(collections) => collections.keys().cloned().collect(),
+            None => {
+                let options = self.query_ctx.state().config().options().clone();
+                self.query_ctx
+                    .catalog(&options.catalog.default_catalog)
+                    .and_then(|c| c.schema(&options.catalog.default_schema))
+                    .map(|s| s.table_names())
+                    .unwrap_or_default()
+            }
+        };
+        names.sort();
+        names
+    }
+
+    fn table_reference(&self) -> Result<TableReference, ODataError> {
+        let collection_name = self.collection_name()?;
+        match &self.collections {
+            Some(collections) => match collections.get(&collection_name) {
+                Some(table) => Ok(table.clone()),
+                None => Err(CollectionNotFound::new(collection_name))?,
+            },
+            None => Ok(TableReference::bare(collection_name)),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[async_trait::async_trait]
+impl ServiceContext for ODataSessionContext {
+    fn service_base_url(&self) -> String {
+        self.service_base_url.clone()
+    }
+
+    async fn list_collections(&self) -> Result<Vec<Arc<dyn CollectionContext>>, ODataError> {
+        let mut collections: Vec<Arc<dyn CollectionContext>> = Vec::new();
+        for name in self.collection_names() {
+            collections.push(Arc::new(
+                self.clone().with_addr(CollectionAddr { name, key: None }),
+            ));
+        }
+        Ok(collections)
+    }
+
+    fn on_unsupported_feature(&self) -> OnUnsupported {
+        self.on_unsupported
+    }
+
+    async fn collection_context(
+        &self,
+        addr: CollectionAddr,
+    ) -> Result<Arc<dyn CollectionContext>, ODataError> {
+        Ok(Arc::new(self.clone().with_addr(addr)))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[async_trait::async_trait]
+impl CollectionContext for ODataSessionContext {
+    fn addr(&self) -> Result<&CollectionAddr, ODataError> {
+        match &self.addr {
+            Some(addr) => Ok(addr),
+            None => Err(CollectionAddressNotAssigned)?,
+        }
+    }
+
+    fn service_base_url(&self) -> Result<String, ODataError> {
+        Ok(self.service_base_url.clone())
+    }
+
+    fn collection_base_url(&self) -> Result<String, ODataError> {
+        Ok(join_url(&self.service_base_url, &self.collection_name()?))
+    }
+
+    fn collection_name(&self) -> Result<String, ODataError> {
+        Ok(self.addr()?.name.clone())
+    }
+
+    async fn last_updated_time(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    async fn schema(&self) -> Result<SchemaRef, ODataError> {
+        Ok(self
+            .query_ctx
+            .table_provider(self.table_reference()?)
+            .await
+            .map_err(|e| {
+                ODataError::handle_no_table_as_collection_not_found(
+                    self.collection_name().unwrap(),
+                    e,
+                )
+            })?
+            .schema())
+    }
+
+    async fn query(
+        &self,
+        query: QueryParams,
+        options: &ApplyOptions,
+    ) -> Result<AppliedQuery, ODataError> {
+        let cache_key = match &self.plan_cache {
+            Some(cache) => {
+                let key = (self.addr()?.clone(), query.clone(), options.clone());
+                if let Some(cached) = cache.get(&key) {
+                    return Ok(AppliedQuery {
+                        df: DataFrame::new(self.query_ctx.state(), cached.df),
+                        count: cached
+                            .count
+                            .map(|plan| DataFrame::new(self.query_ctx.state(), plan)),
+                    });
+                }
+                Some(key)
+            }
+            None => None,
+        };
+
+        let df = self
+            .query_ctx
+            .table(self.table_reference()?)
+            .await
+            .map_err(|e| {
+                ODataError::handle_no_table_as_collection_not_found(
+                    self.collection_name().unwrap(),
+                    e,
+                )
+            })?;
+
+        let applied = query.apply(df, self.addr()?, options, self.default_rows, self.max_rows)?;
+
+        if let (Some(cache), Some(key)) = (&self.plan_cache, cache_key) {
+            cache.insert(
+                key,
+                applied.df.logical_plan().clone(),
+                applied.count.as_ref().map(|df| df.logical_plan().clone()),
+            );
+        }
+
+        Ok(applied)
+    }
+
+    fn on_unsupported_feature(&self) -> OnUnsupported {
+        self.on_unsupported
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Logical plans keyed by the collection address, the decoded query parameters, which are
+/// already normalized (e.g. `$select` is trimmed and `$filter` is parsed), and the options
+/// they are applied with, so equal ones stand for the same query.
+///
+/// Once full, the least recently used plans make room for the new ones. The lock is only held
+/// to look up or store a plan, never across planning itself, so concurrent misses on the same
+/// query may plan it more than once.
+struct PlanCache {
+    plans: Mutex<HashMap<PlanKey, CachedPlans>>,
+    capacity: usize,
+    /// Ticks on every use of a plan, see [`CachedPlans::last_used`]
+    clock: AtomicU64,
+    hits: AtomicUsize,
+}
+
+type PlanKey = (CollectionAddr, QueryParams, ApplyOptions);
+
+/// Plans of the entities and of their count, see [`AppliedQuery`]
+#[derive(Clone)]
+struct CachedPlans {
+    df: LogicalPlan,
+    count: Option<LogicalPlan>,
+    last_used: u64,
+}
+
+impl PlanCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            plans: Mutex::new(HashMap::new()),
+            capacity,
+            clock: AtomicU64::new(0),
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    fn get(&self, key: &PlanKey) -> Option<CachedPlans> {
+        let mut plans = self.plans.lock().unwrap();
+        let cached = plans.get_mut(key)?;
+        cached.last_used = self.clock.fetch_add(1, Ordering::Relaxed);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(cached.clone())
+    }
+
+    fn insert(&self, key: PlanKey, df: LogicalPlan, count: Option<LogicalPlan>) {
+        let mut plans = self.plans.lock().unwrap();
+        if plans.len() >= self.capacity && !plans.contains_key(&key) {
+            let lru = plans
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(lru) = lru {
+                plans.remove(&lru);
+            }
+        }
+        let last_used = self.clock.fetch_add(1, Ordering::Relaxed);
+        plans.insert(
+            key,
+            CachedPlans {
+                df,
+                count,
+                last_used,
+            },
+        );
+    }
+}
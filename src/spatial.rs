@@ -0,0 +1,127 @@
+use std::sync::Arc;
+
+use datafusion::arrow::{
+    array::{Array, AsArray},
+    datatypes::DataType,
+};
+
+///////////////////////////////////////////////////////////////////////////////
+
+// Geospatial columns are not recognized by their arrow type, as they are just binary WKB
+// (see https://libgeos.org/specifications/wkb/). Instead, they are declared via
+// `PropertyOverride::edm_type`, e.g. `Edm.GeographyPoint`.
+
+pub const EDM_GEOGRAPHY_POINT: &str = "Edm.GeographyPoint";
+pub const EDM_GEOMETRY_POINT: &str = "Edm.GeometryPoint";
+
+const NS_GML: &str = "http://www.opengis.net/gml";
+const SRS_WGS84: &str = "http://www.opengis.net/def/crs/EPSG/0/4326";
+
+const WKB_POINT: u32 = 1;
+/// EWKB flag of the geometries prefixed with the SRID
+const EWKB_SRID_FLAG: u32 = 0x2000_0000;
+
+/// Whether the column holds WKB points declared as one of the spatial point EDM types
+pub fn is_wkb_point(data_type: &DataType, edm_type: Option<&str>) -> bool {
+    matches!(data_type, DataType::Binary | DataType::LargeBinary)
+        && matches!(edm_type, Some(EDM_GEOGRAPHY_POINT | EDM_GEOMETRY_POINT))
+}
+
+/// Decodes the `(x, y)` coordinates of a WKB (or EWKB) point, i.e. longitude and latitude
+/// for geographic coordinates
+pub fn decode_wkb_point(wkb: &[u8]) -> Option<(f64, f64)> {
+    let (&byte_order, rest) = wkb.split_first()?;
+    let little_endian = match byte_order {
+        0 => false,
+        1 => true,
+        _ => return None,
+    };
+    let u32_at = |at: usize| -> Option<u32> {
+        let bytes = rest.get(at..at + 4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+    let f64_at = |at: usize| -> Option<f64> {
+        let bytes = rest.get(at..at + 8)?.try_into().ok()?;
+        Some(if little_endian {
+            f64::from_le_bytes(bytes)
+        } else {
+            f64::from_be_bytes(bytes)
+        })
+    };
+
+    let typ = u32_at(0)?;
+    let coords_at = if typ & EWKB_SRID_FLAG != 0 { 8 } else { 4 };
+    if typ & !EWKB_SRID_FLAG != WKB_POINT {
+        return None;
+    }
+    Some((f64_at(coords_at)?, f64_at(coords_at + 8)?))
+}
+
+fn wkb_point_at(col: &Arc<dyn Array>, row: usize) -> Option<(f64, f64)> {
+    let wkb = match col.data_type() {
+        DataType::Binary => col.as_binary::<i32>().value(row),
+        DataType::LargeBinary => col.as_binary::<i64>().value(row),
+        _ => return None,
+    };
+    decode_wkb_point(wkb)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// GML representation of the point used by the Atom format, or `None` if the value is not
+/// a valid WKB point.
+///
+/// Geographic positions are in the latitude-longitude order of EPSG:4326.
+pub(crate) fn encode_gml_point(col: &Arc<dyn Array>, row: usize, edm_type: &str) -> Option<String> {
+    let (x, y) = wkb_point_at(col, row)?;
+    let (pos, srs) = if edm_type == EDM_GEOGRAPHY_POINT {
+        (format!("{y} {x}"), format!(" gml:srsName=\"{SRS_WGS84}\""))
+    } else {
+        (format!("{x} {y}"), String::new())
+    };
+    Some(format!(
+        "<gml:Point xmlns:gml=\"{NS_GML}\"{srs}><gml:pos>{pos}</gml:pos></gml:Point>"
+    ))
+}
+
+/// GeoJSON representation of the point used by the JSON formats, or `None` if the value is not
+/// a valid WKB point
+pub(crate) fn encode_geojson_point(col: &Arc<dyn Array>, row: usize) -> Option<serde_json::Value> {
+    let (x, y) = wkb_point_at(col, row)?;
+    Some(serde_json::json!({
+        "type": "Point",
+        "coordinates": [x, y],
+    }))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_wkb_point() {
+        // POINT(-122.35 47.62), little endian
+        let mut wkb = vec![1, 1, 0, 0, 0];
+        wkb.extend((-122.35f64).to_le_bytes());
+        wkb.extend(47.62f64.to_le_bytes());
+        assert_eq!(decode_wkb_point(&wkb), Some((-122.35, 47.62)));
+
+        // Same point, big endian EWKB with SRID 4326
+        let mut ewkb = vec![0];
+        ewkb.extend((WKB_POINT | EWKB_SRID_FLAG).to_be_bytes());
+        ewkb.extend(4326u32.to_be_bytes());
+        ewkb.extend((-122.35f64).to_be_bytes());
+        ewkb.extend(47.62f64.to_be_bytes());
+        assert_eq!(decode_wkb_point(&ewkb), Some((-122.35, 47.62)));
+
+        // LINESTRING is not a point
+        assert_eq!(decode_wkb_point(&[1, 2, 0, 0, 0]), None);
+        assert_eq!(decode_wkb_point(&wkb[..12]), None);
+    }
+}
@@ -1,14 +1,30 @@
-use std::sync::Arc;
+#![allow(dead_code, unused_macros)]
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use chrono::{DateTime, Utc};
-use datafusion::{arrow::datatypes::SchemaRef, prelude::*, sql::TableReference};
+use datafusion::{
+    arrow::{datatypes::SchemaRef, record_batch::RecordBatch},
+    prelude::*,
+    sql::TableReference,
+};
 use datafusion_odata::{
-    collection::{CollectionAddr, QueryParams},
+    collection::{AppliedQuery, ApplyOptions, CollectionAddr, KeyValue, QueryOption, QueryParams},
     context::*,
-    error::ODataError,
+    error::{KeyColumnNotAssigned, ODataError},
 };
 
 pub async fn fixture(collection_elem: &str) -> Arc<ODataContext> {
+    Arc::new(context(collection_elem).await)
+}
+
+pub async fn context(collection_elem: &str) -> ODataContext {
     let ctx = SessionContext::new();
     ctx.register_parquet(
         "covid19.canada",
@@ -32,19 +48,75 @@ pub async fn fixture(collection_elem: &str) -> Arc<ODataContext> {
     .await
     .unwrap();
 
-    Arc::new(ODataContext::new(
+    ODataContext::new(
+        ctx,
+        "http://example.com/odata".to_string(),
+        Some(CollectionAddr::decode(collection_elem).unwrap()),
+    )
+}
+
+pub async fn fixture_with_batch(
+    collection_elem: &str,
+    table_name: &str,
+    batch: RecordBatch,
+) -> Arc<ODataContext> {
+    Arc::new(batch_context(collection_elem, table_name, batch))
+}
+
+pub fn batch_context(collection_elem: &str, table_name: &str, batch: RecordBatch) -> ODataContext {
+    let ctx = SessionContext::new();
+    ctx.register_batch(table_name, batch).unwrap();
+
+    ODataContext::new(
         ctx,
         "http://example.com/odata".to_string(),
         Some(CollectionAddr::decode(collection_elem).unwrap()),
+    )
+}
+
+pub fn session_context(collection_elem: &str, query_ctx: SessionContext) -> ODataContext {
+    ODataContext::new(
+        query_ctx,
+        "http://example.com/odata".to_string(),
+        Some(CollectionAddr::decode(collection_elem).unwrap()),
+    )
+}
+
+/// Service without any registered collections
+pub fn empty_fixture() -> Arc<ODataContext> {
+    Arc::new(ODataContext::new(
+        SessionContext::new(),
+        "http://example.com/odata".to_string(),
+        None,
     ))
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
+#[derive(Clone)]
 pub struct ODataContext {
     query_ctx: SessionContext,
     service_base_url: String,
     addr: Option<CollectionAddr>,
+    on_unsupported: OnUnsupported,
+    on_invalid_xml_chars: OnInvalidXmlChars,
+    on_null_key: OnNullKey,
+    singleton: bool,
+    property_overrides: HashMap<String, PropertyOverride>,
+    batch_size: Option<usize>,
+    query_delay: Option<std::time::Duration>,
+    updated_column: Option<String>,
+    allowed_query_options: Option<HashSet<QueryOption>>,
+    odata_version: ODataVersion,
+    unaliased_key_column: Option<String>,
+    key_columns: Vec<String>,
+    entity_type_name: Option<String>,
+    property_name_mapper: Option<PropertyNameMapper>,
+    derived_columns: Vec<(String, String)>,
+    visible_columns: Option<HashSet<String>>,
+    functions: Vec<(FunctionDeclaration, String)>,
+    navigation_properties: Vec<NavigationPropertyDeclaration>,
+    validated_batches: Arc<AtomicUsize>,
 }
 
 impl ODataContext {
@@ -57,6 +129,179 @@ impl ODataContext {
             query_ctx,
             service_base_url,
             addr,
+            on_unsupported: OnUnsupported::Error,
+            on_invalid_xml_chars: OnInvalidXmlChars::Strip,
+            on_null_key: OnNullKey::Null,
+            singleton: false,
+            property_overrides: HashMap::new(),
+            batch_size: None,
+            query_delay: None,
+            updated_column: None,
+            allowed_query_options: None,
+            odata_version: ODataVersion::V3,
+            unaliased_key_column: None,
+            key_columns: Vec::new(),
+            entity_type_name: None,
+            property_name_mapper: None,
+            derived_columns: Vec::new(),
+            visible_columns: None,
+            functions: Vec::new(),
+            navigation_properties: Vec::new(),
+            validated_batches: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn with_service_base_url(self, service_base_url: &str) -> Self {
+        Self {
+            service_base_url: service_base_url.to_string(),
+            ..self
+        }
+    }
+
+    /// Simulates a slow query
+    pub fn with_query_delay(self, delay: std::time::Duration) -> Self {
+        Self {
+            query_delay: Some(delay),
+            ..self
+        }
+    }
+
+    pub fn with_updated_column(self, column: &str) -> Self {
+        Self {
+            updated_column: Some(column.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_allowed_query_options(self, options: &[QueryOption]) -> Self {
+        Self {
+            allowed_query_options: Some(options.iter().copied().collect()),
+            ..self
+        }
+    }
+
+    pub fn with_odata_version(self, odata_version: ODataVersion) -> Self {
+        Self {
+            odata_version,
+            ..self
+        }
+    }
+
+    /// Uses the key column directly instead of the synthetic alias
+    pub fn with_key_columns(self, key_columns: &[&str]) -> Self {
+        Self {
+            key_columns: key_columns.iter().map(|c| c.to_string()).collect(),
+            ..self
+        }
+    }
+
+    pub fn with_unaliased_key_column(self, key_column: &str) -> Self {
+        Self {
+            unaliased_key_column: Some(key_column.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_entity_type_name(self, entity_type_name: &str) -> Self {
+        Self {
+            entity_type_name: Some(entity_type_name.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_property_name_mapper(self, mapper: PropertyNameMapper) -> Self {
+        Self {
+            property_name_mapper: Some(mapper),
+            ..self
+        }
+    }
+
+    pub fn with_derived_column(mut self, name: &str, sql: &str) -> Self {
+        self.derived_columns
+            .push((name.to_string(), sql.to_string()));
+        self
+    }
+
+    pub fn with_visible_columns(self, columns: &[&str]) -> Self {
+        Self {
+            visible_columns: Some(columns.iter().map(|c| c.to_string()).collect()),
+            ..self
+        }
+    }
+
+    /// Function returning the result of the SQL query, which refers to the parameters
+    /// in their declaration order as `$1`, `$2`, ...
+    pub fn with_function(
+        mut self,
+        name: &str,
+        parameters: &[(&str, &str)],
+        entity_set: &str,
+        sql: &str,
+    ) -> Self {
+        let declaration = FunctionDeclaration {
+            name: name.to_string(),
+            parameters: parameters
+                .iter()
+                .map(|(name, typ)| (name.to_string(), typ.to_string()))
+                .collect(),
+            entity_set: entity_set.to_string(),
+        };
+        self.functions.push((declaration, sql.to_string()));
+        self
+    }
+
+    pub fn with_navigation_property(mut self, name: &str, entity_set: &str, many: bool) -> Self {
+        self.navigation_properties
+            .push(NavigationPropertyDeclaration {
+                name: name.to_string(),
+                entity_set: entity_set.to_string(),
+                many,
+            });
+        self
+    }
+
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        Self {
+            batch_size: Some(batch_size),
+            ..self
+        }
+    }
+
+    /// Number of record batches passed to the last validation
+    pub fn validated_batches(&self) -> usize {
+        self.validated_batches.load(Ordering::SeqCst)
+    }
+
+    pub fn with_property_override(mut self, column: &str, property: PropertyOverride) -> Self {
+        self.property_overrides.insert(column.to_string(), property);
+        self
+    }
+
+    pub fn with_singleton(self) -> Self {
+        Self {
+            singleton: true,
+            ..self
+        }
+    }
+
+    pub fn with_on_invalid_xml_chars(self, on_invalid_xml_chars: OnInvalidXmlChars) -> Self {
+        Self {
+            on_invalid_xml_chars,
+            ..self
+        }
+    }
+
+    pub fn with_on_null_key(self, on_null_key: OnNullKey) -> Self {
+        Self {
+            on_null_key,
+            ..self
+        }
+    }
+
+    pub fn with_on_unsupported(self, on_unsupported: OnUnsupported) -> Self {
+        Self {
+            on_unsupported,
+            ..self
         }
     }
 }
@@ -80,12 +325,11 @@ impl ServiceContext for ODataContext {
         let mut collections: Vec<Arc<dyn CollectionContext>> = Vec::new();
         for table_name in table_names {
             collections.push(Arc::new(ODataContext {
-                query_ctx: self.query_ctx.clone(),
-                service_base_url: self.service_base_url.clone(),
                 addr: Some(CollectionAddr {
                     name: table_name,
                     key: None,
                 }),
+                ..self.clone()
             }));
         }
 
@@ -93,7 +337,42 @@ impl ServiceContext for ODataContext {
     }
 
     fn on_unsupported_feature(&self) -> OnUnsupported {
-        OnUnsupported::Error
+        self.on_unsupported
+    }
+
+    async fn collection_context(
+        &self,
+        addr: CollectionAddr,
+    ) -> Result<Arc<dyn CollectionContext>, ODataError> {
+        Ok(Arc::new(ODataContext {
+            addr: Some(addr),
+            ..self.clone()
+        }))
+    }
+
+    fn functions(&self) -> Vec<FunctionDeclaration> {
+        self.functions.iter().map(|(f, _)| f.clone()).collect()
+    }
+
+    async fn call_function(
+        &self,
+        name: &str,
+        args: Vec<(String, KeyValue)>,
+    ) -> Result<DataFrame, ODataError> {
+        let (_, sql) = self.functions.iter().find(|(f, _)| f.name == name).unwrap();
+        let params: Vec<_> = args
+            .into_iter()
+            .map(|(_, value)| value.to_scalar().unwrap())
+            .collect();
+        self.query_ctx
+            .sql(sql)
+            .await
+            .and_then(|df| df.with_param_values(params))
+            .map_err(ODataError::from_query_error)
+    }
+
+    fn odata_version(&self) -> ODataVersion {
+        self.odata_version
     }
 }
 
@@ -108,21 +387,87 @@ impl CollectionContext for ODataContext {
     }
 
     fn collection_base_url(&self) -> Result<String, ODataError> {
-        let service_base_url = &self.service_base_url;
-        let collection_name = self.collection_name()?;
-        Ok(format!("{service_base_url}{collection_name}"))
+        Ok(join_url(&self.service_base_url, &self.collection_name()?))
+    }
+
+    fn service(&self) -> Option<&dyn ServiceContext> {
+        Some(self)
     }
 
     fn collection_name(&self) -> Result<String, ODataError> {
         Ok(self.addr()?.name.clone())
     }
 
+    fn entity_type_name(&self) -> Result<String, ODataError> {
+        match &self.entity_type_name {
+            Some(entity_type_name) => Ok(entity_type_name.clone()),
+            None => Ok(to_simple_identifier(&self.collection_name()?)),
+        }
+    }
+
+    fn key_column(&self) -> Result<String, ODataError> {
+        match &self.unaliased_key_column {
+            Some(key_column) => Ok(key_column.clone()),
+            None => Err(KeyColumnNotAssigned)?,
+        }
+    }
+
+    fn key_columns(&self) -> Result<Vec<String>, ODataError> {
+        if self.key_columns.is_empty() {
+            return Ok(vec![self.key_column()?]);
+        }
+        Ok(self.key_columns.clone())
+    }
+
+    fn synthetic_key_column(&self) -> bool {
+        self.unaliased_key_column.is_none()
+    }
+
+    fn navigation_properties(&self) -> Vec<NavigationPropertyDeclaration> {
+        self.navigation_properties.clone()
+    }
+
+    fn allowed_query_options(&self) -> HashSet<QueryOption> {
+        match &self.allowed_query_options {
+            Some(options) => options.clone(),
+            None => QueryOption::ALL.into_iter().collect(),
+        }
+    }
+
+    fn property_name_mapper(&self) -> Option<PropertyNameMapper> {
+        self.property_name_mapper.clone()
+    }
+
+    fn derived_columns(&self) -> Vec<(String, String)> {
+        self.derived_columns.clone()
+    }
+
+    fn visible_columns(&self) -> Option<HashSet<String>> {
+        self.visible_columns.clone()
+    }
+
+    fn property_overrides(&self) -> HashMap<String, PropertyOverride> {
+        self.property_overrides.clone()
+    }
+
+    fn is_singleton(&self) -> bool {
+        self.singleton
+    }
+
+    fn batch_size(&self) -> Option<usize> {
+        self.batch_size
+    }
+
     async fn last_updated_time(&self) -> DateTime<Utc> {
         DateTime::parse_from_rfc3339("2023-01-01T00:00:00Z")
             .unwrap()
             .into()
     }
 
+    fn updated_column(&self) -> Option<String> {
+        self.updated_column.clone()
+    }
+
     async fn schema(&self) -> Result<SchemaRef, ODataError> {
         Ok(self
             .query_ctx
@@ -137,7 +482,15 @@ impl CollectionContext for ODataContext {
             .schema())
     }
 
-    async fn query(&self, query: QueryParams) -> Result<DataFrame, ODataError> {
+    async fn query(
+        &self,
+        query: QueryParams,
+        options: &ApplyOptions,
+    ) -> Result<AppliedQuery, ODataError> {
+        if let Some(delay) = self.query_delay {
+            tokio::time::sleep(delay).await;
+        }
+
         let df = self
             .query_ctx
             .table(TableReference::bare(self.collection_name()?))
@@ -149,19 +502,223 @@ impl CollectionContext for ODataContext {
                 )
             })?;
 
-        query
-            .apply(
-                df,
-                self.addr()?,
-                "offset",
-                &self.key_column_alias(),
-                100,
-                usize::MAX,
-            )
-            .map_err(ODataError::internal)
+        query.apply(df, self.addr()?, options, 100, usize::MAX)
     }
 
     fn on_unsupported_feature(&self) -> OnUnsupported {
-        OnUnsupported::Error
+        self.on_unsupported
     }
+
+    fn on_invalid_xml_chars(&self) -> OnInvalidXmlChars {
+        self.on_invalid_xml_chars
+    }
+
+    fn on_null_key(&self) -> OnNullKey {
+        self.on_null_key.clone()
+    }
+
+    async fn validate(&self, record_batches: &[RecordBatch]) -> Result<(), ODataError> {
+        self.validated_batches
+            .store(record_batches.len(), Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Implements [`ServiceContext`] and [`CollectionContext`] for the focused context of a test,
+/// which wraps an [`ODataContext`] in its `inner` field. The methods listed under `service`
+/// and `collection` are the feature under test, all the other ones are the fixture's:
+///
+/// ```ignore
+/// struct WritableContext {
+///     inner: ODataContext,
+/// }
+///
+/// focused_context!(WritableContext {
+///     collection {
+///         fn is_writable(&self) -> bool {
+///             true
+///         }
+///     }
+/// });
+/// ```
+macro_rules! focused_context {
+    ($ty:ty { $(service { $($service:tt)* })? $(collection { $($collection:tt)* })? }) => {
+        #[async_trait::async_trait]
+        impl datafusion_odata::context::ServiceContext for $ty {
+            fn service_base_url(&self) -> String {
+                datafusion_odata::context::ServiceContext::service_base_url(&self.inner)
+            }
+
+            async fn list_collections(
+                &self,
+            ) -> Result<
+                Vec<std::sync::Arc<dyn datafusion_odata::context::CollectionContext>>,
+                datafusion_odata::error::ODataError,
+            > {
+                self.inner.list_collections().await
+            }
+
+            fn on_unsupported_feature(&self) -> datafusion_odata::context::OnUnsupported {
+                datafusion_odata::context::ServiceContext::on_unsupported_feature(&self.inner)
+            }
+
+            async fn collection_context(
+                &self,
+                addr: datafusion_odata::collection::CollectionAddr,
+            ) -> Result<
+                std::sync::Arc<dyn datafusion_odata::context::CollectionContext>,
+                datafusion_odata::error::ODataError,
+            > {
+                self.inner.collection_context(addr).await
+            }
+
+            fn functions(&self) -> Vec<datafusion_odata::context::FunctionDeclaration> {
+                self.inner.functions()
+            }
+
+            async fn call_function(
+                &self,
+                name: &str,
+                args: Vec<(String, datafusion_odata::collection::KeyValue)>,
+            ) -> Result<datafusion::prelude::DataFrame, datafusion_odata::error::ODataError> {
+                self.inner.call_function(name, args).await
+            }
+
+            fn odata_version(&self) -> datafusion_odata::context::ODataVersion {
+                self.inner.odata_version()
+            }
+
+            $($($service)*)?
+        }
+
+        #[async_trait::async_trait]
+        impl datafusion_odata::context::CollectionContext for $ty {
+            fn addr(
+                &self,
+            ) -> Result<&datafusion_odata::collection::CollectionAddr, datafusion_odata::error::ODataError>
+            {
+                self.inner.addr()
+            }
+
+            fn service_base_url(&self) -> Result<String, datafusion_odata::error::ODataError> {
+                datafusion_odata::context::CollectionContext::service_base_url(&self.inner)
+            }
+
+            fn collection_base_url(&self) -> Result<String, datafusion_odata::error::ODataError> {
+                self.inner.collection_base_url()
+            }
+
+            fn service(&self) -> Option<&dyn datafusion_odata::context::ServiceContext> {
+                Some(self)
+            }
+
+            fn collection_name(&self) -> Result<String, datafusion_odata::error::ODataError> {
+                self.inner.collection_name()
+            }
+
+            fn entity_type_name(&self) -> Result<String, datafusion_odata::error::ODataError> {
+                self.inner.entity_type_name()
+            }
+
+            fn key_column(&self) -> Result<String, datafusion_odata::error::ODataError> {
+                self.inner.key_column()
+            }
+
+            fn key_columns(&self) -> Result<Vec<String>, datafusion_odata::error::ODataError> {
+                self.inner.key_columns()
+            }
+
+            fn synthetic_key_column(&self) -> bool {
+                self.inner.synthetic_key_column()
+            }
+
+            fn property_name_mapper(&self) -> Option<datafusion_odata::context::PropertyNameMapper> {
+                self.inner.property_name_mapper()
+            }
+
+            fn derived_columns(&self) -> Vec<(String, String)> {
+                self.inner.derived_columns()
+            }
+
+            fn visible_columns(&self) -> Option<std::collections::HashSet<String>> {
+                self.inner.visible_columns()
+            }
+
+            fn property_overrides(
+                &self,
+            ) -> std::collections::HashMap<String, datafusion_odata::context::PropertyOverride> {
+                self.inner.property_overrides()
+            }
+
+            fn navigation_properties(
+                &self,
+            ) -> Vec<datafusion_odata::context::NavigationPropertyDeclaration> {
+                self.inner.navigation_properties()
+            }
+
+            fn allowed_query_options(
+                &self,
+            ) -> std::collections::HashSet<datafusion_odata::collection::QueryOption> {
+                self.inner.allowed_query_options()
+            }
+
+            fn is_singleton(&self) -> bool {
+                self.inner.is_singleton()
+            }
+
+            fn batch_size(&self) -> Option<usize> {
+                self.inner.batch_size()
+            }
+
+            fn updated_column(&self) -> Option<String> {
+                self.inner.updated_column()
+            }
+
+            async fn last_updated_time(&self) -> chrono::DateTime<chrono::Utc> {
+                self.inner.last_updated_time().await
+            }
+
+            async fn schema(
+                &self,
+            ) -> Result<datafusion::arrow::datatypes::SchemaRef, datafusion_odata::error::ODataError>
+            {
+                self.inner.schema().await
+            }
+
+            async fn query(
+                &self,
+                query: datafusion_odata::collection::QueryParams,
+                options: &datafusion_odata::collection::ApplyOptions,
+            ) -> Result<datafusion_odata::collection::AppliedQuery, datafusion_odata::error::ODataError>
+            {
+                self.inner.query(query, options).await
+            }
+
+            fn on_unsupported_feature(&self) -> datafusion_odata::context::OnUnsupported {
+                datafusion_odata::context::CollectionContext::on_unsupported_feature(&self.inner)
+            }
+
+            fn on_invalid_xml_chars(&self) -> datafusion_odata::context::OnInvalidXmlChars {
+                self.inner.on_invalid_xml_chars()
+            }
+
+            fn on_null_key(&self) -> datafusion_odata::context::OnNullKey {
+                self.inner.on_null_key()
+            }
+
+            async fn validate(
+                &self,
+                record_batches: &[datafusion::arrow::record_batch::RecordBatch],
+            ) -> Result<(), datafusion_odata::error::ODataError> {
+                self.inner.validate(record_batches).await
+            }
+
+            $($($collection)*)?
+        }
+    };
 }
+
+#[allow(unused_imports)]
+pub(crate) use focused_context;
@@ -1,9 +1,26 @@
 mod shared;
 
-use datafusion_odata::collection::QueryParamsRaw;
+use std::sync::Arc;
+
+use datafusion::arrow::{
+    array::{
+        BinaryArray, Float64Array, Int64Array, RecordBatch, StringArray, TimestampMicrosecondArray,
+        TimestampMillisecondArray, TimestampNanosecondArray, UInt64Array,
+    },
+    datatypes::{DataType, Field, Schema, TimeUnit},
+};
+use datafusion_odata::{
+    collection::{ApplyOptions, CollectionAddr, QueryParamsRaw, SkipToken},
+    context::CollectionContext,
+    error::ODataError,
+    media_type::MEDIA_TYPE_ATOM,
+};
 use indoc::indoc;
 
-use shared::fixture;
+use shared::{
+    batch_context, context, fixture, fixture_with_batch, focused_context, session_context,
+    ODataContext,
+};
 
 #[tokio::test]
 async fn test_collection() {
@@ -14,8 +31,14 @@ async fn test_collection() {
             select: Some("offset,close".to_string()),
             order_by: Some("offset asc".to_string()),
             skip: None,
+            skip_token: None,
             top: Some(2),
             filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
         }),
         axum::http::HeaderMap::new(),
     )
@@ -31,14 +54,14 @@ async fn test_collection() {
              xmlns="http://www.w3.org/2005/Atom"
              xmlns:d="http://schemas.microsoft.com/ado/2007/08/dataservices"
              xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata">
-            <id>http://example.com/odatatickers.spy</id>
+            <id>http://example.com/odata/tickers.spy</id>
             <title type="text">tickers.spy</title>
             <updated>2023-01-01T00:00:00.000Z</updated>
-            <link rel="self" title="tickers.spy" href="tickers.spy"/>
+            <link rel="self" title="tickers.spy" href="http://example.com/odata/tickers.spy"/>
             <entry>
-            <id>http://example.com/odatatickers.spy(0)</id>
-            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.tickers.spy"/>
-            <link rel="edit" title="tickers.spy" href="tickers.spy(0)"/>
+            <id>http://example.com/odata/tickers.spy(0)</id>
+            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.tickers_spy"/>
+            <link rel="self" title="tickers.spy" href="tickers.spy(0)"/>
             <title/>
             <updated>2023-01-01T00:00:00.000Z</updated>
             <author><name/></author>
@@ -50,9 +73,9 @@ async fn test_collection() {
             </content>
             </entry>
             <entry>
-            <id>http://example.com/odatatickers.spy(1)</id>
-            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.tickers.spy"/>
-            <link rel="edit" title="tickers.spy" href="tickers.spy(1)"/>
+            <id>http://example.com/odata/tickers.spy(1)</id>
+            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.tickers_spy"/>
+            <link rel="self" title="tickers.spy" href="tickers.spy(1)"/>
             <title/>
             <updated>2023-01-01T00:00:00.000Z</updated>
             <author><name/></author>
@@ -81,8 +104,14 @@ async fn test_collection_entity_by_id() {
             select: Some("offset,close".to_string()),
             order_by: None,
             skip: None,
+            skip_token: None,
             top: None,
             filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
         }),
         axum::http::HeaderMap::new(),
     )
@@ -98,9 +127,9 @@ async fn test_collection_entity_by_id() {
              xmlns="http://www.w3.org/2005/Atom"
              xmlns:d="http://schemas.microsoft.com/ado/2007/08/dataservices"
              xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata">
-            <id>http://example.com/odatatickers.spy(1)</id>
-            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.tickers.spy"/>
-            <link rel="edit" title="tickers.spy" href="tickers.spy(1)"/>
+            <id>http://example.com/odata/tickers.spy(1)</id>
+            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.tickers_spy"/>
+            <link rel="self" title="tickers.spy" href="tickers.spy(1)"/>
             <title/>
             <updated>2023-01-01T00:00:00.000Z</updated>
             <author><name/></author>
@@ -128,8 +157,14 @@ async fn test_collection_entity_by_id_not_found() {
             select: Some("offset,close".to_string()),
             order_by: None,
             skip: None,
+            skip_token: None,
             top: None,
             filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
         }),
         axum::http::HeaderMap::new(),
     )
@@ -140,6 +175,47 @@ async fn test_collection_entity_by_id_not_found() {
 
 ///////////////////////////////////////////////////////////////////////////////
 
+#[tokio::test]
+async fn test_collection_filter_matches_nothing() {
+    let query = |format: Option<&str>| QueryParamsRaw {
+        select: Some("offset,close".to_string()),
+        order_by: None,
+        skip: None,
+        skip_token: None,
+        top: None,
+        filter: Some("offset eq 999999".parse().unwrap()),
+        expand: None,
+        format: format.map(str::to_string),
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    // Unlike a missing entity, an empty result of a collection query is an empty feed
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(query(None)),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    assert!(resp.body().contains("<feed "));
+    assert!(!resp.body().contains("<entry>"));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(query(Some("json"))),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    assert!(resp.body().contains(r#""value":[]"#));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
 #[tokio::test]
 async fn test_collection_with_filter() {
     let ctx = fixture("tickers.spy").await;
@@ -149,8 +225,14 @@ async fn test_collection_with_filter() {
             select: Some("offset,close".to_string()),
             order_by: Some("offset asc".to_string()),
             skip: None,
+            skip_token: None,
             top: None,
             filter: Some("offset eq 0".parse().unwrap()),
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
         }),
         axum::http::HeaderMap::new(),
     )
@@ -166,14 +248,14 @@ async fn test_collection_with_filter() {
              xmlns="http://www.w3.org/2005/Atom"
              xmlns:d="http://schemas.microsoft.com/ado/2007/08/dataservices"
              xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata">
-            <id>http://example.com/odatatickers.spy</id>
+            <id>http://example.com/odata/tickers.spy</id>
             <title type="text">tickers.spy</title>
             <updated>2023-01-01T00:00:00.000Z</updated>
-            <link rel="self" title="tickers.spy" href="tickers.spy"/>
+            <link rel="self" title="tickers.spy" href="http://example.com/odata/tickers.spy"/>
             <entry>
-            <id>http://example.com/odatatickers.spy(0)</id>
-            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.tickers.spy"/>
-            <link rel="edit" title="tickers.spy" href="tickers.spy(0)"/>
+            <id>http://example.com/odata/tickers.spy(0)</id>
+            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.tickers_spy"/>
+            <link rel="self" title="tickers.spy" href="tickers.spy(0)"/>
             <title/>
             <updated>2023-01-01T00:00:00.000Z</updated>
             <author><name/></author>
@@ -190,3 +272,3334 @@ async fn test_collection_with_filter() {
         .replace('\n', "")
     );
 }
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn accept(media_type: &'static str) -> axum::http::HeaderMap {
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert(
+        http::header::ACCEPT,
+        http::HeaderValue::from_static(media_type),
+    );
+    headers
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_entity_by_id_json() {
+    let ctx = fixture("tickers.spy(1)").await;
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset,close".to_string()),
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        accept("application/json"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        resp.headers()[http::header::CONTENT_TYPE],
+        datafusion_odata::handlers::MEDIA_TYPE_JSON
+    );
+    assert_eq!(
+        *resp.body(),
+        indoc!(
+            r#"
+            {
+            "@odata.context":"http://example.com/odata/$metadata#tickers.spy/$entity",
+            "offset":1,
+            "close":134.5937
+            }
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_entity_by_id_json_verbose() {
+    let ctx = fixture("tickers.spy(1)").await;
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset,close".to_string()),
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        accept("application/json;odata=verbose"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *resp.body(),
+        indoc!(
+            r#"
+            {"d":{
+            "__metadata":{
+            "id":"http://example.com/odata/tickers.spy(1)",
+            "uri":"http://example.com/odata/tickers.spy(1)",
+            "type":"default.tickers_spy"
+            },
+            "offset":"1",
+            "close":134.5937
+            }}
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_entity_by_id_not_found_json() {
+    let ctx = fixture("tickers.spy(999999)").await;
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset,close".to_string()),
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        accept("application/json"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_filter_on_unselected_column() {
+    let ctx = fixture("tickers.spy").await;
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("close".to_string()),
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: Some("offset eq 1".parse().unwrap()),
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *resp.body(),
+        indoc!(
+            r#"
+            <?xml version="1.0" encoding="utf-8"?>
+            <feed
+             xml:base="http://example.com/odata/"
+             xmlns="http://www.w3.org/2005/Atom"
+             xmlns:d="http://schemas.microsoft.com/ado/2007/08/dataservices"
+             xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata">
+            <id>http://example.com/odata/tickers.spy</id>
+            <title type="text">tickers.spy</title>
+            <updated>2023-01-01T00:00:00.000Z</updated>
+            <link rel="self" title="tickers.spy" href="http://example.com/odata/tickers.spy"/>
+            <entry>
+            <id>http://example.com/odata/tickers.spy(1)</id>
+            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.tickers_spy"/>
+            <link rel="self" title="tickers.spy" href="tickers.spy(1)"/>
+            <title/>
+            <updated>2023-01-01T00:00:00.000Z</updated>
+            <author><name/></author>
+            <content type="application/xml">
+            <m:properties>
+            <d:close m:type="Edm.Double">134.5937</d:close>
+            </m:properties>
+            </content>
+            </entry>
+            </feed>
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_singleton() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("mode", DataType::Utf8, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0])),
+            Arc::new(StringArray::from(vec!["live"])),
+        ],
+    )
+    .unwrap();
+    let ctx = Arc::new(batch_context("config", "config", batch).with_singleton());
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx.clone()),
+        axum::extract::Query(QueryParamsRaw {
+            select: None,
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *resp.body(),
+        indoc!(
+            r#"
+            <?xml version="1.0" encoding="utf-8"?>
+            <entry
+             xml:base="http://example.com/odata/"
+             xmlns="http://www.w3.org/2005/Atom"
+             xmlns:d="http://schemas.microsoft.com/ado/2007/08/dataservices"
+             xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata">
+            <id>http://example.com/odata/config</id>
+            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.config"/>
+            <link rel="self" title="config" href="config"/>
+            <title/>
+            <updated>2023-01-01T00:00:00.000Z</updated>
+            <author><name/></author>
+            <content type="application/xml">
+            <m:properties>
+            <d:offset m:type="Edm.Int64">0</d:offset>
+            <d:mode m:type="Edm.String">live</d:mode>
+            </m:properties>
+            </content>
+            </entry>
+            "#
+        )
+        .replace('\n', "")
+    );
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx))
+        .await
+        .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"<EntityContainer Name="default" m:IsDefaultEntityContainer="true"><Singleton Name="config" Type="default.config"/></EntityContainer>"#));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_top_without_order_by_is_stable() {
+    // Entities are stored out of key order, so paging in storage order would skip some
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![Field::new(
+            "offset",
+            DataType::Int64,
+            false,
+        )])),
+        vec![Arc::new(Int64Array::from(vec![3, 0, 2, 1]))],
+    )
+    .unwrap();
+
+    let mut bodies = Vec::new();
+    for _ in 0..2 {
+        let resp = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(fixture_with_batch("items", "items", batch.clone()).await),
+            axum::extract::Query(QueryParamsRaw {
+                select: None,
+                order_by: None,
+                skip: Some(1),
+                skip_token: None,
+                top: Some(2),
+                filter: None,
+                expand: None,
+                format: Some("csv".to_string()),
+                count: None,
+                inline_count: None,
+                apply: None,
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        bodies.push(resp.into_body());
+    }
+
+    assert_eq!(bodies[0], bodies[1]);
+    assert_eq!(bodies[0], "offset\n1\n2\n");
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_order_by_key_not_selected() {
+    let ctx = fixture("tickers.spy").await;
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("close".to_string()),
+            order_by: Some("offset desc".to_string()),
+            skip: None,
+            skip_token: None,
+            top: Some(2),
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+
+    let body = resp.into_body();
+    let first = body.find("<id>http://example.com/odata/tickers.spy(6089)</id>");
+    let second = body.find("<id>http://example.com/odata/tickers.spy(6088)</id>");
+    assert!(first.is_some() && second.is_some());
+    assert!(first < second);
+    assert!(!body.contains("<d:offset"));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_batch_size() {
+    for (batch_size, expected_batches) in [(None, 1), (Some(10), 5)] {
+        let ctx = context("tickers.spy").await;
+        let ctx = Arc::new(match batch_size {
+            Some(batch_size) => ctx.with_batch_size(batch_size),
+            None => ctx,
+        });
+        let resp = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(ctx.clone()),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("offset".to_string()),
+                order_by: None,
+                skip: None,
+                skip_token: None,
+                top: Some(50),
+                filter: None,
+                expand: None,
+                format: None,
+                count: None,
+                inline_count: None,
+                apply: None,
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(resp.body().matches("<entry>").count(), 50);
+        assert_eq!(ctx.validated_batches(), expected_batches);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_csv() {
+    let query = || QueryParamsRaw {
+        select: Some("offset,from_symbol,close".to_string()),
+        order_by: Some("offset desc".to_string()),
+        skip: None,
+        skip_token: None,
+        top: Some(2),
+        filter: Some("offset le 5".parse().unwrap()),
+        expand: None,
+        format: None,
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+    let expected = indoc!(
+        "
+        offset,from_symbol,close
+        5,spy,138.0
+        4,spy,137.875
+        "
+    );
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(QueryParamsRaw {
+            format: Some("csv".to_string()),
+            count: None,
+            inline_count: None,
+            apply: None,
+            ..query()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        resp.headers()[axum::http::header::CONTENT_TYPE],
+        datafusion_odata::handlers::MEDIA_TYPE_CSV
+    );
+    assert_eq!(*resp.body(), expected);
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(query()),
+        accept("text/csv"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), expected);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_prefer_max_page_size() {
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert(
+        "Prefer",
+        "return=minimal, odata.maxpagesize=1".parse().unwrap(),
+    );
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset".to_string()),
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        headers,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(resp.headers()["Preference-Applied"], "odata.maxpagesize=1");
+    assert_eq!(resp.body().matches("<entry>").count(), 1);
+    assert!(resp
+        .body()
+        .contains("<id>http://example.com/odata/tickers.spy(0)</id>"));
+
+    // Empty pages would link to themselves forever
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert("Prefer", "odata.maxpagesize=0".parse().unwrap());
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset".to_string()),
+            filter: Some("offset lt 3".to_string()),
+            ..Default::default()
+        }),
+        headers,
+    )
+    .await
+    .unwrap();
+
+    assert!(!resp.headers().contains_key("Preference-Applied"));
+    assert_eq!(resp.body().matches("<entry>").count(), 3);
+    assert!(!resp.body().contains(r#"rel="next""#));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_next_link() {
+    // Ordering by the key leaves nothing to break the ties with
+    let skip_token = SkipToken {
+        values: vec![Some("1".to_string())],
+    }
+    .encode();
+    let query = |skip_token: Option<String>, media_type: &'static str| async move {
+        let mut headers = accept(media_type);
+        headers.insert("Prefer", "odata.maxpagesize=2".parse().unwrap());
+        datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(fixture("tickers.spy").await),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("offset,close".to_string()),
+                order_by: Some("offset".to_string()),
+                skip_token,
+                filter: Some("offset le 2".parse().unwrap()),
+                ..Default::default()
+            }),
+            headers,
+        )
+        .await
+        .unwrap()
+        .into_body()
+    };
+
+    let next_link = format!(
+        "http://example.com/odata/tickers.spy\
+         ?$filter=offset%20le%202&$select=offset,close&$orderby=offset&$skiptoken={skip_token}",
+    );
+
+    let body = query(None, "application/atom+xml").await;
+    assert_eq!(body.matches("<entry>").count(), 2);
+    assert!(body.ends_with(&format!(
+        r#"<link rel="next" href="{}"/></feed>"#,
+        next_link.replace('&', "&amp;")
+    )));
+    let body = query(Some(skip_token.clone()), "application/atom+xml").await;
+    assert_eq!(body.matches("<entry>").count(), 1);
+    assert!(!body.contains(r#"rel="next""#));
+
+    let body = query(None, "application/json;odata.metadata=minimal").await;
+    assert!(body.ends_with(&format!(r#"],"@odata.nextLink":"{next_link}"}}"#)));
+    let body = query(
+        Some(skip_token.clone()),
+        "application/json;odata.metadata=minimal",
+    )
+    .await;
+    assert!(!body.contains("@odata.nextLink"));
+
+    let body = query(None, "application/json;odata=verbose").await;
+    assert!(body.ends_with(&format!(r#"],"__next":"{next_link}"}}}}"#)));
+    let body = query(Some(skip_token.clone()), "application/json;odata=verbose").await;
+    assert!(!body.contains("__next"));
+}
+
+#[tokio::test]
+async fn test_collection_skip_token_with_ties() {
+    let query = |skip_token: Option<String>, max_page_size: usize| async move {
+        let mut headers = accept("application/json");
+        headers.insert(
+            "Prefer",
+            format!("odata.maxpagesize={max_page_size}")
+                .parse()
+                .unwrap(),
+        );
+        let resp = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(fixture("covid19.canada").await),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("offset,reported_date".to_string()),
+                order_by: Some("reported_date desc".to_string()),
+                skip_token,
+                count: Some(true),
+                filter: Some(
+                    "year(reported_date) eq 2021 and month(reported_date) eq 5"
+                        .parse()
+                        .unwrap(),
+                ),
+                ..Default::default()
+            }),
+            headers,
+        )
+        .await
+        .unwrap();
+        let body: serde_json::Value = serde_json::from_str(resp.body()).unwrap();
+        let entities: Vec<_> = body["value"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                (
+                    e["reported_date"].as_str().unwrap().to_string(),
+                    e["offset"].as_i64().unwrap(),
+                )
+            })
+            .collect();
+        let skip_token = body["@odata.nextLink"]
+            .as_str()
+            .map(|link| link.split_once("$skiptoken=").unwrap().1.to_string());
+        let count = body["@odata.count"].as_u64().unwrap() as usize;
+        (entities, skip_token, count)
+    };
+
+    let (all, skip_token, count) = query(None, 10_000).await;
+    assert!(skip_token.is_none());
+    assert_eq!(count, all.len());
+
+    let mut paged = Vec::new();
+    let mut skip_token = None;
+    loop {
+        // The count covers the whole collection on every page, not what's left after the cursor
+        let (page, next, count) = query(skip_token, 3).await;
+        assert!(page.len() <= 3);
+        assert_eq!(count, all.len());
+        paged.extend(page);
+        skip_token = match next {
+            Some(next) => Some(next),
+            None => break,
+        };
+    }
+
+    // Many entities share the date, which is told apart by the key
+    let mut expected = all.clone();
+    expected.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    assert!(expected.windows(2).any(|w| w[0].0 == w[1].0));
+    assert_eq!(paged, expected);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_skip_token_descending_key() {
+    let query = |skip_token: Option<String>| async move {
+        let mut headers = accept("application/json");
+        headers.insert("Prefer", "odata.maxpagesize=4".parse().unwrap());
+        let resp = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(fixture("tickers.spy").await),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("offset".to_string()),
+                order_by: Some("offset desc".to_string()),
+                filter: Some("offset lt 10".parse().unwrap()),
+                skip_token,
+                ..Default::default()
+            }),
+            headers,
+        )
+        .await
+        .unwrap();
+        let body: serde_json::Value = serde_json::from_str(resp.body()).unwrap();
+        let offsets: Vec<_> = body["value"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["offset"].as_i64().unwrap())
+            .collect();
+        let skip_token = body["@odata.nextLink"]
+            .as_str()
+            .map(|link| link.split_once("$skiptoken=").unwrap().1.to_string());
+        (offsets, skip_token)
+    };
+
+    // Latest entities first, then the older ones
+    let (page, skip_token) = query(None).await;
+    assert_eq!(page, [9, 8, 7, 6]);
+    let (page, skip_token) = query(skip_token).await;
+    assert_eq!(page, [5, 4, 3, 2]);
+    let (page, skip_token) = query(skip_token).await;
+    assert_eq!(page, [1, 0]);
+    assert!(skip_token.is_none());
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_unknown_column_is_bad_request() {
+    for (select, order_by) in [(Some("offset,missing"), None), (None, Some("missing desc"))] {
+        let res = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(fixture("tickers.spy").await),
+            axum::extract::Query(QueryParamsRaw {
+                select: select.map(str::to_string),
+                order_by: order_by.map(str::to_string),
+                skip: None,
+                skip_token: None,
+                top: None,
+                filter: None,
+                expand: None,
+                format: None,
+                count: None,
+                inline_count: None,
+                apply: None,
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await;
+
+        let Err(err) = res else {
+            panic!("expected an error");
+        };
+        let resp = axum::response::IntoResponse::into_response(err);
+        assert_eq!(resp.status(), axum::http::StatusCode::BAD_REQUEST);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_select_star() {
+    let mut bodies = Vec::new();
+    for select in [None, Some("*")] {
+        let resp = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(fixture("tickers.spy").await),
+            axum::extract::Query(QueryParamsRaw {
+                select: select.map(str::to_string),
+                order_by: None,
+                skip: None,
+                skip_token: None,
+                top: Some(2),
+                filter: None,
+                expand: None,
+                format: None,
+                count: None,
+                inline_count: None,
+                apply: None,
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        bodies.push(resp.into_body());
+    }
+
+    assert_eq!(bodies[0], bodies[1]);
+    assert!(bodies[1].contains("<d:close m:type=\"Edm.Double\">"));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct CategoryContext {
+    inner: ODataContext,
+}
+
+focused_context!(CategoryContext {
+    collection {
+        fn category_scheme(&self) -> String {
+            "http://example.com/scheme".to_string()
+        }
+
+        fn category_term(&self) -> Result<String, ODataError> {
+            Ok("quotes".to_string())
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_collection_custom_category() {
+    let ctx = Arc::new(CategoryContext {
+        inner: context("tickers.spy").await,
+    });
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset".to_string()),
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: Some(2),
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        resp.body()
+            .matches(r#"<category scheme="http://example.com/scheme" term="quotes"/>"#)
+            .count(),
+        2
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_entity_by_float_id() {
+    let points = || {
+        RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("offset", DataType::Float64, false),
+                Field::new("label", DataType::Utf8, false),
+            ])),
+            vec![
+                Arc::new(Float64Array::from(vec![0.5, 1.5])),
+                Arc::new(StringArray::from(vec!["a", "b"])),
+            ],
+        )
+        .unwrap()
+    };
+    let query = || QueryParamsRaw {
+        select: None,
+        order_by: None,
+        skip: None,
+        skip_token: None,
+        top: None,
+        filter: None,
+        expand: None,
+        format: None,
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("points(1.5)", "points", points()).await),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains("<id>http://example.com/odata/points(1.5)</id>"));
+    assert!(resp
+        .body()
+        .contains(r#"<d:label m:type="Edm.String">b</d:label>"#));
+
+    // Fractional keys make no sense for string key columns
+    let labels = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![Field::new(
+            "offset",
+            DataType::Utf8,
+            false,
+        )])),
+        vec![Arc::new(StringArray::from(vec!["a"]))],
+    )
+    .unwrap();
+    let res = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("labels(1.5)", "labels", labels).await),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+    assert!(matches!(
+        res,
+        Err(datafusion_odata::error::ODataError::BadRequest(_))
+    ));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct QueryTimeoutContext {
+    inner: ODataContext,
+}
+
+focused_context!(QueryTimeoutContext {
+    collection {
+        fn query_timeout(&self) -> Option<std::time::Duration> {
+            Some(std::time::Duration::from_millis(50))
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_collection_query_timeout() {
+    let ctx = Arc::new(QueryTimeoutContext {
+        inner: context("tickers.spy")
+            .await
+            .with_query_delay(std::time::Duration::from_secs(10)),
+    });
+    let res = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: None,
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+
+    let Err(err) = res else {
+        panic!("expected a timeout");
+    };
+    let resp = axum::response::IntoResponse::into_response(err);
+    assert_eq!(resp.status(), axum::http::StatusCode::GATEWAY_TIMEOUT);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_count() {
+    let query = || QueryParamsRaw {
+        select: Some("offset".to_string()),
+        order_by: None,
+        skip: Some(1),
+        skip_token: None,
+        top: Some(2),
+        filter: Some("offset lt 10".parse().unwrap()),
+        expand: None,
+        format: None,
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(QueryParamsRaw {
+            count: Some(true),
+            ..query()
+        }),
+        accept("application/json"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *resp.body(),
+        concat!(
+            r#"{"@odata.context":"http://example.com/odata/$metadata#tickers.spy","#,
+            r#""@odata.count":10,"#,
+            r#""value":[{"offset":1},{"offset":2}]}"#,
+        )
+    );
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(QueryParamsRaw {
+            inline_count: Some("allpages".to_string()),
+            apply: None,
+            ..query()
+        }),
+        accept("application/json;odata=verbose"),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .starts_with(r#"{"d":{"__count":"10","results":["#));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(QueryParamsRaw {
+            inline_count: Some("allpages".to_string()),
+            apply: None,
+            ..query()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains("<m:count>10</m:count>"));
+    assert_eq!(resp.body().matches("<entry>").count(), 2);
+
+    let res = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(QueryParamsRaw {
+            inline_count: Some("sometimes".to_string()),
+            apply: None,
+            ..query()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+    assert!(matches!(
+        res,
+        Err(datafusion_odata::error::ODataError::BadRequest(_))
+    ));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_entity_by_id_with_select() {
+    let query = || QueryParamsRaw {
+        select: Some("close".to_string()),
+        order_by: None,
+        skip: None,
+        skip_token: None,
+        top: None,
+        filter: None,
+        expand: None,
+        format: None,
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy(1)").await),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains("<id>http://example.com/odata/tickers.spy(1)</id>"));
+    assert!(resp.body().contains(concat!(
+        "<m:properties>",
+        r#"<d:close m:type="Edm.Double">134.5937</d:close>"#,
+        "</m:properties>"
+    )));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy(1)").await),
+        axum::extract::Query(query()),
+        accept("application/json;odata=verbose"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *resp.body(),
+        concat!(
+            r#"{"d":{"__metadata":{"#,
+            r#""id":"http://example.com/odata/tickers.spy(1)","#,
+            r#""uri":"http://example.com/odata/tickers.spy(1)","#,
+            r#""type":"default.tickers_spy"},"#,
+            r#""close":134.5937}}"#,
+        )
+    );
+
+    // Navigation is validated the same way for single entities and feeds
+    let res = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy(1)").await),
+        axum::extract::Query(QueryParamsRaw {
+            expand: Some("Quotes".to_string()),
+            ..query()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+    assert!(matches!(
+        res,
+        Err(datafusion_odata::error::ODataError::BadRequest(_))
+    ));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_filter_column_vs_column() {
+    let filter: datafusion_odata::filter::ODataFilter = "high gt low".parse().unwrap();
+    assert_eq!(
+        datafusion::prelude::Expr::from(filter),
+        datafusion::prelude::col("high").gt(datafusion::prelude::col("low"))
+    );
+
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("high", DataType::Float64, false),
+            Field::new("low", DataType::Int64, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1, 2, 3])),
+            Arc::new(Float64Array::from(vec![10.5, 9.0, 12.0, 7.0])),
+            Arc::new(Int64Array::from(vec![10, 9, 11, 8])),
+        ],
+    )
+    .unwrap();
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("bars", "bars", batch).await),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset".to_string()),
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: Some("high gt low".parse().unwrap()),
+            expand: None,
+            format: Some("csv".to_string()),
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "offset\n0\n2\n");
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_entity_by_id_with_paging_is_bad_request() {
+    for (top, skip, order_by) in [
+        (Some(5), None, None),
+        (None, Some(2), None),
+        (None, None, Some("close desc".to_string())),
+    ] {
+        let res = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(fixture("tickers.spy(1)").await),
+            axum::extract::Query(QueryParamsRaw {
+                select: None,
+                order_by,
+                skip,
+                skip_token: None,
+                top,
+                filter: None,
+                expand: None,
+                format: None,
+                count: None,
+                inline_count: None,
+                apply: None,
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Err(datafusion_odata::error::ODataError::BadRequest(_))
+        ));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_filter_negative_and_scientific_numbers() {
+    let batch = || {
+        RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("offset", DataType::Int64, false),
+                Field::new("close", DataType::Float64, false),
+            ])),
+            vec![
+                Arc::new(Int64Array::from(vec![-1, 0, 1])),
+                Arc::new(Float64Array::from(vec![-12.5, 131.5, 140.0])),
+            ],
+        )
+        .unwrap()
+    };
+
+    for (filter, expected) in [
+        ("offset eq -1", "offset\n-1\n"),
+        ("close gt 1.3e2", "offset\n0\n1\n"),
+        ("close gt 1.35E+2", "offset\n1\n"),
+        ("close lt -1.2e1 or offset in (-5, 1)", "offset\n-1\n1\n"),
+        ("close gt -12.5 and offset lt 1e0", "offset\n0\n"),
+    ] {
+        let resp = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(fixture_with_batch("bars", "bars", batch()).await),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("offset".to_string()),
+                order_by: Some("offset".to_string()),
+                skip: None,
+                skip_token: None,
+                top: None,
+                filter: Some(filter.parse().unwrap()),
+                expand: None,
+                format: Some("csv".to_string()),
+                count: None,
+                inline_count: None,
+                apply: None,
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(*resp.body(), expected, "{filter}");
+    }
+
+    // Numbers within string literals are left intact, regardless of the escaped quotes
+    for (filter, value) in [
+        ("name eq '-1e3'", "-1e3"),
+        (r"name eq 'it\'s -1'", "it's -1"),
+        ("name eq 'it''s -1'", "it's -1"),
+    ] {
+        let filter: datafusion_odata::filter::ODataFilter = filter.parse().unwrap();
+        assert_eq!(
+            datafusion::prelude::Expr::from(filter),
+            datafusion::prelude::col("name").eq(datafusion::prelude::lit(
+                datafusion::scalar::ScalarValue::LargeUtf8(Some(value.to_string()))
+            ))
+        );
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_unaliased_key_column() {
+    let query = || QueryParamsRaw {
+        select: Some("close".to_string()),
+        order_by: None,
+        skip: None,
+        skip_token: None,
+        top: Some(2),
+        filter: None,
+        expand: None,
+        format: None,
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    let ctx = context("tickers.spy")
+        .await
+        .with_unaliased_key_column("offset");
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(ctx)),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(!resp.body().contains("__id__"));
+    assert!(resp
+        .body()
+        .contains("<id>http://example.com/odata/tickers.spy(1)</id>"));
+    // Key column remains a regular property
+    assert!(resp.body().contains(concat!(
+        "<m:properties>",
+        r#"<d:close m:type="Edm.Double">134.5937</d:close>"#,
+        r#"<d:offset m:type="Edm.Int64">1</d:offset>"#,
+        "</m:properties>"
+    )));
+
+    let ctx = context("tickers.spy(1)")
+        .await
+        .with_unaliased_key_column("offset");
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(ctx)),
+        axum::extract::Query(QueryParamsRaw {
+            top: None,
+            ..query()
+        }),
+        accept("application/json;odata=verbose"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *resp.body(),
+        concat!(
+            r#"{"d":{"__metadata":{"#,
+            r#""id":"http://example.com/odata/tickers.spy(1)","#,
+            r#""uri":"http://example.com/odata/tickers.spy(1)","#,
+            r#""type":"default.tickers_spy"},"#,
+            r#""close":134.5937,"offset":"1"}}"#,
+        )
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct CaseInsensitiveContext {
+    inner: ODataContext,
+}
+
+focused_context!(CaseInsensitiveContext {
+    collection {
+        fn case_insensitive_order_by(&self) -> bool {
+            true
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_collection_case_insensitive_order_by() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, false),
+            Field::new("rank", DataType::Int64, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1, 2, 3])),
+            Arc::new(StringArray::from(vec![
+                "banana", "Cherry", "apple", "Banana",
+            ])),
+            Arc::new(Int64Array::from(vec![1, 1, 1, 2])),
+        ],
+    )
+    .unwrap();
+    let query = || QueryParamsRaw {
+        select: Some("name".to_string()),
+        order_by: Some("name, rank desc".to_string()),
+        skip: None,
+        skip_token: None,
+        top: None,
+        filter: None,
+        expand: None,
+        format: Some("csv".to_string()),
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(batch_context("fruits", "fruits", batch.clone()))),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "name\nBanana\nCherry\napple\nbanana\n");
+
+    let ctx = Arc::new(CaseInsensitiveContext {
+        inner: batch_context("fruits", "fruits", batch),
+    });
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx.clone()),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "name\napple\nBanana\nbanana\nCherry\n");
+
+    // Pages continue after the cursor the same way regardless of case
+    let mut names = Vec::new();
+    let mut skip_token = None;
+    loop {
+        let mut headers = accept("application/json");
+        headers.insert("Prefer", "odata.maxpagesize=1".parse().unwrap());
+        let resp = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(ctx.clone()),
+            axum::extract::Query(QueryParamsRaw {
+                // Cursor consists of all the ordering columns
+                select: Some("name,rank".to_string()),
+                format: None,
+                skip_token,
+                ..query()
+            }),
+            headers,
+        )
+        .await
+        .unwrap();
+        let body: serde_json::Value = serde_json::from_str(resp.body()).unwrap();
+        for entity in body["value"].as_array().unwrap() {
+            names.push(entity["name"].as_str().unwrap().to_string());
+        }
+        skip_token = match body["@odata.nextLink"].as_str() {
+            Some(link) => Some(link.split_once("$skiptoken=").unwrap().1.to_string()),
+            None => break,
+        };
+        assert!(names.len() <= 4, "{names:?}");
+    }
+    assert_eq!(names, ["apple", "Banana", "banana", "Cherry"]);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_timestamps_with_timezone() {
+    // 2024-09-11T00:00:00Z
+    let ticks = 1726012800000;
+    let timestamp =
+        |tz: Option<&str>| DataType::Timestamp(TimeUnit::Millisecond, tz.map(Into::into));
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("utc", timestamp(Some("UTC")), false),
+            Field::new("shifted", timestamp(Some("+05:30")), false),
+            Field::new("naive", timestamp(None), false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0])),
+            Arc::new(TimestampMillisecondArray::from(vec![ticks]).with_timezone("UTC")),
+            Arc::new(TimestampMillisecondArray::from(vec![ticks]).with_timezone("+05:30")),
+            Arc::new(TimestampMillisecondArray::from(vec![ticks])),
+        ],
+    )
+    .unwrap();
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(
+        fixture_with_batch("events", "events", batch.clone()).await,
+    ))
+    .await
+    .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<Property Name="utc" Type="Edm.DateTimeOffset" Nullable="false"/>"#,
+        r#"<Property Name="shifted" Type="Edm.DateTimeOffset" Nullable="false"/>"#,
+        r#"<Property Name="naive" Type="Edm.DateTime" Nullable="false"/>"#,
+    )));
+
+    let query = || QueryParamsRaw {
+        select: None,
+        order_by: None,
+        skip: None,
+        skip_token: None,
+        top: None,
+        filter: None,
+        expand: None,
+        format: None,
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("events", "events", batch.clone()).await),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<d:utc m:type="Edm.DateTimeOffset">2024-09-11T00:00:00.000Z</d:utc>"#,
+        r#"<d:shifted m:type="Edm.DateTimeOffset">2024-09-11T05:30:00.000+05:30</d:shifted>"#,
+        r#"<d:naive m:type="Edm.DateTime">2024-09-11T00:00:00.000Z</d:naive>"#,
+    )));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("events", "events", batch).await),
+        axum::extract::Query(query()),
+        accept("application/json;odata=verbose"),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#""utc":"2024-09-11T00:00:00.000Z","#,
+        r#""shifted":"2024-09-11T05:30:00.000+05:30","#,
+        r#""naive":"/Date(1726012800000)/""#,
+    )));
+}
+
+#[tokio::test]
+async fn test_collection_timestamp_units() {
+    // 2024-09-11T00:00:00.123456789Z
+    let nanos = 1726012800123456789;
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new(
+                "micros",
+                DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+                false,
+            ),
+            Field::new(
+                "nanos",
+                DataType::Timestamp(TimeUnit::Nanosecond, None),
+                false,
+            ),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0])),
+            Arc::new(TimestampMicrosecondArray::from(vec![nanos / 1_000]).with_timezone("UTC")),
+            Arc::new(TimestampNanosecondArray::from(vec![nanos])),
+        ],
+    )
+    .unwrap();
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("events", "events", batch.clone()).await),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<d:micros m:type="Edm.DateTimeOffset">2024-09-11T00:00:00.123Z</d:micros>"#,
+        r#"<d:nanos m:type="Edm.DateTime">2024-09-11T00:00:00.123Z</d:nanos>"#,
+    )));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("events", "events", batch.clone()).await),
+        axum::extract::Query(QueryParamsRaw::default()),
+        accept("application/json"),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#""micros":"2024-09-11T00:00:00.123Z","#,
+        r#""nanos":"2024-09-11T00:00:00.123Z""#,
+    )));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("events", "events", batch).await),
+        axum::extract::Query(QueryParamsRaw::default()),
+        accept("application/json;odata=verbose"),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains(r#""nanos":"/Date(1726012800123)/""#));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_count_of_applied_query() {
+    let df = datafusion::prelude::SessionContext::new()
+        .read_batch(
+            RecordBatch::try_new(
+                Arc::new(Schema::new(vec![Field::new(
+                    "offset",
+                    DataType::Int64,
+                    false,
+                )])),
+                vec![Arc::new(Int64Array::from(vec![0, 1, 2, 3, 4]))],
+            )
+            .unwrap(),
+        )
+        .unwrap();
+    let query = QueryParamsRaw {
+        filter: Some("offset lt 4".to_string()),
+        top: Some(1),
+        count: Some(true),
+        ..Default::default()
+    }
+    .decode()
+    .unwrap();
+    let addr = CollectionAddr {
+        name: "items".to_string(),
+        key: None,
+    };
+
+    let applied = query
+        .apply(
+            df,
+            &addr,
+            &ApplyOptions {
+                key_column: "offset".to_string(),
+                key_column_alias: "offset".to_string(),
+                ..Default::default()
+            },
+            100,
+            usize::MAX,
+        )
+        .unwrap();
+    // Count is independent of how the entities are further wrapped by the context
+    let df = applied.df.limit(0, Some(1)).unwrap();
+    assert_eq!(df.count().await.unwrap(), 1);
+    assert_eq!(applied.count.unwrap().count().await.unwrap(), 4);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_apply_groupby_aggregate() {
+    let query = |format: Option<&str>| QueryParamsRaw {
+        select: None,
+        order_by: Some("province".to_string()),
+        skip: None,
+        skip_token: None,
+        top: Some(3),
+        filter: None,
+        expand: None,
+        format: format.map(str::to_string),
+        count: None,
+        inline_count: None,
+        apply: Some("groupby((province),aggregate(total_daily with sum as total))".to_string()),
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("covid19.canada").await),
+        axum::extract::Query(query(Some("csv"))),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *resp.body(),
+        "province,total\nAB,228044\nBC,144694\nON,533896\n"
+    );
+
+    // Feed describes the aggregated properties rather than the ones of the collection
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("covid19.canada").await),
+        axum::extract::Query(query(None)),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains("<d:total m:type=\"Edm.Int64\">"));
+    assert!(!resp.body().contains("<d:total_daily"));
+
+    // Groups are numbered in the order of their group-by values regardless of `$orderby`,
+    // and are entities of their own type that can't be linked to
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("covid19.canada").await),
+        axum::extract::Query(QueryParamsRaw {
+            order_by: Some("total desc".to_string()),
+            ..query(None)
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    let body = resp.body();
+    let ids: Vec<_> = body
+        .match_indices("<id>http://example.com/odata/covid19.canada#")
+        .map(|(i, _)| &body[i + 44..i + 45])
+        .collect();
+    assert_eq!(ids, ["4", "3", "1"]);
+    assert!(body.contains("term=\"default.covid19_canada_group\""));
+    assert!(!body.contains("href=\"covid19.canada("));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("covid19.canada").await),
+        axum::extract::Query(query(Some("json"))),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().starts_with(
+        r#"{"@odata.context":"http://example.com/odata/$metadata#Collection(default.covid19_canada_group)""#
+    ));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_apply_invalid_aggregate_is_bad_request() {
+    // Summing a string property is a valid expression that doesn't type check
+    let res = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("covid19.canada").await),
+        axum::extract::Query(QueryParamsRaw {
+            select: None,
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: Some("groupby((province),aggregate(province with sum as total))".to_string()),
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+    assert!(
+        matches!(res, Err(datafusion_odata::error::ODataError::BadRequest(_))),
+        "{res:?}"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_uint64_above_int64_range() {
+    let large = i64::MAX as u64 + 1;
+    let batch = || {
+        RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("offset", DataType::Int64, false),
+                Field::new("hash", DataType::UInt64, false),
+            ])),
+            vec![
+                Arc::new(Int64Array::from(vec![0, 1, 2])),
+                Arc::new(UInt64Array::from(vec![1, i64::MAX as u64, u64::MAX])),
+            ],
+        )
+        .unwrap()
+    };
+    let query = |filter: Option<&str>, format: Option<&str>| QueryParamsRaw {
+        select: None,
+        order_by: None,
+        skip: None,
+        skip_token: None,
+        top: None,
+        filter: filter.map(|f| f.parse().unwrap()),
+        expand: None,
+        format: format.map(str::to_string),
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    // Values are rendered exactly, even though they don't fit the advertised `Edm.Int64`
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("hashes", "hashes", batch()).await),
+        axum::extract::Query(query(None, None)),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains("<d:hash m:type=\"Edm.Int64\">18446744073709551615</d:hash>"));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("hashes", "hashes", batch()).await),
+        axum::extract::Query(query(None, None)),
+        accept("application/json"),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains("\"hash\":18446744073709551615"));
+
+    // Literals on both sides of the `i64::MAX` boundary are compared as unsigned
+    for (filter, expected) in [
+        (
+            format!("hash ge {large}"),
+            "offset,hash\n2,18446744073709551615\n",
+        ),
+        (
+            format!("hash lt {large}"),
+            "offset,hash\n0,1\n1,9223372036854775807\n",
+        ),
+        (
+            format!("hash eq {}", u64::MAX),
+            "offset,hash\n2,18446744073709551615\n",
+        ),
+        ("hash eq 1".to_string(), "offset,hash\n0,1\n"),
+    ] {
+        let resp = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(fixture_with_batch("hashes", "hashes", batch()).await),
+            axum::extract::Query(query(Some(&filter), Some("csv"))),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(*resp.body(), expected, "{filter}");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_filter_datetimeoffset() {
+    // 2023-01-01T09:00:00Z, 10:00 and 11:00
+    let hour = 3_600_000;
+    let ticks: Vec<i64> = (0..3).map(|i| 1672563600000 + i * hour).collect();
+    let batch = || {
+        RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("offset", DataType::Int64, false),
+                Field::new(
+                    "event_time",
+                    DataType::Timestamp(TimeUnit::Millisecond, Some("+05:30".into())),
+                    false,
+                ),
+                Field::new(
+                    "naive_time",
+                    DataType::Timestamp(TimeUnit::Microsecond, None),
+                    false,
+                ),
+            ])),
+            vec![
+                Arc::new(Int64Array::from(vec![0, 1, 2])),
+                Arc::new(TimestampMillisecondArray::from(ticks.clone()).with_timezone("+05:30")),
+                Arc::new(TimestampMicrosecondArray::from(
+                    ticks.iter().map(|t| t * 1000).collect::<Vec<_>>(),
+                )),
+            ],
+        )
+        .unwrap()
+    };
+
+    let query = |filter: &str| {
+        let query = QueryParamsRaw {
+            select: Some("offset".to_string()),
+            order_by: Some("offset".to_string()),
+            filter: Some(filter.parse().unwrap()),
+            format: Some("csv".to_string()),
+            ..Default::default()
+        };
+        async move {
+            datafusion_odata::handlers::odata_collection_handler(
+                axum::Extension(fixture_with_batch("events", "events", batch()).await),
+                axum::extract::Query(query),
+                axum::http::HeaderMap::new(),
+            )
+            .await
+        }
+    };
+
+    // Literals are compared as instants, regardless of the offsets they are written in,
+    // and timezone-naive columns are compared in UTC
+    for (filter, expected) in [
+        ("event_time gt 2023-01-01T12:00:00+02:00", "offset\n2\n"),
+        ("event_time ge 2023-01-01T10:00:00Z", "offset\n1\n2\n"),
+        ("event_time eq 2023-01-01T14:30:00+05:30", "offset\n0\n"),
+        ("naive_time lt 2023-01-01T12:00:00+02:00", "offset\n0\n"),
+        ("naive_time eq 2023-01-01T11:00:00Z", "offset\n2\n"),
+        // Literals finer than the unit of the column are rounded towards the values that
+        // the comparison accepts
+        ("event_time ge 2023-01-01T10:00:00.0005Z", "offset\n2\n"),
+        ("event_time gt 2023-01-01T09:59:59.9995Z", "offset\n1\n2\n"),
+        ("event_time lt 2023-01-01T10:00:00.0005Z", "offset\n0\n1\n"),
+        ("event_time le 2023-01-01T09:59:59.9995Z", "offset\n0\n"),
+        ("naive_time ge 2023-01-01T10:00:00.0000005Z", "offset\n2\n"),
+    ] {
+        let resp = query(filter).await.unwrap();
+        assert_eq!(*resp.body(), expected, "{filter}");
+    }
+
+    // No value of the column equals them
+    let err = query("event_time eq 2023-01-01T10:00:00.0005Z")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ODataError::BadRequest(_)), "{err:?}");
+    assert_eq!(
+        err.to_string(),
+        "Filter value 2023-01-01T10:00:00.000500Z is more precise than the Millisecond unit of column event_time"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_select_duplicate_columns() {
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset,close, offset,close".to_string()),
+            order_by: Some("offset".to_string()),
+            skip: None,
+            skip_token: None,
+            top: Some(2),
+            filter: None,
+            expand: None,
+            format: Some("csv".to_string()),
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "offset,close\n0,135.5625\n1,134.5937\n");
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct WritableContext {
+    inner: ODataContext,
+}
+
+focused_context!(WritableContext {
+    collection {
+        fn is_writable(&self) -> bool {
+            true
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_collection_read_only_links() {
+    let query = || QueryParamsRaw {
+        select: Some("close".to_string()),
+        order_by: None,
+        skip: None,
+        skip_token: None,
+        top: Some(1),
+        filter: None,
+        expand: None,
+        format: None,
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"<link rel="self" title="tickers.spy" href="tickers.spy(0)"/>"#));
+    assert!(!resp.body().contains(r#"rel="edit""#));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(WritableContext {
+            inner: context("tickers.spy").await,
+        })),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"<link rel="edit" title="tickers.spy" href="tickers.spy(0)"/>"#));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_atom_feed_size_estimate() {
+    let ctx = fixture("tickers.spy").await;
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx.clone()),
+        axum::extract::Query(QueryParamsRaw {
+            select: None,
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: Some(1000),
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+
+    let schema = datafusion_odata::context::CollectionContext::schema(ctx.as_ref())
+        .await
+        .unwrap();
+    let estimate = |num_rows| datafusion_odata::atom::estimate_atom_feed_size(&schema, num_rows);
+
+    // Buffer is allocated once
+    let actual = resp.body().len();
+    assert!(estimate(1000) >= actual, "{} < {actual}", estimate(1000));
+
+    // Every entry takes the same room, wider entries take more
+    assert_eq!(
+        estimate(2000) - estimate(1000),
+        estimate(1000) - estimate(0)
+    );
+    let narrow = schema.project(&[0]).unwrap();
+    assert!(datafusion_odata::atom::estimate_atom_feed_size(&narrow, 1000) < estimate(1000));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_wkb_point_as_geography() {
+    // POINT(-122.35 47.62)
+    let mut wkb = vec![1, 1, 0, 0, 0];
+    wkb.extend((-122.35f64).to_le_bytes());
+    wkb.extend(47.62f64.to_le_bytes());
+
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("location", DataType::Binary, true),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1, 2])),
+            // Last one is a LINESTRING, which is rendered as null rather than failing the feed
+            Arc::new(BinaryArray::from(vec![
+                Some(wkb.as_slice()),
+                None,
+                Some(&[1, 2, 0, 0, 0]),
+            ])),
+        ],
+    )
+    .unwrap();
+    let ctx = || {
+        Arc::new(
+            batch_context("stations", "stations", batch.clone()).with_property_override(
+                "location",
+                datafusion_odata::context::PropertyOverride {
+                    edm_type: Some(datafusion_odata::spatial::EDM_GEOGRAPHY_POINT.to_string()),
+                    ..Default::default()
+                },
+            ),
+        )
+    };
+    let query = || QueryParamsRaw {
+        select: None,
+        order_by: Some("offset".to_string()),
+        skip: None,
+        skip_token: None,
+        top: None,
+        filter: None,
+        expand: None,
+        format: None,
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx()))
+        .await
+        .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"<Property Name="location" Type="Edm.GeographyPoint" Nullable="true"/>"#));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<d:location m:type="Edm.GeographyPoint">"#,
+        r#"<gml:Point xmlns:gml="http://www.opengis.net/gml" "#,
+        r#"gml:srsName="http://www.opengis.net/def/crs/EPSG/0/4326">"#,
+        "<gml:pos>47.62 -122.35</gml:pos></gml:Point>",
+        "</d:location>",
+    )));
+    assert!(resp
+        .body()
+        .contains(r#"<d:location m:type="Edm.GeographyPoint">null</d:location>"#));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(query()),
+        accept("application/json"),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#""location":{"type":"Point","coordinates":[-122.35,47.62]}},"#,
+        r#"{"offset":1,"location":null},"#,
+        r#"{"offset":2,"location":null}"#,
+    )));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_schema_evolved_after_metadata() {
+    let batch = |columns: Vec<(&str, Vec<i64>)>| {
+        let fields = columns
+            .iter()
+            .map(|(name, _)| Field::new(*name, DataType::Int64, false))
+            .collect::<Vec<_>>();
+        let arrays = columns
+            .into_iter()
+            .map(|(_, values)| Arc::new(Int64Array::from(values)) as _)
+            .collect();
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays).unwrap()
+    };
+    let query_ctx = datafusion::prelude::SessionContext::new();
+    query_ctx
+        .register_batch(
+            "trades",
+            batch(vec![("offset", vec![0, 1]), ("price", vec![10, 11])]),
+        )
+        .unwrap();
+    let ctx = Arc::new(session_context("trades", query_ctx.clone()));
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx.clone()))
+        .await
+        .unwrap();
+    assert!(!resp.body().contains("volume"));
+
+    query_ctx.deregister_table("trades").unwrap();
+    query_ctx
+        .register_batch(
+            "trades",
+            batch(vec![
+                ("offset", vec![0, 1]),
+                ("price", vec![10, 11]),
+                ("volume", vec![100, 200]),
+            ]),
+        )
+        .unwrap();
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx.clone()),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"<d:volume m:type="Edm.Int64">200</d:volume>"#));
+
+    // Batches lacking some of the columns of the feed schema only skip those properties
+    let feed_schema = Schema::new(vec![
+        Field::new("offset", DataType::Int64, false),
+        Field::new("price", DataType::Int64, false),
+        Field::new("volume", DataType::Int64, false),
+    ]);
+    let ctx = session_context("trades", query_ctx).with_unaliased_key_column("offset");
+    let mut buf = Vec::new();
+    datafusion_odata::atom::write_atom_feed_from_records(
+        &feed_schema,
+        vec![batch(vec![("offset", vec![0]), ("price", vec![10])])],
+        &ctx,
+        chrono::Utc::now(),
+        None,
+        None,
+        false,
+        &mut quick_xml::Writer::new(&mut buf),
+    )
+    .unwrap();
+    let body = String::from_utf8(buf).unwrap();
+    assert!(body.contains(r#"<d:price m:type="Edm.Int64">10</d:price>"#));
+    assert!(!body.contains("volume"));
+
+    // Key of a different type is a mismatch rather than a missing key
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![Field::new(
+            "offset",
+            DataType::Utf8,
+            false,
+        )])),
+        vec![Arc::new(StringArray::from(vec!["0"]))],
+    )
+    .unwrap();
+    let err = datafusion_odata::atom::write_atom_feed_from_records(
+        &feed_schema,
+        vec![batch],
+        &ctx,
+        chrono::Utc::now(),
+        None,
+        None,
+        false,
+        &mut quick_xml::Writer::new(Vec::new()),
+    )
+    .unwrap_err();
+    assert!(
+        matches!(err, datafusion_odata::error::ODataError::BadRequest(_)),
+        "{err:?}"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_max_data_service_version() {
+    let query = |inline_count: Option<&str>| QueryParamsRaw {
+        select: Some("offset,close".to_string()),
+        top: Some(1),
+        inline_count: inline_count.map(str::to_string),
+        ..Default::default()
+    };
+
+    // v4 JSON is not available to v2 clients, so they get the verbose one
+    let mut headers = accept("application/json");
+    headers.insert("MaxDataServiceVersion", "2.0;NetFx".parse().unwrap());
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(query(None)),
+        headers,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        resp.headers()[http::header::CONTENT_TYPE],
+        datafusion_odata::handlers::MEDIA_TYPE_JSON_VERBOSE
+    );
+    assert_eq!(resp.headers()["DataServiceVersion"], "2.0;");
+    assert!(resp.body().starts_with(r#"{"d":"#));
+
+    // Projections can't be represented in v1
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert("MaxDataServiceVersion", "1.0".parse().unwrap());
+    let res = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(query(Some("allpages"))),
+        headers,
+    )
+    .await;
+    assert!(matches!(
+        res,
+        Err(datafusion_odata::error::ODataError::BadRequest(_))
+    ));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(query(None)),
+        accept("application/json"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.headers()["OData-Version"], "4.0");
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_null_keys() {
+    use datafusion_odata::{collection::KeyValue, context::OnNullKey};
+
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, true),
+            Field::new("name", DataType::Utf8, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![Some(1), None])),
+            Arc::new(StringArray::from(vec!["a", "b"])),
+        ],
+    )
+    .unwrap();
+
+    // Entities with a null key are identified as such by default, though can't be looked up
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("items", "items", batch.clone()).await),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.body().matches("<entry>").count(), 2);
+    assert!(resp.body().contains(r#"href="items(null)""#));
+
+    // Or skipped on request, in which case they aren't counted either
+    let skip = || {
+        Arc::new(batch_context("items", "items", batch.clone()).with_on_null_key(OnNullKey::Skip))
+    };
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(skip()),
+        axum::extract::Query(QueryParamsRaw {
+            inline_count: Some("allpages".to_string()),
+            ..Default::default()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.body().matches("<entry>").count(), 1);
+    assert!(resp.body().contains("<m:count>1</m:count>"));
+    assert!(resp.body().contains(r#"href="items(1)""#));
+    assert!(!resp.body().contains("null"));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(skip()),
+        axum::extract::Query(QueryParamsRaw {
+            inline_count: Some("allpages".to_string()),
+            ..Default::default()
+        }),
+        accept("application/json;odata=verbose"),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains(r#""__count":"1""#));
+    assert!(resp.body().contains(r#""name":"a""#));
+    assert!(!resp.body().contains(r#""name":"b""#));
+
+    // Or identified by the sentinel, which also looks them up
+    let sentinel = || OnNullKey::Sentinel(KeyValue::Int(-1));
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(
+            batch_context("items", "items", batch.clone()).with_on_null_key(sentinel()),
+        )),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.body().matches("<entry>").count(), 2);
+    assert!(resp.body().contains(r#"href="items(-1)""#));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(
+            batch_context("items(-1)", "items", batch).with_on_null_key(sentinel()),
+        )),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains(r#"href="items(-1)""#));
+    assert!(resp
+        .body()
+        .contains(r#"<d:name m:type="Edm.String">b</d:name>"#));
+
+    // Null literal is not a valid key
+    assert!(matches!(
+        CollectionAddr::decode("items(null)"),
+        Err(datafusion_odata::error::ODataError::BadRequest(_))
+    ));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct AtomContentTypeContext {
+    inner: ODataContext,
+}
+
+focused_context!(AtomContentTypeContext {
+    service {
+        fn content_type(&self, media_type: &str) -> String {
+            match media_type {
+                MEDIA_TYPE_ATOM => "application/atom+xml".to_string(),
+                _ => media_type.to_string(),
+            }
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_collection_custom_content_type() {
+    let ctx = || async {
+        Arc::new(AtomContentTypeContext {
+            inner: context("tickers.spy").await,
+        })
+    };
+    let query = || QueryParamsRaw {
+        top: Some(1),
+        ..Default::default()
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx().await),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        resp.headers()[http::header::CONTENT_TYPE],
+        "application/atom+xml"
+    );
+
+    // Other formats keep the default
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx().await),
+        axum::extract::Query(query()),
+        accept("application/json"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        resp.headers()[http::header::CONTENT_TYPE],
+        datafusion_odata::handlers::MEDIA_TYPE_JSON
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_updated_column() {
+    // 2024-09-11T00:00:00Z and a day later
+    let ticks = 1726012800000;
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new(
+                "system_time",
+                DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                true,
+            ),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1, 2])),
+            Arc::new(
+                TimestampMillisecondArray::from(vec![Some(ticks), Some(ticks + 86_400_000), None])
+                    .with_timezone("UTC"),
+            ),
+        ],
+    )
+    .unwrap();
+    let ctx = || {
+        Arc::new(
+            batch_context("events", "events", batch.clone()).with_updated_column("system_time"),
+        )
+    };
+    let query = |select: Option<&str>| QueryParamsRaw {
+        select: select.map(str::to_string),
+        order_by: Some("offset asc".to_string()),
+        ..Default::default()
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(query(None)),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    let updated: Vec<_> = resp
+        .body()
+        .match_indices("<updated>")
+        .map(|(i, _)| &resp.body()[i + 9..i + 33])
+        .collect();
+    // Feed itself, then the entries, the null one falling back to the collection time
+    assert_eq!(
+        updated,
+        [
+            "2023-01-01T00:00:00.000Z",
+            "2024-09-11T00:00:00.000Z",
+            "2024-09-12T00:00:00.000Z",
+            "2023-01-01T00:00:00.000Z",
+        ]
+    );
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(
+            batch_context("events(1)", "events", batch.clone()).with_updated_column("system_time"),
+        )),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains("<updated>2024-09-12T00:00:00.000Z</updated>"));
+
+    // Column is not available once projected out
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(query(Some("offset"))),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        resp.body()
+            .matches("<updated>2023-01-01T00:00:00.000Z</updated>")
+            .count(),
+        4
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct EtagContext {
+    inner: ODataContext,
+}
+
+focused_context!(EtagContext {
+    collection {
+        fn etag_column(&self) -> Option<String> {
+            Some("revision".to_string())
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_collection_etag_column() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("revision", DataType::Int64, true),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1])),
+            Arc::new(Int64Array::from(vec![Some(7), None])),
+        ],
+    )
+    .unwrap();
+    let query = |collection_elem: &'static str| {
+        let batch = batch.clone();
+        async move {
+            datafusion_odata::handlers::odata_collection_handler(
+                axum::Extension(Arc::new(EtagContext {
+                    inner: batch_context(collection_elem, "events", batch),
+                })),
+                axum::extract::Query(QueryParamsRaw {
+                    order_by: (collection_elem == "events").then(|| "offset".to_string()),
+                    ..Default::default()
+                }),
+                axum::http::HeaderMap::new(),
+            )
+            .await
+            .unwrap()
+            .into_body()
+        }
+    };
+
+    // The entity without a version has no etag
+    let body = query("events").await;
+    assert_eq!(
+        body.matches(r#"<entry m:etag="W/&quot;7&quot;">"#).count(),
+        1
+    );
+    assert_eq!(body.matches("<entry>").count(), 1);
+
+    let body = query("events(0)").await;
+    assert!(body.contains(r#"m:etag="W/&quot;7&quot;">"#));
+    let body = query("events(1)").await;
+    assert!(!body.contains("m:etag"));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_deferred_navigation_links() {
+    let query = |collection_elem: &'static str| async move {
+        datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(Arc::new(
+                context(collection_elem)
+                    .await
+                    .with_navigation_property("reports", "covid19.canada", true)
+                    .with_navigation_property("ticker", "tickers.spy", false),
+            )),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("offset".to_string()),
+                top: (collection_elem == "tickers.spy").then_some(2),
+                ..Default::default()
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+        .unwrap()
+        .into_body()
+    };
+
+    let links = |id: &str| {
+        format!(
+            concat!(
+                r#"<link rel="self" title="tickers.spy" href="tickers.spy({id})"/>"#,
+                r#"<link rel="http://schemas.microsoft.com/ado/2007/08/dataservices/related/reports" "#,
+                r#"type="application/atom+xml;type=feed" title="reports" href="tickers.spy({id})/reports"/>"#,
+                r#"<link rel="http://schemas.microsoft.com/ado/2007/08/dataservices/related/ticker" "#,
+                r#"type="application/atom+xml;type=entry" title="ticker" href="tickers.spy({id})/ticker"/>"#,
+                "<title/>",
+            ),
+            id = id
+        )
+    };
+
+    let body = query("tickers.spy").await;
+    assert!(body.contains(&links("0")));
+    assert!(body.contains(&links("1")));
+
+    let body = query("tickers.spy(1)").await;
+    assert!(body.contains(&links("1")));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_visible_columns() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, false),
+            Field::new("ssn", DataType::Utf8, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1])),
+            Arc::new(StringArray::from(vec!["alice", "bob"])),
+            Arc::new(StringArray::from(vec!["123-45-6789", "987-65-4321"])),
+        ],
+    )
+    .unwrap();
+    // Key column stays visible regardless
+    let ctx = || {
+        Arc::new(batch_context("people", "people", batch.clone()).with_visible_columns(&["name"]))
+    };
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx()))
+        .await
+        .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<Property Name="offset" Type="Edm.Int64" Nullable="false"/>"#,
+        r#"<Property Name="name" Type="Edm.String" Nullable="false"/>"#,
+        "</EntityType>",
+    )));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains("alice"));
+    assert!(!resp.body().contains("ssn"));
+    assert!(!resp.body().contains("123-45-6789"));
+
+    for query in [
+        QueryParamsRaw {
+            select: Some("name,ssn".to_string()),
+            ..Default::default()
+        },
+        QueryParamsRaw {
+            filter: Some("ssn eq '123-45-6789'".parse().unwrap()),
+            ..Default::default()
+        },
+        QueryParamsRaw {
+            order_by: Some("ssn".to_string()),
+            ..Default::default()
+        },
+    ] {
+        let err = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(ctx()),
+            axum::extract::Query(query),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, datafusion_odata::error::ODataError::BadRequest(_)),
+            "{err:?}"
+        );
+    }
+
+    // Errors name the offending property rather than listing all the columns
+    let err = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(QueryParamsRaw {
+            order_by: Some("age".to_string()),
+            ..Default::default()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.to_string(), "Property age not found");
+
+    let err = datafusion_odata::handlers::odata_property_value_handler(
+        axum::Extension(Arc::new(
+            batch_context("people(0)", "people", batch).with_visible_columns(&["name"]),
+        )),
+        axum::extract::Path("ssn".to_string()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap_err();
+    assert!(
+        matches!(err, datafusion_odata::error::ODataError::BadRequest(_)),
+        "{err:?}"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_allowed_query_options() {
+    use datafusion_odata::collection::QueryOption;
+
+    let ctx = || async {
+        Arc::new(
+            context("tickers.spy")
+                .await
+                .with_allowed_query_options(&[QueryOption::Select, QueryOption::Top]),
+        )
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx().await),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset".to_string()),
+            top: Some(1),
+            ..Default::default()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), http::StatusCode::OK);
+
+    let err = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx().await),
+        axum::extract::Query(QueryParamsRaw {
+            top: Some(1),
+            order_by: Some("close desc".to_string()),
+            inline_count: Some("allpages".to_string()),
+            ..Default::default()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        datafusion_odata::error::ODataError::BadRequest(_)
+    ));
+    assert_eq!(
+        err.to_string(),
+        "Query options not allowed for the collection: $orderby, $count"
+    );
+
+    // Server-driven paging only links to the pages the collection lets the clients request
+    let next_link = |options: &'static [QueryOption]| async move {
+        let mut headers = accept("application/json");
+        headers.insert("Prefer", "odata.maxpagesize=2".parse().unwrap());
+        let resp = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(Arc::new(
+                context("tickers.spy")
+                    .await
+                    .with_allowed_query_options(options),
+            )),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("offset".to_string()),
+                ..Default::default()
+            }),
+            headers,
+        )
+        .await
+        .unwrap();
+        let body: serde_json::Value = serde_json::from_str(resp.body()).unwrap();
+        body["@odata.nextLink"].as_str().map(str::to_string)
+    };
+    assert_eq!(
+        next_link(&[QueryOption::Select, QueryOption::Skip]).await,
+        Some("http://example.com/odata/tickers.spy?$select=offset&$skip=2".to_string())
+    );
+    assert_eq!(next_link(&[QueryOption::Select]).await, None);
+    assert!(next_link(&[QueryOption::Select, QueryOption::SkipToken])
+        .await
+        .unwrap()
+        .contains("$skiptoken="));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_prefer_omit_null_values() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("price", DataType::Float64, true),
+            Field::new("symbol", DataType::Utf8, true),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1])),
+            Arc::new(Float64Array::from(vec![Some(1.5), None])),
+            Arc::new(StringArray::from(vec![None, Some("spy")])),
+        ],
+    )
+    .unwrap();
+
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert("Prefer", "omit-values=nulls".parse().unwrap());
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("prices", "prices", batch.clone()).await),
+        axum::extract::Query(QueryParamsRaw {
+            order_by: Some("offset".to_string()),
+            ..Default::default()
+        }),
+        headers.clone(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.headers()["Preference-Applied"], "omit-values=nulls");
+    assert!(resp.body().contains(concat!(
+        r#"<m:properties><d:offset m:type="Edm.Int64">0</d:offset>"#,
+        r#"<d:price m:type="Edm.Double">1.5</d:price></m:properties>"#,
+    )));
+    assert!(resp.body().contains(concat!(
+        r#"<m:properties><d:offset m:type="Edm.Int64">1</d:offset>"#,
+        r#"<d:symbol m:type="Edm.String">spy</d:symbol></m:properties>"#,
+    )));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("prices(1)", "prices", batch.clone()).await),
+        axum::extract::Query(QueryParamsRaw::default()),
+        headers,
+    )
+    .await
+    .unwrap();
+    assert!(!resp.body().contains("d:price"));
+
+    // Nulls are kept by default
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("prices", "prices", batch).await),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(!resp.headers().contains_key("Preference-Applied"));
+    assert_eq!(resp.body().matches("null").count(), 2);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_prefer_include_type_annotations() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("price", DataType::Float64, true),
+            Field::new("symbol", DataType::Utf8, true),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0])),
+            Arc::new(Float64Array::from(vec![Some(1.5)])),
+            Arc::new(StringArray::from(vec![Some("spy")])),
+        ],
+    )
+    .unwrap();
+
+    let query = |prefer: Option<&'static str>, media_type: &'static str| {
+        let batch = batch.clone();
+        async move {
+            let mut headers = accept(media_type);
+            if let Some(prefer) = prefer {
+                headers.insert("Prefer", prefer.parse().unwrap());
+            }
+            datafusion_odata::handlers::odata_collection_handler(
+                axum::Extension(fixture_with_batch("prices", "prices", batch).await),
+                axum::extract::Query(QueryParamsRaw::default()),
+                headers,
+            )
+            .await
+            .unwrap()
+        }
+    };
+
+    let resp = query(
+        Some(r#"odata.include-annotations="*""#),
+        "application/json;odata.metadata=minimal",
+    )
+    .await;
+    assert_eq!(
+        resp.headers()["Preference-Applied"],
+        r#"odata.include-annotations="*""#
+    );
+    assert!(resp.body().contains(concat!(
+        r##"{"offset@odata.type":"#Int64","offset":0,"##,
+        r#""price":1.5,"symbol":"spy"}"#,
+    )));
+
+    // Excluding the odata namespace leaves nothing to annotate with
+    let resp = query(
+        Some(r#"odata.include-annotations="-odata.*,display.*""#),
+        "application/json;odata.metadata=minimal",
+    )
+    .await;
+    assert!(!resp.headers().contains_key("Preference-Applied"));
+    assert!(!resp.body().contains("@odata.type"));
+
+    let resp = query(
+        Some(r#"odata.include-annotations="*""#),
+        "application/json;odata=verbose",
+    )
+    .await;
+    assert!(!resp.headers().contains_key("Preference-Applied"));
+    assert!(!resp.body().contains("@odata.type"));
+
+    let resp = query(None, "application/json;odata.metadata=minimal").await;
+    assert!(!resp.headers().contains_key("Preference-Applied"));
+    assert!(!resp.body().contains("@odata.type"));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct ExpandLimitsContext {
+    inner: ODataContext,
+}
+
+focused_context!(ExpandLimitsContext {
+    collection {
+        fn max_expand_depth(&self) -> Option<usize> {
+            Some(2)
+        }
+
+        fn max_expand_breadth(&self) -> Option<usize> {
+            Some(3)
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_collection_expand_limits() {
+    let expand = |expand: &'static str| async move {
+        datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(Arc::new(ExpandLimitsContext {
+                inner: context("tickers.spy").await,
+            })),
+            axum::extract::Query(QueryParamsRaw {
+                expand: Some(expand.to_string()),
+                ..Default::default()
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+        .unwrap_err()
+        .to_string()
+    };
+
+    assert_eq!(
+        expand("Quotes($expand=Trades($expand=Orders))").await,
+        "$expand is nested 3 levels deep, at most 2 allowed"
+    );
+    assert_eq!(
+        expand("Quotes,Trades,Orders,Dividends").await,
+        "$expand includes 4 navigation properties, at most 3 allowed"
+    );
+
+    // Within the limits the navigation properties are looked up as usual
+    assert_eq!(
+        expand("Quotes($expand=Trades)").await,
+        "Navigation property not found: Quotes"
+    );
+}
+
+#[tokio::test]
+async fn test_collection_expand_declared_navigation_property() {
+    let expand = |expand: &'static str| async move {
+        datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(Arc::new(
+                context("tickers.spy")
+                    .await
+                    .with_navigation_property("Quotes", "quotes", true),
+            )),
+            axum::extract::Query(QueryParamsRaw {
+                expand: Some(expand.to_string()),
+                ..Default::default()
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+        .unwrap_err()
+    };
+
+    // Declared, but not expanded inline yet
+    let err = expand("Quotes").await;
+    assert_eq!(
+        err.to_string(),
+        "Unsupported feature: $expand of Quotes (planned)"
+    );
+    assert_eq!(
+        axum::response::IntoResponse::into_response(err).status(),
+        http::StatusCode::NOT_IMPLEMENTED
+    );
+
+    let err = expand("Trades").await;
+    assert_eq!(err.to_string(), "Navigation property not found: Trades");
+    assert_eq!(
+        axum::response::IntoResponse::into_response(err).status(),
+        http::StatusCode::BAD_REQUEST
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_conflicting_query_options() {
+    let query = |format: &'static str, headers| async move {
+        datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(fixture("tickers.spy").await),
+            axum::extract::Query(QueryParamsRaw {
+                top: Some(1),
+                format: Some(format.to_string()),
+                ..Default::default()
+            }),
+            headers,
+        )
+        .await
+    };
+
+    assert_eq!(
+        query("xml", accept("application/json"))
+            .await
+            .unwrap_err()
+            .to_string(),
+        "$format=xml conflicts with Accept: application/json"
+    );
+    assert_eq!(
+        query("json", accept("application/atom+xml, text/csv"))
+            .await
+            .unwrap_err()
+            .to_string(),
+        "$format=json conflicts with Accept: application/atom+xml, text/csv"
+    );
+
+    // Any listed format agreeing with $format, a wildcard or unknown media types are fine
+    for headers in [
+        accept("application/json;odata=verbose"),
+        accept("application/xml, application/json"),
+        accept("application/xml, */*"),
+        accept("text/html"),
+    ] {
+        let resp = query("json", headers).await.unwrap();
+        assert!(resp.body().starts_with(r#"{"@odata.context":"#));
+    }
+
+    let res = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(QueryParamsRaw {
+            count: Some(true),
+            inline_count: Some("none".to_string()),
+            ..Default::default()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+    assert!(matches!(
+        res,
+        Err(datafusion_odata::error::ODataError::BadRequest(_))
+    ));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// Chunks of the body of a collection served through a router by the streaming handler
+async fn stream_collection(
+    ctx: impl CollectionContext + 'static,
+    uri: &str,
+    headers: &[(&str, &str)],
+) -> Vec<String> {
+    use futures::StreamExt;
+    use tower::ServiceExt;
+
+    let ctx: Arc<dyn CollectionContext> = Arc::new(ctx);
+    let router = axum::Router::new()
+        .route(
+            "/tickers.spy",
+            axum::routing::get(datafusion_odata::handlers::odata_collection_stream_handler),
+        )
+        .layer(axum::Extension(ctx));
+    let mut req = http::Request::get(uri);
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+    let resp = router
+        .oneshot(req.body(axum::body::Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    resp.into_body()
+        .into_data_stream()
+        .map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+        .collect()
+        .await
+}
+
+#[tokio::test]
+async fn test_collection_stream_json() {
+    let query = || QueryParamsRaw {
+        select: Some("offset".to_string()),
+        top: Some(5),
+        count: Some(true),
+        ..Default::default()
+    };
+    let chunks = stream_collection(
+        context("tickers.spy").await.with_batch_size(2),
+        "/tickers.spy?$select=offset&$top=5&$count=true",
+        &[("Accept", "application/json")],
+    )
+    .await;
+
+    // Entities of every batch are sent as soon as it arrives
+    assert!(chunks.len() > 2, "{chunks:?}");
+    let body: serde_json::Value = serde_json::from_str(&chunks.concat()).unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "@odata.context": "http://example.com/odata/$metadata#tickers.spy",
+            "@odata.count": 6090,
+            "value": [
+                {"offset": 0},
+                {"offset": 1},
+                {"offset": 2},
+                {"offset": 3},
+                {"offset": 4},
+            ],
+        })
+    );
+
+    // Same document as the buffered one
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        axum::extract::Query(query()),
+        accept("application/json"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(chunks.concat(), *resp.body());
+
+    // Server-driven paging links to the next page once all the entities were written
+    let chunks = stream_collection(
+        context("tickers.spy").await.with_batch_size(2),
+        "/tickers.spy?$select=offset&$orderby=offset",
+        &[
+            ("Accept", "application/json;odata=verbose"),
+            ("Prefer", "odata.maxpagesize=3"),
+        ],
+    )
+    .await;
+    let body: serde_json::Value = serde_json::from_str(&chunks.concat()).unwrap();
+    assert_eq!(body["d"]["results"].as_array().unwrap().len(), 3);
+    let skip_token = SkipToken {
+        values: vec![Some("2".to_string())],
+    }
+    .encode();
+    assert_eq!(
+        body["d"]["__next"],
+        format!(
+            "http://example.com/odata/tickers.spy?$select=offset&$orderby=offset&$skiptoken={skip_token}"
+        )
+    );
+}
+
+struct FlushIntervalContext {
+    inner: ODataContext,
+}
+
+focused_context!(FlushIntervalContext {
+    collection {
+        fn flush_interval_rows(&self) -> Option<usize> {
+            Some(2)
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_collection_stream_atom_flushes() {
+    let chunks = stream_collection(
+        FlushIntervalContext {
+            inner: context("tickers.spy").await,
+        },
+        "/tickers.spy?$top=5",
+        &[("Accept", "application/atom+xml")],
+    )
+    .await;
+
+    // Entries 0-1 along with the header, 2-3, then 4 along with the closing tag
+    assert_eq!(
+        chunks
+            .iter()
+            .map(|chunk| chunk.matches("<entry>").count())
+            .collect::<Vec<_>>(),
+        [2, 2, 1]
+    );
+    assert!(chunks[0].starts_with("<?xml"));
+    assert!(chunks[1].starts_with("<entry>"));
+    assert!(chunks[2].ends_with("</entry></feed>"));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_service_base_url_trailing_slash() {
+    for service_base_url in ["http://example.com/odata", "http://example.com/odata/"] {
+        for (collection, accept) in [
+            ("tickers.spy", axum::http::HeaderMap::new()),
+            ("tickers.spy(1)", axum::http::HeaderMap::new()),
+            ("tickers.spy(1)", accept("application/json;odata=verbose")),
+        ] {
+            let resp = datafusion_odata::handlers::odata_collection_handler(
+                axum::Extension(Arc::new(
+                    context(collection)
+                        .await
+                        .with_service_base_url(service_base_url),
+                )),
+                axum::extract::Query(QueryParamsRaw {
+                    select: Some("offset".to_string()),
+                    top: (collection == "tickers.spy").then_some(2),
+                    ..Default::default()
+                }),
+                accept,
+            )
+            .await
+            .unwrap();
+
+            let body = resp.body();
+            assert!(
+                body.contains("http://example.com/odata/tickers.spy(1)"),
+                "{service_base_url}: {body}"
+            );
+            assert!(
+                !body.contains("odatatickers") && !body.contains("odata//"),
+                "{service_base_url}: {body}"
+            );
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct SelectIncludesKeyContext {
+    inner: ODataContext,
+}
+
+focused_context!(SelectIncludesKeyContext {
+    collection {
+        fn select_includes_key(&self) -> bool {
+            true
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_collection_select_includes_key() {
+    let query = |collection: &'static str| async move {
+        datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(Arc::new(SelectIncludesKeyContext {
+                inner: context(collection).await,
+            })),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("close".to_string()),
+                top: (collection == "tickers.spy").then_some(1),
+                ..Default::default()
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+        .unwrap()
+    };
+
+    let properties = concat!(
+        "<m:properties>",
+        r#"<d:close m:type="Edm.Double">134.5937</d:close>"#,
+        r#"<d:offset m:type="Edm.Int64">1</d:offset>"#,
+        "</m:properties>"
+    );
+    assert!(query("tickers.spy(1)").await.body().contains(properties));
+
+    let resp = query("tickers.spy").await;
+    assert!(resp
+        .body()
+        .contains(r#"<d:offset m:type="Edm.Int64">0</d:offset>"#));
+    assert!(!resp.body().contains("<d:open"));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct MaskingContext {
+    inner: ODataContext,
+}
+
+focused_context!(MaskingContext {
+    collection {
+        // Keeps the first character of the symbols only
+        fn transform_batch(&self, batch: RecordBatch) -> Result<RecordBatch, ODataError> {
+            let Ok(index) = batch.schema().index_of("from_symbol") else {
+                return Ok(batch);
+            };
+            let masked: StringArray = batch
+                .column(index)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap()
+                .iter()
+                .map(|v| v.map(|v| format!("{}**", &v[..1])))
+                .collect();
+            let mut columns = batch.columns().to_vec();
+            columns[index] = Arc::new(masked);
+            Ok(RecordBatch::try_new(batch.schema(), columns).unwrap())
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_collection_transform_batch() {
+    let query = |media_type: &'static str| async move {
+        datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(Arc::new(MaskingContext {
+                inner: context("tickers.spy").await,
+            })),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("offset,from_symbol".to_string()),
+                top: Some(2),
+                ..Default::default()
+            }),
+            accept(media_type),
+        )
+        .await
+        .unwrap()
+        .into_body()
+    };
+
+    let body = query("application/atom+xml").await;
+    assert_eq!(
+        body.matches(r#"<d:from_symbol m:type="Edm.String">s**</d:from_symbol>"#)
+            .count(),
+        2
+    );
+    assert!(!body.contains("spy</d:from_symbol>"));
+
+    let body = query("text/csv").await;
+    assert_eq!(body, "offset,from_symbol\n0,s**\n1,s**\n");
+
+    // Raw values are masked the same way
+    let resp = datafusion_odata::handlers::odata_property_value_handler(
+        axum::Extension(Arc::new(MaskingContext {
+            inner: context("tickers.spy(1)").await,
+        })),
+        axum::extract::Path("from_symbol".to_string()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(body.as_ref(), b"s**");
+
+    // Pages continue after the real values of the masked column
+    let page = |skip_token: Option<String>| async move {
+        let mut headers = accept("application/json");
+        headers.insert("Prefer", "odata.maxpagesize=2".parse().unwrap());
+        let body = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(Arc::new(MaskingContext {
+                inner: context("tickers.spy").await,
+            })),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("offset,from_symbol".to_string()),
+                order_by: Some("from_symbol".to_string()),
+                skip_token,
+                ..Default::default()
+            }),
+            headers,
+        )
+        .await
+        .unwrap()
+        .into_body();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let offsets: Vec<_> = body["value"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["offset"].as_i64().unwrap())
+            .collect();
+        let skip_token = body["@odata.nextLink"]
+            .as_str()
+            .map(|link| link.split_once("$skiptoken=").unwrap().1.to_string());
+        (offsets, skip_token)
+    };
+    let (offsets, skip_token) = page(None).await;
+    assert_eq!(offsets, [0, 1]);
+    let (offsets, _) = page(skip_token).await;
+    assert_eq!(offsets, [2, 3]);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_duplicate_field_names() {
+    // E.g. the output of a join of two tables with an `id` column each
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("id", DataType::Int64, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0])),
+            Arc::new(Int64Array::from(vec![1])),
+        ],
+    )
+    .unwrap();
+
+    let err = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("joined", "joined", batch.clone()).await),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        datafusion_odata::error::ODataError::UnsupportedFeature(_)
+    ));
+    assert_eq!(
+        err.to_string(),
+        "Unsupported feature: Duplicate field name id in the collection schema"
+    );
+
+    let err = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(
+        fixture_with_batch("joined", "joined", batch).await,
+    ))
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Unsupported feature: Duplicate field name id in the collection schema"
+    );
+}
@@ -0,0 +1,594 @@
+mod shared;
+
+use std::sync::Arc;
+
+use datafusion::arrow::{
+    array::{Decimal128Array, Int64Array, RecordBatch, TimestampMillisecondArray},
+    datatypes::{DataType, Field, Schema, TimeUnit},
+};
+use datafusion_odata::{collection::QueryParamsRaw, error::ODataError, filter::ODataFilter};
+use indoc::indoc;
+
+use shared::{
+    context, fixture, fixture_with_batch, focused_context, session_context, ODataContext,
+};
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn prices_batch() -> RecordBatch {
+    RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("price", DataType::Decimal128(10, 2), false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1, 2])),
+            Arc::new(
+                Decimal128Array::from(vec![9999, 10050, 10051])
+                    .with_precision_and_scale(10, 2)
+                    .unwrap(),
+            ),
+        ],
+    )
+    .unwrap()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_filter_decimal_by_fractional_literal() {
+    let ctx = fixture_with_batch("prices", "prices", prices_batch()).await;
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: None,
+            order_by: Some("offset asc".to_string()),
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: Some("price ge 100.5".parse().unwrap()),
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *resp.body(),
+        indoc!(
+            r#"
+            <?xml version="1.0" encoding="utf-8"?>
+            <feed
+             xml:base="http://example.com/odata/"
+             xmlns="http://www.w3.org/2005/Atom"
+             xmlns:d="http://schemas.microsoft.com/ado/2007/08/dataservices"
+             xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata">
+            <id>http://example.com/odata/prices</id>
+            <title type="text">prices</title>
+            <updated>2023-01-01T00:00:00.000Z</updated>
+            <link rel="self" title="prices" href="http://example.com/odata/prices"/>
+            <entry>
+            <id>http://example.com/odata/prices(1)</id>
+            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.prices"/>
+            <link rel="self" title="prices" href="prices(1)"/>
+            <title/>
+            <updated>2023-01-01T00:00:00.000Z</updated>
+            <author><name/></author>
+            <content type="application/xml">
+            <m:properties>
+            <d:offset m:type="Edm.Int64">1</d:offset>
+            <d:price m:type="Edm.Decimal">100.50</d:price>
+            </m:properties>
+            </content>
+            </entry>
+            <entry>
+            <id>http://example.com/odata/prices(2)</id>
+            <category scheme="http://schemas.microsoft.com/ado/2007/08/dataservices/scheme" term="default.prices"/>
+            <link rel="self" title="prices" href="prices(2)"/>
+            <title/>
+            <updated>2023-01-01T00:00:00.000Z</updated>
+            <author><name/></author>
+            <content type="application/xml">
+            <m:properties>
+            <d:offset m:type="Edm.Int64">2</d:offset>
+            <d:price m:type="Edm.Decimal">100.51</d:price>
+            </m:properties>
+            </content>
+            </entry>
+            </feed>
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_filter_decimal_json_keeps_scale() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("price", DataType::Decimal128(38, 10), false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1])),
+            Arc::new(
+                Decimal128Array::from(vec![12345678901234567890123456789, -5])
+                    .with_precision_and_scale(38, 10)
+                    .unwrap(),
+            ),
+        ],
+    )
+    .unwrap();
+
+    let ctx = fixture_with_batch("prices", "prices", batch).await;
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("price".to_string()),
+            order_by: Some("offset asc".to_string()),
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: Some("price ne 0".parse().unwrap()),
+            expand: None,
+            format: Some("json".to_string()),
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(
+        resp.body().contains(concat!(
+            r#""value":[{"price":1234567890123456789.0123456789},"#,
+            r#"{"price":-0.0000000005}]"#,
+        )),
+        "{}",
+        resp.body()
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_filter_decimal_by_finer_literal() {
+    let query = |filter: &str| {
+        let query = QueryParamsRaw {
+            select: Some("price".to_string()),
+            order_by: Some("offset asc".to_string()),
+            filter: Some(filter.parse().unwrap()),
+            format: Some("csv".to_string()),
+            ..Default::default()
+        };
+        async move {
+            datafusion_odata::handlers::odata_collection_handler(
+                axum::Extension(fixture_with_batch("prices", "prices", prices_batch()).await),
+                axum::extract::Query(query),
+                axum::http::HeaderMap::new(),
+            )
+            .await
+            .map(|resp| resp.into_body())
+        }
+    };
+
+    // Rounding the literal to the scale of the column either way would flip some of these
+    for (filter, prices) in [
+        ("price gt 100.505", "100.51\n"),
+        ("price ge 100.505", "100.51\n"),
+        ("price lt 100.505", "99.99\n100.50\n"),
+        ("price le 100.505", "99.99\n100.50\n"),
+        ("100.505 lt price", "100.51\n"),
+        ("price gt -0.001", "99.99\n100.50\n100.51\n"),
+        ("price lt 99.991", "99.99\n"),
+    ] {
+        assert_eq!(
+            query(filter).await.unwrap(),
+            format!("price\n{prices}"),
+            "{filter}"
+        );
+    }
+
+    for filter in [
+        "price eq 100.505",
+        "price ne 100.505",
+        "price in (100.5, 100.505)",
+    ] {
+        let err = query(filter).await.unwrap_err();
+        assert!(
+            matches!(err, ODataError::BadRequest(_)),
+            "{filter}: {err:?}"
+        );
+        assert_eq!(
+            err.to_string(),
+            "Filter value 100.505 has more fractional digits than the Decimal(10, 2) column price",
+        );
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_filter_decimal_literal_overflow() {
+    let ctx = fixture_with_batch("prices", "prices", prices_batch()).await;
+    let res = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: None,
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: Some("price eq 123456789012.5".parse().unwrap()),
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+    assert!(
+        matches!(res, Err(datafusion_odata::error::ODataError::BadRequest(_))),
+        "{res:?}"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_filter_to_sql() {
+    use datafusion::prelude::{col, lit};
+
+    let (expr, sql) =
+        datafusion_odata::filter::filter_to_sql("offset eq 1 and close gt 100").unwrap();
+    assert_eq!(
+        expr,
+        col("offset")
+            .eq(lit(1i64))
+            .and(col("close").gt(lit(100i64)))
+    );
+    assert_eq!(sql, "offset = Int64(1) AND close > Int64(100)");
+
+    let (_, sql) = datafusion_odata::filter::filter_to_sql("x gt 1 or (y lt 2)").unwrap();
+    assert_eq!(sql, "x > Int64(1) OR y < Int64(2)");
+
+    // `and` binds tighter than `or`, and `not` tighter than both
+    let (_, sql) =
+        datafusion_odata::filter::filter_to_sql("not a eq 1 or b ge -2 and c in (1e2, 'x')")
+            .unwrap();
+    assert_eq!(
+        sql,
+        "NOT a = Int64(1) OR b >= Int64(-2) AND c IN ([Int64(100), LargeUtf8(\"x\")])"
+    );
+
+    assert!(matches!(
+        datafusion_odata::filter::filter_to_sql("offset eq"),
+        Err(datafusion_odata::error::ODataError::BadRequest(_))
+    ));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_filter_date_functions() {
+    let query = |filter: &str| QueryParamsRaw {
+        select: Some("offset,reported_date".to_string()),
+        order_by: Some("offset asc".to_string()),
+        top: Some(3),
+        filter: Some(filter.parse().unwrap()),
+        format: Some("csv".to_string()),
+        ..Default::default()
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("covid19.canada").await),
+        axum::extract::Query(query(
+            "year(reported_date) eq 2020 and month(reported_date) eq 3 and day(reported_date) ge 10",
+        )),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *resp.body(),
+        indoc!(
+            "
+            offset,reported_date
+            74,2020-03-10T00:00:00Z
+            75,2020-03-10T00:00:00Z
+            76,2020-03-10T00:00:00Z
+            "
+        )
+    );
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("covid19.canada").await),
+        axum::extract::Query(query("year(reported_date) eq 2023")),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "offset,reported_date\n");
+
+    // All the data is from the past
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture("covid19.canada").await),
+        axum::extract::Query(query("reported_date gt now()")),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "offset,reported_date\n");
+
+    assert!(matches!(
+        datafusion_odata::filter::filter_to_sql("year(reported_date, 1) eq 2020"),
+        Err(datafusion_odata::error::ODataError::BadRequest(_))
+    ));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_filter_now_sub_duration() {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let hour = 3_600_000;
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new(
+                "event_time",
+                DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                false,
+            ),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1, 2])),
+            Arc::new(
+                TimestampMillisecondArray::from(vec![now - 48 * hour, now - hour, now + hour])
+                    .with_timezone("UTC"),
+            ),
+        ],
+    )
+    .unwrap();
+    let query = |filter: &str| {
+        let query = QueryParamsRaw {
+            select: Some("offset".to_string()),
+            order_by: Some("offset asc".to_string()),
+            filter: Some(filter.parse().unwrap()),
+            format: Some("csv".to_string()),
+            ..Default::default()
+        };
+        let batch = batch.clone();
+        async move {
+            datafusion_odata::handlers::odata_collection_handler(
+                axum::Extension(fixture_with_batch("events", "events", batch).await),
+                axum::extract::Query(query),
+                axum::http::HeaderMap::new(),
+            )
+            .await
+            .unwrap()
+            .into_body()
+        }
+    };
+
+    for (filter, expected) in [
+        ("event_time ge now() sub duration'P1D'", "offset\n1\n2\n"),
+        ("event_time lt now() add duration'-PT30M'", "offset\n0\n1\n"),
+        ("event_time sub duration'PT1H59.5S' gt now()", "offset\n"),
+        ("event_time add duration'P1DT1H' lt now()", "offset\n0\n"),
+    ] {
+        assert_eq!(query(filter).await, expected, "{filter}");
+    }
+
+    for filter in [
+        "duration'P'",
+        "duration'P1DT'",
+        "duration'1D'",
+        "duration'PT1.S'",
+    ] {
+        let res = format!("event_time ge now() sub {filter}").parse::<ODataFilter>();
+        assert!(matches!(res, Err(ODataError::BadRequest(_))), "{filter}");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_filter_unsupported_function_is_not_implemented() {
+    use axum::response::IntoResponse;
+
+    let uri: axum::http::Uri = "http://example.com/odata/prices?$filter=round(price)%20eq%20100"
+        .parse()
+        .unwrap();
+    let query = axum::extract::Query::<QueryParamsRaw>::try_from_uri(&uri).unwrap();
+
+    let ctx = fixture_with_batch("prices", "prices", prices_batch()).await;
+    let err = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        query,
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap_err();
+
+    let resp = err.into_response();
+    let status = resp.status();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(
+        std::str::from_utf8(&body).unwrap(),
+        "Unsupported feature: Function round() in $filter (planned)"
+    );
+    assert_eq!(status, http::StatusCode::NOT_IMPLEMENTED);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_filter_unknown_column_is_bad_request() {
+    use axum::response::IntoResponse;
+
+    let uri: axum::http::Uri =
+        "http://example.com/odata/prices?$filter=offset%20gt%200%20and%20prcie%20gt%20100"
+            .parse()
+            .unwrap();
+    let query = axum::extract::Query::<QueryParamsRaw>::try_from_uri(&uri).unwrap();
+
+    let ctx = fixture_with_batch("prices", "prices", prices_batch()).await;
+    let err = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        query,
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap_err();
+
+    let resp = err.into_response();
+    let status = resp.status();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(
+        std::str::from_utf8(&body).unwrap(),
+        "Property prcie referenced by $filter not found"
+    );
+    assert_eq!(status, http::StatusCode::BAD_REQUEST);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct InListSizeContext {
+    inner: ODataContext,
+}
+
+focused_context!(InListSizeContext {
+    collection {
+        fn max_in_list_size(&self) -> Option<usize> {
+            Some(3)
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_filter_in_list() {
+    let query = |filter: &'static str| async move {
+        datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(Arc::new(InListSizeContext {
+                inner: context("tickers.spy").await,
+            })),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("offset".to_string()),
+                filter: Some(filter.parse().unwrap()),
+                ..Default::default()
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+    };
+
+    let body = query("offset in (1, 3, 5)").await.unwrap().into_body();
+    assert_eq!(body.matches("<entry>").count(), 3);
+    for offset in [1, 3, 5] {
+        assert!(body.contains(&format!(
+            r#"<d:offset m:type="Edm.Int64">{offset}</d:offset>"#
+        )));
+    }
+
+    let err = query("offset gt 0 and offset in (1, 3, 5, 7)")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ODataError::BadRequest(_)), "{err:?}");
+    assert_eq!(
+        err.to_string(),
+        "Operator in of $filter has 4 values, more than the maximum of 3"
+    );
+
+    let err = query("offset in (1, 'spy')").await.unwrap_err();
+    assert!(matches!(err, ODataError::BadRequest(_)), "{err:?}");
+    assert_eq!(
+        err.to_string(),
+        "Values of operator in of $filter have mixed types: number, string"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct FilterFunctionContext {
+    inner: ODataContext,
+}
+
+focused_context!(FilterFunctionContext {
+    collection {
+        fn filter_functions(&self) -> Vec<String> {
+            vec!["double".to_string()]
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_filter_session_function() {
+    use datafusion::{
+        arrow::{array::AsArray, datatypes::Int64Type},
+        logical_expr::{create_udf, ColumnarValue, Volatility},
+    };
+
+    let double = create_udf(
+        "double",
+        vec![DataType::Int64],
+        Arc::new(DataType::Int64),
+        Volatility::Immutable,
+        Arc::new(|args: &[ColumnarValue]| {
+            let values = args[0].clone().into_array(1)?;
+            let doubled: Int64Array = values.as_primitive::<Int64Type>().unary(|v| v * 2);
+            Ok(ColumnarValue::Array(Arc::new(doubled)))
+        }),
+    );
+
+    let query_ctx = datafusion::prelude::SessionContext::new();
+    query_ctx.register_batch("prices", prices_batch()).unwrap();
+    query_ctx.register_udf(double);
+
+    let query = |filter: &str| QueryParamsRaw {
+        select: Some("offset".to_string()),
+        filter: Some(filter.parse().unwrap()),
+        format: Some("csv".to_string()),
+        ..Default::default()
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(FilterFunctionContext {
+            inner: session_context("prices", query_ctx.clone()),
+        })),
+        axum::extract::Query(query("double(offset) eq 4 or double(double(offset)) eq 0")),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "offset\n0\n2\n");
+
+    // Registered in the session, but not allowed
+    let err = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(session_context("prices", query_ctx))),
+        axum::extract::Query(query("double(offset) eq 4")),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Unsupported feature: Function double() in $filter (planned)"
+    );
+}
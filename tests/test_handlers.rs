@@ -1,17 +1,40 @@
 mod shared;
 
+use std::sync::Arc;
+
+use datafusion::arrow::{
+    array::{
+        BinaryArray, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray,
+        TimestampMillisecondArray,
+    },
+    datatypes::{DataType, Field, Schema, TimeUnit},
+};
 use indoc::indoc;
 
-use shared::fixture;
+use datafusion_odata::{
+    collection::QueryParamsRaw,
+    context::{
+        snake_case_to_pascal_case, MetadataCache, MetadataVersions, ODataVersion,
+        OnInvalidXmlChars, PropertyOverride, DESCRIPTION_METADATA_KEY, KEY_COLUMN_METADATA_KEY,
+    },
+    error::ODataError,
+};
+use shared::{
+    batch_context, context, empty_fixture, fixture, fixture_with_batch, focused_context,
+    session_context, ODataContext,
+};
 
 ///////////////////////////////////////////////////////////////////////////////
 
 #[tokio::test]
 async fn test_service() {
     let ctx = fixture("tickers.spy").await;
-    let resp = datafusion_odata::handlers::odata_service_handler(axum::Extension(ctx))
-        .await
-        .unwrap();
+    let resp = datafusion_odata::handlers::odata_service_handler(
+        axum::Extension(ctx),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
     assert_eq!(
         *resp.body(),
         indoc!(
@@ -38,6 +61,37 @@ async fn test_service() {
 
 ///////////////////////////////////////////////////////////////////////////////
 
+#[tokio::test]
+async fn test_service_json() {
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert(
+        axum::http::header::ACCEPT,
+        "application/json".parse().unwrap(),
+    );
+    let resp = datafusion_odata::handlers::odata_service_handler(
+        axum::Extension(fixture("tickers.spy").await),
+        headers,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/json;odata.metadata=minimal;charset=utf-8"
+    );
+    assert_eq!(resp.headers().get("odata-version").unwrap(), "4.0");
+    assert_eq!(
+        *resp.body(),
+        concat!(
+            r#"{"@odata.context":"http://example.com/odata/$metadata","value":["#,
+            r#"{"name":"covid19.canada","kind":"EntitySet","url":"covid19.canada"},"#,
+            r#"{"name":"tickers.spy","kind":"EntitySet","url":"tickers.spy"}]}"#,
+        )
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
 #[tokio::test]
 async fn test_metadata() {
     let ctx = fixture("tickers.spy").await;
@@ -52,21 +106,25 @@ async fn test_metadata() {
             <edmx:Edmx xmlns:edmx="http://schemas.microsoft.com/ado/2007/06/edmx" Version="1.0">
             <edmx:DataServices xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata" m:DataServiceVersion="3.0" m:MaxDataServiceVersion="3.0">
             <Schema Namespace="default" xmlns="http://schemas.microsoft.com/ado/2009/11/edm">
-            <EntityType Name="covid19.canada">
+            <EntityType Name="covid19_canada">
             <Key><PropertyRef Name="offset"/></Key>
             <Property Name="offset" Type="Edm.Int64" Nullable="false"/>
             <Property Name="op" Type="Edm.Int32" Nullable="false"/>
-            <Property Name="system_time" Type="Edm.DateTime" Nullable="false"/>
-            <Property Name="reported_date" Type="Edm.DateTime" Nullable="false"/>
+            <Property Name="system_time" Type="Edm.DateTimeOffset" Nullable="false"/>
+            <Property Name="reported_date" Type="Edm.DateTimeOffset" Nullable="false"/>
             <Property Name="province" Type="Edm.String" Nullable="false"/>
             <Property Name="total_daily" Type="Edm.Int64" Nullable="false"/>
             </EntityType>
-            <EntityType Name="tickers.spy">
+            <EntityType Name="covid19_canada_group" OpenType="true">
+            <Key><PropertyRef Name="__id__"/></Key>
+            <Property Name="__id__" Type="Edm.Int64" Nullable="false"/>
+            </EntityType>
+            <EntityType Name="tickers_spy">
             <Key><PropertyRef Name="offset"/></Key>
             <Property Name="offset" Type="Edm.Int64" Nullable="true"/>
             <Property Name="op" Type="Edm.Int32" Nullable="false"/>
-            <Property Name="system_time" Type="Edm.DateTime" Nullable="false"/>
-            <Property Name="event_time" Type="Edm.DateTime" Nullable="true"/>
+            <Property Name="system_time" Type="Edm.DateTimeOffset" Nullable="false"/>
+            <Property Name="event_time" Type="Edm.DateTimeOffset" Nullable="true"/>
             <Property Name="from_symbol" Type="Edm.String" Nullable="false"/>
             <Property Name="to_symbol" Type="Edm.String" Nullable="false"/>
             <Property Name="open" Type="Edm.Double" Nullable="true"/>
@@ -75,9 +133,840 @@ async fn test_metadata() {
             <Property Name="close" Type="Edm.Double" Nullable="true"/>
             <Property Name="volume" Type="Edm.Double" Nullable="true"/>
             </EntityType>
+            <EntityType Name="tickers_spy_group" OpenType="true">
+            <Key><PropertyRef Name="__id__"/></Key>
+            <Property Name="__id__" Type="Edm.Int64" Nullable="false"/>
+            </EntityType>
             <EntityContainer Name="default" m:IsDefaultEntityContainer="true">
-            <EntitySet Name="covid19.canada" EntityType="default.covid19.canada"/>
-            <EntitySet Name="tickers.spy" EntityType="default.tickers.spy"/>
+            <EntitySet Name="covid19.canada" EntityType="default.covid19_canada"/>
+            <EntitySet Name="tickers.spy" EntityType="default.tickers_spy"/>
+            </EntityContainer>
+            </Schema>
+            </edmx:DataServices>
+            </edmx:Edmx>
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_schema() {
+    let ctx = fixture("covid19.canada").await;
+    let resp = datafusion_odata::handlers::odata_collection_schema_handler(axum::Extension(ctx))
+        .await
+        .unwrap();
+    assert_eq!(
+        *resp.body(),
+        indoc!(
+            r#"
+            {"name":"covid19.canada","fields":[
+            {"name":"offset","arrow_type":"Int64","nullable":false,"edm_type":"Edm.Int64"},
+            {"name":"op","arrow_type":"Int32","nullable":false,"edm_type":"Edm.Int32"},
+            {"name":"system_time","arrow_type":"Timestamp(Millisecond, Some(\"UTC\"))","nullable":false,"edm_type":"Edm.DateTimeOffset"},
+            {"name":"reported_date","arrow_type":"Timestamp(Millisecond, Some(\"UTC\"))","nullable":false,"edm_type":"Edm.DateTimeOffset"},
+            {"name":"province","arrow_type":"Utf8","nullable":false,"edm_type":"Edm.String"},
+            {"name":"total_daily","arrow_type":"Int64","nullable":false,"edm_type":"Edm.Int64"}
+            ]}
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_metadata_property_overrides() {
+    let ctx = context("tickers.spy")
+        .await
+        .with_property_override(
+            "offset",
+            PropertyOverride {
+                nullable: Some(false),
+                ..Default::default()
+            },
+        )
+        .with_property_override(
+            "op",
+            PropertyOverride {
+                edm_type: Some("Edm.Int16".to_string()),
+                ..Default::default()
+            },
+        );
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(
+        std::sync::Arc::new(ctx),
+    ))
+    .await
+    .unwrap();
+    assert!(resp.body().contains(
+        &indoc!(
+            r#"
+            <EntityType Name="tickers_spy">
+            <Key><PropertyRef Name="offset"/></Key>
+            <Property Name="offset" Type="Edm.Int64" Nullable="false"/>
+            <Property Name="op" Type="Edm.Int16" Nullable="false"/>
+            "#
+        )
+        .replace('\n', "")
+    ));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_service_empty() {
+    let ctx = empty_fixture();
+    let resp = datafusion_odata::handlers::odata_service_handler(
+        axum::Extension(ctx),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    // An empty workspace is still a valid service document
+    assert_eq!(
+        *resp.body(),
+        indoc!(
+            r#"
+            <?xml version="1.0" encoding="utf-8"?>
+            <service xml:base="http://example.com/odata"
+             xmlns="http://www.w3.org/2007/app"
+             xmlns:atom="http://www.w3.org/2005/Atom">
+            <workspace>
+            <atom:title>default</atom:title>
+            </workspace>
+            </service>
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_metadata_empty() {
+    let ctx = empty_fixture();
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx))
+        .await
+        .unwrap();
+    assert_eq!(
+        *resp.body(),
+        indoc!(
+            r#"
+            <?xml version="1.0" encoding="utf-8"?>
+            <edmx:Edmx xmlns:edmx="http://schemas.microsoft.com/ado/2007/06/edmx" Version="1.0">
+            <edmx:DataServices xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata" m:DataServiceVersion="3.0" m:MaxDataServiceVersion="3.0">
+            <Schema Namespace="default" xmlns="http://schemas.microsoft.com/ado/2009/11/edm">
+            <EntityContainer Name="default" m:IsDefaultEntityContainer="true"/>
+            </Schema>
+            </edmx:DataServices>
+            </edmx:Edmx>
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_key_column_from_metadata() {
+    let schema_with_key = |schema: Schema| {
+        schema.with_metadata(
+            [(KEY_COLUMN_METADATA_KEY.to_string(), "id".to_string())]
+                .into_iter()
+                .collect(),
+        )
+    };
+    let batch = RecordBatch::try_new(
+        Arc::new(schema_with_key(Schema::new(vec![
+            Field::new("name", DataType::Utf8, false),
+            Field::new("id", DataType::Int64, false),
+        ]))),
+        vec![
+            Arc::new(StringArray::from(vec!["a", "b"])),
+            Arc::new(Int64Array::from(vec![10, 20])),
+        ],
+    )
+    .unwrap();
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(
+        fixture_with_batch("things", "things", batch.clone()).await,
+    ))
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"<EntityType Name="things"><Key><PropertyRef Name="id"/></Key>"#));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("things(20)", "things", batch).await),
+        axum::extract::Query(QueryParamsRaw {
+            select: None,
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains("<id>http://example.com/odata/things(20)</id>"));
+    assert!(resp
+        .body()
+        .contains(r#"<d:name m:type="Edm.String">b</d:name>"#));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_key_column_fallback_skips_unsupported_types() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("active", DataType::Boolean, false),
+            Field::new("id", DataType::Int64, false),
+        ])),
+        vec![
+            Arc::new(BooleanArray::from(vec![true, false])),
+            Arc::new(Int64Array::from(vec![10, 20])),
+        ],
+    )
+    .unwrap();
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(
+        fixture_with_batch("things", "things", batch).await,
+    ))
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"<EntityType Name="things"><Key><PropertyRef Name="id"/></Key>"#));
+
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![Field::new(
+            "active",
+            DataType::Boolean,
+            false,
+        )])),
+        vec![Arc::new(BooleanArray::from(vec![true, false]))],
+    )
+    .unwrap();
+    let err = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(fixture_with_batch("things", "things", batch).await),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap_err();
+    assert!(
+        matches!(err, ODataError::KeyColumnNotAssigned(_)),
+        "{err:?}"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_composite_key_columns() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("region", DataType::Utf8, false),
+            Field::new("id", DataType::Int64, false),
+            Field::new("amount", DataType::Int64, false),
+        ])),
+        vec![
+            Arc::new(StringArray::from(vec!["US", "EU", "EU"])),
+            Arc::new(Int64Array::from(vec![1, 2, 1])),
+            Arc::new(Int64Array::from(vec![30, 20, 10])),
+        ],
+    )
+    .unwrap();
+    let ctx = |addr: &str| {
+        Arc::new(batch_context(addr, "sales", batch.clone()).with_key_columns(&["region", "id"]))
+    };
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx("sales")))
+        .await
+        .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<EntityType Name="sales">"#,
+        r#"<Key><PropertyRef Name="region"/><PropertyRef Name="id"/></Key>"#,
+    )));
+
+    // Key columns are kept in the entities regardless of `$select`
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx("sales")),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("amount".to_string()),
+            top: Some(2),
+            ..Default::default()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    let hrefs: Vec<_> = resp
+        .body()
+        .match_indices(r#"<link rel="self" title="sales" href="sales("#)
+        .map(|(i, m)| {
+            let href = &resp.body()[i + m.len() - "sales(".len()..];
+            &href[..href.find('"').unwrap()]
+        })
+        .collect();
+    assert_eq!(
+        hrefs,
+        [
+            "sales(region=&apos;EU&apos;,id=1)",
+            "sales(region=&apos;EU&apos;,id=2)"
+        ]
+    );
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx("sales(region='US',id=1)")),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"href="sales(region=&apos;US&apos;,id=1)""#));
+    assert!(resp
+        .body()
+        .contains(r#"<d:amount m:type="Edm.Int64">30</d:amount>"#));
+
+    // Keys addressing several entities
+    for (addr, message) in [
+        (
+            "sales(region='US')",
+            "Key requires the property id exactly once",
+        ),
+        (
+            "sales(region='US',id=1,id=2)",
+            "Key requires the property id exactly once",
+        ),
+        ("sales(1)", "Key requires the properties region, id"),
+    ] {
+        let res = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(ctx(addr)),
+            axum::extract::Query(QueryParamsRaw::default()),
+            axum::http::HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(res, Err(ODataError::BadRequest(_))), "{addr}");
+        assert_eq!(res.unwrap_err().to_string(), message);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct FieldDocumentationContext {
+    inner: ODataContext,
+}
+
+focused_context!(FieldDocumentationContext {
+    service {
+        fn include_field_documentation(&self) -> bool {
+            true
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_metadata_field_documentation() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("close", DataType::Int64, false).with_metadata(
+                [(
+                    DESCRIPTION_METADATA_KEY.to_string(),
+                    "Closing price in USD".to_string(),
+                )]
+                .into_iter()
+                .collect(),
+            ),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0])),
+            Arc::new(Int64Array::from(vec![138])),
+        ],
+    )
+    .unwrap();
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(
+        batch_context("prices", "prices", batch.clone()),
+    )))
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"<Property Name="close" Type="Edm.Int64" Nullable="false"/>"#));
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(
+        FieldDocumentationContext {
+            inner: batch_context("prices", "prices", batch),
+        },
+    )))
+    .await
+    .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<Property Name="offset" Type="Edm.Int64" Nullable="false"/>"#,
+        r#"<Property Name="close" Type="Edm.Int64" Nullable="false">"#,
+        "<Documentation><Summary>Closing price in USD</Summary></Documentation>",
+        "</Property>",
+    )));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_metadata_entity_type_name() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("buyer", DataType::Utf8, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0])),
+            Arc::new(StringArray::from(vec!["alice"])),
+        ],
+    )
+    .unwrap();
+    let ctx = || {
+        Arc::new(batch_context("Orders", "Orders", batch.clone()).with_entity_type_name("Order"))
+    };
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx()))
+        .await
+        .unwrap();
+    assert!(resp.body().contains(r#"<EntityType Name="Order">"#));
+    assert!(resp
+        .body()
+        .contains(r#"<EntitySet Name="Orders" EntityType="default.Order"/>"#));
+
+    // Entities refer to the type, while their links point to the set
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(QueryParamsRaw {
+            select: None,
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains(r#"term="default.Order""#));
+    assert!(resp
+        .body()
+        .contains(r#"<link rel="self" title="Orders" href="Orders(0)"/>"#));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_pascal_case_property_names() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("trade_id", DataType::Int64, false),
+            Field::new("from_symbol", DataType::Utf8, false),
+            Field::new("close_price", DataType::Int64, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1, 2])),
+            Arc::new(StringArray::from(vec!["spy", "qqq", "dia"])),
+            Arc::new(Int64Array::from(vec![90, 135, 120])),
+        ],
+    )
+    .unwrap();
+    let ctx = || {
+        Arc::new(
+            batch_context("trades", "trades", batch.clone())
+                .with_property_name_mapper(Arc::new(snake_case_to_pascal_case)),
+        )
+    };
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx()))
+        .await
+        .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<EntityType Name="trades"><Key><PropertyRef Name="TradeId"/></Key>"#,
+        r#"<Property Name="TradeId" Type="Edm.Int64" Nullable="false"/>"#,
+        r#"<Property Name="FromSymbol" Type="Edm.String" Nullable="false"/>"#,
+        r#"<Property Name="ClosePrice" Type="Edm.Int64" Nullable="false"/>"#,
+    )));
+
+    let query = |format: Option<&str>| QueryParamsRaw {
+        select: Some("FromSymbol,ClosePrice".to_string()),
+        order_by: Some("ClosePrice desc".to_string()),
+        skip: None,
+        skip_token: None,
+        top: None,
+        filter: Some("ClosePrice gt 100".parse().unwrap()),
+        expand: None,
+        format: format.map(str::to_string),
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(query(Some("csv"))),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "FromSymbol,ClosePrice\nqqq,135\ndia,120\n");
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(query(None)),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<d:FromSymbol m:type="Edm.String">qqq</d:FromSymbol>"#,
+        r#"<d:ClosePrice m:type="Edm.Int64">135</d:ClosePrice>"#,
+    )));
+    assert!(!resp.body().contains("from_symbol"));
+
+    // Columns are only known by their property names
+    let err = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(QueryParamsRaw {
+            filter: Some("close_price gt 100".parse().unwrap()),
+            ..Default::default()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(err, ODataError::BadRequest(_)), "{err:?}");
+
+    // Parts of a composite key are named after the properties too
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(
+            batch_context(
+                "trades(TradeId=1,FromSymbol='qqq')",
+                "trades",
+                batch.clone(),
+            )
+            .with_key_columns(&["trade_id", "from_symbol"])
+            .with_property_name_mapper(Arc::new(snake_case_to_pascal_case)),
+        )),
+        axum::extract::Query(QueryParamsRaw::default()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"<d:ClosePrice m:type="Edm.Int64">135</d:ClosePrice>"#));
+
+    // Properties can't share a name
+    let err = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(
+        batch_context("trades", "trades", batch.clone()).with_property_name_mapper(Arc::new(
+            |name: &str| match name {
+                "trade_id" => "Id".to_string(),
+                _ => "Value".to_string(),
+            },
+        )),
+    )))
+    .await
+    .unwrap_err();
+    assert!(matches!(err, ODataError::Internal(_)), "{err:?}");
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_derived_columns() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("open", DataType::Int64, false),
+            Field::new("close", DataType::Int64, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1, 2])),
+            Arc::new(Int64Array::from(vec![100, 110, 105])),
+            Arc::new(Int64Array::from(vec![110, 105, 120])),
+        ],
+    )
+    .unwrap();
+    let ctx = || {
+        Arc::new(
+            batch_context("tickers", "tickers", batch.clone())
+                .with_derived_column("change", "close - open"),
+        )
+    };
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx()))
+        .await
+        .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<Property Name="close" Type="Edm.Int64" Nullable="false"/>"#,
+        r#"<Property Name="change" Type="Edm.Int64" Nullable="false"/>"#,
+        r#"</EntityType>"#,
+    )));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset,change".to_string()),
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: Some("change gt 0".parse().unwrap()),
+            expand: None,
+            format: Some("csv".to_string()),
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "offset,change\n0,10\n2,15\n");
+}
+
+#[tokio::test]
+async fn test_derived_column_with_session_function() {
+    use datafusion::{
+        arrow::{array::AsArray, datatypes::Int64Type},
+        logical_expr::{create_udf, ColumnarValue, Volatility},
+    };
+
+    let midpoint = create_udf(
+        "midpoint",
+        vec![DataType::Int64, DataType::Int64],
+        Arc::new(DataType::Float64),
+        Volatility::Immutable,
+        Arc::new(|args: &[ColumnarValue]| {
+            let args = ColumnarValue::values_to_arrays(args)?;
+            let (a, b) = (
+                args[0].as_primitive::<Int64Type>(),
+                args[1].as_primitive::<Int64Type>(),
+            );
+            let values: Float64Array = a
+                .iter()
+                .zip(b.iter())
+                .map(|(a, b)| Some((a? + b?) as f64 / 2.0))
+                .collect();
+            Ok(ColumnarValue::Array(Arc::new(values)))
+        }),
+    );
+
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("open", DataType::Int64, false),
+            Field::new("close", DataType::Int64, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1])),
+            Arc::new(Int64Array::from(vec![100, 110])),
+            Arc::new(Int64Array::from(vec![110, 105])),
+        ],
+    )
+    .unwrap();
+    let query_ctx = datafusion::prelude::SessionContext::new();
+    query_ctx.register_udf(midpoint);
+    query_ctx.register_batch("tickers", batch).unwrap();
+    let ctx = || {
+        Arc::new(
+            session_context("tickers", query_ctx.clone())
+                .with_derived_column("mid", "midpoint(open, close)"),
+        )
+    };
+
+    // Functions of the collection's session are known to `$metadata` too
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx()))
+        .await
+        .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"<Property Name="mid" Type="Edm.Double" Nullable="true"/>"#));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx()),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset,mid".to_string()),
+            format: Some("csv".to_string()),
+            ..Default::default()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "offset,mid\n0,105.0\n1,107.5\n");
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_binary_value_range() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("content", DataType::Binary, true),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1])),
+            Arc::new(BinaryArray::from(vec![
+                b"hello".as_ref(),
+                b"world".as_ref(),
+            ])),
+        ],
+    )
+    .unwrap();
+    let value = |range: Option<&str>| {
+        let mut headers = axum::http::HeaderMap::new();
+        if let Some(range) = range {
+            headers.insert(http::header::RANGE, range.parse().unwrap());
+        }
+        datafusion_odata::handlers::odata_property_value_handler(
+            axum::Extension(Arc::new(batch_context("files(1)", "files", batch.clone()))),
+            axum::extract::Path("content".to_string()),
+            headers,
+        )
+    };
+    let body = |resp: axum::response::Response<axum::body::Body>| async {
+        axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap()
+    };
+
+    let resp = value(None).await.unwrap();
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    assert_eq!(resp.headers()[http::header::ACCEPT_RANGES], "bytes");
+    assert_eq!(body(resp).await.as_ref(), b"world");
+
+    let resp = value(Some("bytes=0-1")).await.unwrap();
+    assert_eq!(resp.status(), http::StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.headers()[http::header::CONTENT_RANGE], "bytes 0-1/5");
+    assert_eq!(body(resp).await.as_ref(), b"wo");
+
+    let resp = value(Some("bytes=-3")).await.unwrap();
+    assert_eq!(resp.headers()[http::header::CONTENT_RANGE], "bytes 2-4/5");
+    assert_eq!(body(resp).await.as_ref(), b"rld");
+
+    let resp = value(Some("bytes=5-")).await.unwrap();
+    assert_eq!(resp.status(), http::StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(resp.headers()[http::header::CONTENT_RANGE], "bytes */5");
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct MetadataVersionsContext {
+    inner: ODataContext,
+}
+
+focused_context!(MetadataVersionsContext {
+    service {
+        fn metadata_versions(&self) -> MetadataVersions {
+            MetadataVersions {
+                edmx: "1.1".to_string(),
+                data_service: "2.0".to_string(),
+                max_data_service: "2.0".to_string(),
+            }
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_metadata_versions() {
+    let ctx = Arc::new(MetadataVersionsContext {
+        inner: context("tickers.spy").await,
+    });
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx))
+        .await
+        .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<edmx:Edmx xmlns:edmx="http://schemas.microsoft.com/ado/2007/06/edmx" Version="1.1">"#,
+        r#"<edmx:DataServices xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata" "#,
+        r#"m:DataServiceVersion="2.0" m:MaxDataServiceVersion="2.0">"#,
+    )));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_metadata_v4() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new(
+                "event_time",
+                DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                true,
+            ),
+            Field::new("symbol", DataType::Utf8, true).with_metadata(
+                [(DESCRIPTION_METADATA_KEY.to_string(), "Ticker".to_string())]
+                    .into_iter()
+                    .collect(),
+            ),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0])),
+            Arc::new(TimestampMillisecondArray::from(vec![0]).with_timezone("UTC")),
+            Arc::new(StringArray::from(vec!["spy"])),
+        ],
+    )
+    .unwrap();
+
+    let ctx = FieldDocumentationContext {
+        inner: batch_context("tickers", "tickers", batch).with_odata_version(ODataVersion::V4),
+    };
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(ctx)))
+        .await
+        .unwrap();
+    assert_eq!(
+        *resp.body(),
+        indoc!(
+            r#"
+            <?xml version="1.0" encoding="utf-8"?>
+            <edmx:Edmx xmlns:edmx="http://docs.oasis-open.org/odata/ns/edmx" Version="4.0">
+            <edmx:DataServices>
+            <Schema Namespace="default" xmlns="http://docs.oasis-open.org/odata/ns/edm">
+            <EntityType Name="tickers">
+            <Key><PropertyRef Name="offset"/></Key>
+            <Property Name="offset" Type="Edm.Int64" Nullable="false"/>
+            <Property Name="event_time" Type="Edm.DateTimeOffset"/>
+            <Property Name="symbol" Type="Edm.String">
+            <Annotation Term="Org.OData.Core.V1.Description" String="Ticker"/>
+            </Property>
+            </EntityType>
+            <EntityType Name="tickers_group" OpenType="true">
+            <Key><PropertyRef Name="__id__"/></Key>
+            <Property Name="__id__" Type="Edm.Int64" Nullable="false"/>
+            </EntityType>
+            <EntityContainer Name="default">
+            <EntitySet Name="tickers" EntityType="default.tickers"/>
             </EntityContainer>
             </Schema>
             </edmx:DataServices>
@@ -87,3 +976,483 @@ async fn test_metadata() {
         .replace('\n', "")
     );
 }
+
+#[tokio::test]
+async fn test_metadata_v4_nullable_default() {
+    let ctx = context("covid19.canada")
+        .await
+        .with_odata_version(ODataVersion::V4);
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(ctx)))
+        .await
+        .unwrap();
+    let body = resp.into_body();
+    assert!(!body.contains(r#"Nullable="true""#));
+    assert!(body.contains(r#"<Property Name="close" Type="Edm.Double"/>"#));
+    assert!(body.contains(r#"<Property Name="province" Type="Edm.String" Nullable="false"/>"#));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct PrettyPrintContext {
+    inner: ODataContext,
+}
+
+focused_context!(PrettyPrintContext {
+    service {
+        fn pretty_print_xml(&self) -> bool {
+            true
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_pretty_print_xml() {
+    let ctx = || async {
+        Arc::new(PrettyPrintContext {
+            inner: context("tickers.spy").await,
+        })
+    };
+
+    let resp = datafusion_odata::handlers::odata_service_handler(
+        axum::Extension(ctx().await),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains("<workspace>\n    <atom:title>default</atom:title>"));
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx().await))
+        .await
+        .unwrap();
+    assert!(resp
+        .body()
+        .contains("<Key>\n          <PropertyRef Name=\"offset\"/>\n        </Key>"));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx().await),
+        axum::extract::Query(QueryParamsRaw {
+            top: Some(1),
+            ..Default::default()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains("\n      <m:properties>\n        <d:offset m:type=\"Edm.Int64\">0</d:offset>\n"));
+
+    // Compact by default
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(
+        fixture("tickers.spy").await,
+    ))
+    .await
+    .unwrap();
+    assert!(!resp.body().contains('\n'));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_entity_ref() {
+    use datafusion_odata::handlers::EntityRefParams;
+
+    let entity_ref = |id: Option<&'static str>| async move {
+        let ctx = context("tickers.spy")
+            .await
+            .with_service_base_url("http://example.com/odata/");
+        datafusion_odata::handlers::odata_entity_ref_handler(
+            axum::Extension(Arc::new(ctx)),
+            axum::extract::Query(EntityRefParams {
+                id: id.map(str::to_string),
+            }),
+            axum::extract::Query(QueryParamsRaw {
+                select: Some("offset,close".to_string()),
+                format: Some("json".to_string()),
+                ..Default::default()
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+    };
+
+    for id in ["http://example.com/odata/tickers.spy(1)", "tickers.spy(1)"] {
+        let resp = entity_ref(Some(id)).await.unwrap();
+        assert_eq!(
+            *resp.body(),
+            indoc!(
+                r#"
+                {
+                "@odata.context":"http://example.com/odata/$metadata#tickers.spy/$entity",
+                "offset":1,
+                "close":134.5937
+                }
+                "#
+            )
+            .replace('\n', "")
+        );
+    }
+
+    for id in [
+        None,
+        Some("http://example.org/odata/tickers.spy(1)"),
+        Some("http://example.com/odatatickers.spy(1)"),
+        Some("http://example.com/odata2/tickers.spy(1)"),
+        Some("tickers.spy"),
+    ] {
+        assert!(
+            matches!(
+                entity_ref(id).await,
+                Err(datafusion_odata::error::ODataError::BadRequest(_))
+            ),
+            "{id:?}"
+        );
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_metadata_dotted_collection_name() {
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(
+        fixture("tickers.spy").await,
+    ))
+    .await
+    .unwrap();
+    let body = resp.body();
+
+    let entity_set = r#"<EntitySet Name="tickers.spy" EntityType=""#;
+    let start = body.find(entity_set).unwrap() + entity_set.len();
+    let entity_type = &body[start..start + body[start..].find('"').unwrap()];
+
+    // Clients take everything up to the last dot for the namespace
+    let (namespace, type_name) = entity_type.rsplit_once('.').unwrap();
+    assert_eq!(namespace, "default");
+    assert_eq!(type_name, "tickers_spy");
+    assert!(body.contains(r#"<Schema Namespace="default""#));
+    assert!(body.contains(r#"<EntityType Name="tickers_spy">"#));
+}
+
+#[tokio::test]
+async fn test_metadata_entity_type_name_collision() {
+    use datafusion::{datasource::MemTable, prelude::SessionContext, sql::TableReference};
+
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![Field::new(
+            "offset",
+            DataType::Int64,
+            false,
+        )])),
+        vec![Arc::new(Int64Array::from(vec![0]))],
+    )
+    .unwrap();
+    let query_ctx = SessionContext::new();
+    let register = |name: &str| {
+        let table = MemTable::try_new(batch.schema(), vec![vec![batch.clone()]]).unwrap();
+        query_ctx
+            .register_table(TableReference::bare(name), Arc::new(table))
+            .unwrap();
+    };
+
+    // Identifiers can't start with a digit
+    register("2024.sales");
+    register("a_b");
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(
+        session_context("a_b", query_ctx.clone()),
+    )))
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains(r#"<EntitySet Name="2024.sales" EntityType="default._2024_sales"/>"#));
+    assert!(resp.body().contains(r#"<EntityType Name="a_b">"#));
+
+    // Simplified names of the distinct collections would declare the same type twice
+    register("a.b");
+    let res = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(
+        session_context("a_b", query_ctx),
+    )))
+    .await;
+    assert!(matches!(res, Err(ODataError::Internal(_))));
+    assert!(format!("{res:?}").contains("Collections a.b and a_b both declare entity type a_b"));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_functions() {
+    let ctx = || async {
+        context("tickers.spy").await.with_function(
+            "GetFirstTicks",
+            &[("count", "Edm.Int64")],
+            "tickers.spy",
+            r#"SELECT * FROM "tickers.spy" WHERE "offset" < $1"#,
+        )
+    };
+
+    let resp =
+        datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(ctx().await)))
+            .await
+            .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<FunctionImport Name="GetFirstTicks" EntitySet="tickers.spy" "#,
+        r#"ReturnType="Collection(default.tickers_spy)" m:HttpMethod="GET">"#,
+        r#"<Parameter Name="count" Type="Edm.Int64" Mode="In"/>"#,
+        "</FunctionImport>"
+    )));
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(
+        ctx().await.with_odata_version(ODataVersion::V4),
+    )))
+    .await
+    .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<Function Name="GetFirstTicks">"#,
+        r#"<Parameter Name="count" Type="Edm.Int64"/>"#,
+        r#"<ReturnType Type="Collection(default.tickers_spy)"/>"#,
+        "</Function>"
+    )));
+    assert!(resp.body().contains(
+        r#"<FunctionImport Name="GetFirstTicks" Function="default.GetFirstTicks" EntitySet="tickers.spy"/>"#
+    ));
+
+    let call = |function_call: &'static str| async move {
+        datafusion_odata::handlers::odata_function_handler(
+            axum::Extension(Arc::new(ctx().await)),
+            axum::extract::Path(function_call.to_string()),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+    };
+
+    let resp = call("GetFirstTicks(count=3)").await.unwrap();
+    assert_eq!(resp.body().matches("<entry>").count(), 3);
+    assert!(resp
+        .body()
+        .contains(r#"<link rel="self" title="tickers.spy" href="tickers.spy(2)"/>"#));
+    assert!(resp.body().contains("<d:from_symbol "));
+
+    // The entities of the set hide the same columns wherever they come from
+    let resp = datafusion_odata::handlers::odata_function_handler(
+        axum::Extension(Arc::new(ctx().await.with_visible_columns(&["offset"]))),
+        axum::extract::Path("GetFirstTicks(count=3)".to_string()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.body().matches("<entry>").count(), 3);
+    assert!(resp.body().contains("<d:offset"));
+    assert!(!resp.body().contains("<d:from_symbol "));
+
+    assert!(matches!(
+        call("GetLastTicks(count=3)").await,
+        Err(ODataError::CollectionNotFound(_))
+    ));
+    assert_eq!(
+        call("GetFirstTicks()").await.unwrap_err().to_string(),
+        "Function GetFirstTicks() requires the parameter count"
+    );
+    assert_eq!(
+        call("GetFirstTicks(count=3,limit=1)")
+            .await
+            .unwrap_err()
+            .to_string(),
+        "Function GetFirstTicks() has no parameter limit"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct VerboseErrorsContext {
+    inner: ODataContext,
+    verbose: bool,
+}
+
+focused_context!(VerboseErrorsContext {
+    service {
+        fn verbose_errors(&self) -> bool {
+            self.verbose
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_verbose_errors() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("text", DataType::Utf8, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0])),
+            Arc::new(StringArray::from(vec!["a\0b"])),
+        ],
+    )
+    .unwrap();
+
+    let error_body = |verbose: bool| {
+        let ctx = VerboseErrorsContext {
+            inner: batch_context("scraped", "scraped", batch.clone())
+                .with_on_invalid_xml_chars(OnInvalidXmlChars::Error),
+            verbose,
+        };
+        async move {
+            let err = datafusion_odata::handlers::odata_collection_handler(
+                axum::Extension(Arc::new(ctx)),
+                axum::extract::Query(QueryParamsRaw::default()),
+                axum::http::HeaderMap::new(),
+            )
+            .await
+            .unwrap_err();
+            let resp = axum::response::IntoResponse::into_response(err);
+            assert_eq!(resp.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+    };
+
+    assert_eq!(error_body(false).await, "Internal error");
+    assert_eq!(
+        error_body(true).await,
+        "Internal error: Value of text contains characters not allowed in XML"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone)]
+struct MetadataCacheContext {
+    inner: ODataContext,
+    cache: Arc<MetadataCache>,
+    schema_epoch: u64,
+}
+
+focused_context!(MetadataCacheContext {
+    service {
+        fn metadata_cache(&self) -> Option<Arc<MetadataCache>> {
+            Some(self.cache.clone())
+        }
+
+        fn schema_epoch(&self) -> u64 {
+            self.schema_epoch
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_metadata_cache() {
+    let cache = Arc::new(MetadataCache::default());
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![Field::new(
+            "offset",
+            DataType::Int64,
+            false,
+        )])),
+        vec![Arc::new(Int64Array::from(vec![0]))],
+    )
+    .unwrap();
+
+    let metadata = |ctx: MetadataCacheContext| async move {
+        datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(ctx)))
+            .await
+            .unwrap()
+            .into_body()
+    };
+
+    let ctx = MetadataCacheContext {
+        inner: context("tickers.spy").await,
+        cache: cache.clone(),
+        schema_epoch: 1,
+    };
+    let body = metadata(ctx.clone()).await;
+    assert!(body.contains(r#"<EntitySet Name="tickers.spy""#));
+    assert_eq!(cache.hits(), 0);
+    assert_eq!(metadata(ctx).await, body);
+    assert_eq!(cache.hits(), 1);
+
+    // The collections changed, but the context didn't signal it yet
+    let ctx = MetadataCacheContext {
+        inner: batch_context("prices", "prices", batch),
+        cache: cache.clone(),
+        schema_epoch: 1,
+    };
+    assert_eq!(metadata(ctx.clone()).await, body);
+    assert_eq!(cache.hits(), 2);
+
+    let ctx = MetadataCacheContext {
+        schema_epoch: 2,
+        ..ctx
+    };
+    let body = metadata(ctx).await;
+    assert!(body.contains(r#"<EntitySet Name="prices""#));
+    assert!(!body.contains("tickers.spy"));
+    assert_eq!(cache.hits(), 2);
+
+    // The visibility of the columns depends on the caller
+    let ctx = MetadataCacheContext {
+        inner: context("tickers.spy")
+            .await
+            .with_visible_columns(&["offset"]),
+        cache: cache.clone(),
+        schema_epoch: 3,
+    };
+    let body = metadata(ctx.clone()).await;
+    assert!(!body.contains(r#"<Property Name="from_symbol""#));
+    let ctx = MetadataCacheContext {
+        inner: context("tickers.spy").await,
+        ..ctx
+    };
+    assert!(metadata(ctx)
+        .await
+        .contains(r#"<Property Name="from_symbol""#));
+    assert_eq!(cache.hits(), 2);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_metadata_associations() {
+    let ctx = context("tickers.spy")
+        .await
+        .with_navigation_property("reports", "covid19.canada", true)
+        .with_navigation_property("ticker", "tickers.spy", false);
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(ctx)))
+        .await
+        .unwrap();
+    let body = resp.into_body();
+
+    assert!(body.contains(concat!(
+        r#"<Property Name="volume" Type="Edm.Double" Nullable="true"/>"#,
+        r#"<NavigationProperty Name="reports" Relationship="default.tickers_spy_reports" FromRole="tickers_spy" ToRole="reports"/>"#,
+        r#"<NavigationProperty Name="ticker" Relationship="default.tickers_spy_ticker" FromRole="tickers_spy" ToRole="ticker"/>"#,
+        "</EntityType>",
+    )));
+    assert!(body.contains(concat!(
+        r#"<Association Name="tickers_spy_reports">"#,
+        r#"<End Type="default.tickers_spy" Role="tickers_spy" Multiplicity="*"/>"#,
+        r#"<End Type="default.covid19_canada" Role="reports" Multiplicity="*"/>"#,
+        "</Association>",
+        r#"<Association Name="tickers_spy_ticker">"#,
+        r#"<End Type="default.tickers_spy" Role="tickers_spy" Multiplicity="*"/>"#,
+        r#"<End Type="default.tickers_spy" Role="ticker" Multiplicity="0..1"/>"#,
+        "</Association>",
+        r#"<EntityContainer Name="default" m:IsDefaultEntityContainer="true">"#,
+    )));
+    assert!(body.contains(concat!(
+        r#"<AssociationSet Name="tickers_spy_reports" Association="default.tickers_spy_reports">"#,
+        r#"<End Role="tickers_spy" EntitySet="tickers.spy"/>"#,
+        r#"<End Role="reports" EntitySet="covid19.canada"/>"#,
+        "</AssociationSet>",
+    )));
+
+    let ctx = context("tickers.spy")
+        .await
+        .with_navigation_property("orders", "orders", true);
+    let res =
+        datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(ctx))).await;
+    assert!(matches!(res, Err(ODataError::Internal(_))));
+}
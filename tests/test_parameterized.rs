@@ -0,0 +1,184 @@
+//! Collections whose key part of the address holds query parameters rather than
+//! an entity key, e.g. `SalesByRegion(region='EU')`
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use datafusion::{
+    arrow::{
+        array::{Int64Array, RecordBatch, StringArray},
+        datatypes::{DataType, Field, Schema, SchemaRef},
+    },
+    prelude::*,
+    scalar::ScalarValue,
+};
+use datafusion_odata::{
+    collection::{
+        AppliedQuery, ApplyOptions, CollectionAddr, KeyValue, QueryParams, QueryParamsRaw,
+    },
+    context::{CollectionContext, OnUnsupported},
+    error::ODataError,
+};
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct SalesByRegion {
+    query_ctx: SessionContext,
+    addr: CollectionAddr,
+}
+
+impl SalesByRegion {
+    fn new(collection_path_element: &str) -> Self {
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("offset", DataType::Int64, false),
+                Field::new("region", DataType::Utf8, false),
+                Field::new("amount", DataType::Int64, false),
+            ])),
+            vec![
+                Arc::new(Int64Array::from(vec![0, 1, 2, 3])),
+                Arc::new(StringArray::from(vec!["EU", "US", "EU", "EU"])),
+                Arc::new(Int64Array::from(vec![10, 20, 30, 40])),
+            ],
+        )
+        .unwrap();
+
+        let query_ctx = SessionContext::new();
+        query_ctx.register_batch("sales", batch).unwrap();
+
+        Self {
+            query_ctx,
+            addr: CollectionAddr::decode(collection_path_element).unwrap(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CollectionContext for SalesByRegion {
+    fn addr(&self) -> Result<&CollectionAddr, ODataError> {
+        Ok(&self.addr)
+    }
+
+    fn service_base_url(&self) -> Result<String, ODataError> {
+        Ok("http://example.com/odata/".to_string())
+    }
+
+    fn collection_base_url(&self) -> Result<String, ODataError> {
+        Ok(format!(
+            "http://example.com/odata/{}",
+            self.collection_name()?
+        ))
+    }
+
+    fn collection_name(&self) -> Result<String, ODataError> {
+        Ok(self.addr.name.clone())
+    }
+
+    fn is_parameterized(&self) -> bool {
+        true
+    }
+
+    async fn last_updated_time(&self) -> DateTime<Utc> {
+        DateTime::UNIX_EPOCH
+    }
+
+    async fn schema(&self) -> Result<SchemaRef, ODataError> {
+        Ok(self
+            .query_ctx
+            .table("sales")
+            .await
+            .map_err(ODataError::internal)?
+            .schema()
+            .inner()
+            .clone())
+    }
+
+    async fn query(
+        &self,
+        query: QueryParams,
+        options: &ApplyOptions,
+    ) -> Result<AppliedQuery, ODataError> {
+        let Some(KeyValue::String(region)) = self.addr.parameter("region") else {
+            Err(ODataError::bad_request("Parameter region is required"))?
+        };
+
+        let df = self
+            .query_ctx
+            .sql("SELECT * FROM sales WHERE region = $region")
+            .await
+            .and_then(|df| {
+                df.with_param_values(vec![("region", ScalarValue::Utf8(Some(region.clone())))])
+            })
+            .map_err(ODataError::internal)?;
+
+        // Parameters are already bound, so the key must not filter the entities
+        let addr = CollectionAddr {
+            name: self.addr.name.clone(),
+            key: None,
+        };
+        query.apply(df, &addr, options, 100, usize::MAX)
+    }
+
+    fn on_unsupported_feature(&self) -> OnUnsupported {
+        OnUnsupported::Error
+    }
+}
+
+fn query() -> QueryParamsRaw {
+    QueryParamsRaw {
+        select: Some("amount".to_string()),
+        order_by: Some("amount desc".to_string()),
+        skip: None,
+        skip_token: None,
+        top: Some(2),
+        filter: None,
+        expand: None,
+        format: Some("csv".to_string()),
+        count: None,
+        inline_count: None,
+        apply: None,
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_parameterized_collection() {
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(SalesByRegion::new("SalesByRegion(region='EU')"))),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "amount\n40\n30\n");
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(SalesByRegion::new("SalesByRegion(region='EU')"))),
+        axum::extract::Query(QueryParamsRaw {
+            format: None,
+            ..query()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .starts_with("<?xml version=\"1.0\" encoding=\"utf-8\"?><feed "));
+    assert!(resp
+        .body()
+        .contains("<id>http://example.com/odata/SalesByRegion(3)</id>"));
+    assert_eq!(resp.body().matches("<entry>").count(), 2);
+}
+
+#[tokio::test]
+async fn test_parameterized_collection_missing_parameter() {
+    let res = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(SalesByRegion::new("SalesByRegion(country='FR')"))),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+    assert!(matches!(res, Err(ODataError::BadRequest(_))));
+}
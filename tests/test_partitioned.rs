@@ -0,0 +1,175 @@
+mod shared;
+
+use std::sync::Arc;
+
+use datafusion::{
+    arrow::{
+        array::{Float64Array, Int64Array, RecordBatch, StringArray},
+        datatypes::{DataType, Field, Schema},
+    },
+    dataframe::DataFrameWriteOptions,
+    datasource::{file_format::parquet::ParquetFormat, listing::ListingOptions, MemTable},
+    prelude::*,
+};
+use datafusion_odata::collection::QueryParamsRaw;
+
+use shared::{focused_context, session_context, ODataContext};
+
+///////////////////////////////////////////////////////////////////////////////
+
+// Writes `<dir>/region=east/*.parquet` and `<dir>/region=west/*.parquet`
+async fn write_partitioned_dataset(dir: &std::path::Path) {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("region", DataType::Utf8, false),
+            Field::new("value", DataType::Float64, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1, 2, 3])),
+            Arc::new(StringArray::from(vec!["east", "west", "east", "west"])),
+            Arc::new(Float64Array::from(vec![1.0, 2.0, 3.0, 4.0])),
+        ],
+    )
+    .unwrap();
+
+    let ctx = SessionContext::new();
+    ctx.read_batch(batch)
+        .unwrap()
+        .write_parquet(
+            dir.to_str().unwrap(),
+            DataFrameWriteOptions::new().with_partition_by(vec!["region".to_string()]),
+            None,
+        )
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_partitioned_parquet_collection() {
+    let dir = tempfile::TempDir::new().unwrap();
+    write_partitioned_dataset(dir.path()).await;
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+    // Partition columns become regular properties of the collection
+    let options = ListingOptions::new(Arc::new(ParquetFormat::default()))
+        .with_file_extension(".parquet")
+        .with_table_partition_cols(vec![("region".to_string(), DataType::Utf8)]);
+    let query_ctx = SessionContext::new();
+    query_ctx
+        .register_listing_table(
+            "measurements",
+            format!("{}/", dir.path().display()),
+            options,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    let ctx = Arc::new(session_context("measurements", query_ctx));
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx.clone()))
+        .await
+        .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<Property Name="offset" Type="Edm.Int64" Nullable="false"/>"#,
+        r#"<Property Name="value" Type="Edm.Double" Nullable="false"/>"#,
+        r#"<Property Name="region" Type="Edm.String" Nullable="false"/>"#,
+    )));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: Some("offset,region".to_string()),
+            order_by: Some("offset".to_string()),
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: Some("csv".to_string()),
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        *resp.body(),
+        "offset,region\n0,east\n1,west\n2,east\n3,west\n"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct StableOrderContext {
+    inner: ODataContext,
+}
+
+focused_context!(StableOrderContext {
+    collection {
+        fn stable_order(&self) -> bool {
+            true
+        }
+    }
+});
+
+#[tokio::test]
+async fn test_stable_order_across_partitions() {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("offset", DataType::Int64, false),
+        Field::new("value", DataType::Float64, false),
+    ]));
+    let partition = |offsets: Vec<i64>, values: Vec<f64>| {
+        vec![RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int64Array::from(offsets)),
+                Arc::new(Float64Array::from(values)),
+            ],
+        )
+        .unwrap()]
+    };
+    let table = MemTable::try_new(
+        schema.clone(),
+        vec![
+            partition(vec![4, 5], vec![1.0, 2.0]),
+            partition(vec![2, 3], vec![2.0, 1.0]),
+            partition(vec![0, 1], vec![1.0, 2.0]),
+        ],
+    )
+    .unwrap();
+    let query_ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(4));
+    query_ctx.register_table("values", Arc::new(table)).unwrap();
+    let ctx = Arc::new(StableOrderContext {
+        inner: session_context("values", query_ctx),
+    });
+
+    let query = |order_by: Option<&str>| {
+        datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(ctx.clone()),
+            axum::extract::Query(QueryParamsRaw {
+                order_by: order_by.map(str::to_string),
+                format: Some("csv".to_string()),
+                ..Default::default()
+            }),
+            axum::http::HeaderMap::new(),
+        )
+    };
+
+    for _ in 0..10 {
+        let resp = query(None).await.unwrap();
+        assert_eq!(
+            *resp.body(),
+            "offset,value\n0,1.0\n1,2.0\n2,2.0\n3,1.0\n4,1.0\n5,2.0\n"
+        );
+
+        let resp = query(Some("value desc")).await.unwrap();
+        assert_eq!(
+            *resp.body(),
+            "offset,value\n1,2.0\n2,2.0\n5,2.0\n0,1.0\n3,1.0\n4,1.0\n"
+        );
+    }
+}
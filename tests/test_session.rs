@@ -0,0 +1,311 @@
+mod shared;
+
+use std::{collections::HashMap, sync::Arc};
+
+use datafusion::{
+    arrow::{
+        array::{Int64Array, RecordBatch, StringArray},
+        datatypes::{DataType, Field, Schema},
+    },
+    prelude::*,
+    sql::TableReference,
+};
+use datafusion_odata::{
+    collection::{CollectionAddr, QueryParamsRaw},
+    context::CollectionContext,
+    error::ODataError,
+    session::ODataSessionContext,
+};
+
+///////////////////////////////////////////////////////////////////////////////
+
+async fn session() -> ODataSessionContext {
+    let ctx = SessionContext::new();
+    ctx.register_parquet(
+        "tickers.spy",
+        "examples/data/tickers.parquet",
+        ParquetReadOptions {
+            file_extension: ".parquet",
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    ODataSessionContext::new(ctx, "http://example.com/odata/").with_collections(HashMap::from([(
+        "Tickers".to_string(),
+        TableReference::bare("tickers.spy"),
+    )]))
+}
+
+fn query() -> QueryParamsRaw {
+    QueryParamsRaw {
+        select: Some("offset,close".to_string()),
+        order_by: None,
+        skip: None,
+        skip_token: None,
+        top: Some(2),
+        filter: None,
+        expand: None,
+        format: None,
+        count: None,
+        inline_count: None,
+        apply: None,
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_aliased_collection_is_listed() {
+    let ctx = Arc::new(session().await);
+    let resp = datafusion_odata::handlers::odata_service_handler(
+        axum::Extension(ctx),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+
+    assert!(resp.body().contains("<collection href=\"Tickers\">"));
+    assert!(!resp.body().contains("tickers.spy"));
+}
+
+#[tokio::test]
+async fn test_aliased_collection_is_queried() {
+    let ctx = Arc::new(session().await.with_addr(CollectionAddr {
+        name: "Tickers".to_string(),
+        key: None,
+    }));
+
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx.clone()))
+        .await
+        .unwrap();
+    assert!(resp
+        .body()
+        .contains("<EntitySet Name=\"Tickers\" EntityType=\"default.Tickers\"/>"));
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp
+        .body()
+        .contains("<id>http://example.com/odata/Tickers(0)</id>"));
+    assert_eq!(resp.body().matches("<entry>").count(), 2);
+}
+
+#[tokio::test]
+async fn test_table_name_is_hidden_by_alias() {
+    let ctx = Arc::new(session().await.with_addr(CollectionAddr {
+        name: "tickers.spy".to_string(),
+        key: None,
+    }));
+
+    let res = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+    assert!(matches!(res, Err(ODataError::CollectionNotFound(_))));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_plan_cache_reuses_plans() {
+    const REQUESTS: usize = 200;
+
+    let tickers = CollectionAddr {
+        name: "Tickers".to_string(),
+        key: None,
+    };
+    let repeated_query = || QueryParamsRaw {
+        filter: Some("close gt 130".parse().unwrap()),
+        order_by: Some("close desc".to_string()),
+        ..query()
+    };
+
+    // Planning only, see `benches/plan_cache.rs` for the time it saves
+    let plan = |ctx: ODataSessionContext| async move {
+        for _ in 0..REQUESTS {
+            ctx.query(
+                repeated_query().decode().unwrap(),
+                &ctx.apply_options().await.unwrap(),
+            )
+            .await
+            .unwrap();
+        }
+        ctx.plan_cache_hits()
+    };
+
+    assert_eq!(plan(session().await.with_addr(tickers.clone())).await, 0);
+    assert_eq!(
+        plan(
+            session()
+                .await
+                .with_addr(tickers.clone())
+                .with_plan_cache(16)
+        )
+        .await,
+        REQUESTS - 1
+    );
+
+    // Cached plans produce the same results
+    let ctx = Arc::new(session().await.with_addr(tickers).with_plan_cache(16));
+    let mut bodies = Vec::new();
+    for _ in 0..2 {
+        let resp = datafusion_odata::handlers::odata_collection_handler(
+            axum::Extension(ctx.clone()),
+            axum::extract::Query(QueryParamsRaw {
+                format: Some("csv".to_string()),
+                ..repeated_query()
+            }),
+            axum::http::HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        bodies.push(resp.into_body());
+    }
+    assert_eq!(ctx.plan_cache_hits(), 1);
+    assert_eq!(bodies[0], bodies[1]);
+    assert_eq!(bodies[0], "offset,close\n5579,477.71\n5580,477.55\n");
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_plan_cache_reuses_plans_of_now_filters() {
+    let ctx = session()
+        .await
+        .with_addr(CollectionAddr {
+            name: "Tickers".to_string(),
+            key: None,
+        })
+        .with_plan_cache(16);
+    let now_query = || QueryParamsRaw {
+        filter: Some("event_time lt now()".parse().unwrap()),
+        ..query()
+    };
+
+    let first = ctx
+        .query(
+            now_query().decode().unwrap(),
+            &ctx.apply_options().await.unwrap(),
+        )
+        .await
+        .unwrap();
+    let second = ctx
+        .query(
+            now_query().decode().unwrap(),
+            &ctx.apply_options().await.unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(ctx.plan_cache_hits(), 1);
+    assert_eq!(
+        first.df.collect().await.unwrap(),
+        second.df.collect().await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_plan_cache_evicts_least_recently_used() {
+    let ctx = session()
+        .await
+        .with_addr(CollectionAddr {
+            name: "Tickers".to_string(),
+            key: None,
+        })
+        .with_plan_cache(2);
+    let query_top = |top| QueryParamsRaw {
+        top: Some(top),
+        ..query()
+    };
+    let run = |top| {
+        let ctx = &ctx;
+        async move {
+            let hits = ctx.plan_cache_hits();
+            ctx.query(
+                query_top(top).decode().unwrap(),
+                &ctx.apply_options().await.unwrap(),
+            )
+            .await
+            .unwrap();
+            ctx.plan_cache_hits() > hits
+        }
+    };
+
+    assert!(!run(1).await);
+    assert!(!run(2).await);
+    // Refreshes 1, so that 2 is evicted to make room for 3
+    assert!(run(1).await);
+    assert!(!run(3).await);
+    assert!(run(1).await);
+    assert!(run(3).await);
+    assert!(!run(2).await);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_in_memory_collection() {
+    let batch = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("symbol", DataType::Utf8, false),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1])),
+            Arc::new(StringArray::from(vec!["SPY", "QQQ"])),
+        ],
+    )
+    .unwrap();
+    let ctx = ODataSessionContext::from_batches("http://example.com/odata/", [("symbols", batch)])
+        .unwrap();
+    let addr = |key| CollectionAddr::decode(key).unwrap();
+
+    let resp =
+        datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(ctx.clone())))
+            .await
+            .unwrap();
+    assert!(resp.body().contains(concat!(
+        r#"<EntityType Name="symbols"><Key><PropertyRef Name="offset"/></Key>"#,
+        r#"<Property Name="offset" Type="Edm.Int64" Nullable="false"/>"#,
+        r#"<Property Name="symbol" Type="Edm.String" Nullable="false"/>"#,
+    )));
+
+    let query = || QueryParamsRaw {
+        select: None,
+        top: None,
+        format: Some("csv".to_string()),
+        ..query()
+    };
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(ctx.clone().with_addr(addr("symbols")))),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*resp.body(), "offset,symbol\n0,SPY\n1,QQQ\n");
+
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(ctx.with_addr(addr("symbols(1)")))),
+        axum::extract::Query(QueryParamsRaw {
+            format: None,
+            ..query()
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(resp.body().contains("<entry "));
+    assert!(resp
+        .body()
+        .contains(r#"<d:symbol m:type="Edm.String">QQQ</d:symbol>"#));
+}
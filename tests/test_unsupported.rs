@@ -0,0 +1,253 @@
+mod shared;
+
+use std::sync::Arc;
+
+use datafusion::arrow::{
+    array::{
+        Array, ArrayRef, Int32Array, Int32Builder, Int64Array, IntervalDayTimeArray, MapBuilder,
+        RecordBatch, StringArray, StringBuilder, StructArray,
+    },
+    datatypes::{DataType, Field, Fields, IntervalDayTime, IntervalUnit, Schema},
+};
+use datafusion_odata::{collection::QueryParamsRaw, context::OnUnsupported};
+use indoc::indoc;
+
+use shared::batch_context;
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn struct_batch() -> RecordBatch {
+    let fields = Fields::from(vec![
+        Field::new("a", DataType::Int32, false),
+        Field::new("b", DataType::Utf8, false),
+    ]);
+    let point = StructArray::new(
+        fields.clone(),
+        vec![
+            Arc::new(Int32Array::from(vec![1])) as ArrayRef,
+            Arc::new(StringArray::from(vec!["x"])) as ArrayRef,
+        ],
+        None,
+    );
+
+    RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("point", DataType::Struct(fields), false),
+        ])),
+        vec![Arc::new(Int64Array::from(vec![0])), Arc::new(point)],
+    )
+    .unwrap()
+}
+
+fn map_batch() -> RecordBatch {
+    let mut tags = MapBuilder::new(None, StringBuilder::new(), Int32Builder::new());
+    tags.keys().append_value("x");
+    tags.values().append_value(1);
+    tags.keys().append_value("y");
+    tags.values().append_null();
+    tags.append(true).unwrap();
+    let tags = tags.finish();
+
+    RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("tags", tags.data_type().clone(), false),
+        ])),
+        vec![Arc::new(Int64Array::from(vec![0])), Arc::new(tags)],
+    )
+    .unwrap()
+}
+
+fn interval_batch() -> RecordBatch {
+    RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("offset", DataType::Int64, false),
+            Field::new("period", DataType::Interval(IntervalUnit::DayTime), true),
+        ])),
+        vec![
+            Arc::new(Int64Array::from(vec![0, 1])),
+            Arc::new(IntervalDayTimeArray::from(vec![
+                Some(IntervalDayTime::new(3, 5000)),
+                None,
+            ])),
+        ],
+    )
+    .unwrap()
+}
+
+async fn get_properties(on_unsupported: OnUnsupported) -> String {
+    get_batch_properties(struct_batch(), on_unsupported, axum::http::HeaderMap::new()).await
+}
+
+async fn get_batch_properties(
+    batch: RecordBatch,
+    on_unsupported: OnUnsupported,
+    headers: axum::http::HeaderMap,
+) -> String {
+    let ctx = batch_context("points", "points", batch).with_on_unsupported(on_unsupported);
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(Arc::new(ctx)),
+        axum::extract::Query(QueryParamsRaw {
+            select: None,
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: None,
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        headers,
+    )
+    .await
+    .unwrap();
+
+    let body = resp.body();
+    if !body.starts_with('<') {
+        return body.clone();
+    }
+    let start = body.find("<m:properties>").unwrap();
+    let end = body.find("</m:properties>").unwrap() + "</m:properties>".len();
+    body[start..end].to_string()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_unsupported_struct_stringify() {
+    assert_eq!(
+        get_properties(OnUnsupported::Stringify).await,
+        indoc!(
+            r#"
+            <m:properties>
+            <d:offset m:type="Edm.Int64">0</d:offset>
+            <d:point m:type="Edm.String">{"a":1,"b":"x"}</d:point>
+            </m:properties>
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_unsupported_struct_null() {
+    assert_eq!(
+        get_properties(OnUnsupported::Null).await,
+        indoc!(
+            r#"
+            <m:properties>
+            <d:offset m:type="Edm.Int64">0</d:offset>
+            <d:point m:type="Edm.String">null</d:point>
+            </m:properties>
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+#[tokio::test]
+async fn test_unsupported_struct_null_is_nullable() {
+    let ctx =
+        batch_context("points", "points", struct_batch()).with_on_unsupported(OnUnsupported::Null);
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(Arc::new(ctx)))
+        .await
+        .unwrap();
+    assert!(
+        resp.body()
+            .contains(r#"<Property Name="point" Type="Edm.String" Nullable="true"/>"#),
+        "{}",
+        resp.body()
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_unsupported_struct_warn_skips() {
+    assert_eq!(
+        get_properties(OnUnsupported::Warn).await,
+        indoc!(
+            r#"
+            <m:properties>
+            <d:offset m:type="Edm.Int64">0</d:offset>
+            </m:properties>
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_unsupported_map_stringify() {
+    assert_eq!(
+        get_batch_properties(
+            map_batch(),
+            OnUnsupported::Stringify,
+            axum::http::HeaderMap::new()
+        )
+        .await,
+        indoc!(
+            r#"
+            <m:properties>
+            <d:offset m:type="Edm.Int64">0</d:offset>
+            <d:tags m:type="Edm.String">{"x":1,"y":null}</d:tags>
+            </m:properties>
+            "#
+        )
+        .replace('\n', "")
+    );
+}
+
+#[tokio::test]
+async fn test_unsupported_struct_stringify_json() {
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert(
+        axum::http::header::ACCEPT,
+        "application/json".parse().unwrap(),
+    );
+    let body = get_batch_properties(struct_batch(), OnUnsupported::Stringify, headers).await;
+    assert!(
+        body.contains(r#""point":"{\"a\":1,\"b\":\"x\"}""#),
+        "{body}"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_unsupported_interval_stringify() {
+    assert_eq!(
+        get_batch_properties(
+            interval_batch(),
+            OnUnsupported::Stringify,
+            axum::http::HeaderMap::new()
+        )
+        .await,
+        indoc!(
+            r#"
+            <m:properties>
+            <d:offset m:type="Edm.Int64">0</d:offset>
+            <d:period m:type="Edm.String">3 days 5.000 secs</d:period>
+            </m:properties>
+            "#
+        )
+        .replace('\n', "")
+    );
+
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert(
+        axum::http::header::ACCEPT,
+        "application/json".parse().unwrap(),
+    );
+    let body = get_batch_properties(interval_batch(), OnUnsupported::Stringify, headers).await;
+    assert!(body.contains(r#""period":"3 days 5.000 secs""#), "{body}");
+    assert!(body.contains(r#""period":null"#), "{body}");
+}
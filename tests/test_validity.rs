@@ -0,0 +1,348 @@
+//! Structural checks of the generated documents, as opposed to byte-exact
+//! comparisons, to make sure the output is consumable by real OData clients
+
+mod shared;
+
+use std::{collections::HashSet, sync::Arc};
+
+use datafusion::arrow::{
+    array::{Int64Array, RecordBatch, StringArray},
+    datatypes::{DataType, Field, Schema},
+};
+use datafusion_odata::{collection::QueryParamsRaw, context::OnInvalidXmlChars};
+use quick_xml::events::Event;
+
+use shared::{batch_context, context, fixture};
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn req_attr(&self, name: &str) -> &str {
+        self.attr(name)
+            .unwrap_or_else(|| panic!("<{}> is missing attribute {name}", self.name))
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+        self.children.iter().filter(move |c| c.name == name)
+    }
+
+    fn child<'a>(&'a self, name: &'a str) -> &'a Element {
+        let mut it = self.children(name);
+        let child = it
+            .next()
+            .unwrap_or_else(|| panic!("<{}> has no <{name}> child", self.name));
+        assert!(it.next().is_none(), "<{}> has multiple <{name}>", self.name);
+        child
+    }
+}
+
+/// Parses the document into a tree, panicking if it is not well-formed
+fn parse_xml(xml: &str) -> Element {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    reader.config_mut().check_end_names = true;
+
+    let new_element = |e: &quick_xml::events::BytesStart| Element {
+        name: String::from_utf8(e.name().as_ref().to_vec()).unwrap(),
+        attrs: e
+            .attributes()
+            .map(|a| {
+                let a = a.expect("malformed attribute");
+                (
+                    String::from_utf8(a.key.as_ref().to_vec()).unwrap(),
+                    a.unescape_value().unwrap().into_owned(),
+                )
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    let mut stack: Vec<Element> = vec![Element::default()];
+    loop {
+        match reader.read_event().expect("malformed XML") {
+            Event::Start(e) => stack.push(new_element(&e)),
+            Event::Empty(e) => stack.last_mut().unwrap().children.push(new_element(&e)),
+            Event::End(_) => {
+                let el = stack.pop().unwrap();
+                stack.last_mut().unwrap().children.push(el);
+            }
+            Event::Text(t) => stack
+                .last_mut()
+                .unwrap()
+                .text
+                .push_str(&t.unescape().expect("malformed text")),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    let mut document = stack.pop().unwrap();
+    assert!(stack.is_empty(), "unclosed elements");
+    assert_eq!(
+        document.children.len(),
+        1,
+        "document must have a single root"
+    );
+    document.children.pop().unwrap()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn assert_valid_csdl(xml: &str) {
+    let edmx = parse_xml(xml);
+    assert_eq!(edmx.name, "edmx:Edmx");
+    edmx.req_attr("xmlns:edmx");
+    edmx.req_attr("Version");
+
+    let ds = edmx.child("edmx:DataServices");
+    ds.req_attr("xmlns:m");
+    ds.req_attr("m:DataServiceVersion");
+
+    let schemas: Vec<_> = ds.children("Schema").collect();
+    assert!(!schemas.is_empty(), "no schemas");
+
+    let mut qualified_types = HashSet::new();
+    for schema in &schemas {
+        let namespace = schema.req_attr("Namespace");
+        schema.req_attr("xmlns");
+
+        for typ in schema.children("EntityType") {
+            let name = typ.req_attr("Name");
+
+            let mut properties = HashSet::new();
+            for prop in typ.children("Property") {
+                assert!(
+                    properties.insert(prop.req_attr("Name")),
+                    "duplicate property in {name}"
+                );
+                assert!(prop.req_attr("Type").starts_with("Edm."));
+                assert!(matches!(prop.req_attr("Nullable"), "true" | "false"));
+            }
+
+            let key = typ.child("Key");
+            let refs: Vec<_> = key.children("PropertyRef").collect();
+            assert!(!refs.is_empty(), "empty key in {name}");
+            for r in refs {
+                let key_name = r.req_attr("Name");
+                assert!(
+                    properties.contains(key_name),
+                    "key {key_name} of {name} is not a property"
+                );
+            }
+
+            qualified_types.insert(format!("{namespace}.{name}"));
+        }
+    }
+
+    for schema in &schemas {
+        for container in schema.children("EntityContainer") {
+            container.req_attr("Name");
+            for set in container.children("EntitySet") {
+                set.req_attr("Name");
+                let typ = set.req_attr("EntityType");
+                assert!(qualified_types.contains(typ), "unknown entity type {typ}");
+            }
+        }
+    }
+}
+
+fn assert_valid_atom_feed(xml: &str) {
+    let feed = parse_xml(xml);
+    assert_eq!(feed.name, "feed");
+    assert_eq!(feed.req_attr("xmlns"), "http://www.w3.org/2005/Atom");
+    feed.req_attr("xmlns:d");
+    feed.req_attr("xmlns:m");
+    assert!(!feed.child("id").text.is_empty());
+    feed.child("title");
+    feed.child("updated");
+
+    for entry in feed.children("entry") {
+        assert!(!entry.child("id").text.is_empty());
+        entry.child("category").req_attr("term");
+        entry.child("updated");
+        entry.child("author");
+
+        let content = entry.child("content");
+        assert_eq!(content.req_attr("type"), "application/xml");
+        for prop in content.child("m:properties").children.iter() {
+            assert!(prop.name.starts_with("d:"), "unexpected {}", prop.name);
+            prop.req_attr("m:type");
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_metadata_is_valid_csdl() {
+    let ctx = fixture("tickers.spy").await;
+    let resp = datafusion_odata::handlers::odata_metadata_handler(axum::Extension(ctx))
+        .await
+        .unwrap();
+    assert_valid_csdl(resp.body());
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_collection_is_valid_atom() {
+    let ctx = fixture("tickers.spy").await;
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: None,
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: Some(10),
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_valid_atom_feed(resp.body());
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Resolves a link against `xml:base` the way clients do (RFC 3986, for the
+/// simple relative forms used in feeds)
+fn resolve(base: &str, href: &str) -> String {
+    if href.starts_with("http://") || href.starts_with("https://") {
+        href.to_string()
+    } else {
+        format!("{}{href}", &base[..=base.rfind('/').unwrap()])
+    }
+}
+
+#[tokio::test]
+async fn test_feed_links_resolve_to_ids() {
+    let ctx = Arc::new(
+        context("tickers.spy")
+            .await
+            .with_service_base_url("http://example.com/odata/"),
+    );
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(QueryParamsRaw {
+            select: None,
+            order_by: None,
+            skip: None,
+            skip_token: None,
+            top: Some(3),
+            filter: None,
+            expand: None,
+            format: None,
+            count: None,
+            inline_count: None,
+            apply: None,
+        }),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+
+    let feed = parse_xml(resp.body());
+    let base = feed.req_attr("xml:base");
+    let link = |el: &Element, rel: &str| {
+        let link = el
+            .children("link")
+            .find(|l| l.attr("rel") == Some(rel))
+            .unwrap_or_else(|| panic!("no {rel} link"));
+        resolve(base, link.req_attr("href"))
+    };
+
+    assert_eq!(
+        feed.child("id").text,
+        "http://example.com/odata/tickers.spy"
+    );
+    assert_eq!(link(&feed, "self"), feed.child("id").text);
+
+    let entries: Vec<_> = feed.children("entry").collect();
+    assert_eq!(entries.len(), 3);
+    for entry in entries {
+        assert_eq!(link(entry, "self"), entry.child("id").text);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[tokio::test]
+async fn test_control_characters_are_stripped() {
+    let batch = || {
+        RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("offset", DataType::Int64, false),
+                Field::new("text", DataType::Utf8, false),
+            ])),
+            vec![
+                Arc::new(Int64Array::from(vec![0])),
+                Arc::new(StringArray::from(vec!["a\0b\u{1}c\td"])),
+            ],
+        )
+        .unwrap()
+    };
+    let query = || QueryParamsRaw {
+        select: None,
+        order_by: None,
+        skip: None,
+        skip_token: None,
+        top: None,
+        filter: None,
+        expand: None,
+        format: None,
+        count: None,
+        inline_count: None,
+        apply: None,
+    };
+
+    let ctx = Arc::new(batch_context("scraped", "scraped", batch()));
+    let resp = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+
+    assert!(!resp.body().contains('\0'));
+    assert_valid_atom_feed(resp.body());
+    assert!(resp
+        .body()
+        .contains("<d:text m:type=\"Edm.String\">abc\td</d:text>"));
+
+    let ctx = Arc::new(
+        batch_context("scraped", "scraped", batch())
+            .with_on_invalid_xml_chars(OnInvalidXmlChars::Error),
+    );
+    let res = datafusion_odata::handlers::odata_collection_handler(
+        axum::Extension(ctx),
+        axum::extract::Query(query()),
+        axum::http::HeaderMap::new(),
+    )
+    .await;
+    assert!(matches!(
+        res,
+        Err(datafusion_odata::error::ODataError::Internal(_))
+    ));
+}